@@ -0,0 +1,361 @@
+//! Typed RPC clients for Twinleaf I/O devices.
+//!
+//! `#[derive(TioRpcs)]` adds one method per RPC to a struct wrapping an RPC
+//! port (`twinleaf::tio::proxy::Port`, or anything with the same `rpc`
+//! method), so that RPC names and types are checked at compile time:
+//!
+//! ```ignore
+//! use twinleaf::tio::proxy;
+//! use twinleaf_derive::TioRpcs;
+//!
+//! #[derive(TioRpcs)]
+//! #[rpcs(prefix = "test")]
+//! #[rpc("test.frequency": f64, rw)]
+//! #[rpc("test.status": u8, r)]
+//! #[rpc("test.go": (), action)]
+//! struct TestDevice {
+//!     port: proxy::Port,
+//! }
+//!
+//! // dev.frequency()?, dev.set_frequency(12.5)?, dev.status()?, dev.go()?
+//! ```
+//!
+//! RPCs can also be read from a file, relative to the crate root, with
+//! `#[rpcs(file = "vmr.rpcs")]`. Both the RPC list cache format
+//! (`<meta hex> <name>` lines) and the output of `tio rpc list` are
+//! accepted. Only RPCs under the `prefix`, if any, are included, and the
+//! prefix is left out of method names. RPCs of unknown type (meta `0000` in
+//! a cache file, `???` in a listing) are left out.
+//!
+//! Getters are named after the RPC, setters get a `set_` prefix and return
+//! the value read back by the device. Actions take no argument. With
+//! `#[rpcs(mutable)]` methods take `&mut self`, for ports such as
+//! `twinleaf::device::Device`.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitStr, Token, Type};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Access {
+    Read,
+    Write,
+    ReadWrite,
+    Action,
+}
+
+struct RpcDef {
+    name: String,
+    ty: Type,
+    access: Access,
+    span: Span,
+}
+
+/// `#[rpc("name": type, access)]`
+impl Parse for RpcDef {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name: LitStr = input.parse()?;
+        input.parse::<Token![:]>()?;
+        let ty: Type = input.parse()?;
+        input.parse::<Token![,]>()?;
+        let access: Ident = input.parse()?;
+        let access = match access.to_string().as_str() {
+            "r" => Access::Read,
+            "w" => Access::Write,
+            "rw" => Access::ReadWrite,
+            "action" => Access::Action,
+            _ => {
+                return Err(syn::Error::new(
+                    access.span(),
+                    "expected one of: r, w, rw, action",
+                ))
+            }
+        };
+        Ok(RpcDef {
+            name: name.value(),
+            ty,
+            access,
+            span: name.span(),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Options {
+    prefix: Option<String>,
+    file: Option<LitStr>,
+    port: Option<Ident>,
+    mutable: bool,
+}
+
+/// Type and access of an RPC from its `rpc.info` metadata, `None` for RPCs
+/// without a plain value type. Meta 0 is an RPC of unknown type.
+fn rpc_from_meta(meta: u16) -> Option<(&'static str, Access)> {
+    if meta == 0 {
+        return None;
+    }
+    let size = (meta >> 4) & 0xF;
+    let ty = match (meta & 0xF, size) {
+        (0, 0) | (1, 0) => "()",
+        (0, 1) => "u8",
+        (0, 2) => "u16",
+        (0, 4) => "u32",
+        (0, 8) => "u64",
+        (1, 1) => "i8",
+        (1, 2) => "i16",
+        (1, 4) => "i32",
+        (1, 8) => "i64",
+        (2, 4) => "f32",
+        (2, 8) => "f64",
+        (3, _) => "String",
+        _ => return None,
+    };
+    rpc_access(ty, (meta & 0x0100) != 0, (meta & 0x0200) != 0).map(|a| (ty, a))
+}
+
+/// Whether `first` is the permission column of `tio rpc list`, such as
+/// `RW-`, `--P` or `???`.
+fn is_listing_perm(first: &str) -> bool {
+    let perm = first.as_bytes();
+    first == "???"
+        || (perm.len() == 3
+            && matches!(perm[0], b'R' | b'-')
+            && matches!(perm[1], b'W' | b'-')
+            && matches!(perm[2], b'P' | b'-'))
+}
+
+/// Type and access of an RPC from its `tio rpc list` description, whose
+/// permissions passed `is_listing_perm`.
+fn rpc_from_listing(perm: &str, ty: &str) -> Option<(&'static str, Access)> {
+    if perm == "???" {
+        return None;
+    }
+    let ty = match ty {
+        "" => "()",
+        "u8" => "u8",
+        "u16" => "u16",
+        "u32" => "u32",
+        "u64" => "u64",
+        "i8" => "i8",
+        "i16" => "i16",
+        "i32" => "i32",
+        "i64" => "i64",
+        "f32" => "f32",
+        "f64" => "f64",
+        t if t.starts_with("string") => "String",
+        _ => return None,
+    };
+    let perm = perm.as_bytes();
+    rpc_access(ty, perm[0] == b'R', perm[1] == b'W').map(|a| (ty, a))
+}
+
+fn rpc_access(ty: &str, readable: bool, writable: bool) -> Option<Access> {
+    match (ty, readable, writable) {
+        ("()", _, _) => Some(Access::Action),
+        (_, true, true) => Some(Access::ReadWrite),
+        (_, true, false) => Some(Access::Read),
+        (_, false, true) => Some(Access::Write),
+        _ => None,
+    }
+}
+
+fn load_rpc_file(file: &LitStr) -> syn::Result<(Vec<RpcDef>, String)> {
+    let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let path = std::path::Path::new(&dir).join(file.value());
+    let text = std::fs::read_to_string(&path).map_err(|e| {
+        syn::Error::new(
+            file.span(),
+            format!("cannot read {}: {}", path.display(), e),
+        )
+    })?;
+
+    let mut rpcs = vec![];
+    for line in text.lines() {
+        let line = line.trim();
+        let (first, rest) = match line.split_once(' ') {
+            Some(x) => x,
+            // Blank lines, or the checksum at the end of a cache file.
+            None => continue,
+        };
+        let rest = rest.trim();
+        let parsed = if let Ok(meta) = u16::from_str_radix(first, 16) {
+            rpc_from_meta(meta).map(|x| (rest.to_string(), x))
+        } else if is_listing_perm(first) {
+            // tio rpc list: "RW- name(type)"
+            let (name, ty) = match rest.split_once('(') {
+                Some((name, ty)) => (name, ty.trim_end_matches(')')),
+                None => (rest, ""),
+            };
+            rpc_from_listing(first, ty).map(|x| (name.to_string(), x))
+        } else {
+            return Err(syn::Error::new(
+                file.span(),
+                format!("unrecognized line in RPC list: {:?}", line),
+            ));
+        };
+        if let Some((name, (ty, access))) = parsed {
+            rpcs.push(RpcDef {
+                name,
+                ty: syn::parse_str(ty)?,
+                access,
+                span: file.span(),
+            });
+        }
+    }
+    Ok((rpcs, path.display().to_string()))
+}
+
+fn parse_options(input: &DeriveInput) -> syn::Result<(Options, Vec<RpcDef>)> {
+    let mut options = Options::default();
+    let mut rpcs = vec![];
+    for attr in &input.attrs {
+        if attr.path().is_ident("rpc") {
+            rpcs.push(attr.parse_args::<RpcDef>()?);
+        } else if attr.path().is_ident("rpcs") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("prefix") {
+                    let prefix: LitStr = meta.value()?.parse()?;
+                    options.prefix = Some(prefix.value().trim_end_matches('.').to_string());
+                } else if meta.path.is_ident("file") {
+                    options.file = Some(meta.value()?.parse()?);
+                } else if meta.path.is_ident("port") {
+                    let port: LitStr = meta.value()?.parse()?;
+                    options.port = Some(port.parse()?);
+                } else if meta.path.is_ident("mutable") {
+                    options.mutable = true;
+                } else {
+                    return Err(meta.error("expected one of: prefix, file, port, mutable"));
+                }
+                Ok(())
+            })?;
+        }
+    }
+    Ok((options, rpcs))
+}
+
+/// Method name for an RPC, relative to the prefix. `None` if the RPC is not
+/// under the prefix.
+fn method_name(name: &str, prefix: Option<&str>) -> Option<String> {
+    let rel = match prefix {
+        Some(prefix) => name.strip_prefix(prefix)?.strip_prefix('.')?,
+        None => name,
+    };
+    let ident: String = rel
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        Some(format!("_{}", ident))
+    } else {
+        Some(ident)
+    }
+}
+
+fn make_ident(name: &str, span: Span) -> Ident {
+    syn::parse_str::<Ident>(name).unwrap_or_else(|_| Ident::new_raw(name, span))
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let (options, mut rpcs) = parse_options(&input)?;
+    let mut tracked = vec![];
+    if let Some(file) = &options.file {
+        let (from_file, path) = load_rpc_file(file)?;
+        rpcs.extend(from_file);
+        tracked.push(path);
+    }
+
+    let port = match (&options.port, &input.data) {
+        (Some(port), _) => quote!(#port),
+        (None, Data::Struct(data)) => match &data.fields {
+            Fields::Named(fields) if fields.named.len() == 1 => {
+                let field = fields.named[0].ident.as_ref().unwrap();
+                quote!(#field)
+            }
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => quote!(0),
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "select the RPC port field with #[rpcs(port = \"field\")]",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "TioRpcs can only be derived for structs",
+            ))
+        }
+    };
+    let receiver = if options.mutable {
+        quote!(&mut self)
+    } else {
+        quote!(&self)
+    };
+    let error = quote!(::twinleaf::tio::proxy::RpcError);
+
+    let mut methods = vec![];
+    for rpc in &rpcs {
+        let Some(base) = method_name(&rpc.name, options.prefix.as_deref()) else {
+            if options.file.is_some() {
+                continue;
+            }
+            return Err(syn::Error::new(rpc.span, "RPC is not under the prefix"));
+        };
+        let name = &rpc.name;
+        let ty = &rpc.ty;
+        let getter = make_ident(&base, rpc.span);
+        let setter = format_ident!("set_{}", base, span = rpc.span);
+        let is_string = matches!(ty, Type::Path(p) if p.path.is_ident("String"));
+        let arg_ty = if is_string { quote!(&str) } else { quote!(#ty) };
+
+        if matches!(rpc.access, Access::Read | Access::ReadWrite) {
+            let doc = format!("Read `{}`.", name);
+            methods.push(quote! {
+                #[doc = #doc]
+                pub fn #getter(#receiver) -> ::std::result::Result<#ty, #error> {
+                    self.#port.rpc::<(), #ty>(#name, ())
+                }
+            });
+        }
+        if matches!(rpc.access, Access::Write | Access::ReadWrite) {
+            let doc = format!("Set `{}`, returning the new value.", name);
+            methods.push(quote! {
+                #[doc = #doc]
+                pub fn #setter(#receiver, value: #arg_ty) -> ::std::result::Result<#ty, #error> {
+                    self.#port.rpc::<#arg_ty, #ty>(#name, value)
+                }
+            });
+        }
+        if rpc.access == Access::Action {
+            let doc = format!("Call `{}`.", name);
+            methods.push(quote! {
+                #[doc = #doc]
+                pub fn #getter(#receiver) -> ::std::result::Result<(), #error> {
+                    self.#port.rpc::<(), ()>(#name, ())
+                }
+            });
+        }
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        // Rebuild when the RPC list file changes.
+        #(const _: &[u8] = include_bytes!(#tracked);)*
+
+        impl #impl_generics #ident #ty_generics #where_clause {
+            #(#methods)*
+        }
+    })
+}
+
+/// Generate typed methods for device RPCs. See the crate documentation.
+#[proc_macro_derive(TioRpcs, attributes(rpcs, rpc))]
+pub fn derive_tio_rpcs(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
@@ -1,18 +1,23 @@
 use clap::{CommandFactory, Parser};
 use twinleaf_tools::tools::{
+    analyze::run_analyze,
     health::run_health,
     list::list_devices,
     monitor::run_monitor,
     proxy::run_proxy,
     proxy_nmea::run_nmea_proxy,
+    rpc_script::rpc_run,
+    settings::{diff_settings, restore_settings, save_settings},
     tio_test::run_test,
     tool::{
-        dump, firmware_upgrade, list_rpcs, log, log_csv, log_dump, log_hdf, log_inspect,
-        log_metadata, meta_reroute, rpc, rpc_dump,
+        dump, list_rpcs, log, log_csv, log_dump, log_hdf, log_inspect, log_metadata, meta_reroute,
+        rpc, rpc_dump, rpc_watch,
     },
+    upgrade::firmware_upgrade,
 };
 use twinleaf_tools::{
-    Commands, LogSubcommands, MetaSubcommands, ProxySubcommands, RPCSubcommands, TioCli,
+    Commands, LogSubcommands, MetaSubcommands, ProxySubcommands, RPCSubcommands,
+    SettingsSubcommands, TioCli,
 };
 
 fn main() -> eyre::Result<()> {
@@ -34,8 +39,26 @@ fn main() -> eyre::Result<()> {
             fps,
             colors,
             depth,
-        } => run_monitor(tio, fps, colors, depth),
+            filter,
+            calibration,
+            derive,
+            history,
+            history_size,
+            gap_fill,
+        } => run_monitor(
+            tio,
+            fps,
+            colors,
+            depth,
+            filter,
+            calibration,
+            derive,
+            history,
+            history_size,
+            gap_fill,
+        ),
         Commands::Health(health_cli) => run_health(health_cli),
+        Commands::Analyze(analyze_cli) => run_analyze(analyze_cli),
         Commands::Rpc {
             tio,
             subcommands,
@@ -51,6 +74,20 @@ fn main() -> eyre::Result<()> {
                 rpc_name,
                 capture,
             }) => rpc_dump(&tio, rpc_name, capture),
+            Some(RPCSubcommands::Watch {
+                tio,
+                rpcs,
+                interval,
+                timestamp_format,
+            }) => rpc_watch(&tio, rpcs, interval, &timestamp_format),
+            Some(RPCSubcommands::Run {
+                tio,
+                script,
+                defines,
+                verbose,
+                keep_going,
+                check,
+            }) => rpc_run(&tio, &script, defines, verbose, keep_going, check),
             None => rpc(
                 &tio,
                 rpc_name.unwrap_or("".to_string()),
@@ -65,7 +102,10 @@ fn main() -> eyre::Result<()> {
             data,
             meta,
             depth,
-        } => dump(&tio, data, meta, depth),
+            filter,
+            calibration,
+            utc,
+        } => dump(&tio, data, meta, depth, filter, calibration, utc),
         Commands::Log {
             tio,
             subcommands,
@@ -93,13 +133,19 @@ fn main() -> eyre::Result<()> {
                 meta,
                 sensor,
                 depth,
-            }) => log_dump(files, data, meta, sensor, depth),
+                filter,
+                calibration,
+            }) => log_dump(files, data, meta, sensor, depth, filter, calibration),
             Some(LogSubcommands::Inspect { files }) => log_inspect(files),
             Some(LogSubcommands::Csv {
                 args,
                 sensor,
                 output,
-            }) => log_csv(args, sensor, output),
+                filter,
+                calibration,
+                derive,
+                utc,
+            }) => log_csv(args, sensor, output, filter, calibration, derive, utc),
             Some(LogSubcommands::Hdf {
                 files,
                 output,
@@ -108,6 +154,8 @@ fn main() -> eyre::Result<()> {
                 debug,
                 split_level,
                 split_policy,
+                calibration,
+                derive,
             }) => log_hdf(
                 files,
                 output,
@@ -116,14 +164,28 @@ fn main() -> eyre::Result<()> {
                 debug,
                 split_level,
                 split_policy,
+                calibration,
+                derive,
             ),
             None => log(&tio, file, unbuffered, raw, depth, duration),
         },
-        Commands::Upgrade {
-            tio,
-            firmware_path,
-            yes,
-        } => firmware_upgrade(&tio, firmware_path, yes),
+        Commands::Settings(subcommand) => match subcommand {
+            SettingsSubcommands::Save { tio, file } => save_settings(&tio, file),
+            SettingsSubcommands::Restore {
+                tio,
+                file,
+                dry_run,
+                yes,
+            } => restore_settings(&tio, file, dry_run, yes),
+            SettingsSubcommands::Diff {
+                tio,
+                file,
+                with_root,
+                with_route,
+                writable,
+            } => diff_settings(&tio, file, with_root, with_route, writable),
+        },
+        Commands::Upgrade(upgrade_cli) => firmware_upgrade(upgrade_cli),
         Commands::Completions { shell } => {
             clap_complete::generate(shell, &mut TioCli::command(), "tio", &mut std::io::stdout());
             Ok(())
@@ -1,30 +1,19 @@
 use clap::{
-    builder::{PossibleValuesParser, TypedValueParser, ValueHint},
+    builder::ValueHint,
     Subcommand, ValueEnum,
 };
 use clap_complete::Shell;
+use twinleaf::data::derived::DerivedSpec;
+use twinleaf::data::dsp::Pipeline;
+use twinleaf::data::noise::{Averaging, Window};
 use twinleaf::device::RpcValueType;
 
-const RPC_TYPE_NAMES: &[&str] = &[
-    "u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64", "f32", "f64", "string",
-];
-
-fn parse_rpc_type(s: &str) -> RpcValueType {
-    match s {
-        "u8" => RpcValueType::Int { signed: false, size: 1 },
-        "u16" => RpcValueType::Int { signed: false, size: 2 },
-        "u32" => RpcValueType::Int { signed: false, size: 4 },
-        "u64" => RpcValueType::Int { signed: false, size: 8 },
-        "i8" => RpcValueType::Int { signed: true, size: 1 },
-        "i16" => RpcValueType::Int { signed: true, size: 2 },
-        "i32" => RpcValueType::Int { signed: true, size: 4 },
-        "i64" => RpcValueType::Int { signed: true, size: 8 },
-        "f32" => RpcValueType::Float { size: 4 },
-        "f64" => RpcValueType::Float { size: 8 },
-        "string" => RpcValueType::String { max_len: None },
-        // PossibleValuesParser validates against RPC_TYPE_NAMES first.
-        _ => unreachable!("possible values already validated"),
-    }
+fn parse_rpc_type(s: &str) -> Result<RpcValueType, String> {
+    RpcValueType::from_type_str(s.trim()).ok_or_else(|| {
+        "expected u8..u64, i8..i64, f32, f64, string, an array such as [f32; 3] or [u16], \
+         or a struct such as {x: f32, y: f32}"
+            .to_string()
+    })
 }
 
 #[derive(Parser, Debug)]
@@ -59,6 +48,29 @@ pub enum Commands {
         /// Routing depth limit (default: unlimited)
         #[arg(long = "depth")]
         depth: Option<usize>,
+        /// Filter numeric columns, e.g. "notch:60:3,lowpass:10:4"
+        #[arg(long = "filter", value_name = "SPEC")]
+        filter: Option<Pipeline>,
+
+        /// Calibrate samples with the transforms in this TOML file
+        #[arg(long = "calibration", value_name = "FILE", value_hint = ValueHint::FilePath)]
+        calibration: Option<String>,
+
+        /// Add a derived column, e.g. "B[nT]=sqrt(x^2+y^2+z^2)" (repeatable)
+        #[arg(long = "derive", value_name = "NAME=EXPR")]
+        derive: Vec<DerivedSpec>,
+
+        /// Keep sample history on disk in this directory, to scroll plots back with { and }
+        #[arg(long = "history", value_name = "DIR", value_hint = ValueHint::DirPath)]
+        history: Option<PathBuf>,
+
+        /// Disk space for sample history in MiB
+        #[arg(long = "history-size", value_name = "MIB", default_value_t = 4096, requires = "history")]
+        history_size: u64,
+
+        /// Fill gaps of up to this many lost samples rather than restarting plots (0, the default, disables)
+        #[arg(long = "gap-fill", value_name = "SAMPLES", default_value_t = 0)]
+        gap_fill: u32,
     },
 
     /// Live timing and rate diagnostics
@@ -80,6 +92,18 @@ pub enum Commands {
         /// Routing depth limit (default: unlimited)
         #[arg(long = "depth")]
         depth: Option<usize>,
+
+        /// Filter numeric columns of data samples, e.g. "notch:60:3,lowpass:10:4,decimate:5"
+        #[arg(long = "filter", value_name = "SPEC", requires = "data")]
+        filter: Option<Pipeline>,
+
+        /// Calibrate data samples with the transforms in this TOML file
+        #[arg(long = "calibration", value_name = "FILE", value_hint = ValueHint::FilePath, requires = "data")]
+        calibration: Option<String>,
+
+        /// Also show the UTC of data samples, estimated from arrival times if needed
+        #[arg(long = "utc", requires = "data")]
+        utc: bool,
     },
 
     /// Log samples to a file
@@ -112,6 +136,9 @@ pub enum Commands {
         duration: Option<std::time::Duration>,
     },
 
+    /// Noise statistics of columns in binary log file(s)
+    Analyze(AnalyzeCli),
+
     /// Execute a device RPC
     #[command(args_conflicts_with_subcommands = true, arg_required_else_help = true)]
     Rpc {
@@ -134,20 +161,20 @@ pub enum Commands {
         )]
         rpc_arg: Option<String>,
 
-        /// RPC request type
+        /// RPC request type (e.g. u16, f32, string, [f32; 3], {x: f32, y: u8})
         #[arg(
             short = 't',
             long = "req-type",
-            value_parser = PossibleValuesParser::new(RPC_TYPE_NAMES).map(|s: String| parse_rpc_type(&s)),
+            value_parser = parse_rpc_type,
             help_heading = "Type Options",
         )]
         req_type: Option<RpcValueType>,
 
-        /// RPC reply type
+        /// RPC reply type, same syntax as the request type
         #[arg(
             short = 'T',
             long = "rep-type",
-            value_parser = PossibleValuesParser::new(RPC_TYPE_NAMES).map(|s: String| parse_rpc_type(&s)),
+            value_parser = parse_rpc_type,
             help_heading = "Type Options",
         )]
         rep_type: Option<RpcValueType>,
@@ -157,20 +184,13 @@ pub enum Commands {
         debug: bool,
     },
 
+    /// Save, restore and compare device settings
+    #[command(subcommand)]
+    Settings(SettingsSubcommands),
+
     /// Upgrade device firmware
     #[command(alias = "firmware-upgrade")]
-    Upgrade {
-        #[command(flatten)]
-        tio: TioOpts,
-
-        /// Input firmware image path
-        #[arg(value_hint = ValueHint::FilePath, value_parser = parse_existing_file)]
-        firmware_path: PathBuf,
-
-        /// Skip confirmation prompt
-        #[arg(short = 'y', long = "yes")]
-        yes: bool,
-    },
+    Upgrade(UpgradeCli),
 
     /// Multiplex a sensor over TCP
     Proxy(ProxyCli),
@@ -221,6 +241,142 @@ pub enum RPCSubcommands {
         #[arg(long)]
         capture: bool,
     },
+    /// Print RPC values whenever they change
+    Watch {
+        #[command(flatten)]
+        tio: TioOpts,
+
+        /// RPCs to watch, optionally with their own poll interval
+        #[arg(
+            required = true,
+            value_name = "NAME[@INTERVAL]",
+            value_hint = ValueHint::Other,
+            value_parser = parse_watch_spec
+        )]
+        rpcs: Vec<(String, Option<std::time::Duration>)>,
+
+        /// Default poll interval (e.g. 500ms, 10s)
+        #[arg(short = 'i', long, default_value = "1s", value_parser = humantime::parse_duration)]
+        interval: std::time::Duration,
+
+        /// Timestamp format
+        #[arg(short = 't', long = "timestamp", default_value = "%F %T%.3f ")]
+        timestamp_format: String,
+    },
+    /// Run a script of RPC calls and checks
+    #[command(long_about = "\
+Run a script of RPC calls and checks.
+
+Scripts have one statement per line, and '#' starts a comment:
+
+  set <rpc> <value>                 Write an RPC
+  get <rpc>                         Read and print an RPC
+  call <rpc> [arg]                  Call an RPC
+  let <var> = <value>               Set a variable, used as $var or ${var}
+  let <var> = get <rpc>             Read an RPC into a variable
+  wait <duration>                   Pause, e.g. 500ms or 2s
+  echo <text>...                    Print a message
+  expect <rpc> <op> <value>         Check an RPC value (==, !=, <, <=, >, >=)
+  expect <rpc> == <value> +- <tol>  Check an RPC value within a tolerance
+  repeat <count> ... end            Repeat statements
+  for <var> in <items>... ... end   Loop over values and ranges (1..5, 1..=5)
+
+Exits with an error if an RPC fails or an expectation is not met.")]
+    Run {
+        #[command(flatten)]
+        tio: TioOpts,
+
+        /// Script file
+        #[arg(value_hint = ValueHint::FilePath, value_parser = parse_existing_file)]
+        script: PathBuf,
+
+        /// Define a script variable
+        #[arg(short = 'D', long = "define", value_name = "NAME=VALUE", value_parser = parse_define)]
+        defines: Vec<(String, String)>,
+
+        /// Print every step and passed expectation
+        #[arg(short = 'v', long)]
+        verbose: bool,
+
+        /// Continue after failed expectations, failing at the end
+        #[arg(short = 'k', long)]
+        keep_going: bool,
+
+        /// Only check the script syntax
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+fn parse_define(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| "expected NAME=VALUE".to_string())
+}
+
+fn parse_watch_spec(s: &str) -> Result<(String, Option<std::time::Duration>), String> {
+    match s.split_once('@') {
+        Some((name, interval)) => {
+            let interval = humantime::parse_duration(interval).map_err(|e| e.to_string())?;
+            Ok((name.to_string(), Some(interval)))
+        }
+        None => Ok((s.to_string(), None)),
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SettingsSubcommands {
+    /// Save all readable and writable RPC values to a TOML file
+    Save {
+        #[command(flatten)]
+        tio: TioOpts,
+
+        /// Output settings file path
+        #[arg(value_hint = ValueHint::FilePath)]
+        file: PathBuf,
+    },
+    /// Write the values saved in a TOML file back to the device
+    Restore {
+        #[command(flatten)]
+        tio: TioOpts,
+
+        /// Input settings file path
+        #[arg(value_hint = ValueHint::FilePath, value_parser = parse_existing_file)]
+        file: PathBuf,
+
+        /// Only show what would change
+        #[arg(short = 'n', long = "dry-run")]
+        dry_run: bool,
+
+        /// Skip confirmation prompt
+        #[arg(short = 'y', long = "yes")]
+        yes: bool,
+    },
+    /// Compare device settings with a saved file or another device
+    Diff {
+        #[command(flatten)]
+        tio: TioOpts,
+
+        /// Settings file to compare with
+        #[arg(
+            value_hint = ValueHint::FilePath,
+            value_parser = parse_existing_file,
+            required_unless_present_any = ["with_root", "with_route"],
+        )]
+        file: Option<PathBuf>,
+
+        /// Compare with the device at this root address
+        #[arg(long = "with-root", conflicts_with = "file", value_hint = ValueHint::Url)]
+        with_root: Option<String>,
+
+        /// Compare with the device at this sensor path
+        #[arg(long = "with-sensor", conflicts_with = "file", value_parser = parse_device_route)]
+        with_route: Option<DeviceRoute>,
+
+        /// Only compare writable settings
+        #[arg(short = 'w', long = "writable")]
+        writable: bool,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -260,6 +416,14 @@ pub enum LogSubcommands {
         /// Routing depth limit (default: unlimited)
         #[arg(long = "depth")]
         depth: Option<usize>,
+
+        /// Filter numeric columns of data samples, e.g. "notch:60:3,lowpass:10:4,decimate:5"
+        #[arg(long = "filter", value_name = "SPEC", requires = "data")]
+        filter: Option<Pipeline>,
+
+        /// Calibrate data samples with the transforms in this TOML file
+        #[arg(long = "calibration", value_name = "FILE", value_hint = ValueHint::FilePath, requires = "data")]
+        calibration: Option<String>,
     },
 
     /// Summarize the contents of binary log file(s)
@@ -282,6 +446,22 @@ pub enum LogSubcommands {
         /// Output filename prefix
         #[arg(short = 'o')]
         output: Option<String>,
+
+        /// Filter numeric columns, e.g. "notch:60:3,lowpass:10:4,decimate:5"
+        #[arg(long = "filter", value_name = "SPEC")]
+        filter: Option<Pipeline>,
+
+        /// Calibrate samples with the transforms in this TOML file
+        #[arg(long = "calibration", value_name = "FILE", value_hint = ValueHint::FilePath)]
+        calibration: Option<String>,
+
+        /// Add a derived column to its stream, e.g. "B[nT]=sqrt(x^2+y^2+z^2)" (repeatable)
+        #[arg(long = "derive", value_name = "NAME=EXPR")]
+        derive: Vec<DerivedSpec>,
+
+        /// Add a utc column after time, for devices whose time reference is Unix
+        #[arg(long = "utc")]
+        utc: bool,
     },
 
     /// Convert binary log files to HDF5 format
@@ -314,6 +494,14 @@ pub enum LogSubcommands {
         /// When to detect discontinuities (continuous=any gap, monotonic=only time backward)
         #[arg(short = 'p', long = "policy", default_value = "continuous")]
         split_policy: SplitPolicy,
+
+        /// Calibrate samples with the transforms in this TOML file
+        #[arg(long = "calibration", value_name = "FILE", value_hint = ValueHint::FilePath)]
+        calibration: Option<String>,
+
+        /// Add a derived column to its stream, e.g. "B[nT]=sqrt(x^2+y^2+z^2)" (repeatable)
+        #[arg(long = "derive", value_name = "NAME=EXPR")]
+        derive: Vec<DerivedSpec>,
     },
 }
 
@@ -504,6 +692,40 @@ fn nonneg_f64(s: &str) -> Result<f64, String> {
     }
 }
 
+#[derive(Parser, Debug)]
+pub struct UpgradeCli {
+    #[command(flatten)]
+    tio: TioOpts,
+
+    /// Input firmware image path
+    #[arg(value_hint = ValueHint::FilePath, value_parser = parse_existing_file)]
+    firmware_path: PathBuf,
+
+    /// Upgrade every device in the tree under --sensor, hubs last
+    #[arg(long)]
+    all: bool,
+
+    /// With --all, only upgrade devices with this name
+    #[arg(long, value_name = "NAME", requires = "all")]
+    only: Option<String>,
+
+    /// Firmware hash the image reports once installed, checked before and after the upgrade
+    #[arg(long, value_name = "HASH")]
+    hash: Option<String>,
+
+    /// Times a failed upload starts over from the first chunk
+    #[arg(long, default_value = "3")]
+    retries: usize,
+
+    /// Upgrade devices that already run this firmware
+    #[arg(long)]
+    force: bool,
+
+    /// Skip confirmation prompt
+    #[arg(short = 'y', long = "yes")]
+    yes: bool,
+}
+
 #[derive(Parser, Debug)]
 #[command(
     name = "tio-test",
@@ -545,6 +767,65 @@ pub struct TestCli {
     port: u16,
 }
 
+#[derive(Parser, Debug)]
+#[command(
+    name = "tio-analyze",
+    version,
+    about = "Allan deviation, spectral density and band noise of logged columns"
+)]
+pub struct AnalyzeCli {
+    /// Input log file(s), read in order as one log
+    #[arg(value_hint = ValueHint::FilePath, required = true, num_args = 1..)]
+    files: Vec<String>,
+
+    /// Analyze the columns matching a glob pattern (e.g. "/*/vector/*", "sine")
+    #[arg(short = 'g', long = "glob")]
+    filter: Option<String>,
+
+    /// Frequency band for noise floor and RMS, as LOW:HIGH in Hz (default: all but DC)
+    #[arg(short = 'b', long = "band", value_name = "LOW:HIGH", value_parser = parse_band)]
+    band: Option<(f64, f64)>,
+
+    /// Welch segment length in seconds
+    #[arg(long = "segment", default_value = "10", value_name = "SECONDS", value_parser = positive_f64)]
+    segment: f64,
+
+    /// Welch window (rectangular, hann, hamming, blackman, flattop)
+    #[arg(short = 'w', long = "window", default_value = "hann")]
+    window: Window,
+
+    /// How to average Welch segments (mean, median)
+    #[arg(long = "averaging", default_value = "mean")]
+    averaging: Averaging,
+
+    /// Write PREFIX.summary.csv, PREFIX.allan.csv and PREFIX.asd.csv
+    #[arg(short = 'o', value_name = "PREFIX")]
+    output: Option<String>,
+}
+
+fn parse_band(s: &str) -> Result<(f64, f64), String> {
+    let (low, high) = s
+        .split_once(':')
+        .ok_or_else(|| "expected LOW:HIGH in Hz".to_string())?;
+    let low = nonneg_f64(low.trim())?;
+    let high = nonneg_f64(high.trim())?;
+    if low >= high {
+        return Err("LOW must be below HIGH".into());
+    }
+    Ok((low, high))
+}
+
+fn positive_f64(s: &str) -> Result<f64, String> {
+    let v: f64 = s
+        .parse()
+        .map_err(|e: std::num::ParseFloatError| e.to_string())?;
+    if v > 0.0 {
+        Ok(v)
+    } else {
+        Err("must be > 0".into())
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(
     name = "tio-proxy",
@@ -560,10 +841,38 @@ pub struct ProxyCli {
     #[arg(value_hint = ValueHint::Url)]
     sensor_url: Option<String>,
 
+    /// Standby URL for the same sensor, used if the active link disconnects (repeatable, tried in order)
+    #[arg(long = "standby", value_name = "URL", value_hint = ValueHint::Url)]
+    standby: Vec<String>,
+
     /// TCP port to listen on for clients
     #[arg(short = 'p', long = "port", default_value = "7855")]
     port: u16,
 
+    /// Additional TCP port serving a reduced data feed, shaped by the --shaped-* options
+    #[arg(long = "shaped-port")]
+    shaped_port: Option<u16>,
+
+    /// Only forward these stream ids on the shaped port (comma separated)
+    #[arg(long = "shaped-streams", value_delimiter = ',')]
+    shaped_streams: Vec<u8>,
+
+    /// Forward one in N data packets of each stream on the shaped port
+    #[arg(long = "shaped-decimate", default_value = "1")]
+    shaped_decimate: u32,
+
+    /// Maximum data packets per second per client on the shaped port
+    #[arg(long = "shaped-max-rate")]
+    shaped_max_rate: Option<f64>,
+
+    /// UDP port to listen on for clients (one tio packet per datagram)
+    #[arg(long = "udp-port")]
+    udp_port: Option<u16>,
+
+    /// Forget UDP clients not heard from for this long (seconds)
+    #[arg(long = "udp-timeout", default_value = "5")]
+    udp_timeout: u64,
+
     /// Kick off slow clients instead of dropping traffic
     #[arg(short = 'k', long)]
     kick_slow: bool,
@@ -609,6 +918,22 @@ pub struct ProxyCli {
     #[arg(long)]
     dump_hb: bool,
 
+    /// Append a JSON-lines record of every client RPC to this file
+    #[arg(long = "audit-log", value_hint = ValueHint::FilePath)]
+    audit_log: Option<std::path::PathBuf>,
+
+    /// Record all sensor traffic to log files in this directory
+    #[arg(long = "record", value_hint = ValueHint::DirPath)]
+    record: Option<std::path::PathBuf>,
+
+    /// Start a new record file after this many megabytes
+    #[arg(long = "record-size", requires = "record")]
+    record_size: Option<u64>,
+
+    /// Start a new record file after this long (e.g. 30m, 1h, 1day)
+    #[arg(long = "record-interval", requires = "record", value_parser = humantime::parse_duration)]
+    record_interval: Option<std::time::Duration>,
+
     /// Deprecated; running without -s <url> now auto-detects by default.
     #[arg(short = 'a', long = "auto", hide = true)]
     auto: bool,
@@ -0,0 +1,268 @@
+// tio analyze
+//
+// Noise characterization of logged columns: Allan and modified Allan
+// deviation, amplitude spectral density, and noise over a frequency band.
+
+use crate::AnalyzeCli;
+use color_eyre::Help;
+use console::style;
+use eyre::{eyre, WrapErr};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use twinleaf::data::noise::{
+    self, decade_factors, AllanPoint, NoiseError, Series, Spectrum, Welch,
+};
+use twinleaf::data::ColumnFilter;
+
+struct Analysis {
+    path: String,
+    units: String,
+    rate: f64,
+    samples: usize,
+    runs: usize,
+    mean: f64,
+    std: f64,
+    spectrum: Spectrum,
+    floor: Option<f64>,
+    rms: Option<f64>,
+    adev: Vec<AllanPoint>,
+    mdev: Vec<AllanPoint>,
+}
+
+impl Analysis {
+    fn min_adev(&self) -> Option<&AllanPoint> {
+        self.adev
+            .iter()
+            .min_by(|a, b| a.deviation.total_cmp(&b.deviation))
+    }
+}
+
+fn analyze(series: &Series, cli: &AnalyzeCli) -> Result<Option<Analysis>, NoiseError> {
+    let Some(run) = series.longest() else {
+        return Ok(None);
+    };
+    let values = &run.values;
+    let n = values.len();
+    if n < 2 {
+        return Ok(None);
+    }
+    let mean = values.iter().sum::<f64>() / n as f64;
+    let std = (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1) as f64).sqrt();
+
+    let segment = ((cli.segment * run.rate).round() as usize).clamp(2, n);
+    let spectrum = Welch::new(segment)
+        .with_window(cli.window)
+        .with_averaging(cli.averaging)
+        .psd(values, run.rate)?;
+    let (low, high) = cli.band.unwrap_or((spectrum.resolution, run.rate / 2.0));
+    let (floor, rms) = match (
+        spectrum.noise_floor(low, high),
+        spectrum.band_rms(low, high),
+    ) {
+        (Ok(floor), Ok(rms)) => (Some(floor), Some(rms)),
+        _ => (None, None),
+    };
+
+    let factors = decade_factors(n / 2);
+    Ok(Some(Analysis {
+        path: series.path(),
+        units: series.column.units.clone(),
+        rate: run.rate,
+        samples: n,
+        runs: series.runs.len(),
+        mean,
+        std,
+        floor,
+        rms,
+        adev: noise::allan_deviation(values, run.rate, &factors)?,
+        mdev: noise::modified_allan_deviation(values, run.rate, &factors)?,
+        spectrum,
+    }))
+}
+
+fn fmt_value(v: Option<f64>) -> String {
+    v.map_or_else(|| "-".to_string(), |v| format!("{:.4e}", v))
+}
+
+pub fn run_analyze(cli: AnalyzeCli) -> eyre::Result<()> {
+    let filter = match &cli.filter {
+        Some(p) => Some(ColumnFilter::new(p).map_err(|e| eyre!("invalid column filter: {}", e))?),
+        None => None,
+    };
+    let series = noise::read_log(&cli.files, filter.as_ref())
+        .wrap_err_with(|| format!("could not read {}", cli.files.join(", ")))?;
+    if series.is_empty() {
+        return Err(eyre!("no numeric columns to analyze")).suggestion(
+            "list the logged columns with \"tio log inspect\" and check the -g pattern",
+        );
+    }
+
+    let mut analyses = Vec::new();
+    for s in &series {
+        match analyze(s, &cli) {
+            Ok(Some(a)) => analyses.push(a),
+            Ok(None) => {}
+            Err(e) => eprintln!("{} {}: {}", style("skipping").yellow(), s.path(), e),
+        }
+    }
+
+    let band = match cli.band {
+        Some((low, high)) => format!("{}–{} Hz", low, high),
+        None => "all but DC".to_string(),
+    };
+    let rule = style("─".repeat(112)).dim();
+    println!();
+    println!("{rule}");
+    println!(
+        " {}  {}",
+        style("Noise Summary").bold(),
+        style(format!(
+            "{} window, {} of {} s segments, band {}",
+            cli.window, cli.averaging, cli.segment, band
+        ))
+        .dim()
+    );
+    println!("{rule}");
+    println!(
+        " {}",
+        style(format!(
+            "{:<24} {:>8} {:>9} {:>11} {:>11} {:>11} {:>11} {:>11} {:>9}",
+            "column", "units", "rate", "mean", "std", "floor/√Hz", "band rms", "min adev", "at tau"
+        ))
+        .bold()
+        .cyan()
+    );
+    for a in &analyses {
+        let min = a.min_adev();
+        println!(
+            " {:<24} {:>8} {:>9} {:>11} {:>11} {:>11} {:>11} {:>11} {:>9}",
+            a.path,
+            a.units,
+            format!("{} Hz", a.rate),
+            fmt_value(Some(a.mean)),
+            fmt_value(Some(a.std)),
+            fmt_value(a.floor),
+            fmt_value(a.rms),
+            fmt_value(min.map(|p| p.deviation)),
+            min.map_or("-".to_string(), |p| format!("{} s", p.tau)),
+        );
+        if a.runs > 1 {
+            println!(
+                "   {}",
+                style(format!(
+                    "longest of {} continuous runs, {} samples",
+                    a.runs, a.samples
+                ))
+                .dim()
+            );
+        }
+    }
+
+    for a in &analyses {
+        println!();
+        println!(
+            " {}  {}",
+            style(&a.path).bold().cyan(),
+            style(format!("{} samples at {} Hz", a.samples, a.rate)).dim()
+        );
+        println!(
+            "   {}",
+            style(format!(
+                "{:>10} {:>11} {:>11} {:>9}",
+                "tau [s]", "adev", "mdev", "terms"
+            ))
+            .bold()
+        );
+        for (i, p) in a.adev.iter().enumerate() {
+            let mdev = a
+                .mdev
+                .get(i)
+                .filter(|m| m.tau == p.tau)
+                .map(|m| m.deviation);
+            println!(
+                "   {:>10} {:>11} {:>11} {:>9}",
+                p.tau,
+                fmt_value(Some(p.deviation)),
+                fmt_value(mdev),
+                p.terms
+            );
+        }
+    }
+
+    if let Some(prefix) = &cli.output {
+        write_csv(prefix, &analyses)?;
+    }
+    Ok(())
+}
+
+fn create(path: &str) -> eyre::Result<BufWriter<File>> {
+    Ok(BufWriter::new(
+        File::create(path).wrap_err_with(|| format!("could not create {}", path))?,
+    ))
+}
+
+fn write_csv(prefix: &str, analyses: &[Analysis]) -> eyre::Result<()> {
+    let path = format!("{}.summary.csv", prefix);
+    let mut out = create(&path)?;
+    writeln!(
+        out,
+        "column,units,rate,samples,runs,mean,std,noise_floor,band_rms,min_adev,min_adev_tau"
+    )?;
+    let opt = |v: Option<f64>| v.map_or(String::new(), |v| v.to_string());
+    for a in analyses {
+        let min = a.min_adev();
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{},{}",
+            a.path,
+            a.units,
+            a.rate,
+            a.samples,
+            a.runs,
+            a.mean,
+            a.std,
+            opt(a.floor),
+            opt(a.rms),
+            opt(min.map(|p| p.deviation)),
+            opt(min.map(|p| p.tau)),
+        )?;
+    }
+    out.flush()?;
+    println!("\nWrote {}", path);
+
+    let path = format!("{}.allan.csv", prefix);
+    let mut out = create(&path)?;
+    writeln!(out, "column,tau,adev,mdev,terms")?;
+    for a in analyses {
+        for (i, p) in a.adev.iter().enumerate() {
+            let mdev = a
+                .mdev
+                .get(i)
+                .filter(|m| m.tau == p.tau)
+                .map(|m| m.deviation);
+            writeln!(
+                out,
+                "{},{},{},{},{}",
+                a.path,
+                p.tau,
+                p.deviation,
+                opt(mdev),
+                p.terms
+            )?;
+        }
+    }
+    out.flush()?;
+    println!("Wrote {}", path);
+
+    let path = format!("{}.asd.csv", prefix);
+    let mut out = create(&path)?;
+    writeln!(out, "column,frequency,asd")?;
+    for a in analyses {
+        for (f, asd) in a.spectrum.frequencies.iter().zip(a.spectrum.asd()) {
+            writeln!(out, "{},{},{}", a.path, f, asd)?;
+        }
+    }
+    out.flush()?;
+    println!("Wrote {}", path);
+    Ok(())
+}
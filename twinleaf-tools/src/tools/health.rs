@@ -26,7 +26,10 @@ use std::{
     time::{Duration, Instant, SystemTime},
 };
 use twinleaf::{
-    data::BoundaryReason,
+    data::{
+        clock::{OnlineSlope, MIN_DRIFT_SAMPLES},
+        BoundaryReason,
+    },
     device::{DeviceEvent, DeviceTree, RpcClient, RpcList, RpcRegistry, TreeEvent, TreeItem},
     tio::{
         self,
@@ -103,63 +106,6 @@ impl TimeWindow {
     }
 }
 
-struct OnlineSlope {
-    n: u64,
-    sum_x: f64,
-    sum_y: f64,
-    sum_xx: f64,
-    sum_xy: f64,
-    x0: f64,
-    y0: f64,
-}
-
-impl Default for OnlineSlope {
-    fn default() -> Self {
-        Self {
-            n: 0,
-            sum_x: 0.0,
-            sum_y: 0.0,
-            sum_xx: 0.0,
-            sum_xy: 0.0,
-            x0: 0.0,
-            y0: 0.0,
-        }
-    }
-}
-
-impl OnlineSlope {
-    fn push(&mut self, x: f64, y: f64) {
-        if self.n == 0 {
-            self.x0 = x;
-            self.y0 = y;
-        }
-        let dx = x - self.x0;
-        let dy = y - self.y0;
-        self.n += 1;
-        self.sum_x += dx;
-        self.sum_y += dy;
-        self.sum_xx += dx * dx;
-        self.sum_xy += dx * dy;
-    }
-
-    fn slope(&self) -> Option<f64> {
-        if self.n < 2 {
-            return None;
-        }
-        let denom = self.n as f64 * self.sum_xx - self.sum_x * self.sum_x;
-        if denom.abs() < f64::EPSILON {
-            return None;
-        }
-        Some((self.n as f64 * self.sum_xy - self.sum_x * self.sum_y) / denom)
-    }
-
-    fn reset(&mut self) {
-        *self = Self::default();
-    }
-}
-
-const MIN_DRIFT_SAMPLES: u64 = 50;
-
 #[derive(Default)]
 struct StreamStats {
     host_epoch: Option<Instant>,
@@ -209,9 +155,11 @@ impl StreamStats {
 
         // Drift / PPM via incremental OLS
         self.drift_slope.push(host_time, t_data);
-        if self.drift_slope.n >= MIN_DRIFT_SAMPLES {
-            if let Some(beta) = self.drift_slope.slope() {
-                let host_elapsed = host_time - self.drift_slope.x0;
+        if self.drift_slope.len() >= MIN_DRIFT_SAMPLES {
+            if let (Some(beta), Some((host_start, _))) =
+                (self.drift_slope.slope(), self.drift_slope.first())
+            {
+                let host_elapsed = host_time - host_start;
                 self.drift_s = (beta - 1.0) * host_elapsed;
                 self.ppm = (beta - 1.0) * 1e6;
             }
@@ -255,7 +203,7 @@ impl StreamStats {
 
     // floor of stale_dur
     fn stale_threshold(&self, floor: Duration) -> Duration {
-        if self.rate_slope.n >= 2 && self.rate_smps > 0.0 {
+        if self.rate_slope.len() >= 2 && self.rate_smps > 0.0 {
             let period = Duration::from_secs_f64(2.0 / self.rate_smps);
             std::cmp::max(floor, period)
         } else {
@@ -569,7 +517,7 @@ impl App {
 
     fn tick(&mut self, now: Instant) {
         for (_key, st) in self.stats.iter_mut() {
-            if st.is_stale(now, self.stale_dur) && st.rate_slope.n >= 2 {
+            if st.is_stale(now, self.stale_dur) && st.rate_slope.len() >= 2 {
                 st.reset_timing();
                 st.rate_slope.reset();
                 st.received_count = 0;
@@ -1,7 +1,11 @@
+pub mod analyze;
 pub mod health;
 pub mod list;
 pub mod monitor;
 pub mod proxy;
 pub mod proxy_nmea;
+pub mod rpc_script;
+pub mod settings;
 pub mod tio_test;
 pub mod tool;
+pub mod upgrade;
@@ -6,13 +6,16 @@ use std::{
     collections::{BTreeMap, HashMap, HashSet},
     fs::File,
     io::{self, Read},
+    path::PathBuf,
     str::FromStr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
+use crate::tools::tool::load_calibration;
 use crate::tui::rpc_palette::{PaletteEvent, RpcPalette, RpcReq};
 use crate::tui::rpc_worker::{spawn_rpc_worker, RpcWorkerReq, RpcWorkerResp};
-use crate::tui::tree_worker::spawn_tree_worker;
+use crate::tui::tree_worker::spawn_clocked_tree_worker;
 use crate::TioOpts;
 use clap::Parser;
 use crossbeam::channel::{self, Sender};
@@ -27,17 +30,22 @@ use ratatui::{
 };
 use toml_edit::{DocumentMut, InlineTable, Value};
 use twinleaf::{
-    data::{AlignedWindow, Buffer, ColumnBatch, ColumnData, DeviceFullMetadata, Sample},
+    data::{
+        derived::{DeriveError, DerivedColumn, DerivedSpec},
+        dsp::{Pipeline, SampleFilter},
+        noise::Welch,
+        AlignedWindow, Archive, Buffer, ColumnData, DeviceFullMetadata, ReadError, ResampleMethod,
+        Sample,
+    },
     device::{DeviceEvent, DeviceTree, RpcClient, RpcList, RpcRegistry, TreeEvent, TreeItem},
     tio::{
         self,
         proto::{
             identifiers::{ColumnKey, StreamKey},
-            DeviceRoute, ProxyStatus,
+            ColumnMetadata, DeviceRoute, ProxyStatus,
         },
     },
 };
-use welch_sde::{Build, SpectralDensity};
 
 const MIN_PLOT_WINDOW_SECONDS: f64 = 0.5;
 const MAX_PLOT_WINDOW_SECONDS: f64 = 60.0;
@@ -366,6 +374,8 @@ pub enum Action {
     AdjustWindow(f64),
     AdjustPlotWidth(i16),
     AdjustPrecision(i8),
+    /// Move the plot back in time by a fraction of its window.
+    ScrollHistory(f64),
 }
 
 #[derive(Debug, Clone)]
@@ -375,6 +385,9 @@ pub struct ViewConfig {
     pub show_routes: bool,
     pub show_fft: bool,
     pub plot_window_seconds: f64,
+    /// End time of a plot scrolled back into history, or `None` to follow
+    /// the latest samples.
+    pub plot_end: Option<f64>,
     pub plot_width_percent: u16,
     pub axis_precision: usize,
     pub follow_selection: bool,
@@ -392,6 +405,7 @@ impl Default for ViewConfig {
             show_routes: false,
             show_fft: false,
             plot_window_seconds: 5.0,
+            plot_end: None,
             plot_width_percent: 70,
             axis_precision: 3,
             follow_selection: true,
@@ -446,7 +460,12 @@ pub struct App {
     pub device_status: HashMap<DeviceRoute, DeviceStatus>,
     pub last: BTreeMap<StreamKey, (Sample, Instant)>,
     pub device_metadata: HashMap<DeviceRoute, DeviceFullMetadata>,
+    pub derived: Vec<DerivedColumn>,
     pub window_aligned: Option<AlignedWindow>,
+    /// How far the plotted window ends behind the latest sample, in seconds.
+    pub history_lag: Option<f64>,
+    /// Why the on-disk history stopped recording.
+    pub history_error: Option<String>,
 
     pub footer_height: u16,
     pub rpc_registries: HashMap<DeviceRoute, RpcRegistry>,
@@ -468,7 +487,10 @@ impl App {
             device_status: HashMap::new(),
             last: BTreeMap::new(),
             device_metadata: HashMap::new(),
+            derived: Vec::new(),
             window_aligned: None,
+            history_lag: None,
+            history_error: None,
             footer_height: 0,
             rpc_registries: HashMap::new(),
             palette: RpcPalette::default(),
@@ -568,6 +590,13 @@ impl App {
                 let new_p = self.view.axis_precision as i16 + delta as i16;
                 self.view.axis_precision = new_p.clamp(0, 5) as usize;
             }
+            Action::ScrollHistory(fraction) => {
+                let end = self.view.plot_end.or_else(|| {
+                    let win = self.window_aligned.as_ref()?;
+                    win.timestamps.last().copied()
+                });
+                self.view.plot_end = end.map(|end| end - fraction * self.view.plot_window_seconds);
+            }
         }
         false
     }
@@ -632,6 +661,13 @@ impl App {
                                 },
                             });
                         }
+                        for column in self.stream_derived(&key) {
+                            new_items.push(NavPos::Column {
+                                device_idx: dev_idx,
+                                stream_idx,
+                                spec: column.key.clone(),
+                            });
+                        }
                     }
                 }
             }
@@ -732,6 +768,52 @@ impl App {
         }
     }
 
+    /// Derived columns shown with the stream `key`.
+    fn stream_derived<'a>(
+        &'a self,
+        key: &'a StreamKey,
+    ) -> impl Iterator<Item = &'a DerivedColumn> + 'a {
+        self.derived
+            .iter()
+            .filter(move |column| column.key.stream_key() == *key)
+    }
+
+    /// Latest value of a derived column, from the last sample of each
+    /// stream it uses.
+    fn derived_value(&self, column: &DerivedColumn) -> f64 {
+        let values: Vec<f64> = column
+            .inputs
+            .iter()
+            .map(|key| {
+                self.last
+                    .get(&key.stream_key())
+                    .and_then(|(sample, _)| sample.columns.get(key.column_id))
+                    .and_then(|col| col.value.try_as_f64())
+                    .unwrap_or(f64::NAN)
+            })
+            .collect();
+        column.evaluate(&values)
+    }
+
+    /// Add the derived columns in `pending` whose columns have all shown up.
+    fn bind_derived(
+        &mut self,
+        pending: &mut Vec<DerivedSpec>,
+        buffer: &mut Buffer,
+    ) -> Result<(), DeriveError> {
+        let mut result = Ok(());
+        pending.retain(|spec| match buffer.derive(spec) {
+            Ok(_) => false,
+            Err(DeriveError::UnknownColumn(_)) => true,
+            Err(e) => {
+                result = Err(e);
+                true
+            }
+        });
+        self.derived = buffer.derived_columns().to_vec();
+        result
+    }
+
     pub fn handle_sample(&mut self, sample: Sample, route: DeviceRoute, buffer: &mut Buffer) {
         let stream_key = StreamKey::new(route.clone(), sample.stream.stream_id);
         buffer.process_sample(sample.clone(), stream_key.clone());
@@ -739,8 +821,19 @@ impl App {
     }
 
     pub fn update_plot_window(&mut self, buffer: &Buffer) {
+        self.history_lag = None;
+        self.history_error = buffer
+            .archive()
+            .and_then(Archive::error)
+            .map(|err| err.to_string());
         if !self.view.show_plot {
             self.window_aligned = None;
+            self.view.plot_end = None;
+            return;
+        }
+
+        if let Some(end) = self.view.plot_end {
+            self.window_aligned = self.read_history(buffer, end);
             return;
         }
 
@@ -750,37 +843,61 @@ impl App {
             let n_samples = (self.view.plot_window_seconds * run.effective_rate)
                 .ceil()
                 .max(10.0) as usize;
-            buffer.read_aligned_window(&[col], n_samples).ok()
+            // Derived columns may use streams at different rates.
+            buffer
+                .read_aligned_window(std::slice::from_ref(&col), n_samples)
+                .or_else(|err| match err {
+                    ReadError::SamplingRateMismatch { .. } => {
+                        buffer.read_resampled_window(&[col], n_samples, ResampleMethod::Linear)
+                    }
+                    err => Err(err),
+                })
+                .ok()
         });
     }
 
+    /// Read the plot window ending at `end`, from memory or the on-disk
+    /// history. Scrolling past the latest sample returns to the live view.
+    fn read_history(&mut self, buffer: &Buffer, end: f64) -> Option<AlignedWindow> {
+        let col = self.current_selection()?;
+        let window = self.view.plot_window_seconds;
+        let (earliest, latest) = buffer.time_bounds(&col.stream_key())?;
+        if end >= latest {
+            self.view.plot_end = None;
+            return None;
+        }
+        let end = end.max((earliest + window).min(latest));
+        self.view.plot_end = Some(end);
+        self.history_lag = Some(latest - end);
+        buffer
+            .read_aligned_time_range(std::slice::from_ref(&col), end - window, end)
+            .or_else(|err| match err {
+                ReadError::SamplingRateMismatch { .. } => buffer.read_resampled_time_range(
+                    &[col],
+                    end - window,
+                    end,
+                    ResampleMethod::Linear,
+                ),
+                err => Err(err),
+            })
+            .ok()
+    }
+
     pub fn get_plot_data(&self) -> Option<(Vec<(f64, f64)>, f64, f64)> {
         let spec = self.current_selection()?;
         let win = self.window_aligned.as_ref()?;
-        let batch = win.columns.get(&spec)?;
+        let values = win.column_f64(&spec)?;
         if win.timestamps.is_empty() {
             return None;
         }
-        let data: Vec<(f64, f64)> = match batch {
-            ColumnBatch::F64(v) => win
-                .timestamps
-                .iter()
-                .copied()
-                .zip(v.iter().copied())
-                .collect(),
-            ColumnBatch::I64(v) => win
-                .timestamps
-                .iter()
-                .copied()
-                .zip(v.iter().map(|&x| x as f64))
-                .collect(),
-            ColumnBatch::U64(v) => win
-                .timestamps
-                .iter()
-                .copied()
-                .zip(v.iter().map(|&x| x as f64))
-                .collect(),
-        };
+        // Filled samples are NaN, and left out of the plot.
+        let data: Vec<(f64, f64)> = win
+            .timestamps
+            .iter()
+            .copied()
+            .zip(values)
+            .filter(|(_, v)| v.is_finite())
+            .collect();
         if data.is_empty() {
             return None;
         }
@@ -816,11 +933,15 @@ impl App {
             return FftStatus::WaitingForSamples;
         };
 
-        let signal: Vec<f64> = match batch {
-            ColumnBatch::F64(v) => v.clone(),
-            ColumnBatch::I64(v) => v.iter().map(|&x| x as f64).collect(),
-            ColumnBatch::U64(v) => v.iter().map(|&x| x as f64).collect(),
-        };
+        // The spectrum needs evenly spaced samples, so only those after the
+        // last gap are used.
+        let signal = batch.to_f64();
+        let after_gaps = win
+            .gaps
+            .get(&stream_key)
+            .and_then(|gaps| gaps.last())
+            .map_or(0, |gap| gap.end);
+        let signal = &signal[after_gaps..];
 
         if signal.len() < MIN_FFT_SAMPLES {
             return FftStatus::TooFewSamples {
@@ -831,25 +952,21 @@ impl App {
             };
         }
 
-        let (fft_signal, segment_size, hop_size) = latest_complete_welch_signal(&signal);
+        let (fft_signal, segment_size, hop_size) = latest_complete_welch_signal(signal);
 
-        let mean_val = fft_signal.iter().sum::<f64>() / fft_signal.len() as f64;
-        let detrended: Vec<f64> = fft_signal.iter().map(|x| x - mean_val).collect();
-
-        let welch: SpectralDensity<f64> = SpectralDensity::builder(&detrended, sampling_hz).build();
-        let sd = welch.periodogram();
-        let pts: Vec<(f64, f64)> = sd
-            .frequency()
-            .into_iter()
-            .zip(sd.iter().copied())
-            .filter_map(|(f, d)| {
-                if f > 0.0 && d.is_finite() && d > 0.0 {
-                    Some((f, d.sqrt()))
-                } else {
-                    None
-                }
-            })
-            .collect();
+        let pts: Vec<(f64, f64)> = match Welch::new(segment_size)
+            .with_overlap(WELCH_DEFAULT_OVERLAP)
+            .psd(fft_signal, sampling_hz)
+        {
+            Ok(spectrum) => spectrum
+                .frequencies
+                .iter()
+                .zip(spectrum.asd())
+                .filter(|&(&f, d)| f > 0.0 && d.is_finite() && d > 0.0)
+                .map(|(&f, d)| (f, d))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
 
         if pts.is_empty() {
             return FftStatus::NoValidFrequencyBins {
@@ -939,6 +1056,8 @@ fn get_action(ev: Event, app: &mut App) -> Option<Action> {
                 KeyCode::Char(']') => Some(Action::AdjustPlotWidth(-5)),
                 KeyCode::Char(',') | KeyCode::Char('<') => Some(Action::AdjustPrecision(-1)),
                 KeyCode::Char('.') | KeyCode::Char('>') => Some(Action::AdjustPrecision(1)),
+                KeyCode::Char('{') => Some(Action::ScrollHistory(0.5)),
+                KeyCode::Char('}') => Some(Action::ScrollHistory(-0.5)),
                 _ => None,
             },
         }
@@ -1086,20 +1205,16 @@ fn build_left_lines(app: &mut App, now: Instant) -> (Vec<Line<'static>>, HashMap
     }
 
     let mut global_idx = 0;
-    app.view.desc_width = app
-        .last
-        .values()
-        .flat_map(|(s, _)| s.columns.iter())
-        .map(|c| c.desc.description.len())
-        .max()
-        .unwrap_or(0);
-    app.view.units_width = app
-        .last
-        .values()
-        .flat_map(|(s, _)| s.columns.iter())
-        .map(|c| c.desc.units.len())
-        .max()
-        .unwrap_or(0);
+    let descs = || {
+        app.last
+            .values()
+            .flat_map(|(s, _)| s.columns.iter().map(|c| &c.desc))
+            .chain(app.derived.iter().map(|c| &c.metadata))
+    };
+    let desc_width = descs().map(|d| d.description.len()).max().unwrap_or(0);
+    let units_width = descs().map(|d| d.units.len()).max().unwrap_or(0);
+    app.view.desc_width = desc_width;
+    app.view.units_width = units_width;
 
     for (dev_idx, route) in routes.iter().enumerate() {
         let dev = app.device_metadata.get(route).map(|m| m.device.as_ref());
@@ -1167,7 +1282,19 @@ fn build_left_lines(app: &mut App, now: Instant) -> (Vec<Line<'static>>, HashMap
             let key = StreamKey::new(route.clone(), sid);
             if let Some((sample, seen)) = app.last.get(&key) {
                 let is_stale = now.saturating_duration_since(*seen) > stale_threshold(sample);
-                for col in &sample.columns {
+                let derived: Vec<(Arc<ColumnMetadata>, ColumnData)> = app
+                    .stream_derived(&key)
+                    .map(|column| {
+                        let value = ColumnData::Float(app.derived_value(column));
+                        (column.metadata.clone(), value)
+                    })
+                    .collect();
+                let rows = sample
+                    .columns
+                    .iter()
+                    .map(|col| (&col.desc, &col.value))
+                    .chain(derived.iter().map(|(meta, value)| (meta, value)));
+                for (meta, value) in rows {
                     let nav_idx = global_idx;
                     global_idx += 1;
                     map.insert(nav_idx, lines.len());
@@ -1179,20 +1306,20 @@ fn build_left_lines(app: &mut App, now: Instant) -> (Vec<Line<'static>>, HashMap
                         .plot_mode(app.view.show_plot);
 
                     let label_style = ctx.resolve();
-                    let (val_str, val_f64) = fmt_value(&col.value);
+                    let (val_str, val_f64) = fmt_value(value);
                     let val_col = app
                         .view
                         .theme
-                        .get_value_color(&sample.stream.name, &col.desc.name, val_f64)
+                        .get_value_color(&sample.stream.name, &meta.name, val_f64)
                         .unwrap_or(Color::Reset);
                     let val_style = ctx.color(val_col).resolve();
 
-                    let mut desc = col.desc.description.clone();
+                    let mut desc = meta.description.clone();
                     if desc.len() < app.view.desc_width {
                         desc.push_str(&" ".repeat(app.view.desc_width - desc.len()));
                     }
 
-                    let units = col.desc.units.clone();
+                    let units = meta.units.clone();
                     let padded_units = if app.view.units_width > 0 && !units.is_empty() {
                         format!("{:>width$}", units, width = app.view.units_width)
                     } else if app.view.units_width > 0 {
@@ -1233,14 +1360,13 @@ fn render_footer(f: &mut Frame, app: &mut App, area: Rect) {
             key_span("h"),
             Span::raw(" Toggle Footer"),
         ]);
-        f.render_widget(
-            Paragraph::new(vec![minimal]).block(
-                Block::default()
-                    .borders(Borders::TOP)
-                    .border_style(Style::default().fg(Color::DarkGray)),
-            ),
-            area,
-        );
+        let mut block = Block::default()
+            .borders(Borders::TOP)
+            .border_style(Style::default().fg(Color::DarkGray));
+        if let Some(status) = history_status(app) {
+            block = block.title(status);
+        }
+        f.render_widget(Paragraph::new(vec![minimal]).block(block), area);
         return;
     }
 
@@ -1307,7 +1433,11 @@ fn render_footer(f: &mut Frame, app: &mut App, area: Rect) {
         key_span("<"),
         key_sep(),
         key_span(">"),
-        Span::raw(" Plot Precision"),
+        Span::raw(" Plot Precision  "),
+        key_span("{"),
+        key_sep(),
+        key_span("}"),
+        Span::raw(" History"),
     ]);
 
     let scroll_line = Line::from(vec![
@@ -1345,17 +1475,32 @@ fn render_footer(f: &mut Frame, app: &mut App, area: Rect) {
         quit_line,
     ];
 
-    let block = Block::default()
+    let mut block = Block::default()
         .borders(Borders::TOP)
         .border_style(Style::default().fg(Color::DarkGray))
         .title(Span::styled(
             " Controls ",
             Style::default().add_modifier(Modifier::BOLD),
         ));
+    if let Some(status) = history_status(app) {
+        block = block.title(status);
+    }
 
     f.render_widget(Paragraph::new(lines).block(block), area);
 }
 
+/// Right-aligned footer title for a history that stopped recording.
+fn history_status(app: &App) -> Option<Line<'static>> {
+    let err = app.history_error.as_ref()?;
+    Some(
+        Line::from(Span::styled(
+            format!(" History stopped: {} ", err),
+            Style::default().fg(Color::Red),
+        ))
+        .right_aligned(),
+    )
+}
+
 fn key_span(text: &str) -> Span<'static> {
     Span::styled(
         format!(" {} ", text),
@@ -1529,10 +1674,16 @@ fn render_graphics_panel(f: &mut Frame, app: &App, area: Rect) {
                 }
             }
         } else {
-            let title = format!(
-                "{} — {} ({:.1}s)",
-                route, desc, app.view.plot_window_seconds
-            );
+            let title = match app.history_lag {
+                Some(lag) => format!(
+                    "{} — {} ({:.1}s, {:.1}s ago)",
+                    route, desc, app.view.plot_window_seconds, lag
+                ),
+                None => format!(
+                    "{} — {} ({:.1}s)",
+                    route, desc, app.view.plot_window_seconds
+                ),
+            };
             let block = Block::default().title(title).borders(Borders::ALL);
 
             if let Some((data, _, _)) = app.get_plot_data() {
@@ -1680,20 +1831,32 @@ fn get_num(it: &InlineTable, k: &str) -> Option<f64> {
     it.get(k)
         .and_then(|v| v.as_float().or(v.as_integer().map(|i| i as f64)))
 }
+#[allow(clippy::too_many_arguments)]
 pub fn run_monitor(
     tio: TioOpts,
     fps: u32,
     colors: Option<String>,
     depth: Option<usize>,
+    filter: Option<Pipeline>,
+    calibration: Option<String>,
+    derive: Vec<DerivedSpec>,
+    history: Option<PathBuf>,
+    history_size_mib: u64,
+    gap_fill: u32,
 ) -> eyre::Result<()> {
+    use color_eyre::Help;
     use eyre::WrapErr;
 
+    let mut filter = filter.map(SampleFilter::new);
+    let calibration = load_calibration(calibration)?;
+
     let proxy = tio::proxy::Interface::new(&tio.root);
     let parent_route: DeviceRoute = tio.route.clone();
 
-    let tree = DeviceTree::open(&proxy, parent_route.clone())
+    let mut tree = DeviceTree::open(&proxy, parent_route.clone())
         .wrap_err_with(|| format!("could not open device tree on {}", tio.root))?;
-    let data_rx = spawn_tree_worker(tree);
+    tree.set_calibration(calibration);
+    let data_rx = spawn_clocked_tree_worker(tree);
 
     let rpc_client = RpcClient::open(&proxy, parent_route.clone())
         .wrap_err_with(|| format!("could not open RPC client on {}", tio.root))?;
@@ -1719,7 +1882,14 @@ pub fn run_monitor(
         }
     }
 
-    let mut buffer = Buffer::new(MONITOR_BUFFER_CAPACITY_SAMPLES);
+    let mut buffer = Buffer::new(MONITOR_BUFFER_CAPACITY_SAMPLES).with_gap_fill(gap_fill);
+    if let Some(dir) = &history {
+        let archive = Archive::create(dir, history_size_mib.saturating_mul(1 << 20))
+            .wrap_err_with(|| format!("could not create history in {}", dir.display()))?;
+        buffer = buffer.with_archive(archive);
+    }
+    // Derived columns are added once the columns they use have shown up.
+    let mut pending = derive;
 
     // UI
     let mut term = ratatui::init();
@@ -1730,10 +1900,26 @@ pub fn run_monitor(
         crossbeam::select! {
             recv(data_rx) -> item => {
                 match item {
-                    Ok(TreeItem::Sample(sample, route)) => {
-                        app.handle_sample(sample, route, &mut buffer);
+                    Ok((TreeItem::Sample(sample, route), clock)) => {
+                        if let Some(clock) = clock {
+                            buffer.set_clock(sample.time_ref(), clock);
+                        }
+                        let sample = match &mut filter {
+                            Some(filter) => match filter.process(sample, &route) {
+                                Ok(sample) => sample,
+                                Err(e) => {
+                                    ratatui::restore();
+                                    return Err(eyre::Report::new(e)
+                                        .wrap_err(format!("could not filter samples from {}", route)));
+                                }
+                            },
+                            None => Some(sample),
+                        };
+                        if let Some(sample) = sample {
+                            app.handle_sample(sample, route, &mut buffer);
+                        }
                     }
-                    Ok(TreeItem::Event(event)) => {
+                    Ok((TreeItem::Event(event), _)) => {
                         app.handle_event(event, &rpc_tx);
                     }
                     Err(_) => break 'main,
@@ -1771,6 +1957,12 @@ pub fn run_monitor(
             }
 
             recv(ui_tick) -> _ => {
+                if let Err(e) = app.bind_derived(&mut pending, &mut buffer) {
+                    ratatui::restore();
+                    return Err(eyre::Report::new(e)
+                        .wrap_err("could not add derived column")
+                        .suggestion("name columns by path, e.g. {/0/vector/x}"));
+                }
                 app.update_plot_window(&buffer);
                 app.rebuild_nav_items();
                 app.tick_blink();
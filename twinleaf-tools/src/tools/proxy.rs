@@ -1,12 +1,13 @@
 //! tio proxy
 //!
 //! Multiplexes access to a sensor, exposing the functionality of tio::proxy
-//! via TCP.
+//! via TCP, and optionally UDP.
 
 use crate::ProxyCli;
+use std::collections::hash_map::{Entry, HashMap};
 use std::io;
-use std::net::TcpListener;
-use std::time::Duration;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::time::{Duration, Instant};
 use tio::{proto, proxy};
 use twinleaf::device::discovery::{self, PortInterface};
 use twinleaf::tio;
@@ -24,9 +25,28 @@ macro_rules! log{
     };
 }
 
+/// Names record files like `tio log` names log files, adding a suffix when
+/// more than one file is started in the same second.
+fn record_file_namer(dir: std::path::PathBuf) -> impl FnMut() -> std::path::PathBuf + Send {
+    move || {
+        let stem = chrono::Local::now().format("log.%Y%m%d-%H%M%S").to_string();
+        let mut path = dir.join(format!("{}.tio", stem));
+        let mut n = 1;
+        while path.exists() {
+            path = dir.join(format!("{}-{}.tio", stem, n));
+            n += 1;
+        }
+        path
+    }
+}
+
+/// A new TCP client, with the traffic shaping of the port it connected to.
+type TcpClient = (std::net::TcpStream, Option<proxy::ClientQos>);
+
 fn create_listener_thread(
     addr: std::net::SocketAddr,
-    client_send: crossbeam::channel::Sender<std::net::TcpStream>,
+    qos: Option<proxy::ClientQos>,
+    client_send: crossbeam::channel::Sender<TcpClient>,
 ) -> io::Result<()> {
     let listener = TcpListener::bind(addr)?;
     std::thread::Builder::new()
@@ -34,7 +54,9 @@ fn create_listener_thread(
         .spawn(move || {
             for res in listener.incoming() {
                 match res {
-                    Ok(stream) => client_send.send(stream).expect("New client queue full"),
+                    Ok(stream) => client_send
+                        .send((stream, qos.clone()))
+                        .expect("New client queue full"),
                     Err(err) => eprintln!("error accepting client: {}", err),
                 };
             }
@@ -42,9 +64,164 @@ fn create_listener_thread(
     Ok(())
 }
 
+fn start_tcp_listeners(
+    tcp_port: u16,
+    qos: Option<proxy::ClientQos>,
+    client_send: crossbeam::channel::Sender<TcpClient>,
+) -> eyre::Result<()> {
+    use color_eyre::Help;
+
+    let started_v6 = create_listener_thread(
+        std::net::SocketAddr::new(
+            std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+            tcp_port,
+        ),
+        qos.clone(),
+        client_send.clone(),
+    );
+    let started_v4 = if let (Ok(()), false) = (&started_v6, cfg!(windows)) {
+        // If v6 started correctly and we are not in windows, pretend
+        // v4 also started correctly. The OS will pass the new clients
+        // through the v6 socket.
+        Ok(())
+    } else {
+        create_listener_thread(
+            std::net::SocketAddr::new(
+                std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+                tcp_port,
+            ),
+            qos,
+            client_send,
+        )
+    };
+    if let (Err(e1), Err(e2)) = (started_v6, started_v4) {
+        let addr_in_use = matches!(e1.kind(), io::ErrorKind::AddrInUse)
+            || matches!(e2.kind(), io::ErrorKind::AddrInUse);
+        let err = eyre::eyre!("could not bind TCP port {}: v6={}, v4={}", tcp_port, e1, e2);
+        return Err(if addr_in_use {
+            err.suggestion(format!(
+                "another 'tio proxy' is likely running on port {}; try --port <N>",
+                tcp_port
+            ))
+        } else {
+            err
+        });
+    }
+    Ok(())
+}
+
+/// A UDP client, as seen by the main loop: packets received from `addr`
+/// arrive on `rx`, and replies go back through `sock`. The listener drops the
+/// sending side of `rx` when the client expires.
+struct UdpClient {
+    addr: SocketAddr,
+    rx: crossbeam::channel::Receiver<tio::Packet>,
+    sock: UdpSocket,
+}
+
+fn create_udp_listener_thread(
+    port: u16,
+    client_timeout: Duration,
+    client_send: crossbeam::channel::Sender<UdpClient>,
+    tf: String,
+    verbose: bool,
+) -> io::Result<()> {
+    let sock = UdpSocket::bind(SocketAddr::new(
+        std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+        port,
+    ))
+    .or_else(|_| {
+        UdpSocket::bind(SocketAddr::new(
+            std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+            port,
+        ))
+    })?;
+    sock.set_read_timeout(Some(Duration::from_millis(250)))?;
+    std::thread::Builder::new()
+        .name("udp-listener".to_string())
+        .spawn(move || {
+            let mut clients: HashMap<
+                SocketAddr,
+                (crossbeam::channel::Sender<tio::Packet>, Instant),
+            > = HashMap::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let received = match sock.recv_from(&mut buf) {
+                    Ok((size, addr)) => Some((size, addr)),
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        None
+                    }
+                    Err(e) => {
+                        log!(tf, "UDP listener error: {}", e);
+                        None
+                    }
+                };
+
+                clients.retain(|addr, (_, last_rx)| {
+                    let alive = last_rx.elapsed() < client_timeout;
+                    if !alive && verbose {
+                        log!(tf, "UDP client {} timed out", addr);
+                    }
+                    alive
+                });
+
+                let (size, addr) = if let Some(r) = received { r } else { continue };
+                // Same framing as tio::port::udp: exactly one packet per datagram.
+                let pkt = match tio::Packet::deserialize(&buf[..size]) {
+                    Ok((pkt, len)) if len == size => pkt,
+                    _ => {
+                        if verbose {
+                            log!(tf, "Ignoring malformed datagram from {}", addr);
+                        }
+                        continue;
+                    }
+                };
+
+                let (tx, last_rx) = match clients.entry(addr) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let reply_sock = match sock.try_clone() {
+                            Ok(s) => s,
+                            Err(e) => {
+                                log!(tf, "Failed to set up UDP client {}: {}", addr, e);
+                                continue;
+                            }
+                        };
+                        let (tx, rx) = crossbeam::channel::bounded::<tio::Packet>(
+                            proxy::Interface::get_client_tx_channel_size(),
+                        );
+                        if client_send
+                            .send(UdpClient {
+                                addr,
+                                rx,
+                                sock: reply_sock,
+                            })
+                            .is_err()
+                        {
+                            // Main loop is gone
+                            break;
+                        }
+                        entry.insert((tx, Instant::now()))
+                    }
+                };
+                *last_rx = Instant::now();
+                if let Err(crossbeam::channel::TrySendError::Disconnected(_)) = tx.try_send(pkt) {
+                    // The client thread exited, forget it so it can reconnect.
+                    clients.remove(&addr);
+                }
+            }
+        })?;
+    Ok(())
+}
+
 pub fn run_proxy(proxy_cli: ProxyCli) -> eyre::Result<()> {
     use color_eyre::{Help, SectionExt};
-    use eyre::bail;
+    use eyre::{bail, WrapErr};
 
     // Handle --enum mode (deprecated; now delegates to `tio list`)
     if proxy_cli.enumerate {
@@ -105,14 +282,84 @@ pub fn run_proxy(proxy_cli: ProxyCli) -> eyre::Result<()> {
 
     let subtree = proxy_cli.subtree;
 
+    let shaped_qos = proxy::ClientQos {
+        streams: if proxy_cli.shaped_streams.is_empty() {
+            None
+        } else {
+            Some(proxy_cli.shaped_streams.clone())
+        },
+        decimation: proxy_cli.shaped_decimate,
+        max_data_rate: proxy_cli.shaped_max_rate,
+        rpc_priority: true,
+    };
+
+    let mut options = proxy::ProxyOptions {
+        standby_urls: proxy_cli.standby.clone(),
+        introspection: true,
+        ..Default::default()
+    };
+    if let Some(path) = &proxy_cli.audit_log {
+        let log = proxy::RpcAuditLog::open(path)
+            .wrap_err_with(|| format!("could not open audit log {}", path.display()))?;
+        options.audit_log = Some(log);
+    }
+    if let Some(dir) = &proxy_cli.record {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("could not create record directory {}", dir.display()))?;
+        let mut recorder = proxy::ProxyRecorder::new(record_file_namer(dir.clone()));
+        if let Some(mb) = proxy_cli.record_size {
+            recorder = recorder.roll_size(mb.saturating_mul(1_000_000));
+        }
+        if let Some(interval) = proxy_cli.record_interval {
+            recorder = recorder.roll_interval(interval);
+        }
+        options.recorder = Some(recorder);
+    }
+
     println!("tio proxy starting:");
     println!(
         "  Sensor: {} {}",
         sensor_url,
         if auto_detected { "(auto-detected)" } else { "" }
     );
+    for url in &proxy_cli.standby {
+        println!("  Standby: {}", url);
+    }
     println!("  TCP port: {}", tcp_port);
+    if let Some(shaped_port) = proxy_cli.shaped_port {
+        print!("  Shaped TCP port: {}", shaped_port);
+        if let Some(streams) = &shaped_qos.streams {
+            let ids: Vec<String> = streams.iter().map(|id| id.to_string()).collect();
+            print!(" streams={}", ids.join(","));
+        }
+        if shaped_qos.decimation > 1 {
+            print!(" decimate={}", shaped_qos.decimation);
+        }
+        if let Some(rate) = shaped_qos.max_data_rate {
+            print!(" max-rate={}/s", rate);
+        }
+        println!();
+    }
+    if let Some(udp_port) = proxy_cli.udp_port {
+        println!(
+            "  UDP port: {} (client timeout {}s)",
+            udp_port, proxy_cli.udp_timeout
+        );
+    }
+    if let Some(dir) = &proxy_cli.record {
+        print!("  Recording to: {}", dir.display());
+        if let Some(mb) = proxy_cli.record_size {
+            print!(" roll-size={}MB", mb);
+        }
+        if let Some(interval) = proxy_cli.record_interval {
+            print!(" roll-interval={}", humantime::format_duration(interval));
+        }
+        println!();
+    }
     println!("  Subtree: {}", subtree);
+    if let Some(path) = &proxy_cli.audit_log {
+        println!("  RPC audit log: {}", path.display());
+    }
     if verbose || debugging || dump_traffic || dump_data || dump_meta || dump_hb {
         print!("  Flags:");
         if verbose {
@@ -141,47 +388,36 @@ pub fn run_proxy(proxy_cli: ProxyCli) -> eyre::Result<()> {
     println!();
 
     let new_client = {
-        let (client_send, new_client) = crossbeam::channel::bounded::<std::net::TcpStream>(10);
-        let started_v6 = create_listener_thread(
-            std::net::SocketAddr::new(
-                std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
-                tcp_port,
-            ),
-            client_send.clone(),
-        );
-        let started_v4 = if let (Ok(()), false) = (&started_v6, cfg!(windows)) {
-            // If v6 started correctly and we are not in windows, pretend
-            // v4 also started correctly. The OS will pass the new clients
-            // through the v6 socket.
-            Ok(())
-        } else {
-            create_listener_thread(
-                std::net::SocketAddr::new(
-                    std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
-                    tcp_port,
-                ),
-                client_send.clone(),
-            )
-        };
-        if let (Err(e1), Err(e2)) = (started_v6, started_v4) {
-            let addr_in_use = matches!(e1.kind(), io::ErrorKind::AddrInUse)
-                || matches!(e2.kind(), io::ErrorKind::AddrInUse);
-            let err = eyre::eyre!("could not bind TCP port {}: v6={}, v4={}", tcp_port, e1, e2);
-            return Err(if addr_in_use {
-                err.suggestion(format!(
-                    "another 'tio proxy' is likely running on port {}; try --port <N>",
-                    tcp_port
-                ))
-            } else {
-                err
-            });
+        let (client_send, new_client) = crossbeam::channel::bounded::<TcpClient>(10);
+        start_tcp_listeners(tcp_port, None, client_send.clone())?;
+        if let Some(shaped_port) = proxy_cli.shaped_port {
+            start_tcp_listeners(shaped_port, Some(shaped_qos.clone()), client_send)?;
         }
         new_client
     };
 
+    let new_udp_client = if let Some(udp_port) = proxy_cli.udp_port {
+        let (client_send, new_udp_client) = crossbeam::channel::bounded::<UdpClient>(10);
+        create_udp_listener_thread(
+            udp_port,
+            Duration::from_secs(proxy_cli.udp_timeout),
+            client_send,
+            tf.clone(),
+            verbose,
+        )
+        .map_err(|e| eyre::eyre!("could not bind UDP port {}: {}", udp_port, e))?;
+        new_udp_client
+    } else {
+        crossbeam::channel::never()
+    };
+
     let (status_send, port_status) = crossbeam::channel::bounded::<proxy::Event>(100);
-    let proxy =
-        proxy::Interface::new_proxy(&sensor_url, Some(reconnect_timeout), Some(status_send));
+    let proxy = proxy::Interface::new_proxy_with_options(
+        &sensor_url,
+        Some(reconnect_timeout),
+        Some(status_send),
+        options,
+    );
 
     // This is used by the proxy itself to communicate with the device tree.
     // for now only used to receive log messages and dump traffic.
@@ -203,7 +439,7 @@ pub fn run_proxy(proxy_cli: ProxyCli) -> eyre::Result<()> {
     loop {
         select! {
             recv(new_client) -> tcp_client => {
-                if let Ok(stream) = tcp_client {
+                if let Ok((stream, qos)) = tcp_client {
                     let addr = match stream.peer_addr() {
                         Ok(addr) => addr.to_string(),
                         Err(err) => {
@@ -222,9 +458,9 @@ pub fn run_proxy(proxy_cli: ProxyCli) -> eyre::Result<()> {
                     };
 
                     if verbose {
-                        log!(tf, "Accepted client from {}", addr);
+                        log!(tf, "Accepted {}client from {}", if qos.is_some() { "shaped " } else { "" }, addr);
                     }
-                    let port = proxy.new_port(Some(Duration::from_millis(2000)), subtree.clone(), usize::MAX, true, true).expect("Failed to create new proxy port");
+                    let port = proxy.new_port_with_qos(Some(&addr), qos.unwrap_or_default(), Some(Duration::from_millis(2000)), subtree.clone(), usize::MAX).expect("Failed to create new proxy port");
                     let tf = tf.clone();
                     std::thread::spawn(move || {
                         let mut is_slow = false;
@@ -298,14 +534,73 @@ pub fn run_proxy(proxy_cli: ProxyCli) -> eyre::Result<()> {
                     bail!("listener thread died unexpectedly");
                 }
             }
+            recv(new_udp_client) -> udp_client => {
+                let UdpClient { addr, rx, sock } = if let Ok(c) = udp_client { c } else {
+                    bail!("UDP listener thread died unexpectedly");
+                };
+                if verbose {
+                    log!(tf, "New UDP client from {}", addr);
+                }
+                let peer = format!("udp://{}", addr);
+                let port = proxy.new_peer_port(&peer, Some(Duration::from_millis(2000)), subtree.clone(), usize::MAX, true, true).expect("Failed to create new proxy port");
+                let tf = tf.clone();
+                std::thread::spawn(move || {
+                    loop {
+                        select! {
+                            recv(port.receiver()) -> res => {
+                                let pkt = if let Ok(pkt) = res { pkt } else {
+                                    log!(tf, "Disconnecting client {} due to internal error receiving tio data in thread", peer);
+                                    break;
+                                };
+                                if dump_traffic && matches!(pkt.payload, proto::Payload::RpcRequest(_) | proto::Payload::RpcReply(_) | proto::Payload::RpcError(_)) {
+                                    log!(tf, "{}->{} -- {:?}", pkt.routing, peer, pkt.payload);
+                                }
+                                let raw = if let Ok(raw) = pkt.serialize() { raw } else { continue };
+                                if let Err(e) = sock.send_to(&raw, addr) {
+                                    if verbose {
+                                        log!(tf, "Disconnecting client {}: {}", peer, e);
+                                    }
+                                    break;
+                                }
+                            }
+                            recv(rx) -> res => {
+                                let pkt = if let Ok(pkt) = res { pkt } else {
+                                    // Expired by the listener
+                                    break;
+                                };
+                                if dump_traffic {
+                                    log!(tf, "{}->{} -- {:?}", peer, pkt.routing, pkt.payload);
+                                }
+                                if port.try_send(pkt).is_err() {
+                                    log!(tf, "Disconnecting client {} due to internal error forwarding tio data in thread", peer);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                });
+            }
             recv(port_status) -> status => {
                 if let Ok(evt) = status {
                     match evt {
                         proxy::Event::SensorDisconnected => {
                             log!(tf, "Sensor disconnected");
                         }
+                        proxy::Event::LinkActive(url) => {
+                            if !proxy_cli.standby.is_empty() {
+                                log!(tf, "Sensor reconnected via {}", url);
+                            }
+                        }
                         proxy::Event::SensorReconnected => {
-                            log!(tf, "Sensor reconnected");
+                            if proxy_cli.standby.is_empty() {
+                                log!(tf, "Sensor reconnected");
+                            }
+                        }
+                        proxy::Event::LinkSerialMismatch { url, expected, found } => {
+                            log!(tf, "Not using {}: serial number {} does not match {}", url, found, expected);
+                        }
+                        proxy::Event::LinkUnresponsive(url) => {
+                            log!(tf, "Not using {}: no response from sensor", url);
                         }
                         proxy::Event::FailedToReconnect => {
                             log!(tf, "Stopping reconnection attempts due to timeout");
@@ -313,6 +608,15 @@ pub fn run_proxy(proxy_cli: ProxyCli) -> eyre::Result<()> {
                         proxy::Event::FailedToConnect => {
                             log!(tf, "Fatal proxy error: failed to connect to sensor");
                         }
+                        proxy::Event::AuditLogFailed(err) => {
+                            log!(tf, "RPC audit log disabled after write error: {}", err);
+                        }
+                        proxy::Event::RecordFileStarted(path) => {
+                            log!(tf, "Recording to {}", path.display());
+                        }
+                        proxy::Event::RecordFailed(err) => {
+                            log!(tf, "Recording stopped after write error: {}", err);
+                        }
                         proxy::Event::FatalError(err) => {
                             log!(tf, "Fatal proxy error: {:?}", err);
                             // the proxy thread will exit and we'll detect it at the next iteration.
@@ -56,8 +56,12 @@ fn broadcast_to_client(mut stream: TcpStream, port: tio::proxy::Port) {
             continue;
         }
 
-        // Convert timestamp to NMEA format (HHMMSS.SS)
-        let timestamp = sample.timestamp_end();
+        // Convert UTC time of day to NMEA format (HHMMSS.SS)
+        let clock = device.clock(&sample.time_ref());
+        let timestamp = sample
+            .utc(clock.as_ref())
+            .unwrap_or_else(|| sample.timestamp_end())
+            % 86400.0;
         let hours = (timestamp / 3600.0) as u32;
         let minutes = ((timestamp % 3600.0) / 60.0) as u32;
         let seconds = timestamp % 60.0;
@@ -0,0 +1,600 @@
+//! RPC scripts for `tio rpc run`
+//!
+//! A script is a sequence of RPC calls and checks, one statement per line:
+//!
+//! ```text
+//! # Comments start with '#'
+//! let f = 10                   # variables, used as $f or ${f}
+//! set test.frequency $f        # write an RPC
+//! get test.frequency           # read and print an RPC
+//! let amp = get test.amplitude # read an RPC into a variable
+//! call test.go                 # call an RPC with an optional argument
+//! wait 500ms                   # pause
+//! echo amplitude is $amp       # print a message
+//! expect test.frequency == $f          # check an RPC value
+//! expect test.amplitude == 1 +- 0.05   # ... within a tolerance
+//! expect dev.name != "other device"    # ... also !=, <, <=, >, >=
+//! repeat 3                     # loops, ended by 'end'
+//!     call test.go
+//! end
+//! for f in 1 2 5..=8           # items and integer ranges (a..b, a..=b)
+//!     set test.frequency $f
+//! end
+//! ```
+//!
+//! The whole script is parsed before anything is sent to the device.
+//! Values are encoded and decoded according to the type reported by the
+//! device for each RPC.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use eyre::{bail, eyre, WrapErr};
+use twinleaf::device::util::{parse_rpc_spec, rpc_decode_reply, rpc_encode_arg};
+use twinleaf::device::{RpcValue, RpcValueType};
+use twinleaf::tio::{proto, proxy};
+
+use crate::TioOpts;
+
+#[derive(Debug, Clone, Copy)]
+enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Compare {
+    fn parse(s: &str) -> Option<Compare> {
+        Some(match s {
+            "==" => Compare::Eq,
+            "!=" => Compare::Ne,
+            "<" => Compare::Lt,
+            "<=" => Compare::Le,
+            ">" => Compare::Gt,
+            ">=" => Compare::Ge,
+            _ => return None,
+        })
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Compare::Eq => "==",
+            Compare::Ne => "!=",
+            Compare::Lt => "<",
+            Compare::Le => "<=",
+            Compare::Gt => ">",
+            Compare::Ge => ">=",
+        }
+    }
+}
+
+/// Script statement. Arguments are kept as written, and variables in them
+/// are substituted when the statement runs.
+#[derive(Debug)]
+enum Stmt {
+    Set {
+        rpc: String,
+        value: String,
+    },
+    Get {
+        rpc: String,
+        var: Option<String>,
+    },
+    Call {
+        rpc: String,
+        arg: Option<String>,
+    },
+    Let {
+        var: String,
+        value: String,
+    },
+    Wait(String),
+    Echo(Vec<String>),
+    Expect {
+        rpc: String,
+        op: Compare,
+        value: String,
+        tolerance: Option<String>,
+    },
+    Repeat {
+        count: String,
+        body: Vec<Line>,
+    },
+    For {
+        var: String,
+        items: Vec<String>,
+        body: Vec<Line>,
+    },
+}
+
+#[derive(Debug)]
+struct Line {
+    number: usize,
+    stmt: Stmt,
+}
+
+/// Split a line into words. Double quotes group words, and `#` outside
+/// quotes starts a comment.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => token.push(c),
+                        None => return Err("unterminated string".to_string()),
+                    },
+                    Some(c) => token.push(c),
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+struct Parser<'a> {
+    lines: std::iter::Enumerate<std::str::Lines<'a>>,
+}
+
+impl Parser<'_> {
+    /// Parse statements until `end`, if `in_block`, or the end of the
+    /// script.
+    fn block(&mut self, in_block: Option<usize>) -> eyre::Result<Vec<Line>> {
+        let mut stmts = vec![];
+        while let Some((index, text)) = self.lines.next() {
+            let number = index + 1;
+            let tokens = tokenize(text).map_err(|e| eyre!("line {}: {}", number, e))?;
+            if tokens.is_empty() {
+                continue;
+            }
+            if tokens[0] == "end" && tokens.len() == 1 {
+                if in_block.is_none() {
+                    bail!("line {}: 'end' without 'repeat' or 'for'", number);
+                }
+                return Ok(stmts);
+            }
+            let mut stmt = statement(tokens).map_err(|e| eyre!("line {}: {}", number, e))?;
+            if let Stmt::Repeat { body, .. } | Stmt::For { body, .. } = &mut stmt {
+                *body = self.block(Some(number))?;
+            }
+            stmts.push(Line { number, stmt });
+        }
+        match in_block {
+            Some(start) => bail!("line {}: block is missing 'end'", start),
+            None => Ok(stmts),
+        }
+    }
+}
+
+/// Parse one statement. Bodies of blocks are left empty.
+fn statement(tokens: Vec<String>) -> eyre::Result<Stmt> {
+    let args: Vec<&str> = tokens.iter().map(|s| s.as_str()).collect();
+    Ok(match args.as_slice() {
+        ["set", rpc, value] => Stmt::Set {
+            rpc: rpc.to_string(),
+            value: value.to_string(),
+        },
+        ["get", rpc] => Stmt::Get {
+            rpc: rpc.to_string(),
+            var: None,
+        },
+        ["call", rpc] => Stmt::Call {
+            rpc: rpc.to_string(),
+            arg: None,
+        },
+        ["call", rpc, arg] => Stmt::Call {
+            rpc: rpc.to_string(),
+            arg: Some(arg.to_string()),
+        },
+        ["let", var, "=", "get", rpc] if is_identifier(var) => Stmt::Get {
+            rpc: rpc.to_string(),
+            var: Some(var.to_string()),
+        },
+        ["let", var, "=", value] if is_identifier(var) => Stmt::Let {
+            var: var.to_string(),
+            value: value.to_string(),
+        },
+        ["wait", duration] => Stmt::Wait(duration.to_string()),
+        ["echo", ..] => Stmt::Echo(tokens[1..].to_vec()),
+        ["expect", rpc, op, value, rest @ ..] => {
+            let op = Compare::parse(op).ok_or_else(|| eyre!("unknown comparison '{}'", op))?;
+            let tolerance = match rest {
+                [] => None,
+                ["+-", tol] if matches!(op, Compare::Eq | Compare::Ne) => Some(tol.to_string()),
+                ["+-", _] => bail!("a tolerance can only be used with == or !="),
+                _ => bail!("expected 'expect <rpc> <op> <value> [+- <tolerance>]'"),
+            };
+            Stmt::Expect {
+                rpc: rpc.to_string(),
+                op,
+                value: value.to_string(),
+                tolerance,
+            }
+        }
+        ["repeat", count] => Stmt::Repeat {
+            count: count.to_string(),
+            body: vec![],
+        },
+        ["for", var, "in", items @ ..] if is_identifier(var) && !items.is_empty() => Stmt::For {
+            var: var.to_string(),
+            items: items.iter().map(|s| s.to_string()).collect(),
+            body: vec![],
+        },
+        [cmd, ..] => match *cmd {
+            "set" | "get" | "call" | "let" | "wait" | "expect" | "repeat" | "for" => {
+                bail!("wrong arguments for '{}'", cmd)
+            }
+            _ => bail!("unknown statement '{}'", cmd),
+        },
+        [] => unreachable!("empty lines are skipped"),
+    })
+}
+
+fn parse_script(text: &str) -> eyre::Result<Vec<Line>> {
+    Parser {
+        lines: text.lines().enumerate(),
+    }
+    .block(None)
+}
+
+/// Expand an integer range `a..b` or `a..=b`, or keep `item` as is.
+fn expand_item(item: &str) -> eyre::Result<Vec<String>> {
+    let (start, end, inclusive) = match item.split_once("..") {
+        Some((start, end)) => match end.strip_prefix('=') {
+            Some(end) => (start, end, true),
+            None => (start, end, false),
+        },
+        None => return Ok(vec![item.to_string()]),
+    };
+    let start: i64 = start
+        .parse()
+        .map_err(|_| eyre!("invalid range start in '{}'", item))?;
+    let end: i64 = end
+        .parse()
+        .map_err(|_| eyre!("invalid range end in '{}'", item))?;
+    let range: Vec<String> = if inclusive {
+        (start..=end).map(|i| i.to_string()).collect()
+    } else {
+        (start..end).map(|i| i.to_string()).collect()
+    };
+    Ok(range)
+}
+
+fn as_f64(value: &RpcValue) -> Option<f64> {
+    match value {
+        RpcValue::U64(n) => Some(*n as f64),
+        RpcValue::I64(n) => Some(*n as f64),
+        RpcValue::F64(x) => Some(*x),
+        _ => None,
+    }
+}
+
+struct Runner<'a> {
+    device: &'a proxy::Port,
+    vars: HashMap<String, String>,
+    types: HashMap<String, RpcValueType>,
+    verbose: bool,
+    keep_going: bool,
+    checks: usize,
+    failures: usize,
+}
+
+impl Runner<'_> {
+    /// Substitute `$name` and `${name}` in `s`. `$$` is a literal `$`.
+    fn expand(&self, s: &str) -> eyre::Result<String> {
+        let mut out = String::new();
+        let mut rest = s;
+        while let Some(pos) = rest.find('$') {
+            out.push_str(&rest[..pos]);
+            rest = &rest[pos + 1..];
+            if let Some(r) = rest.strip_prefix('$') {
+                out.push('$');
+                rest = r;
+                continue;
+            }
+            let (name, after) = if let Some(r) = rest.strip_prefix('{') {
+                let end = r
+                    .find('}')
+                    .ok_or_else(|| eyre!("unterminated '${{' in '{}'", s))?;
+                (&r[..end], &r[end + 1..])
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                (&rest[..end], &rest[end..])
+            };
+            let value = self
+                .vars
+                .get(name)
+                .ok_or_else(|| eyre!("undefined variable '{}'", name))?;
+            out.push_str(value);
+            rest = after;
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn rpc_type(&mut self, rpc: &str) -> eyre::Result<RpcValueType> {
+        if let Some(kind) = self.types.get(rpc) {
+            return Ok(kind.clone());
+        }
+        let meta: u16 = match self.device.rpc("rpc.info", rpc) {
+            Ok(meta) => meta,
+            Err(proxy::RpcError::ExecError(err))
+                if matches!(err.error, proto::RpcErrorCode::NotFound) =>
+            {
+                bail!("RPC '{}' not found", rpc)
+            }
+            Err(err) => {
+                return Err(eyre::Report::new(err).wrap_err(format!("failed to look up {}", rpc)))
+            }
+        };
+        let kind = match parse_rpc_spec(meta, rpc.to_string()).data_kind {
+            RpcValueType::Raw { .. } => RpcValueType::String { max_len: None },
+            kind => kind,
+        };
+        self.types.insert(rpc.to_string(), kind.clone());
+        Ok(kind)
+    }
+
+    fn call(&mut self, rpc: &str, arg: Option<&str>) -> eyre::Result<RpcValue> {
+        let kind = self.rpc_type(rpc)?;
+        let arg = match arg {
+            Some(arg) => rpc_encode_arg(arg, &kind)
+                .wrap_err_with(|| format!("could not encode argument for {}", rpc))?,
+            None => vec![],
+        };
+        let reply = self
+            .device
+            .raw_rpc(rpc, &arg)
+            .wrap_err_with(|| format!("RPC {} failed", rpc))?;
+        if reply.is_empty() {
+            return Ok(RpcValue::Unit);
+        }
+        rpc_decode_reply(&reply, &kind)
+            .wrap_err_with(|| format!("could not decode reply from {}", rpc))
+    }
+
+    fn expect(
+        &mut self,
+        number: usize,
+        rpc: &str,
+        op: Compare,
+        expected: &str,
+        tolerance: Option<&str>,
+    ) -> eyre::Result<()> {
+        let value = self.call(rpc, None)?;
+        let tolerance = match tolerance {
+            Some(tol) => Some(
+                tol.parse::<f64>()
+                    .map_err(|_| eyre!("invalid tolerance '{}'", tol))?,
+            ),
+            None => None,
+        };
+        // Round the expected value to the RPC's own type, so that 0.1
+        // matches the reply of an f32 RPC. Values the type cannot hold, such
+        // as 1.5 for an integer, are compared as they are.
+        let kind = self.rpc_type(rpc)?;
+        let want = rpc_encode_arg(expected, &kind)
+            .ok()
+            .and_then(|arg| rpc_decode_reply(&arg, &kind).ok())
+            .and_then(|want| as_f64(&want))
+            .or_else(|| expected.parse::<f64>().ok());
+        let passed = match (as_f64(&value), want) {
+            (Some(got), Some(want)) => {
+                let tol = tolerance.unwrap_or(0.0);
+                match op {
+                    Compare::Eq => (got - want).abs() <= tol,
+                    Compare::Ne => (got - want).abs() > tol,
+                    Compare::Lt => got < want,
+                    Compare::Le => got <= want,
+                    Compare::Gt => got > want,
+                    Compare::Ge => got >= want,
+                }
+            }
+            _ if tolerance.is_some() => bail!("a tolerance needs numeric values"),
+            _ => {
+                let got = value.to_string();
+                match op {
+                    Compare::Eq => got == expected,
+                    Compare::Ne => got != expected,
+                    _ => bail!("'{}' needs numeric values", op.as_str()),
+                }
+            }
+        };
+
+        self.checks += 1;
+        let tol = tolerance.map(|t| format!(" +- {}", t)).unwrap_or_default();
+        let description = format!(
+            "line {}: {} {} {}{} (got {})",
+            number,
+            rpc,
+            op.as_str(),
+            expected,
+            tol,
+            value
+        );
+        if passed {
+            if self.verbose {
+                println!("PASS  {}", description);
+            }
+            Ok(())
+        } else {
+            self.failures += 1;
+            println!("FAIL  {}", description);
+            if self.keep_going {
+                Ok(())
+            } else {
+                bail!("expectation failed")
+            }
+        }
+    }
+
+    fn run(&mut self, lines: &[Line]) -> eyre::Result<()> {
+        for line in lines {
+            self.run_line(line)
+                .wrap_err_with(|| format!("error at line {}", line.number))?;
+        }
+        Ok(())
+    }
+
+    fn run_line(&mut self, line: &Line) -> eyre::Result<()> {
+        match &line.stmt {
+            Stmt::Set { rpc, value } => {
+                let (rpc, value) = (self.expand(rpc)?, self.expand(value)?);
+                let reply = self.call(&rpc, Some(&value))?;
+                if self.verbose {
+                    println!("{} = {}", rpc, reply);
+                }
+            }
+            Stmt::Get { rpc, var } => {
+                let rpc = self.expand(rpc)?;
+                let value = self.call(&rpc, None)?;
+                match var {
+                    Some(var) => {
+                        self.vars.insert(var.clone(), value.to_string());
+                    }
+                    None => println!("{} = {}", rpc, value),
+                }
+            }
+            Stmt::Call { rpc, arg } => {
+                let rpc = self.expand(rpc)?;
+                let arg = arg.as_deref().map(|a| self.expand(a)).transpose()?;
+                let reply = self.call(&rpc, arg.as_deref())?;
+                if self.verbose || !matches!(reply, RpcValue::Unit) {
+                    println!("{} -> {}", rpc, reply);
+                }
+            }
+            Stmt::Let { var, value } => {
+                let value = self.expand(value)?;
+                self.vars.insert(var.clone(), value);
+            }
+            Stmt::Wait(duration) => {
+                let duration = self.expand(duration)?;
+                let duration: Duration = humantime::parse_duration(&duration)
+                    .wrap_err_with(|| format!("invalid duration '{}'", duration))?;
+                if self.verbose {
+                    println!("wait {}", humantime::format_duration(duration));
+                }
+                std::thread::sleep(duration);
+            }
+            Stmt::Echo(words) => {
+                let words = words
+                    .iter()
+                    .map(|w| self.expand(w))
+                    .collect::<eyre::Result<Vec<_>>>()?;
+                println!("{}", words.join(" "));
+            }
+            Stmt::Expect {
+                rpc,
+                op,
+                value,
+                tolerance,
+            } => {
+                let rpc = self.expand(rpc)?;
+                let value = self.expand(value)?;
+                let tolerance = tolerance.as_deref().map(|t| self.expand(t)).transpose()?;
+                self.expect(line.number, &rpc, *op, &value, tolerance.as_deref())?;
+            }
+            Stmt::Repeat { count, body } => {
+                let count = self.expand(count)?;
+                let count: u64 = count
+                    .parse()
+                    .map_err(|_| eyre!("invalid repeat count '{}'", count))?;
+                for _ in 0..count {
+                    self.run(body)?;
+                }
+            }
+            Stmt::For { var, items, body } => {
+                let mut values = vec![];
+                for item in items {
+                    values.extend(expand_item(&self.expand(item)?)?);
+                }
+                for value in values {
+                    self.vars.insert(var.clone(), value);
+                    self.run(body)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run the script at `path` on the device selected by `tio`. `vars`
+/// predefines script variables. Fails on the first error, or failed
+/// expectation unless `keep_going`, in which case it fails at the end if
+/// any did.
+pub fn rpc_run(
+    tio: &TioOpts,
+    path: &std::path::Path,
+    vars: Vec<(String, String)>,
+    verbose: bool,
+    keep_going: bool,
+    check_only: bool,
+) -> eyre::Result<()> {
+    let text = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("could not read {}", path.display()))?;
+    let script =
+        parse_script(&text).wrap_err_with(|| format!("invalid script {}", path.display()))?;
+    if check_only {
+        println!("{}: OK", path.display());
+        return Ok(());
+    }
+
+    let proxy = proxy::Interface::new(&tio.root);
+    let device = proxy
+        .device_rpc(tio.route.clone())
+        .wrap_err_with(|| format!("could not open device at {}", tio.root))?;
+    let mut runner = Runner {
+        device: &device,
+        vars: vars.into_iter().collect(),
+        types: HashMap::new(),
+        verbose,
+        keep_going,
+        checks: 0,
+        failures: 0,
+    };
+    runner.run(&script)?;
+
+    if runner.checks > 0 {
+        println!(
+            "{} of {} expectations passed",
+            runner.checks - runner.failures,
+            runner.checks
+        );
+    }
+    if runner.failures > 0 {
+        let plural = if runner.failures == 1 { "" } else { "s" };
+        bail!("{} failed expectation{}", runner.failures, plural);
+    }
+    Ok(())
+}
@@ -0,0 +1,196 @@
+//! `tio settings` — save device settings to a TOML file, restore them, and
+//! compare them with a file or another device.
+
+use std::path::PathBuf;
+
+use crate::TioOpts;
+use twinleaf::device::rpc::{Setting, SettingDiff, SettingsSnapshot};
+use twinleaf::device::RpcClient;
+use twinleaf::tio::proto::DeviceRoute;
+use twinleaf::tio::proxy;
+
+pub fn save_settings(tio: &TioOpts, file: PathBuf) -> eyre::Result<()> {
+    use eyre::WrapErr;
+
+    let proxy = proxy::Interface::new(&tio.root);
+    let route = tio.route.clone();
+    let rpc_client = RpcClient::open(&proxy, route.clone())
+        .wrap_err_with(|| format!("could not open RPC client for {}", tio.root))?;
+    let snapshot = rpc_client
+        .save_settings(&route)
+        .wrap_err("failed to read device settings")?;
+    warn_unread(&snapshot);
+
+    std::fs::write(&file, snapshot.to_toml())
+        .wrap_err_with(|| format!("could not write {}", file.display()))?;
+    println!(
+        "Saved {} settings to {}",
+        snapshot.settings.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+pub fn restore_settings(
+    tio: &TioOpts,
+    file: PathBuf,
+    dry_run: bool,
+    skip_confirm: bool,
+) -> eyre::Result<()> {
+    use color_eyre::Help;
+    use eyre::WrapErr;
+
+    let text = std::fs::read_to_string(&file)
+        .wrap_err_with(|| format!("could not read {}", file.display()))?;
+    let snapshot = SettingsSnapshot::from_toml(&text)
+        .wrap_err_with(|| format!("could not load {}", file.display()))?;
+
+    let proxy = proxy::Interface::new(&tio.root);
+    let route = tio.route.clone();
+    let rpc_client = RpcClient::open(&proxy, route.clone())
+        .wrap_err_with(|| format!("could not open RPC client for {}", tio.root))?;
+
+    let dev_name: Option<String> = rpc_client.get(&route, "dev.name").ok();
+    if let (Some(saved), Some(current)) = (&snapshot.device, &dev_name) {
+        if saved != current {
+            eprintln!(
+                "Warning: settings were saved from '{}', restoring to '{}'",
+                saved, current
+            );
+        }
+    }
+
+    let plan = rpc_client
+        .plan_restore(&route, &snapshot)
+        .wrap_err("failed to compare settings with the device")?;
+    print!("{}", plan.preview());
+    println!(
+        "{} to change, {} unchanged, {} skipped",
+        plan.changes.len(),
+        plan.unchanged,
+        plan.skipped.len()
+    );
+    if dry_run || plan.changes.is_empty() {
+        return Ok(());
+    }
+
+    if !skip_confirm {
+        print!(
+            "Apply {} changes to '{}'? [y/N] ",
+            plan.changes.len(),
+            dev_name.as_deref().unwrap_or("device")
+        );
+        std::io::Write::flush(&mut std::io::stdout()).unwrap();
+        let mut input = String::new();
+        std::io::stdin().read_line(&mut input).unwrap();
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Aborted.");
+            return Ok(());
+        }
+    }
+
+    rpc_client
+        .apply_restore(&route, &plan)
+        .wrap_err("failed to restore settings")
+        .suggestion("settings before the failed one were already written")?;
+    println!("Restored {} settings", plan.changes.len());
+    Ok(())
+}
+
+fn read_device(
+    root: &str,
+    route: &DeviceRoute,
+    writable_only: bool,
+) -> eyre::Result<SettingsSnapshot> {
+    use eyre::WrapErr;
+
+    let proxy = proxy::Interface::new(root);
+    let rpc_client = RpcClient::open(&proxy, route.clone())
+        .wrap_err_with(|| format!("could not open RPC client for {}", root))?;
+    let snapshot = if writable_only {
+        rpc_client.save_settings(route)
+    } else {
+        rpc_client.read_values(route)
+    }
+    .wrap_err_with(|| format!("failed to read settings of {} {}", root, route))?;
+    warn_unread(&snapshot);
+    Ok(snapshot)
+}
+
+fn warn_unread(snapshot: &SettingsSnapshot) {
+    for (_, err) in &snapshot.unread {
+        eprintln!("Warning: {} (skipped)", err);
+    }
+}
+
+fn describe(setting: &Setting) -> String {
+    format!(
+        "{} ({} {})",
+        setting.value,
+        setting.kind.type_str(),
+        setting.perm_str()
+    )
+}
+
+pub fn diff_settings(
+    tio: &TioOpts,
+    file: Option<PathBuf>,
+    with_root: Option<String>,
+    with_route: Option<DeviceRoute>,
+    writable_only: bool,
+) -> eyre::Result<()> {
+    use eyre::WrapErr;
+
+    let (right, right_label) = if let Some(file) = file {
+        let text = std::fs::read_to_string(&file)
+            .wrap_err_with(|| format!("could not read {}", file.display()))?;
+        let snapshot = SettingsSnapshot::from_toml(&text)
+            .wrap_err_with(|| format!("could not load {}", file.display()))?;
+        (snapshot, file.display().to_string())
+    } else {
+        let root = with_root.unwrap_or_else(|| tio.root.clone());
+        let route = with_route.unwrap_or_else(|| tio.route.clone());
+        let label = format!("{} {}", root, route);
+        (read_device(&root, &route, writable_only)?, label)
+    };
+    // Files saved by `tio settings save` only have writable settings, so
+    // read-only values would all show up as differences.
+    let writable_only = writable_only || right.settings.iter().all(|s| s.writable);
+    let left = read_device(&tio.root, &tio.route, writable_only)?;
+    let left_label = format!("{} {}", tio.root, tio.route);
+
+    let name = |s: &SettingsSnapshot| s.device.clone().unwrap_or("?".to_string());
+    println!("< {} ({})", left_label, name(&left));
+    println!("> {} ({})", right_label, name(&right));
+
+    let diffs = left.diff(&right);
+    for diff in &diffs {
+        match diff {
+            SettingDiff::OnlyLeft(s) => println!("< {}: {}", s.name, describe(s)),
+            SettingDiff::OnlyRight(s) => println!("> {}: {}", s.name, describe(s)),
+            SettingDiff::Type { left, right } => println!(
+                "! {}: {} | {} (type differs)",
+                left.name,
+                describe(left),
+                describe(right)
+            ),
+            SettingDiff::Value { left, right } => println!(
+                "~ {}: {} | {}{}",
+                left.name,
+                describe(left),
+                describe(right),
+                if left.perm_str() != right.perm_str() {
+                    " (permissions differ)"
+                } else {
+                    ""
+                }
+            ),
+        }
+    }
+    println!(
+        "{} differences in {} compared settings",
+        diffs.len(),
+        left.settings.len().max(right.settings.len())
+    );
+    Ok(())
+}
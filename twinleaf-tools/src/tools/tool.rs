@@ -2,15 +2,20 @@ use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
+use std::sync::Arc;
 
 use crate::TioOpts;
 use crate::{SplitLevel, SplitPolicy};
 use tio::proto::DeviceRoute;
 use tio::proxy;
-use tio::util;
-use twinleaf::data::DeviceDataParser;
+use twinleaf::data::calibration::Calibration;
+use twinleaf::data::derived::{DerivedSpec, SampleDeriver};
+use twinleaf::data::dsp::{Pipeline, SampleFilter};
+use twinleaf::data::{DeviceDataParser, Sample};
+use twinleaf::device::bulk::BulkTransfer;
+use twinleaf::device::rpc::WatchEvent;
 use twinleaf::device::util::{rpc_decode_reply, rpc_encode_arg};
-use twinleaf::device::{Device, DeviceTree, RpcClient, RpcValue, RpcValueType};
+use twinleaf::device::{Device, DeviceTree, RpcClient, RpcValue, RpcValueType, RpcWatcher};
 use twinleaf::tio;
 
 fn record_missing_metadata(
@@ -175,25 +180,9 @@ pub fn rpc_dump(tio: &TioOpts, rpc_name: String, is_capture: bool) -> eyre::Resu
             .wrap_err_with(|| format!("failed to trigger {}", trigger_rpc_name))?;
     }
 
-    let mut full_reply = vec![];
-
-    for i in 0u16..=65535u16 {
-        match device.raw_rpc(&rpc_name, &i.to_le_bytes().to_vec()) {
-            Ok(mut rep) => full_reply.append(&mut rep),
-            Err(proxy::RpcError::ExecError(err)) => {
-                if let tio::proto::RpcErrorCode::InvalidArgs = err.error {
-                    break;
-                } else {
-                    return Err(eyre::Report::new(proxy::RpcError::ExecError(err))
-                        .wrap_err(format!("RPC {} failed at chunk {}", rpc_name, i)));
-                }
-            }
-            Err(e) => {
-                return Err(eyre::Report::new(e)
-                    .wrap_err(format!("RPC {} failed at chunk {}", rpc_name, i)));
-            }
-        }
-    }
+    let full_reply = BulkTransfer::new(&device, &rpc_name)
+        .read()
+        .wrap_err_with(|| format!("RPC {} failed", rpc_name))?;
 
     if let Ok(s) = std::str::from_utf8(&full_reply) {
         println!("{}", s);
@@ -205,9 +194,132 @@ pub fn rpc_dump(tio: &TioOpts, rpc_name: String, is_capture: bool) -> eyre::Resu
     Ok(())
 }
 
-pub fn dump(tio: &TioOpts, data: bool, meta: bool, depth: Option<usize>) -> eyre::Result<()> {
+pub fn rpc_watch(
+    tio: &TioOpts,
+    rpcs: Vec<(String, Option<std::time::Duration>)>,
+    interval: std::time::Duration,
+    timestamp_format: &str,
+) -> eyre::Result<()> {
     use eyre::WrapErr;
+    use std::time::{Duration, Instant};
 
+    let proxy = proxy::Interface::new(&tio.root);
+    let route = tio.route.clone();
+    let client = RpcClient::open(&proxy, route.clone())
+        .wrap_err_with(|| format!("could not open RPC client for {}", tio.root))?;
+    // Only used for its events, to learn about RPCs changed by other clients.
+    let mut device = Device::open(&proxy, route.clone())
+        .wrap_err_with(|| format!("could not open device at {}", tio.root))?;
+
+    let mut watcher = RpcWatcher::new(route);
+    for (name, rpc_interval) in rpcs {
+        watcher
+            .watch(&client, &name, rpc_interval.unwrap_or(interval))
+            .wrap_err_with(|| format!("cannot watch {}", name))?;
+    }
+
+    loop {
+        device.drain().wrap_err("lost connection to device")?;
+        for event in device.drain_events() {
+            watcher.handle_event(&event);
+        }
+
+        for event in watcher.poll(&client) {
+            let (time, text) = match &event {
+                WatchEvent::Changed { time, value, .. } => (time, value.to_string()),
+                WatchEvent::Failed { time, error, .. } => (time, format!("error: {}", error)),
+                WatchEvent::DecodeFailed { time, error, .. } => (time, format!("error: {}", error)),
+            };
+            let time = chrono::DateTime::<chrono::Local>::from(*time);
+            println!("{}{} {}", time.format(timestamp_format), event.name(), text);
+        }
+
+        // Keep the wait short, to notice invalidations quickly.
+        let wait = watcher
+            .next_deadline()
+            .map(|t| t.saturating_duration_since(Instant::now()))
+            .unwrap_or_default()
+            .min(Duration::from_millis(50));
+        std::thread::sleep(wait);
+    }
+}
+
+/// Read the calibration file at `path`, if any.
+pub fn load_calibration(path: Option<String>) -> eyre::Result<Option<Arc<Calibration>>> {
+    use color_eyre::Help;
+    use eyre::WrapErr;
+
+    let Some(path) = path else {
+        return Ok(None);
+    };
+    let calibration = Calibration::load(&path)
+        .wrap_err_with(|| format!("could not load calibration from {}", path))
+        .suggestion(
+            "tables are keyed by serial number and column path, e.g. [\"SN1234\".\"/vector/x\"]",
+        )?;
+    Ok(Some(Arc::new(calibration)))
+}
+
+fn new_parser(ignore_session: bool, calibration: &Option<Arc<Calibration>>) -> DeviceDataParser {
+    let mut parser = DeviceDataParser::new(ignore_session);
+    parser.set_calibration(calibration.clone());
+    parser
+}
+
+/// Run `sample` through `filter`, if any. Returns `None` for samples
+/// dropped by decimation.
+fn filter_sample(
+    filter: &mut Option<SampleFilter>,
+    sample: Sample,
+    route: &DeviceRoute,
+) -> eyre::Result<Option<Sample>> {
+    use eyre::WrapErr;
+
+    let Some(filter) = filter else {
+        return Ok(Some(sample));
+    };
+    let stream = sample.stream.name.clone();
+    filter
+        .process(sample, route)
+        .wrap_err_with(|| format!("could not filter stream {} at route {}", stream, route))
+}
+
+/// Append the derived columns of `sample`'s stream.
+fn derive_sample(
+    deriver: &mut SampleDeriver,
+    sample: &mut Sample,
+    route: &DeviceRoute,
+) -> eyre::Result<()> {
+    use color_eyre::Help;
+    use eyre::WrapErr;
+
+    deriver
+        .apply(sample, route)
+        .wrap_err("could not bind derived columns")
+        .suggestion("name columns by path, e.g. {/0/vector/x}")
+}
+
+fn report_unresolved_derived(deriver: &SampleDeriver) {
+    for spec in deriver.unresolved() {
+        eprintln!(
+            "Warning: derived column {} was not computed because some of its columns were not found.",
+            spec
+        );
+    }
+}
+
+pub fn dump(
+    tio: &TioOpts,
+    data: bool,
+    meta: bool,
+    depth: Option<usize>,
+    filter: Option<Pipeline>,
+    calibration: Option<String>,
+    utc: bool,
+) -> eyre::Result<()> {
+    use eyre::WrapErr;
+
+    let calibration = load_calibration(calibration)?;
     let proxy = proxy::Interface::new(&tio.root);
     let route = tio.route.clone();
     let port_depth = depth.unwrap_or(tio::proto::TIO_PACKET_MAX_ROUTING_SIZE);
@@ -243,11 +355,17 @@ pub fn dump(tio: &TioOpts, data: bool, meta: bool, depth: Option<usize>) -> eyre
         // Sample mode (-d or -d -m): use DeviceTree for parsed samples
         (true, _) => {
             let mut tree = DeviceTree::new(port, route.clone());
+            tree.set_calibration(calibration);
+            let mut filter = filter.map(SampleFilter::new);
 
             loop {
                 match tree.next() {
                     Ok((sample, sample_route)) => {
-                        print_sample(&sample, Some(&sample_route), meta, true);
+                        if let Some(sample) = filter_sample(&mut filter, sample, &sample_route)? {
+                            let clock = tree.clock(&sample.time_ref());
+                            let utc = sample.utc(clock.as_ref()).filter(|_| utc);
+                            print_sample(&sample, Some(&sample_route), meta, true, utc);
+                        }
                     }
                     Err(e) => {
                         return Err(eyre::Report::new(e).wrap_err("device stream ended"));
@@ -265,6 +383,7 @@ fn print_sample(
     route: Option<&DeviceRoute>,
     print_meta: bool,
     print_data: bool,
+    utc: Option<f64>,
 ) {
     let route_str = if let Some(r) = route {
         format!("{} ", r)
@@ -287,7 +406,10 @@ fn print_sample(
     }
 
     if print_data {
-        println!("{}{}", route_str, sample);
+        match utc {
+            Some(utc) => println!("{}{} UTC {:.6}", route_str, sample, utc),
+            None => println!("{}{}", route_str, sample),
+        }
     }
 }
 
@@ -599,9 +721,12 @@ pub fn log_dump(
     meta: bool,
     sensor: String,
     depth: Option<usize>,
+    filter: Option<Pipeline>,
+    calibration: Option<String>,
 ) -> eyre::Result<()> {
     use eyre::WrapErr;
 
+    let calibration = load_calibration(calibration)?;
     let target_route = DeviceRoute::from_str(&sensor).unwrap_or_else(|_| DeviceRoute::root());
     let max_depth = depth;
 
@@ -677,6 +802,7 @@ pub fn log_dump(
             let mut parsers: HashMap<DeviceRoute, DeviceDataParser> = HashMap::new();
             let ignore_session = files.len() > 1;
             let mut missing_metadata_routes: HashSet<DeviceRoute> = HashSet::new();
+            let mut filter = filter.map(SampleFilter::new);
 
             for (_path, file_data) in iter_packets(&files)? {
                 let mut rest: &[u8] = &file_data;
@@ -689,14 +815,18 @@ pub fn log_dump(
 
                     let parser = parsers
                         .entry(pkt.routing.clone())
-                        .or_insert_with(|| DeviceDataParser::new(ignore_session));
+                        .or_insert_with(|| new_parser(ignore_session, &calibration));
 
                     let samples = parser.process_packet(&pkt);
                     record_missing_metadata(&mut missing_metadata_routes, &pkt, samples.len());
 
                     for sample in samples {
                         if route_matches(&pkt.routing) {
-                            print_sample(&sample, Some(&pkt.routing), meta, true);
+                            let Some(sample) = filter_sample(&mut filter, sample, &pkt.routing)?
+                            else {
+                                continue;
+                            };
+                            print_sample(&sample, Some(&pkt.routing), meta, true, None);
                             printed_any = true;
                         } else if in_subtree(&pkt.routing) {
                             deeper_routes.insert(pkt.routing.clone());
@@ -1007,6 +1137,10 @@ pub fn log_csv(
     args: Vec<String>,
     sensor: Option<String>,
     output: Option<String>,
+    filter: Option<Pipeline>,
+    calibration: Option<String>,
+    derive: Vec<DerivedSpec>,
+    utc: bool,
 ) -> eyre::Result<()> {
     use color_eyre::Help;
     use eyre::{bail, WrapErr};
@@ -1042,6 +1176,7 @@ pub fn log_csv(
     }
 
     let target_id = stream_arg.parse::<u8>().ok();
+    let calibration = load_calibration(calibration)?;
 
     let target_route = if let Some(path) = sensor {
         DeviceRoute::from_str(&path).map_err(|_| eyre::eyre!("invalid route: {}", path))?
@@ -1061,6 +1196,8 @@ pub fn log_csv(
 
     let mut file: Option<File> = None;
     let mut created_output = false;
+    let mut filter = filter.map(SampleFilter::new);
+    let mut deriver = SampleDeriver::new(derive);
     let mut header_written: bool = false;
 
     for path in &files {
@@ -1075,18 +1212,23 @@ pub fn log_csv(
 
             let parser = parsers
                 .entry(pkt.routing.clone())
-                .or_insert_with(|| DeviceDataParser::new(ignore_session));
+                .or_insert_with(|| new_parser(ignore_session, &calibration));
             let samples = parser.process_packet(&pkt);
 
             if pkt.routing == target_route {
                 record_missing_metadata(&mut missing_metadata_routes, &pkt, samples.len());
             }
 
-            if pkt.routing != target_route {
+            // Derived columns may use the columns of any stream.
+            if pkt.routing != target_route && deriver.is_empty() {
                 continue;
             }
 
-            for sample in samples {
+            for mut sample in samples {
+                derive_sample(&mut deriver, &mut sample, &pkt.routing)?;
+                if pkt.routing != target_route {
+                    continue;
+                }
                 let is_match = if let Some(id) = target_id {
                     sample.stream.stream_id == id
                 } else {
@@ -1096,9 +1238,15 @@ pub fn log_csv(
                 if !is_match {
                     continue;
                 }
+                let Some(sample) = filter_sample(&mut filter, sample, &target_route)? else {
+                    continue;
+                };
 
                 if !header_written {
                     let mut headers: Vec<String> = vec!["time".to_string()];
+                    if utc {
+                        headers.push("utc".to_string());
+                    }
                     headers.extend(sample.columns.iter().map(|col| col.desc.name.clone()));
 
                     if file.is_none() {
@@ -1111,6 +1259,20 @@ pub fn log_csv(
                         created_output = !existed;
                         file = Some(opened);
                     }
+                    // Record what calibration did to the values below.
+                    if let Some(applied) = parser.stream_calibration(sample.stream.stream_id) {
+                        for (position, col) in sample.columns.iter().enumerate() {
+                            if let Some(transforms) = applied.describe(position) {
+                                writeln!(
+                                    file.as_mut().unwrap(),
+                                    "# calibration of {}: {}",
+                                    col.desc.name,
+                                    transforms
+                                )
+                                .wrap_err_with(|| format!("failed to write {}", output_path))?;
+                            }
+                        }
+                    }
                     writeln!(file.as_mut().unwrap(), "{}", headers.join(","))
                         .wrap_err_with(|| format!("failed to write {}", output_path))?;
                     header_written = true;
@@ -1118,6 +1280,11 @@ pub fn log_csv(
 
                 let mut values: Vec<String> = Vec::new();
                 values.push(format!("{:.6}", sample.timestamp_end()));
+                if utc {
+                    let clock = parser.clock(&sample.time_ref());
+                    let time = sample.utc(clock.as_ref());
+                    values.push(time.map_or(String::new(), |t| format!("{:.6}", t)));
+                }
 
                 values.extend(sample.columns.iter().map(|col| col.value.to_string()));
 
@@ -1127,6 +1294,8 @@ pub fn log_csv(
         }
     }
 
+    report_unresolved_derived(&deriver);
+
     if !header_written {
         drop(file);
         if created_output {
@@ -1151,6 +1320,7 @@ pub fn log_csv(
 }
 
 #[cfg(feature = "hdf5")]
+#[allow(clippy::too_many_arguments)]
 pub fn log_hdf(
     files: Vec<String>,
     output: Option<String>,
@@ -1159,6 +1329,8 @@ pub fn log_hdf(
     debug: bool,
     split_level: SplitLevel,
     split_policy: SplitPolicy,
+    calibration: Option<String>,
+    derive: Vec<DerivedSpec>,
 ) -> eyre::Result<()> {
     use eyre::{bail, WrapErr};
     use indicatif::{ProgressBar, ProgressStyle};
@@ -1206,6 +1378,8 @@ pub fn log_hdf(
         split_level.clone().into(),
     )
     .wrap_err_with(|| format!("could not create HDF5 file {}", output))?;
+    let calibration = load_calibration(calibration)?;
+    let mut deriver = SampleDeriver::new(derive);
 
     let mut parsers: HashMap<tio::proto::DeviceRoute, DeviceDataParser> = HashMap::new();
     let ignore_session = files.len() > 1;
@@ -1242,12 +1416,13 @@ pub fn log_hdf(
 
             let parser = parsers
                 .entry(pkt.routing.clone())
-                .or_insert_with(|| DeviceDataParser::new(ignore_session));
+                .or_insert_with(|| new_parser(ignore_session, &calibration));
 
             let samples = parser.process_packet(&pkt);
             record_missing_metadata(&mut missing_metadata_routes, &pkt, samples.len());
 
-            for sample in samples {
+            for mut sample in samples {
+                derive_sample(&mut deriver, &mut sample, &pkt.routing)?;
                 let key = StreamKey::new(pkt.routing.clone(), sample.stream.stream_id);
 
                 if debug {
@@ -1259,8 +1434,9 @@ pub fn log_hdf(
                     }
                 }
 
+                let applied = parser.stream_calibration(sample.stream.stream_id);
                 writer
-                    .write_sample(sample, key)
+                    .write_calibrated_sample(sample, key, applied)
                     .wrap_err("failed to write sample to HDF5")?;
             }
         }
@@ -1272,6 +1448,7 @@ pub fn log_hdf(
     let stats = writer.finish().wrap_err("failed to finalize HDF5")?;
 
     report_missing_metadata(missing_metadata_routes.into_iter().collect(), false);
+    report_unresolved_derived(&deriver);
 
     use console::style;
 
@@ -1357,6 +1534,7 @@ pub fn log_hdf(
 }
 
 #[cfg(not(feature = "hdf5"))]
+#[allow(clippy::too_many_arguments)]
 pub fn log_hdf(
     _files: Vec<String>,
     _output: Option<String>,
@@ -1365,6 +1543,8 @@ pub fn log_hdf(
     _debug: bool,
     _split_level: SplitLevel,
     _split_policy: SplitPolicy,
+    _calibration: Option<String>,
+    _derive: Vec<DerivedSpec>,
 ) -> eyre::Result<()> {
     use color_eyre::Help;
     Err(
@@ -1372,164 +1552,3 @@ pub fn log_hdf(
             .suggestion("reinstall with: cargo install twinleaf-tools --features hdf5"),
     )
 }
-
-pub fn firmware_upgrade(
-    tio: &TioOpts,
-    firmware_path: std::path::PathBuf,
-    skip_confirm: bool,
-) -> eyre::Result<()> {
-    use color_eyre::Help;
-    use eyre::WrapErr;
-    use indicatif::{ProgressBar, ProgressStyle};
-
-    let firmware_data = std::fs::read(&firmware_path)
-        .wrap_err_with(|| format!("could not read firmware file {:?}", firmware_path))?;
-
-    println!("Loaded {} bytes firmware", firmware_data.len());
-
-    let proxy = proxy::Interface::new(&tio.root);
-    let route = tio.route.clone();
-    let device = proxy
-        .device_rpc(route)
-        .wrap_err_with(|| format!("could not open device at {}", tio.root))?;
-
-    let dev_name: String = device
-        .rpc("dev.name", ())
-        .wrap_err("failed to query device name")?;
-
-    if !skip_confirm {
-        print!("Upgrade firmware on '{}'? [y/N] ", dev_name);
-        std::io::Write::flush(&mut std::io::stdout()).unwrap();
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
-        if !input.trim().eq_ignore_ascii_case("y") {
-            println!("Aborted.");
-            return Ok(());
-        }
-    }
-
-    match device.action("dev.stop") {
-        Ok(()) => {}
-        Err(proxy::RpcError::ExecError(ref e))
-            if matches!(
-                e.error,
-                tio::proto::RpcErrorCode::NotFound | tio::proto::RpcErrorCode::WrongDeviceState
-            ) => {}
-        Err(e) => {
-            return Err(
-                eyre::Report::new(e).wrap_err("failed to stop device before firmware upgrade")
-            );
-        }
-    }
-
-    let total_chunks = firmware_data.len().div_ceil(288);
-    let pb = ProgressBar::new(total_chunks as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent}%")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
-
-    let power_cycle_hint = "power cycle the device before retrying";
-
-    let mut next_send_chunk: u16 = 0;
-    let mut next_ack_chunk: u16 = 0;
-    let mut more_to_send = true;
-    const MAX_CHUNKS_IN_FLIGHT: u16 = 2;
-
-    while more_to_send || (next_ack_chunk != next_send_chunk) {
-        if more_to_send && ((next_send_chunk - next_ack_chunk) < MAX_CHUNKS_IN_FLIGHT) {
-            let offset = usize::from(next_send_chunk) * 288;
-            let chunk_end = if (offset + 288) > firmware_data.len() {
-                firmware_data.len()
-            } else {
-                offset + 288
-            };
-
-            device
-                .send(util::PacketBuilder::make_rpc_request(
-                    "dev.firmware.upload",
-                    &firmware_data[offset..chunk_end],
-                    next_send_chunk,
-                    DeviceRoute::root(),
-                ))
-                .wrap_err_with(|| {
-                    format!(
-                        "failed to send firmware chunk {}/{}",
-                        next_send_chunk + 1,
-                        total_chunks
-                    )
-                })
-                .suggestion(power_cycle_hint)?;
-            next_send_chunk += 1;
-            more_to_send = chunk_end < firmware_data.len();
-        }
-
-        let pkt = if more_to_send && ((next_send_chunk - next_ack_chunk) < MAX_CHUNKS_IN_FLIGHT) {
-            match device.try_recv() {
-                Ok(pkt) => pkt,
-                Err(proxy::RecvError::WouldBlock) => continue,
-                Err(e) => {
-                    return Err(eyre::Report::new(e)
-                        .wrap_err("failed to receive firmware upload ack")
-                        .suggestion(power_cycle_hint));
-                }
-            }
-        } else {
-            device
-                .recv()
-                .wrap_err("failed to receive firmware upload ack")
-                .suggestion(power_cycle_hint)?
-        };
-
-        match pkt.payload {
-            tio::proto::Payload::RpcReply(rep) => {
-                if rep.id != next_ack_chunk {
-                    return Err(eyre::eyre!(
-                        "firmware chunk ack out of order (expected {}, got {})",
-                        next_ack_chunk,
-                        rep.id
-                    )
-                    .suggestion(power_cycle_hint));
-                }
-                next_ack_chunk += 1;
-                pb.set_position(next_ack_chunk as u64);
-            }
-            tio::proto::Payload::RpcError(err) => {
-                return Err(eyre::eyre!(
-                    "device rejected firmware chunk {}/{}: {}",
-                    next_ack_chunk + 1,
-                    total_chunks,
-                    err.error
-                )
-                .suggestion(power_cycle_hint));
-            }
-            _ => continue,
-        }
-    }
-
-    pb.finish_and_clear();
-
-    device
-        .action("dev.firmware.upgrade")
-        .wrap_err("device rejected firmware commit")
-        .suggestion(power_cycle_hint)?;
-
-    // Wait 5 seconds before returning to ensure the device is not
-    // power-cycled while the firmware upgrade is being committed to flash.
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_style(
-        ProgressStyle::default_spinner()
-            .template("{spinner:.green} {msg}")
-            .unwrap(),
-    );
-    spinner.set_message("Finalizing upgrade...");
-    for _ in 0..50 {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        spinner.tick();
-    }
-    spinner.finish_with_message("Firmware upgrade complete.");
-
-    Ok(())
-}
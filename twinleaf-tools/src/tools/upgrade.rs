@@ -0,0 +1,529 @@
+//! `tio upgrade` — check a firmware image, upload it to one device or to
+//! every device in a tree, and verify the firmware each device reports
+//! afterwards.
+
+use std::cmp::Reverse;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::UpgradeCli;
+use twinleaf::device::bulk::BulkTransfer;
+use twinleaf::device::{Device, DeviceTree};
+use twinleaf::tio::proto::{DeviceRoute, RpcErrorCode};
+use twinleaf::tio::proxy;
+
+const CHUNK_SIZE: usize = 288;
+/// Largest image that can be sent with 16-bit chunk numbers.
+const MAX_IMAGE_SIZE: usize = CHUNK_SIZE << 16;
+/// How long to listen for devices with `--all`.
+const DISCOVERY_TIME: Duration = Duration::from_secs(2);
+/// How long a device gets to report its firmware before the upgrade.
+const METADATA_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a device gets to come back after the upgrade.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+
+const POWER_CYCLE_HINT: &str = "power cycle the device before retrying";
+
+struct FirmwareImage {
+    data: Vec<u8>,
+    /// Hash the firmware reports once installed, from `--hash`.
+    hash: Option<String>,
+}
+
+impl FirmwareImage {
+    fn load(path: &Path, hash: Option<String>) -> eyre::Result<FirmwareImage> {
+        use color_eyre::Help;
+        use eyre::WrapErr;
+
+        let data = std::fs::read(path)
+            .wrap_err_with(|| format!("could not read firmware file {:?}", path))?;
+        if data.is_empty() {
+            return Err(eyre::eyre!("firmware file {:?} is empty", path));
+        }
+        if data.len() > MAX_IMAGE_SIZE {
+            return Err(eyre::eyre!(
+                "firmware file {:?} is {} bytes, more than the {} bytes that can be uploaded",
+                path,
+                data.len(),
+                MAX_IMAGE_SIZE
+            ));
+        }
+        if let Some(format) = container_format(&data) {
+            return Err(eyre::eyre!(
+                "firmware file {:?} is {}, not a firmware image",
+                path,
+                format
+            )
+            .suggestion("upload the raw .bin image built for the device"));
+        }
+
+        let image = FirmwareImage { data, hash };
+        if let Some(hash) = &image.hash {
+            if !image.mentions(hash) {
+                return Err(eyre::eyre!(
+                    "firmware file {:?} does not contain the hash {}",
+                    path,
+                    hash
+                )
+                .suggestion("check that --hash matches the image"));
+            }
+        }
+        Ok(image)
+    }
+
+    /// Whether the image embeds the text `hash`, as firmware builds do with
+    /// the hash they report in their metadata.
+    fn mentions(&self, hash: &str) -> bool {
+        hash.len() >= 4 && self.data.windows(hash.len()).any(|w| w == hash.as_bytes())
+    }
+
+    /// Whether a device reporting `firmware_hash` runs this image.
+    fn is_installed(&self, firmware_hash: &str) -> bool {
+        match &self.hash {
+            Some(hash) => hash == firmware_hash,
+            None => self.mentions(firmware_hash),
+        }
+    }
+}
+
+/// Formats that are easily mistaken for firmware images.
+fn container_format(data: &[u8]) -> Option<&'static str> {
+    let text = |extra: &[u8]| {
+        data.iter()
+            .all(|b| b.is_ascii_alphanumeric() || b"\r\n".contains(b) || extra.contains(b))
+    };
+    if data.starts_with(b"\x7fELF") {
+        Some("an ELF file")
+    } else if data.starts_with(b"PK\x03\x04") {
+        Some("a zip archive")
+    } else if data.starts_with(b":") && text(b":") {
+        Some("an Intel HEX file")
+    } else if data.starts_with(b"S0") && text(b"") {
+        Some("a Motorola S-record file")
+    } else {
+        None
+    }
+}
+
+/// Name and firmware hash of the device at `route`, `None` if it does not
+/// answer within `timeout`.
+fn read_metadata(
+    proxy: &proxy::Interface,
+    route: &DeviceRoute,
+    timeout: Duration,
+) -> Option<(String, String)> {
+    let mut device = Device::open(proxy, route.clone()).ok()?;
+    let meta = device.get_metadata_timeout(timeout).ok()?;
+    Some((meta.device.name.clone(), meta.device.firmware_hash.clone()))
+}
+
+/// Routes of the devices under `root`, deepest first so that hubs are
+/// upgraded after the devices behind them.
+fn discover_routes(proxy: &proxy::Interface, root: &DeviceRoute) -> eyre::Result<Vec<DeviceRoute>> {
+    use eyre::WrapErr;
+
+    let mut tree = DeviceTree::open(proxy, root.clone())
+        .wrap_err_with(|| format!("could not open device tree at {}", root))?;
+    let deadline = Instant::now() + DISCOVERY_TIME;
+    while Instant::now() < deadline {
+        if tree
+            .try_next_item()
+            .wrap_err("failed to discover devices")?
+            .is_none()
+        {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    let mut routes = tree.known_routes();
+    routes.sort_by_key(|r| (Reverse(r.len()), r.clone()));
+    Ok(routes)
+}
+
+fn confirm(prompt: &str) -> bool {
+    print!("{} [y/N] ", prompt);
+    std::io::Write::flush(&mut std::io::stdout()).unwrap();
+    let mut input = String::new();
+    std::io::stdin().read_line(&mut input).unwrap();
+    input.trim().eq_ignore_ascii_case("y")
+}
+
+/// Stop the device, so that the next upload starts a new image.
+fn stop(device: &proxy::Port) -> eyre::Result<()> {
+    match device.action("dev.stop") {
+        Ok(()) => Ok(()),
+        Err(proxy::RpcError::ExecError(ref e))
+            if matches!(
+                e.error,
+                RpcErrorCode::NotFound | RpcErrorCode::WrongDeviceState
+            ) =>
+        {
+            Ok(())
+        }
+        Err(e) => {
+            Err(eyre::Report::new(e).wrap_err("failed to stop device before firmware upgrade"))
+        }
+    }
+}
+
+/// Upload the image to the device at `route` and commit it. An upload that
+/// fails starts over from the first chunk, up to `retries` times.
+fn upload(
+    proxy: &proxy::Interface,
+    route: &DeviceRoute,
+    image: &FirmwareImage,
+    retries: usize,
+) -> eyre::Result<()> {
+    use color_eyre::Help;
+    use eyre::WrapErr;
+    use indicatif::{ProgressBar, ProgressStyle};
+
+    let open = || {
+        proxy
+            .device_rpc(route.clone())
+            .wrap_err_with(|| format!("could not open device at {}", route))
+    };
+
+    let total_chunks = image.data.len().div_ceil(CHUNK_SIZE);
+    let pb = ProgressBar::new(total_chunks as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {percent}%")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+
+    // The device appends each chunk to the image, so a chunk sent twice
+    // would be written twice. Rather than resending chunks, start the
+    // whole image over after stopping the device again, on a new port so
+    // that late replies to the failed attempt are not taken for new ones.
+    let mut attempt = 0;
+    let (device, result) = loop {
+        let device = match open().and_then(|device| stop(&device).map(|()| device)) {
+            Ok(device) => device,
+            Err(e) => {
+                pb.finish_and_clear();
+                return Err(e);
+            }
+        };
+        pb.set_position(0);
+        let result = BulkTransfer::new(&device, "dev.firmware.upload")
+            .window(2)
+            .chunk_size(CHUNK_SIZE)
+            .retries(0)
+            .on_progress(|p| pb.set_position(p.chunks as u64))
+            .write(&image.data);
+        match result {
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                pb.println(format!(
+                    "Upload failed: {}, starting over ({}/{})",
+                    e, attempt, retries
+                ));
+            }
+            result => break (device, result),
+        }
+    };
+    pb.finish_and_clear();
+    result
+        .wrap_err("firmware upload failed")
+        .suggestion(POWER_CYCLE_HINT)?;
+
+    device
+        .action("dev.firmware.upgrade")
+        .wrap_err("device rejected firmware commit")
+        .suggestion(POWER_CYCLE_HINT)?;
+
+    // Wait 5 seconds before returning to ensure the device is not
+    // power-cycled while the firmware upgrade is being committed to flash.
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_style(
+        ProgressStyle::default_spinner()
+            .template("{spinner:.green} {msg}")
+            .unwrap(),
+    );
+    spinner.set_message("Finalizing upgrade...");
+    for _ in 0..50 {
+        std::thread::sleep(Duration::from_millis(100));
+        spinner.tick();
+    }
+    spinner.finish_and_clear();
+    Ok(())
+}
+
+enum Verification {
+    /// The device reports the image's firmware.
+    Verified(String),
+    /// The device reports a new firmware, which can't be matched with the
+    /// image without `--hash`.
+    Unverified(String),
+    /// The device reports some other firmware.
+    Mismatch(String),
+    /// The device still reports the firmware it had before.
+    Unchanged(String),
+    NoResponse,
+}
+
+/// Wait for the device to come back, and check the firmware it reports.
+fn verify(
+    proxy: &proxy::Interface,
+    route: &DeviceRoute,
+    image: &FirmwareImage,
+    before: Option<&str>,
+) -> Verification {
+    let deadline = Instant::now() + RESTART_TIMEOUT;
+    let hash = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Verification::NoResponse;
+        }
+        match read_metadata(proxy, route, remaining.min(METADATA_TIMEOUT)) {
+            Some((_, hash)) => break hash,
+            None => std::thread::sleep(Duration::from_millis(500)),
+        }
+    };
+    if image.is_installed(&hash) {
+        Verification::Verified(hash)
+    } else if image.hash.is_some() {
+        Verification::Mismatch(hash)
+    } else if Some(hash.as_str()) == before {
+        Verification::Unchanged(hash)
+    } else {
+        Verification::Unverified(hash)
+    }
+}
+
+struct Target {
+    route: DeviceRoute,
+    name: String,
+    before: Option<String>,
+}
+
+struct Row {
+    route: DeviceRoute,
+    name: String,
+    before: String,
+    after: String,
+    result: String,
+    ok: bool,
+}
+
+fn print_summary(rows: &[Row]) {
+    let header = ["ROUTE", "DEVICE", "BEFORE", "AFTER", "RESULT"];
+    let cells: Vec<[String; 5]> = rows
+        .iter()
+        .map(|r| {
+            [
+                r.route.to_string(),
+                r.name.clone(),
+                r.before.clone(),
+                r.after.clone(),
+                r.result.clone(),
+            ]
+        })
+        .collect();
+    let mut widths = header.map(str::len);
+    for row in &cells {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.len());
+        }
+    }
+    let print_row = |row: [&str; 5]| {
+        println!(
+            "{:<w0$}  {:<w1$}  {:<w2$}  {:<w3$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            row[4],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        );
+    };
+    println!();
+    print_row(header);
+    for row in &cells {
+        print_row(row.each_ref().map(String::as_str));
+    }
+}
+
+fn upgrade_one(
+    proxy: &proxy::Interface,
+    image: &FirmwareImage,
+    cli: &UpgradeCli,
+) -> eyre::Result<()> {
+    use color_eyre::Help;
+    use eyre::WrapErr;
+
+    let route = &cli.tio.route;
+    let (name, before) = match read_metadata(proxy, route, METADATA_TIMEOUT) {
+        Some((name, hash)) => (name, Some(hash)),
+        None => {
+            let device = proxy
+                .device_rpc(route.clone())
+                .wrap_err_with(|| format!("could not open device at {}", cli.tio.root))?;
+            let name: String = device
+                .rpc("dev.name", ())
+                .wrap_err("failed to query device name")?;
+            (name, None)
+        }
+    };
+
+    if let Some(hash) = &before {
+        if !cli.force && image.is_installed(hash) {
+            println!(
+                "'{}' already runs this firmware ({}), use --force to upgrade anyway.",
+                name, hash
+            );
+            return Ok(());
+        }
+    }
+    if !cli.yes && !confirm(&format!("Upgrade firmware on '{}'?", name)) {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    upload(proxy, route, image, cli.retries)?;
+
+    match verify(proxy, route, image, before.as_deref()) {
+        Verification::Verified(hash) => {
+            println!("Firmware upgrade complete, '{}' runs {}.", name, hash);
+            Ok(())
+        }
+        Verification::Unverified(hash) => {
+            println!(
+                "Firmware upgrade complete, '{}' now reports {}.",
+                name, hash
+            );
+            Ok(())
+        }
+        Verification::Mismatch(hash) => Err(eyre::eyre!(
+            "after the upgrade '{}' reports firmware {}, expected {}",
+            name,
+            hash,
+            image.hash.as_deref().unwrap_or_default()
+        )
+        .suggestion(POWER_CYCLE_HINT)),
+        Verification::Unchanged(hash) => Err(eyre::eyre!(
+            "after the upgrade '{}' still reports the previous firmware {}",
+            name,
+            hash
+        )
+        .suggestion(POWER_CYCLE_HINT)),
+        Verification::NoResponse => Err(eyre::eyre!(
+            "'{}' did not report its firmware after the upgrade",
+            name
+        )
+        .suggestion("check the connection, the upgrade may still have succeeded")),
+    }
+}
+
+fn upgrade_all(
+    proxy: &proxy::Interface,
+    image: &FirmwareImage,
+    cli: &UpgradeCli,
+) -> eyre::Result<()> {
+    use color_eyre::Help;
+
+    let routes = discover_routes(proxy, &cli.tio.route)?;
+    if routes.is_empty() {
+        return Err(eyre::eyre!("no devices found under {}", cli.tio.route));
+    }
+
+    let mut rows = vec![];
+    let mut targets = vec![];
+    for route in routes {
+        let (name, before) = match read_metadata(proxy, &route, METADATA_TIMEOUT) {
+            Some((name, hash)) => (name, Some(hash)),
+            None => ("?".to_string(), None),
+        };
+        if cli.only.as_ref().is_some_and(|only| *only != name) {
+            continue;
+        }
+        match &before {
+            Some(hash) if !cli.force && image.is_installed(hash) => rows.push(Row {
+                route,
+                name,
+                before: hash.clone(),
+                after: hash.clone(),
+                result: "already up to date".to_string(),
+                ok: true,
+            }),
+            _ => targets.push(Target {
+                route,
+                name,
+                before,
+            }),
+        }
+    }
+
+    if targets.is_empty() {
+        print_summary(&rows);
+        return Ok(());
+    }
+    println!("Devices to upgrade:");
+    for t in &targets {
+        println!(
+            "  {}  {}  ({})",
+            t.route,
+            t.name,
+            t.before.as_deref().unwrap_or("unknown firmware")
+        );
+    }
+    if !cli.yes && !confirm(&format!("Upgrade firmware on {} devices?", targets.len())) {
+        println!("Aborted.");
+        return Ok(());
+    }
+
+    for t in targets {
+        println!("Upgrading '{}' at {}", t.name, t.route);
+        let before = t.before.clone().unwrap_or_else(|| "?".to_string());
+        let (after, result, ok) = match upload(proxy, &t.route, image, cli.retries) {
+            Err(e) => {
+                eprintln!("Error: {:#}", e);
+                ("?".to_string(), "upload failed".to_string(), false)
+            }
+            Ok(()) => match verify(proxy, &t.route, image, t.before.as_deref()) {
+                Verification::Verified(h) => (h, "verified".to_string(), true),
+                Verification::Unverified(h) => (h, "upgraded, unverified".to_string(), true),
+                Verification::Mismatch(h) => (h, "wrong firmware".to_string(), false),
+                Verification::Unchanged(h) => (h, "not upgraded".to_string(), false),
+                Verification::NoResponse => ("?".to_string(), "no response".to_string(), false),
+            },
+        };
+        rows.push(Row {
+            route: t.route,
+            name: t.name,
+            before,
+            after,
+            result,
+            ok,
+        });
+    }
+
+    rows.sort_by(|a, b| a.route.cmp(&b.route));
+    print_summary(&rows);
+
+    let failed = rows.iter().filter(|r| !r.ok).count();
+    if failed > 0 {
+        return Err(
+            eyre::eyre!("{} of {} devices failed to upgrade", failed, rows.len())
+                .suggestion(POWER_CYCLE_HINT)
+                .suggestion("devices that report this firmware are skipped when retrying"),
+        );
+    }
+    Ok(())
+}
+
+pub fn firmware_upgrade(cli: UpgradeCli) -> eyre::Result<()> {
+    let image = FirmwareImage::load(&cli.firmware_path, cli.hash.clone())?;
+    println!("Loaded {} bytes firmware", image.data.len());
+
+    let proxy = proxy::Interface::new(&cli.tio.root);
+    if cli.all {
+        upgrade_all(&proxy, &image, &cli)
+    } else {
+        upgrade_one(&proxy, &image, &cli)
+    }
+}
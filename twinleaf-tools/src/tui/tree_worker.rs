@@ -3,6 +3,7 @@
 //! on `tree.next_item()`.
 
 use crossbeam::channel::{self, Receiver};
+use twinleaf::data::clock::UtcMapping;
 use twinleaf::device::{DeviceTree, TreeItem};
 
 /// Spawn a worker thread that owns the given [`DeviceTree`] and streams its
@@ -20,3 +21,22 @@ pub fn spawn_tree_worker(tree: DeviceTree) -> Receiver<TreeItem> {
     });
     rx
 }
+
+/// Like [`spawn_tree_worker`], sending with each item the host estimate of
+/// UTC for the clock of its sample, see [`DeviceTree::clock`].
+pub fn spawn_clocked_tree_worker(tree: DeviceTree) -> Receiver<(TreeItem, Option<UtcMapping>)> {
+    let (tx, rx) = channel::unbounded::<(TreeItem, Option<UtcMapping>)>();
+    std::thread::spawn(move || {
+        let mut tree = tree;
+        while let Ok(item) = tree.next_item() {
+            let clock = match &item {
+                TreeItem::Sample(sample, _) => tree.clock(&sample.time_ref()),
+                TreeItem::Event(_) => None,
+            };
+            if tx.send((item, clock)).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
@@ -0,0 +1,467 @@
+//! Archive
+//! Disk-backed history for a `Buffer`, see `Buffer::with_archive`.
+//!
+//! Every buffered sample is also appended to memory-mapped segment files,
+//! one series of fixed-size records per run, so that time range reads can
+//! reach past what the buffer keeps in memory, including runs that have
+//! ended. The oldest segments are deleted to stay within a byte budget, and
+//! all segment files are deleted when the archive is dropped. Each archive
+//! keeps its files in a directory of its own, so archives given the same
+//! directory never touch each other's files.
+//!
+//! Reads of several streams return the samples of the first stream in the
+//! range, each paired with the nearest sample of every other stream, which
+//! must lie within half of that stream's sample period. The other streams
+//! need not be sampled in phase with the first, or even at its rate.
+//!
+//! Each record holds the sample number and timestamp, then one 8 byte value
+//! per column: `f64`, `i64` or `u64` as the column's `BufferType`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use memmap2::MmapMut;
+
+use crate::data::{AlignedWindow, ColumnBatch, ColumnData, ReadError, RunId, Sample};
+use crate::tio::proto::identifiers::{ColumnId, ColumnKey, SampleNumber, SessionId, StreamKey};
+use crate::tio::proto::{BufferType, ColumnMetadata, SegmentMetadata, StreamMetadata};
+
+/// Records per segment file.
+const SEGMENT_RECORDS: usize = 65_536;
+/// Bytes of the sample number and timestamp at the start of each record.
+const RECORD_HEADER: usize = 16;
+
+/// Archives created by this process, to name their directories.
+static ARCHIVES: AtomicU64 = AtomicU64::new(0);
+
+struct Segment {
+    path: PathBuf,
+    map: MmapMut,
+    /// Order of creation across all runs, for retention.
+    sequence: u64,
+    len: usize,
+}
+
+impl Segment {
+    fn bytes(&self) -> u64 {
+        self.map.len() as u64
+    }
+
+    fn timestamp(&self, record_size: usize, idx: usize) -> f64 {
+        let at = idx * record_size + 8;
+        f64::from_le_bytes(self.map[at..at + 8].try_into().unwrap())
+    }
+
+    fn sample_number(&self, record_size: usize, idx: usize) -> SampleNumber {
+        let at = idx * record_size;
+        u64::from_le_bytes(self.map[at..at + 8].try_into().unwrap()) as SampleNumber
+    }
+
+    fn word(&self, record_size: usize, idx: usize, column: usize) -> [u8; 8] {
+        let at = idx * record_size + RECORD_HEADER + column * 8;
+        self.map[at..at + 8].try_into().unwrap()
+    }
+
+    /// Records with timestamps between `start` and `end`.
+    fn range(&self, record_size: usize, start: f64, end: f64) -> std::ops::Range<usize> {
+        let partition = |pred: &dyn Fn(f64) -> bool| {
+            let (mut lo, mut hi) = (0, self.len);
+            while lo < hi {
+                let mid = (lo + hi) / 2;
+                if pred(self.timestamp(record_size, mid)) {
+                    lo = mid + 1;
+                } else {
+                    hi = mid;
+                }
+            }
+            lo
+        };
+        partition(&|t| t < start)..partition(&|t| t <= end)
+    }
+}
+
+struct ArchivedRun {
+    stream_key: StreamKey,
+    run_id: RunId,
+    session_id: SessionId,
+    stream_metadata: Arc<StreamMetadata>,
+    segment_metadata: Arc<SegmentMetadata>,
+    columns: Vec<(Arc<ColumnMetadata>, BufferType)>,
+    record_size: usize,
+    segments: VecDeque<Segment>,
+}
+
+impl ArchivedRun {
+    fn column_index(&self, column_id: ColumnId) -> Option<usize> {
+        self.columns.iter().position(|(m, _)| m.index == column_id)
+    }
+
+    /// Seconds between samples, zero if unknown.
+    fn period(&self) -> f64 {
+        let segment = &self.segment_metadata;
+        if segment.sampling_rate == 0 {
+            return 0.0;
+        }
+        f64::from(segment.decimation.max(1)) / f64::from(segment.sampling_rate)
+    }
+
+    fn time_bounds(&self) -> Option<(f64, f64)> {
+        let first = self.segments.iter().find(|s| s.len > 0)?;
+        let last = self.segments.iter().rev().find(|s| s.len > 0)?;
+        Some((
+            first.timestamp(self.record_size, 0),
+            last.timestamp(self.record_size, last.len - 1),
+        ))
+    }
+}
+
+/// Disk-backed history of the samples of a `Buffer`.
+pub struct Archive {
+    dir: PathBuf,
+    max_bytes: u64,
+    bytes: u64,
+    next_sequence: u64,
+    runs: Vec<ArchivedRun>,
+    error: Option<io::Error>,
+}
+
+impl Archive {
+    /// Keep up to `max_bytes` of segment files in a new directory under
+    /// `dir`, which is created if needed. The new directory is named after
+    /// the process and removed when the archive is dropped.
+    pub fn create(dir: impl AsRef<Path>, max_bytes: u64) -> io::Result<Archive> {
+        let parent = dir.as_ref();
+        fs::create_dir_all(parent)?;
+        let dir = loop {
+            let n = ARCHIVES.fetch_add(1, Ordering::Relaxed);
+            let dir = parent.join(format!("archive-{}-{}", std::process::id(), n));
+            // Skip directories left behind by an earlier process of this id.
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        };
+        Ok(Archive {
+            dir,
+            max_bytes,
+            bytes: 0,
+            next_sequence: 0,
+            runs: Vec::new(),
+            error: None,
+        })
+    }
+
+    /// The directory holding this archive's segment files.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Bytes of segment files on disk.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The error that stopped archiving, if any.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+
+    /// Earliest and latest archived timestamps of a stream, over all runs.
+    pub fn time_bounds(&self, stream_key: &StreamKey) -> Option<(f64, f64)> {
+        self.runs
+            .iter()
+            .filter(|run| run.stream_key == *stream_key)
+            .filter_map(ArchivedRun::time_bounds)
+            .reduce(|(a0, a1), (b0, b1)| (a0.min(b0), a1.max(b1)))
+    }
+
+    pub(crate) fn push(&mut self, run_id: RunId, stream_key: &StreamKey, sample: &Sample) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(err) = self.try_push(run_id, stream_key, sample) {
+            self.error = Some(err);
+        }
+    }
+
+    fn try_push(
+        &mut self,
+        run_id: RunId,
+        stream_key: &StreamKey,
+        sample: &Sample,
+    ) -> io::Result<()> {
+        let idx = match self
+            .runs
+            .iter()
+            .rposition(|run| run.run_id == run_id && run.stream_key == *stream_key)
+        {
+            Some(idx) => idx,
+            None => {
+                let columns: Vec<_> = sample
+                    .columns
+                    .iter()
+                    .map(|col| (col.desc.clone(), col.desc.data_type.buffer_type()))
+                    .collect();
+                self.runs.push(ArchivedRun {
+                    stream_key: stream_key.clone(),
+                    run_id,
+                    session_id: sample.device.session_id,
+                    stream_metadata: sample.stream.clone(),
+                    segment_metadata: sample.segment.clone(),
+                    record_size: RECORD_HEADER + 8 * columns.len(),
+                    columns,
+                    segments: VecDeque::new(),
+                });
+                self.runs.len() - 1
+            }
+        };
+
+        let full = self.runs[idx]
+            .segments
+            .back()
+            .is_none_or(|s| s.len == SEGMENT_RECORDS);
+        if full {
+            let sequence = self.next_sequence;
+            self.next_sequence += 1;
+            let run = &self.runs[idx];
+            let path = self.dir.join(format!(
+                "run{}-stream{}-{:06}.seg",
+                run.run_id, run.stream_key.stream_id, sequence
+            ));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            file.set_len((SEGMENT_RECORDS * run.record_size) as u64)?;
+            // The file was just created in the archive's own directory, and
+            // is only accessed through this mapping until it is deleted.
+            let map = unsafe { MmapMut::map_mut(&file)? };
+            self.bytes += map.len() as u64;
+            self.runs[idx].segments.push_back(Segment {
+                path,
+                map,
+                sequence,
+                len: 0,
+            });
+            self.enforce_budget();
+        }
+
+        let run = self
+            .runs
+            .iter_mut()
+            .rfind(|run| run.run_id == run_id && run.stream_key == *stream_key)
+            .expect("the run's newest segment is never removed");
+        let record_size = run.record_size;
+        let segment = run.segments.back_mut().unwrap();
+        let at = segment.len * record_size;
+        let record = &mut segment.map[at..at + record_size];
+        record[0..8].copy_from_slice(&u64::from(sample.n).to_le_bytes());
+        record[8..16].copy_from_slice(&sample.timestamp_end().to_le_bytes());
+        for (i, (metadata, kind)) in run.columns.iter().enumerate() {
+            let value = sample
+                .columns
+                .get(metadata.index)
+                .filter(|col| col.desc.index == metadata.index)
+                .map(|col| &col.value);
+            let word = match (kind, value) {
+                (BufferType::Float, Some(ColumnData::Float(v))) => v.to_le_bytes(),
+                (BufferType::Float, Some(ColumnData::Int(v))) => (*v as f64).to_le_bytes(),
+                (BufferType::Float, _) => f64::NAN.to_le_bytes(),
+                (BufferType::Int, Some(ColumnData::Int(v))) => v.to_le_bytes(),
+                (BufferType::UInt, Some(ColumnData::UInt(v))) => v.to_le_bytes(),
+                _ => [0; 8],
+            };
+            let at = RECORD_HEADER + i * 8;
+            record[at..at + 8].copy_from_slice(&word);
+        }
+        segment.len += 1;
+        Ok(())
+    }
+
+    /// Delete the oldest segments, other than those being written, until
+    /// the archive is within its budget.
+    fn enforce_budget(&mut self) {
+        while self.bytes > self.max_bytes {
+            let oldest = self
+                .runs
+                .iter()
+                .enumerate()
+                .filter(|(_, run)| run.segments.len() > 1 || !self.is_newest_run(run))
+                .filter_map(|(i, run)| run.segments.front().map(|s| (i, s.sequence)))
+                .min_by_key(|&(_, sequence)| sequence);
+            let Some((idx, _)) = oldest else {
+                break;
+            };
+            let segment = self.runs[idx].segments.pop_front().unwrap();
+            self.bytes -= segment.bytes();
+            let path = segment.path.clone();
+            drop(segment);
+            let _ = fs::remove_file(path);
+            if self.runs[idx].segments.is_empty() {
+                self.runs.remove(idx);
+            }
+        }
+    }
+
+    /// Whether `run` is the latest archived run of its stream, still being
+    /// written.
+    fn is_newest_run(&self, run: &ArchivedRun) -> bool {
+        self.runs
+            .iter()
+            .rev()
+            .find(|r| r.stream_key == run.stream_key)
+            .is_some_and(|r| r.run_id == run.run_id)
+    }
+
+    /// Aligned samples of `by_stream` between `start` and `end`, read from
+    /// every archived run of each stream in order. The first stream sets the
+    /// timestamps, and the others give their nearest sample within half
+    /// their sample period, or no data.
+    pub(crate) fn read_time_range(
+        &self,
+        by_stream: &HashMap<StreamKey, Vec<ColumnId>>,
+        start: f64,
+        end: f64,
+    ) -> Result<AlignedWindow, ReadError> {
+        let no_data = || ReadError::NoDataInTimeRange {
+            requested_start: start,
+            requested_end: end,
+        };
+        let mut window = AlignedWindow {
+            sample_numbers: HashMap::new(),
+            timestamps: Vec::new(),
+            columns: HashMap::new(),
+            stream_metadata: HashMap::new(),
+            segment_metadata: HashMap::new(),
+            column_metadata: HashMap::new(),
+            session_ids: HashMap::new(),
+            run_ids: HashMap::new(),
+            time_offsets: HashMap::new(),
+            gaps: HashMap::new(),
+        };
+
+        let mut keys: Vec<&StreamKey> = by_stream.keys().collect();
+        keys.sort();
+        for stream_key in keys {
+            let col_ids = &by_stream[stream_key];
+            let mut sample_numbers = Vec::new();
+            let mut timestamps: Vec<f64> = Vec::new();
+            let mut values: Vec<Vec<[u8; 8]>> = vec![Vec::new(); col_ids.len()];
+            let mut last_run = None;
+
+            for run in self.runs.iter().filter(|run| run.stream_key == *stream_key) {
+                let indices = col_ids
+                    .iter()
+                    .map(|&column_id| {
+                        run.column_index(column_id)
+                            .ok_or(ReadError::ColumnNotFound {
+                                stream_key: stream_key.clone(),
+                                column_id,
+                            })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let rs = run.record_size;
+                let before = timestamps.len();
+                for segment in &run.segments {
+                    for idx in segment.range(rs, start, end) {
+                        let t = segment.timestamp(rs, idx);
+                        // Keep time increasing across runs whose clocks overlap.
+                        if timestamps.last().is_some_and(|&last| t <= last) {
+                            continue;
+                        }
+                        timestamps.push(t);
+                        sample_numbers.push(segment.sample_number(rs, idx));
+                        for (out, &column) in values.iter_mut().zip(&indices) {
+                            out.push(segment.word(rs, idx, column));
+                        }
+                    }
+                }
+                if timestamps.len() > before {
+                    last_run = Some((run, indices));
+                }
+            }
+
+            let Some((run, indices)) = last_run else {
+                return Err(no_data());
+            };
+            if window.run_ids.is_empty() {
+                window.timestamps = timestamps;
+            } else {
+                let tolerance = run.period() / 2.0 + 1e-9;
+                let nearest = window
+                    .timestamps
+                    .iter()
+                    .map(|&t| {
+                        let after = timestamps.partition_point(|&s| s < t);
+                        let before = after.checked_sub(1);
+                        [before, Some(after)]
+                            .into_iter()
+                            .flatten()
+                            .filter(|&i| i < timestamps.len())
+                            .min_by(|&a, &b| {
+                                (timestamps[a] - t)
+                                    .abs()
+                                    .total_cmp(&(timestamps[b] - t).abs())
+                            })
+                            .filter(|&i| (timestamps[i] - t).abs() <= tolerance)
+                    })
+                    .collect::<Option<Vec<usize>>>()
+                    .ok_or_else(no_data)?;
+                sample_numbers = nearest.iter().map(|&i| sample_numbers[i]).collect();
+                for words in &mut values {
+                    *words = nearest.iter().map(|&i| words[i]).collect();
+                }
+            }
+            for ((&column_id, &column), words) in col_ids.iter().zip(&indices).zip(values) {
+                let (metadata, kind) = &run.columns[column];
+                let batch = match kind {
+                    BufferType::Float => {
+                        ColumnBatch::F64(words.into_iter().map(f64::from_le_bytes).collect())
+                    }
+                    BufferType::Int => {
+                        ColumnBatch::I64(words.into_iter().map(i64::from_le_bytes).collect())
+                    }
+                    BufferType::UInt => {
+                        ColumnBatch::U64(words.into_iter().map(u64::from_le_bytes).collect())
+                    }
+                };
+                let key = ColumnKey::new(stream_key.route.clone(), stream_key.stream_id, column_id);
+                window.columns.insert(key.clone(), batch);
+                window.column_metadata.insert(key, metadata.clone());
+            }
+            window
+                .sample_numbers
+                .insert(stream_key.clone(), sample_numbers);
+            window
+                .stream_metadata
+                .insert(stream_key.clone(), run.stream_metadata.clone());
+            window
+                .segment_metadata
+                .insert(stream_key.clone(), run.segment_metadata.clone());
+            window
+                .session_ids
+                .insert(stream_key.clone(), run.session_id);
+            window.run_ids.insert(stream_key.clone(), run.run_id);
+        }
+        Ok(window)
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        for run in self.runs.drain(..) {
+            for segment in run.segments {
+                let path = segment.path.clone();
+                drop(segment);
+                let _ = fs::remove_file(path);
+            }
+        }
+        let _ = fs::remove_dir(&self.dir);
+    }
+}
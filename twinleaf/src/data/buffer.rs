@@ -1,15 +1,28 @@
-use crate::data::{ColumnData, CursorPosition, Sample};
+use crate::data::archive::Archive;
+use crate::data::clock::{TimeOffset, TimeRef, UtcMapping};
+use crate::data::derived::{DeriveError, DerivedColumn, DerivedSpec, FIRST_DERIVED_COLUMN_ID};
+use crate::data::{Boundary, BoundaryReason, ColumnData, CursorPosition, Sample};
 use crate::tio::proto::identifiers::*;
 use crate::tio::proto::meta::MetadataEpoch;
 use crate::tio::proto::{BufferType, ColumnMetadata, DeviceRoute, SegmentMetadata, StreamMetadata};
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, VecDeque},
+    ops::Range,
     sync::Arc,
 };
 
 pub type RunId = u64;
 
+/// Largest difference between timestamps on the same clock that match.
+const TIMESTAMP_TOLERANCE: f64 = 1e-9;
+
+/// Value of signed integer columns at samples filled in for lost ones, see
+/// `Buffer::with_gap_fill`. Float columns hold NaN.
+pub const FILL_I64: i64 = i64::MIN;
+/// Like `FILL_I64`, for unsigned integer columns.
+pub const FILL_U64: u64 = u64::MAX;
+
 #[derive(Debug, Clone)]
 pub enum ColumnBatch {
     F64(Vec<f64>),
@@ -29,6 +42,16 @@ impl ColumnBatch {
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// The values as `f64`, for analysis of integer columns. See
+    /// `AlignedWindow::column_f64` to leave out filled samples.
+    pub fn to_f64(&self) -> Vec<f64> {
+        match self {
+            Self::F64(v) => v.clone(),
+            Self::I64(v) => v.iter().map(|&x| x as f64).collect(),
+            Self::U64(v) => v.iter().map(|&x| x as f64).collect(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -41,9 +64,43 @@ pub struct AlignedWindow {
     pub column_metadata: HashMap<ColumnKey, Arc<ColumnMetadata>>,
     pub session_ids: HashMap<StreamKey, SessionId>,
     pub run_ids: HashMap<StreamKey, RunId>,
+    /// Rows of each stream filled in for lost samples, see
+    /// `Buffer::with_gap_fill`. Streams without gaps have no entry.
+    pub gaps: HashMap<StreamKey, Vec<Range<usize>>>,
+    /// Offsets from `timestamps` to the times of the streams on another
+    /// time reference than the window's.
+    pub time_offsets: HashMap<StreamKey, TimeOffset>,
 }
 
-#[derive(Debug, thiserror::Error)]
+impl AlignedWindow {
+    /// The values of column `key` as `f64`, NaN at the rows filled in for
+    /// lost samples.
+    pub fn column_f64(&self, key: &ColumnKey) -> Option<Vec<f64>> {
+        let mut values = self.columns.get(key)?.to_f64();
+        for gap in self.gaps.get(&key.stream_key()).into_iter().flatten() {
+            values[gap.clone()].fill(f64::NAN);
+        }
+        Some(values)
+    }
+}
+
+/// How the `read_resampled_*` reads put streams sampled at different rates
+/// on a common time base.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResampleMethod {
+    /// Each timestamp gets the last sample at or before it (zero-order
+    /// hold). The time base is the fastest stream.
+    Hold,
+    /// Linear interpolation between the samples around each timestamp.
+    /// The time base is the fastest stream.
+    Linear,
+    /// Mean of the samples since the previous timestamp, decimating faster
+    /// streams. The time base is the slowest stream. Intervals without
+    /// samples hold the previous one.
+    Mean,
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum ReadError {
     #[error("no columns requested")]
     NoColumnsRequested,
@@ -162,6 +219,65 @@ impl ColumnBuffer {
         }
     }
 
+    fn value_f64(&self, idx: usize) -> f64 {
+        match self {
+            Self::F64 { data, .. } => data[idx],
+            Self::I64 { data, .. } => data[idx] as f64,
+            Self::U64 { data, .. } => data[idx] as f64,
+        }
+    }
+
+    fn push_fill(&mut self) {
+        match self {
+            Self::F64 { data, .. } => data.push_back(f64::NAN),
+            Self::I64 { data, .. } => data.push_back(FILL_I64),
+            Self::U64 { data, .. } => data.push_back(FILL_U64),
+        }
+    }
+
+    /// Values at `picks`. Held samples keep their type, interpolated and
+    /// averaged ones are floats, NaN if they use a `filled` sample.
+    fn resample(&self, picks: &[Pick], filled: &VecDeque<bool>) -> ColumnBatch {
+        let held = |pick: &Pick| match pick {
+            Pick::At(idx) => Some(*idx),
+            _ => None,
+        };
+        if let Some(indices) = picks.iter().map(held).collect::<Option<Vec<_>>>() {
+            return match self {
+                Self::F64 { data, .. } => {
+                    ColumnBatch::F64(indices.iter().map(|&i| data[i]).collect())
+                }
+                Self::I64 { data, .. } => {
+                    ColumnBatch::I64(indices.iter().map(|&i| data[i]).collect())
+                }
+                Self::U64 { data, .. } => {
+                    ColumnBatch::U64(indices.iter().map(|&i| data[i]).collect())
+                }
+            };
+        }
+        let value = |idx: usize| {
+            if filled[idx] {
+                f64::NAN
+            } else {
+                self.value_f64(idx)
+            }
+        };
+        ColumnBatch::F64(
+            picks
+                .iter()
+                .map(|pick| match pick {
+                    Pick::At(idx) => value(*idx),
+                    Pick::Between(idx, frac) => {
+                        let a = value(*idx);
+                        let b = value(*idx + 1);
+                        a + (b - a) * frac
+                    }
+                    Pick::Mean(range) => range.clone().map(value).sum::<f64>() / range.len() as f64,
+                })
+                .collect(),
+        )
+    }
+
     fn get_range(&self, start: usize, count: usize) -> ColumnBatch {
         match self {
             Self::F64 { data, .. } => {
@@ -177,6 +293,14 @@ impl ColumnBuffer {
     }
 }
 
+/// Where a resampled value comes from in a stream's buffer.
+enum Pick {
+    At(usize),
+    /// Between a sample and the next one, at a fraction of the way.
+    Between(usize, f64),
+    Mean(std::ops::Range<usize>),
+}
+
 struct RunBuffer {
     run_id: RunId,
     session_id: SessionId,
@@ -184,6 +308,8 @@ struct RunBuffer {
     segment_metadata: Arc<SegmentMetadata>,
     sample_numbers: VecDeque<SampleNumber>,
     timestamps: VecDeque<f64>,
+    /// Whether each sample was filled in for a lost one.
+    filled: VecDeque<bool>,
     columns: HashMap<ColumnId, ColumnBuffer>,
     capacity: usize,
 }
@@ -198,6 +324,7 @@ impl RunBuffer {
             segment_metadata: sample.segment.clone(),
             sample_numbers: VecDeque::with_capacity(alloc),
             timestamps: VecDeque::with_capacity(alloc),
+            filled: VecDeque::with_capacity(alloc),
             columns: HashMap::new(),
             capacity,
         }
@@ -210,6 +337,7 @@ impl RunBuffer {
     fn push(&mut self, sample: &Sample) {
         self.sample_numbers.push_back(sample.n);
         self.timestamps.push_back(sample.timestamp_end());
+        self.filled.push_back(false);
         self.segment_metadata = sample.segment.clone();
 
         for col in &sample.columns {
@@ -220,9 +348,25 @@ impl RunBuffer {
         }
     }
 
+    /// Stand in for the lost sample `n` ending at `timestamp`.
+    fn push_fill(&mut self, n: SampleNumber, timestamp: f64) {
+        self.sample_numbers.push_back(n);
+        self.timestamps.push_back(timestamp);
+        self.filled.push_back(true);
+        for col in self.columns.values_mut() {
+            col.push_fill();
+        }
+    }
+
+    /// Filled rows among the `count` samples from `start`.
+    fn gaps(&self, start: usize, count: usize) -> Vec<Range<usize>> {
+        filled_ranges(self.filled.iter().skip(start).take(count).copied())
+    }
+
     fn pop_front(&mut self) {
         self.sample_numbers.pop_front();
         self.timestamps.pop_front();
+        self.filled.pop_front();
         for col in self.columns.values_mut() {
             col.pop_front();
         }
@@ -256,6 +400,45 @@ impl RunBuffer {
             .map(|idx| idx + 1)
     }
 
+    /// Sources of the values at `times`, which must be sorted and within
+    /// the buffered samples.
+    /// `period` is the time base's sample period, for the averaging interval
+    /// of the first timestamp.
+    fn picks(&self, times: &[f64], method: ResampleMethod, period: f64) -> Vec<Pick> {
+        let mut prev = times.first().map(|t| t - period);
+        times
+            .iter()
+            .map(|&t| {
+                let end = self.timestamps.partition_point(|&x| x <= t);
+                let last = end.saturating_sub(1);
+                let pick = match method {
+                    ResampleMethod::Hold => Pick::At(last),
+                    ResampleMethod::Linear => {
+                        let t0 = self.timestamps[last];
+                        match self.timestamps.get(last + 1) {
+                            Some(&t1) if (t0 < t) && (t1 > t0) => {
+                                Pick::Between(last, (t - t0) / (t1 - t0))
+                            }
+                            _ => Pick::At(last),
+                        }
+                    }
+                    ResampleMethod::Mean => {
+                        let start = prev
+                            .map(|p| self.timestamps.partition_point(|&x| x <= p))
+                            .unwrap_or(last);
+                        if start < end {
+                            Pick::Mean(start..end)
+                        } else {
+                            Pick::At(last)
+                        }
+                    }
+                };
+                prev = Some(t);
+                pick
+            })
+            .collect()
+    }
+
     fn timestamps_range(&self, start: usize, count: usize) -> Vec<f64> {
         self.timestamps
             .iter()
@@ -274,6 +457,7 @@ pub struct ActiveRun {
     pub time_ref_epoch: MetadataEpoch,
     pub last_sample_number: SampleNumber,
     pub last_timestamp: f64,
+    pub time_ref: TimeRef,
     buffer: RunBuffer,
 }
 
@@ -288,6 +472,7 @@ impl ActiveRun {
             time_ref_epoch: segment.time_ref_epoch.clone(),
             last_sample_number: sample.n,
             last_timestamp: sample.timestamp_end(),
+            time_ref: sample.time_ref(),
             buffer: RunBuffer::new(run_id, sample, capacity),
         }
     }
@@ -297,13 +482,18 @@ pub struct Buffer {
     capacity: usize,
     active_runs: HashMap<StreamKey, ActiveRun>,
     next_run_id: RunId,
+    derived: Vec<DerivedColumn>,
+    archive: Option<Archive>,
+    max_gap_fill: u32,
+    clocks: HashMap<TimeRef, UtcMapping>,
 }
 
 enum AlignmentMode<'a> {
     LastN(usize),
+    /// `n` samples after the cursors, or all of them if `None`.
     FromCursors {
         cursors: &'a HashMap<StreamKey, CursorPosition>,
-        n: usize,
+        n: Option<usize>,
     },
     CommonTail,
     TimeRange {
@@ -312,17 +502,71 @@ enum AlignmentMode<'a> {
     },
 }
 
+/// Which timestamps of the time base a resampled read returns.
+enum ResampleRange {
+    LastN(usize),
+    CommonTail,
+    TimeRange { start: f64, end: f64 },
+}
+
 impl Buffer {
     pub fn new(capacity: usize) -> Self {
         Self {
             capacity,
             active_runs: HashMap::new(),
             next_run_id: 0,
+            derived: Vec::new(),
+            archive: None,
+            max_gap_fill: 0,
+            clocks: HashMap::new(),
         }
     }
 
+    /// Also keep every sample in `archive`, so that time range reads reach
+    /// past the samples kept in memory and into ended runs.
+    pub fn with_archive(mut self, archive: Archive) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    pub fn archive(&self) -> Option<&Archive> {
+        self.archive.as_ref()
+    }
+
+    /// Keep runs going over gaps of up to `max_samples` lost samples within
+    /// one session, segment and rate, rather than starting a new run.
+    /// The lost samples are filled with NaN, or `FILL_I64` and `FILL_U64`
+    /// in integer columns, and listed in `AlignedWindow::gaps`.
+    pub fn with_gap_fill(mut self, max_samples: u32) -> Self {
+        self.max_gap_fill = max_samples;
+        self
+    }
+
+    /// Number of samples lost just before `sample` that gap fill keeps in
+    /// the current run.
+    fn fillable_gap(&self, sample: &Sample, stream_key: &StreamKey) -> Option<u32> {
+        let Some(Boundary {
+            reason: BoundaryReason::SamplesLost { expected, received },
+            ..
+        }) = &sample.boundary
+        else {
+            return None;
+        };
+        let active = self.active_runs.get(stream_key)?;
+        let missing = received.wrapping_sub(*expected);
+        let segment = &sample.segment;
+        let rate = f64::from(segment.sampling_rate) / f64::from(segment.decimation);
+        let same_run = *expected == active.last_sample_number.wrapping_add(1)
+            && active.session_id == sample.device.session_id
+            && active.segment_id == segment.segment_id
+            && (active.effective_rate - rate).abs() <= 0.001;
+        (same_run && missing <= self.max_gap_fill).then_some(missing)
+    }
+
     pub fn process_sample(&mut self, sample: Sample, stream_key: StreamKey) {
-        let needs_new_run = !sample.is_continuous() || !self.active_runs.contains_key(&stream_key);
+        let gap = self.fillable_gap(&sample, &stream_key);
+        let needs_new_run = gap.is_none()
+            && (!sample.is_continuous() || !self.active_runs.contains_key(&stream_key));
 
         if needs_new_run {
             let new_run_id = self.next_run_id;
@@ -334,6 +578,22 @@ impl Buffer {
         }
 
         let active = self.active_runs.get_mut(&stream_key).unwrap();
+        if let Some(missing) = gap {
+            let period = 1.0 / active.effective_rate;
+            let end = sample.timestamp_end();
+            for k in 0..missing {
+                let n = active.last_sample_number.wrapping_add(1 + k);
+                active
+                    .buffer
+                    .push_fill(n, end - f64::from(missing - k) * period);
+                if active.buffer.len() > self.capacity {
+                    active.buffer.pop_front();
+                }
+            }
+        }
+        if let Some(archive) = &mut self.archive {
+            archive.push(active.run_id, &stream_key, &sample);
+        }
         active.buffer.push(&sample);
         active.last_sample_number = sample.n;
         active.last_timestamp = sample.timestamp_end();
@@ -344,19 +604,170 @@ impl Buffer {
         }
     }
 
+    /// Relate the clock `time_ref` to UTC with `clock`, such as the latest
+    /// `Device::clock`, for aligning streams on different clocks.
+    pub fn set_clock(&mut self, time_ref: TimeRef, clock: UtcMapping) {
+        self.clocks.insert(time_ref, clock);
+    }
+
     pub fn get_run(&self, stream_key: &StreamKey) -> Option<&ActiveRun> {
         self.active_runs.get(stream_key)
     }
 
+    /// Streams with an active run, grouped by the clock their times are on.
+    pub fn time_refs(&self) -> BTreeMap<TimeRef, Vec<StreamKey>> {
+        let mut groups: BTreeMap<TimeRef, Vec<StreamKey>> = BTreeMap::new();
+        for (key, run) in &self.active_runs {
+            groups
+                .entry(run.time_ref.clone())
+                .or_default()
+                .push(key.clone());
+        }
+        for keys in groups.values_mut() {
+            keys.sort();
+        }
+        groups
+    }
+
+    /// Seconds to add to a time of stream `from` to get the time of stream
+    /// `to`: exactly zero on the same time reference, otherwise estimated
+    /// through UTC. `None` if either stream has no active run or its clock
+    /// cannot be related to UTC.
+    pub fn time_offset(&self, from: &StreamKey, to: &StreamKey) -> Option<TimeOffset> {
+        let from = self.active_runs.get(from)?;
+        let to = self.active_runs.get(to)?;
+        if from.time_ref == to.time_ref {
+            return Some(TimeOffset::default());
+        }
+        let utc = |run: &ActiveRun| {
+            let clock = self.clocks.get(&run.time_ref);
+            TimeOffset::to_utc(&run.time_ref_epoch, clock, run.last_timestamp)
+        };
+        Some(utc(from)?.between(&utc(to)?))
+    }
+
+    /// Like `time_offset`, unknown rather than `None` for unrelated clocks.
+    fn read_offset(&self, base: &StreamKey, key: &StreamKey) -> TimeOffset {
+        self.time_offset(base, key)
+            .unwrap_or_else(TimeOffset::unknown)
+    }
+
+    /// Offset from the times of `base` to those of `key`, and how far
+    /// apart matching timestamps may be: a rounding error on the same time
+    /// reference, or across references half a sample period widened by
+    /// three standard deviations of the offset, so that jitter in the host
+    /// arrival times the offset is estimated from doesn't break alignment.
+    /// `None` if the clocks cannot be related, in which case reads take the
+    /// times as they are.
+    fn time_match(
+        &self,
+        base: &StreamKey,
+        key: &StreamKey,
+    ) -> Result<Option<(f64, f64)>, ReadError> {
+        let run = self.active_run(key)?;
+        if self.active_run(base)?.time_ref == run.time_ref {
+            return Ok(Some((0.0, TIMESTAMP_TOLERANCE)));
+        }
+        Ok(self
+            .time_offset(base, key)
+            .filter(|offset| offset.uncertainty.is_finite())
+            .map(|offset| {
+                let tolerance = 0.5 / run.effective_rate + 3.0 * offset.uncertainty;
+                (offset.seconds, tolerance)
+            }))
+    }
+
+    /// Start of the samples of `key` at `times` of `base`: the sample
+    /// nearest the first time, followed by one matching each of the rest.
+    /// `None` if the clocks cannot be related. `InsufficientData` if `key`
+    /// has no such samples, for instance because they have not arrived yet.
+    fn matching_start(
+        &self,
+        base: &StreamKey,
+        key: &StreamKey,
+        times: &[f64],
+    ) -> Result<Option<usize>, ReadError> {
+        let buf = self.active_buffer(key)?;
+        let (Some((offset, tolerance)), Some(&first)) =
+            (self.time_match(base, key)?, times.first())
+        else {
+            return Ok(None);
+        };
+        let target = first + offset;
+        let after = buf.timestamps.partition_point(|&t| t < target);
+        let start = match after.checked_sub(1) {
+            Some(before)
+                if after == buf.len()
+                    || target - buf.timestamps[before] <= buf.timestamps[after] - target =>
+            {
+                before
+            }
+            _ => after,
+        };
+        let candidate = buf.timestamps.iter().skip(start).copied();
+        if buf.len() >= start + times.len()
+            && timestamps_match_iter(times, candidate, offset, tolerance)
+        {
+            return Ok(Some(start));
+        }
+        Err(ReadError::InsufficientData {
+            stream_key: key.clone(),
+            requested: times.len(),
+            available: buf.len().saturating_sub(start).min(times.len()),
+        })
+    }
+
+    /// Earliest and latest timestamps of a stream that time range reads can
+    /// reach, in memory or in the archive.
+    pub fn time_bounds(&self, stream_key: &StreamKey) -> Option<(f64, f64)> {
+        let buf = &self.active_runs.get(stream_key)?.buffer;
+        let memory = Some((*buf.timestamps.front()?, *buf.timestamps.back()?));
+        let archived = self
+            .archive
+            .as_ref()
+            .and_then(|a| a.time_bounds(stream_key));
+        match (memory, archived) {
+            (Some((a0, a1)), Some((b0, b1))) => Some((a0.min(b0), a1.max(b1))),
+            (bounds, None) | (None, bounds) => bounds,
+        }
+    }
+
+    /// Add a derived column, binding its references to the columns of the
+    /// buffered streams. Reads return it like any other column, evaluated on
+    /// the aligned or resampled values of its inputs.
+    pub fn derive(&mut self, spec: &DerivedSpec) -> Result<ColumnKey, DeriveError> {
+        let column_id = FIRST_DERIVED_COLUMN_ID + self.derived.len();
+        let catalog = self.active_runs.iter().flat_map(|(stream_key, run)| {
+            let stream = run.buffer.stream_metadata.name.as_str();
+            run.buffer.columns.iter().map(move |(&id, col)| {
+                (
+                    ColumnKey::new(stream_key.route.clone(), stream_key.stream_id, id),
+                    stream,
+                    col.metadata().name.as_str(),
+                )
+            })
+        });
+        let column = spec.resolve(catalog, column_id)?;
+        let key = column.key.clone();
+        self.derived.push(column);
+        Ok(key)
+    }
+
+    pub fn derived_columns(&self) -> &[DerivedColumn] {
+        &self.derived
+    }
+
     pub fn read_aligned_window(
         &self,
         columns: &[ColumnKey],
         n: usize,
     ) -> Result<AlignedWindow, ReadError> {
-        let by_stream = self.prepare_stream_selection(columns)?;
-        let (slices, timestamps) =
-            self.compute_aligned_slices(&by_stream, AlignmentMode::LastN(n))?;
-        self.build_window_from_slices(&by_stream, &slices, timestamps)
+        self.with_derived(columns, |columns| {
+            let by_stream = self.prepare_stream_selection(columns)?;
+            let (slices, timestamps) =
+                self.compute_aligned_slices(&by_stream, AlignmentMode::LastN(n))?;
+            self.build_window_from_slices(&by_stream, &slices, timestamps)
+        })
     }
 
     pub fn read_from_cursor(
@@ -365,17 +776,42 @@ impl Buffer {
         cursors: &HashMap<StreamKey, CursorPosition>,
         n: usize,
     ) -> Result<AlignedWindow, ReadError> {
-        let by_stream = self.prepare_stream_selection(columns)?;
-        let (slices, timestamps) =
-            self.compute_aligned_slices(&by_stream, AlignmentMode::FromCursors { cursors, n })?;
-        self.build_window_from_slices(&by_stream, &slices, timestamps)
+        self.with_derived(columns, |columns| {
+            let by_stream = self.prepare_stream_selection(columns)?;
+            let (slices, timestamps) = self.compute_aligned_slices(
+                &by_stream,
+                AlignmentMode::FromCursors {
+                    cursors,
+                    n: Some(n),
+                },
+            )?;
+            self.build_window_from_slices(&by_stream, &slices, timestamps)
+        })
+    }
+
+    /// Like `read_from_cursor`, for all samples after the cursors.
+    pub fn read_since_cursor(
+        &self,
+        columns: &[ColumnKey],
+        cursors: &HashMap<StreamKey, CursorPosition>,
+    ) -> Result<AlignedWindow, ReadError> {
+        self.with_derived(columns, |columns| {
+            let by_stream = self.prepare_stream_selection(columns)?;
+            let (slices, timestamps) = self.compute_aligned_slices(
+                &by_stream,
+                AlignmentMode::FromCursors { cursors, n: None },
+            )?;
+            self.build_window_from_slices(&by_stream, &slices, timestamps)
+        })
     }
 
     pub fn read_aligned_tail(&self, columns: &[ColumnKey]) -> Result<AlignedWindow, ReadError> {
-        let by_stream = self.prepare_stream_selection(columns)?;
-        let (slices, timestamps) =
-            self.compute_aligned_slices(&by_stream, AlignmentMode::CommonTail)?;
-        self.build_window_from_slices(&by_stream, &slices, timestamps)
+        self.with_derived(columns, |columns| {
+            let by_stream = self.prepare_stream_selection(columns)?;
+            let (slices, timestamps) =
+                self.compute_aligned_slices(&by_stream, AlignmentMode::CommonTail)?;
+            self.build_window_from_slices(&by_stream, &slices, timestamps)
+        })
     }
 
     pub fn read_aligned_time_range(
@@ -384,22 +820,336 @@ impl Buffer {
         start_time: f64,
         end_time: f64,
     ) -> Result<AlignedWindow, ReadError> {
-        let by_stream = self.prepare_stream_selection(columns)?;
-        let (slices, timestamps) = self.compute_aligned_slices(
-            &by_stream,
-            AlignmentMode::TimeRange {
+        self.with_derived(columns, |columns| {
+            let by_stream = self.prepare_stream_selection(columns)?;
+            let read = self
+                .compute_aligned_slices(
+                    &by_stream,
+                    AlignmentMode::TimeRange {
+                        start: start_time,
+                        end: end_time,
+                    },
+                )
+                .and_then(|(slices, timestamps)| {
+                    self.build_window_from_slices(&by_stream, &slices, timestamps)
+                })
+                .map_err(|err| match err {
+                    ReadError::InsufficientData { .. } => ReadError::NoDataInTimeRange {
+                        requested_start: start_time.min(end_time),
+                        requested_end: start_time.max(end_time),
+                    },
+                    other => other,
+                });
+            match (read, &self.archive) {
+                (
+                    Err(
+                        err @ (ReadError::RequestedRangeExceedsRetention { .. }
+                        | ReadError::NoDataInTimeRange { .. }),
+                    ),
+                    Some(archive),
+                ) => {
+                    let (start, end) = normalize_time_bounds(start_time, end_time);
+                    archive
+                        .read_time_range(&by_stream, start, end)
+                        .map_err(|archive_err| match archive_err {
+                            ReadError::NoDataInTimeRange { .. } => err,
+                            other => other,
+                        })
+                }
+                (read, _) => read,
+            }
+        })
+    }
+
+    /// Aligned samples of the last `seconds` up to the latest timestamp
+    /// common to all requested streams, or as many as the buffer retains.
+    pub fn read_aligned_seconds(
+        &self,
+        columns: &[ColumnKey],
+        seconds: f64,
+    ) -> Result<AlignedWindow, ReadError> {
+        self.with_derived(columns, |columns| {
+            let by_stream = self.prepare_stream_selection(columns)?;
+            let ref_key = Self::reference_stream_key(&by_stream);
+            let (available_start, available_end) = self
+                .aligned_retained_time_bounds(&by_stream, ref_key)?
+                .ok_or_else(|| ReadError::InsufficientData {
+                    stream_key: ref_key.clone(),
+                    requested: 1,
+                    available: 0,
+                })?;
+            let start = (available_end - seconds).max(available_start);
+            let (slices, timestamps) = self.compute_aligned_slices(
+                &by_stream,
+                AlignmentMode::TimeRange {
+                    start,
+                    end: available_end,
+                },
+            )?;
+            self.build_window_from_slices(&by_stream, &slices, timestamps)
+        })
+    }
+
+    /// Last `n` timestamps of the time base, see `ResampleMethod`, with all
+    /// requested columns resampled onto them. Unlike `read_aligned_window`,
+    /// the streams may have different rates. Only timestamps at which every
+    /// stream has data are returned.
+    ///
+    /// `sample_numbers` of the resampled streams are those of the last
+    /// sample at or before each timestamp.
+    pub fn read_resampled_window(
+        &self,
+        columns: &[ColumnKey],
+        n: usize,
+        method: ResampleMethod,
+    ) -> Result<AlignedWindow, ReadError> {
+        self.read_resampled(columns, method, ResampleRange::LastN(n))
+    }
+
+    /// Like `read_resampled_window`, for all timestamps of the time base at
+    /// which every stream has data.
+    pub fn read_resampled_tail(
+        &self,
+        columns: &[ColumnKey],
+        method: ResampleMethod,
+    ) -> Result<AlignedWindow, ReadError> {
+        self.read_resampled(columns, method, ResampleRange::CommonTail)
+    }
+
+    /// Like `read_resampled_window`, for the timestamps of the time base
+    /// between `start_time` and `end_time`.
+    pub fn read_resampled_time_range(
+        &self,
+        columns: &[ColumnKey],
+        start_time: f64,
+        end_time: f64,
+        method: ResampleMethod,
+    ) -> Result<AlignedWindow, ReadError> {
+        self.read_resampled(
+            columns,
+            method,
+            ResampleRange::TimeRange {
                 start: start_time,
                 end: end_time,
             },
-        )?;
-        self.build_window_from_slices(&by_stream, &slices, timestamps)
-            .map_err(|err| match err {
-                ReadError::InsufficientData { .. } => ReadError::NoDataInTimeRange {
-                    requested_start: start_time.min(end_time),
-                    requested_end: start_time.max(end_time),
-                },
-                other => other,
-            })
+        )
+    }
+
+    fn read_resampled(
+        &self,
+        columns: &[ColumnKey],
+        method: ResampleMethod,
+        range: ResampleRange,
+    ) -> Result<AlignedWindow, ReadError> {
+        self.with_derived(columns, |columns| {
+            self.read_resampled_inputs(columns, method, range)
+        })
+    }
+
+    fn read_resampled_inputs(
+        &self,
+        columns: &[ColumnKey],
+        method: ResampleMethod,
+        range: ResampleRange,
+    ) -> Result<AlignedWindow, ReadError> {
+        if columns.is_empty() {
+            return Err(ReadError::NoColumnsRequested);
+        }
+        let by_stream = group_columns_by_stream(columns);
+        let base_key = self.time_base_key(&by_stream, method)?;
+        let base_run = self.active_run(&base_key)?;
+        let base_buf = &base_run.buffer;
+
+        let insufficient = |requested| ReadError::InsufficientData {
+            stream_key: base_key.clone(),
+            requested,
+            available: 0,
+        };
+        let (common_start, common_end) = self
+            .aligned_retained_time_bounds(&by_stream, &base_key)?
+            .ok_or_else(|| insufficient(0))?;
+        let first = base_buf.timestamps.partition_point(|&t| t < common_start);
+        let last = base_buf.timestamps.partition_point(|&t| t <= common_end);
+
+        let (start, end) = match range {
+            ResampleRange::LastN(n) => {
+                if first >= last {
+                    return Err(insufficient(n));
+                }
+                (last.saturating_sub(n).max(first), last)
+            }
+            ResampleRange::CommonTail => {
+                if first >= last {
+                    return Err(insufficient(0));
+                }
+                (first, last)
+            }
+            ResampleRange::TimeRange { start, end } => {
+                let (requested_start, requested_end) = normalize_time_bounds(start, end);
+                if requested_start < common_start || requested_end > common_end {
+                    return Err(ReadError::RequestedRangeExceedsRetention {
+                        requested_start,
+                        requested_end,
+                        available_start: common_start,
+                        available_end: common_end,
+                    });
+                }
+                let s = base_buf
+                    .timestamps
+                    .partition_point(|&t| t < requested_start);
+                let e = base_buf.timestamps.partition_point(|&t| t <= requested_end);
+                if s >= e {
+                    return Err(ReadError::NoDataInTimeRange {
+                        requested_start,
+                        requested_end,
+                    });
+                }
+                (s, e)
+            }
+        };
+
+        let timestamps = base_buf.timestamps_range(start, end - start);
+        let slices = HashMap::from([(base_key.clone(), (start, end - start))]);
+        let base_only = HashMap::from([(base_key.clone(), by_stream[&base_key].clone())]);
+        let mut window = self.build_window_from_slices(&base_only, &slices, timestamps)?;
+
+        let period = 1.0 / base_run.effective_rate;
+        for (stream_key, col_ids) in &by_stream {
+            if *stream_key == base_key {
+                continue;
+            }
+            let buf = self.active_buffer(stream_key)?;
+            let offset = self
+                .time_match(&base_key, stream_key)?
+                .map_or(0.0, |(offset, _)| offset);
+            let times: Vec<f64> = window.timestamps.iter().map(|t| t + offset).collect();
+            let picks = buf.picks(&times, method, period);
+            let stream_gaps = filled_ranges(picks.iter().map(|pick| match pick {
+                Pick::At(idx) => buf.filled[*idx],
+                Pick::Between(idx, _) => buf.filled[*idx] || buf.filled[*idx + 1],
+                Pick::Mean(range) => range.clone().any(|i| buf.filled[i]),
+            }));
+            if !stream_gaps.is_empty() {
+                window.gaps.insert(stream_key.clone(), stream_gaps);
+            }
+            let stream_sample_numbers = picks
+                .iter()
+                .map(|pick| match pick {
+                    Pick::At(idx) | Pick::Between(idx, _) => buf.sample_numbers[*idx],
+                    Pick::Mean(range) => buf.sample_numbers[range.end - 1],
+                })
+                .collect();
+            window
+                .sample_numbers
+                .insert(stream_key.clone(), stream_sample_numbers);
+            window
+                .stream_metadata
+                .insert(stream_key.clone(), buf.stream_metadata.clone());
+            window
+                .segment_metadata
+                .insert(stream_key.clone(), buf.segment_metadata.clone());
+            window
+                .session_ids
+                .insert(stream_key.clone(), buf.session_id);
+            window.run_ids.insert(stream_key.clone(), buf.run_id);
+            if self.active_run(stream_key)?.time_ref != base_run.time_ref {
+                window
+                    .time_offsets
+                    .insert(stream_key.clone(), self.read_offset(&base_key, stream_key));
+            }
+
+            for &col_id in col_ids {
+                let col_buf = buf.columns.get(&col_id).ok_or(ReadError::ColumnNotFound {
+                    stream_key: stream_key.clone(),
+                    column_id: col_id,
+                })?;
+                let key = ColumnKey::new(stream_key.route.clone(), stream_key.stream_id, col_id);
+                window
+                    .columns
+                    .insert(key.clone(), col_buf.resample(&picks, &buf.filled));
+                window
+                    .column_metadata
+                    .insert(key, col_buf.metadata().clone());
+            }
+        }
+        Ok(window)
+    }
+
+    /// Read `columns` with `read`, reading the inputs of derived columns in
+    /// their place and evaluating them on the result.
+    fn with_derived<F>(&self, columns: &[ColumnKey], read: F) -> Result<AlignedWindow, ReadError>
+    where
+        F: FnOnce(&[ColumnKey]) -> Result<AlignedWindow, ReadError>,
+    {
+        let derived: Vec<&DerivedColumn> = columns
+            .iter()
+            .filter_map(|key| self.derived.iter().find(|c| c.key == *key))
+            .collect();
+        if derived.is_empty() {
+            return read(columns);
+        }
+
+        let mut inputs: Vec<ColumnKey> = columns
+            .iter()
+            .filter(|&key| derived.iter().all(|c| c.key != *key))
+            .cloned()
+            .collect();
+        for column in &derived {
+            for input in &column.inputs {
+                if !inputs.contains(input) {
+                    inputs.push(input.clone());
+                }
+            }
+        }
+
+        let mut window = read(&inputs)?;
+        for column in derived {
+            let batches: Vec<&ColumnBatch> =
+                column.inputs.iter().map(|k| &window.columns[k]).collect();
+            let mut batch = column.evaluate_batches(&batches);
+            if let ColumnBatch::F64(values) = &mut batch {
+                for input in &column.inputs {
+                    for rows in window.gaps.get(&input.stream_key()).into_iter().flatten() {
+                        values[rows.clone()].fill(f64::NAN);
+                    }
+                }
+            }
+            window.columns.insert(column.key.clone(), batch);
+            window
+                .column_metadata
+                .insert(column.key.clone(), column.metadata.clone());
+        }
+        window.columns.retain(|key, _| columns.contains(key));
+        window
+            .column_metadata
+            .retain(|key, _| columns.contains(key));
+        Ok(window)
+    }
+
+    /// The fastest stream for `Hold` and `Linear`, the slowest for `Mean`.
+    fn time_base_key(
+        &self,
+        by_stream: &HashMap<StreamKey, Vec<ColumnId>>,
+        method: ResampleMethod,
+    ) -> Result<StreamKey, ReadError> {
+        let mut keys: Vec<&StreamKey> = by_stream.keys().collect();
+        keys.sort();
+        let mut best: Option<(&StreamKey, f64)> = None;
+        for key in keys {
+            let rate = self.active_run(key)?.effective_rate;
+            let better = match (best, method) {
+                (None, _) => true,
+                (Some((_, r)), ResampleMethod::Mean) => rate < r,
+                (Some((_, r)), _) => rate > r,
+            };
+            if better {
+                best = Some((key, rate));
+            }
+        }
+        Ok(best
+            .expect("time base requires at least one stream")
+            .0
+            .clone())
     }
 
     fn compute_aligned_slices(
@@ -419,19 +1169,54 @@ impl Buffer {
                         available: 0,
                     });
                 }
-                let count = n.min(available);
-                let start = available.saturating_sub(count);
+                // End at the latest time that every stream on another clock
+                // has reached, so that devices whose packets arrive
+                // separately line up.
+                let mut end = available;
+                for stream_key in by_stream.keys() {
+                    if self.same_time_ref(ref_key, stream_key)? {
+                        continue;
+                    }
+                    let last = self.active_buffer(stream_key)?.timestamps.back();
+                    if let (Some(&last), Some((offset, tolerance))) =
+                        (last, self.time_match(ref_key, stream_key)?)
+                    {
+                        let last = last - offset + tolerance;
+                        end = end.min(ref_buf.timestamps.partition_point(|&t| t <= last));
+                    }
+                }
+                if end == 0 {
+                    return Err(ReadError::InsufficientData {
+                        stream_key: ref_key.clone(),
+                        requested: n,
+                        available: 0,
+                    });
+                }
+                let count = n.min(end);
+                let start = end - count;
                 let timestamps = ref_buf.timestamps_range(start, count);
-                let slices = by_stream
-                    .keys()
-                    .map(|k| (k.clone(), (start, count)))
-                    .collect();
+                let mut slices = HashMap::new();
+                for stream_key in by_stream.keys() {
+                    // Streams on the same clock line up by index.
+                    if self.same_time_ref(ref_key, stream_key)? {
+                        slices.insert(stream_key.clone(), (start, count));
+                        continue;
+                    }
+                    let buf = self.active_buffer(stream_key)?;
+                    // Without a relation between the clocks, take the
+                    // latest samples of each stream.
+                    let s = self
+                        .matching_start(ref_key, stream_key, &timestamps)?
+                        .unwrap_or_else(|| buf.len().saturating_sub(count));
+                    slices.insert(stream_key.clone(), (s, count));
+                }
                 Ok((slices, timestamps))
             }
 
             AlignmentMode::FromCursors { cursors, n } => {
                 let mut start = 0;
                 let mut reference_key: Option<StreamKey> = None;
+                let mut available = usize::MAX;
 
                 for stream_key in by_stream.keys() {
                     let active = self.active_run(stream_key)?;
@@ -451,7 +1236,7 @@ impl Buffer {
                     if buf.sample_numbers.is_empty() {
                         return Err(ReadError::InsufficientData {
                             stream_key: stream_key.clone(),
-                            requested: n,
+                            requested: n.unwrap_or(1),
                             available: 0,
                         });
                     }
@@ -462,19 +1247,22 @@ impl Buffer {
                             cursor_sample: cursor.last_sample_number,
                             earliest_available: *buf.sample_numbers.front().unwrap(),
                         })?;
-                    if s + n > buf.len() {
+                    let stream_available = buf.len().saturating_sub(s);
+                    if stream_available < n.unwrap_or(1) {
                         return Err(ReadError::InsufficientData {
                             stream_key: stream_key.clone(),
-                            requested: n,
-                            available: buf.len().saturating_sub(s),
+                            requested: n.unwrap_or(1),
+                            available: stream_available,
                         });
                     }
+                    available = available.min(stream_available);
                     if reference_key.is_none() {
                         start = s;
                         reference_key = Some(stream_key.clone());
                     }
                 }
 
+                let n = n.unwrap_or(available);
                 let ref_key = reference_key.unwrap();
                 let ref_buf = self.active_buffer(&ref_key)?;
                 let timestamps = ref_buf.timestamps_range(start, n);
@@ -486,6 +1274,7 @@ impl Buffer {
                 let mut global_start = f64::MIN;
                 let mut global_end = f64::MAX;
 
+                let ref_key = Self::reference_stream_key(by_stream);
                 for stream_key in by_stream.keys() {
                     let buf = self.active_buffer(stream_key)?;
                     if buf.timestamps.is_empty() {
@@ -495,8 +1284,11 @@ impl Buffer {
                             available: 0,
                         });
                     }
-                    let first = *buf.timestamps.front().unwrap();
-                    let last = *buf.timestamps.back().unwrap();
+                    let offset = self
+                        .time_match(ref_key, stream_key)?
+                        .map_or(0.0, |(offset, _)| offset);
+                    let first = *buf.timestamps.front().unwrap() - offset;
+                    let last = *buf.timestamps.back().unwrap() - offset;
                     global_start = global_start.max(first);
                     global_end = global_end.min(last);
                 }
@@ -509,7 +1301,6 @@ impl Buffer {
                     });
                 }
 
-                let ref_key = Self::reference_stream_key(by_stream);
                 let ref_buf = self.active_buffer(ref_key)?;
                 let start = ref_buf
                     .timestamps
@@ -523,10 +1314,18 @@ impl Buffer {
                     .unwrap_or(ref_buf.len().saturating_sub(1));
                 let count = end.saturating_sub(start) + 1;
                 let timestamps = ref_buf.timestamps_range(start, count);
-                let slices = by_stream
-                    .keys()
-                    .map(|k| (k.clone(), (start, count)))
-                    .collect();
+                let mut slices = HashMap::new();
+                for stream_key in by_stream.keys() {
+                    // Streams on the same clock line up by index, as do
+                    // those on clocks without a relation.
+                    let s = if self.same_time_ref(ref_key, stream_key)? {
+                        start
+                    } else {
+                        self.matching_start(ref_key, stream_key, &timestamps)?
+                            .unwrap_or(start)
+                    };
+                    slices.insert(stream_key.clone(), (s, count));
+                }
                 Ok((slices, timestamps))
             }
 
@@ -536,8 +1335,9 @@ impl Buffer {
             } => {
                 let (requested_start, requested_end) = normalize_time_bounds(start_time, end_time);
 
+                let ref_key = Self::reference_stream_key(by_stream);
                 let (available_start, available_end) = self
-                    .aligned_retained_time_bounds(by_stream)?
+                    .aligned_retained_time_bounds(by_stream, ref_key)?
                     .ok_or(ReadError::NoDataInTimeRange {
                         requested_start,
                         requested_end,
@@ -552,7 +1352,6 @@ impl Buffer {
                     });
                 }
 
-                let ref_key = Self::reference_stream_key(by_stream);
                 let ref_buf = self.active_buffer(ref_key)?;
                 let ref_start = ref_buf.timestamps.partition_point(|&t| t < requested_start);
                 let ref_end = ref_buf.timestamps.partition_point(|&t| t <= requested_end);
@@ -579,30 +1378,13 @@ impl Buffer {
                     if stream_key == ref_key {
                         continue;
                     }
-                    let buf = self.active_buffer(stream_key)?;
-                    let s = buf.timestamps.partition_point(|&t| t < requested_start);
-                    let e = buf.timestamps.partition_point(|&t| t <= requested_end);
-                    if s >= e {
-                        return Err(ReadError::NoDataInTimeRange {
-                            requested_start,
-                            requested_end,
-                        });
-                    }
-                    let count = e - s;
-                    if count != ref_count {
-                        return Err(ReadError::NoDataInTimeRange {
-                            requested_start,
-                            requested_end,
-                        });
-                    }
-                    let stream_timestamps = buf.timestamps.iter().skip(s).take(count).copied();
-                    if !timestamps_match_iter(&timestamps, stream_timestamps) {
-                        return Err(ReadError::NoDataInTimeRange {
+                    let s = self
+                        .matching_start(ref_key, stream_key, &timestamps)?
+                        .ok_or(ReadError::NoDataInTimeRange {
                             requested_start,
                             requested_end,
-                        });
-                    }
-                    slices.insert(stream_key.clone(), (s, count));
+                        })?;
+                    slices.insert(stream_key.clone(), (s, ref_count));
                 }
 
                 Ok((slices, timestamps))
@@ -610,9 +1392,12 @@ impl Buffer {
         }
     }
 
+    /// Time span that every stream in `by_stream` retains, in the times of
+    /// `base`.
     fn aligned_retained_time_bounds(
         &self,
         by_stream: &HashMap<StreamKey, Vec<ColumnId>>,
+        base: &StreamKey,
     ) -> Result<Option<(f64, f64)>, ReadError> {
         let mut global_start = f64::MIN;
         let mut global_end = f64::MAX;
@@ -623,9 +1408,12 @@ impl Buffer {
             else {
                 return Ok(None);
             };
+            let offset = self
+                .time_match(base, stream_key)?
+                .map_or(0.0, |(offset, _)| offset);
 
-            global_start = global_start.max(first);
-            global_end = global_end.min(last);
+            global_start = global_start.max(first - offset);
+            global_end = global_end.min(last - offset);
         }
 
         if global_start > global_end {
@@ -649,6 +1437,10 @@ impl Buffer {
         let mut column_metadata = HashMap::new();
         let mut session_ids = HashMap::new();
         let mut run_ids = HashMap::new();
+        let mut time_offsets = HashMap::new();
+        let mut gaps = HashMap::new();
+        let ref_key = Self::reference_stream_key(by_stream);
+        let ref_time = &self.active_run(ref_key)?.time_ref;
 
         for (stream_key, col_ids) in by_stream {
             let buf = self.active_buffer(stream_key)?;
@@ -682,6 +1474,13 @@ impl Buffer {
             segment_metadata.insert(stream_key.clone(), buf.segment_metadata.clone());
             session_ids.insert(stream_key.clone(), buf.session_id);
             run_ids.insert(stream_key.clone(), buf.run_id);
+            if self.active_run(stream_key)?.time_ref != *ref_time {
+                time_offsets.insert(stream_key.clone(), self.read_offset(ref_key, stream_key));
+            }
+            let stream_gaps = buf.gaps(start, count);
+            if !stream_gaps.is_empty() {
+                gaps.insert(stream_key.clone(), stream_gaps);
+            }
 
             for &col_id in col_ids {
                 let col_buf = buf.columns.get(&col_id).ok_or(ReadError::ColumnNotFound {
@@ -712,6 +1511,8 @@ impl Buffer {
             column_metadata,
             session_ids,
             run_ids,
+            time_offsets,
+            gaps,
         })
     }
 
@@ -772,6 +1573,10 @@ impl Buffer {
         Ok(&self.active_run(stream_key)?.buffer)
     }
 
+    fn same_time_ref(&self, a: &StreamKey, b: &StreamKey) -> Result<bool, ReadError> {
+        Ok(self.active_run(a)?.time_ref == self.active_run(b)?.time_ref)
+    }
+
     fn reference_stream_key<'a>(by_stream: &'a HashMap<StreamKey, Vec<ColumnId>>) -> &'a StreamKey {
         by_stream
             .keys()
@@ -791,6 +1596,21 @@ fn group_columns_by_stream(columns: &[ColumnKey]) -> HashMap<StreamKey, Vec<Colu
     by_stream
 }
 
+/// Ranges of consecutive `true` rows.
+fn filled_ranges<I>(filled: I) -> Vec<Range<usize>>
+where
+    I: Iterator<Item = bool>,
+{
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (row, _) in filled.enumerate().filter(|(_, filled)| *filled) {
+        match ranges.last_mut() {
+            Some(range) if range.end == row => range.end += 1,
+            _ => ranges.push(row..row + 1),
+        }
+    }
+    ranges
+}
+
 fn normalize_time_bounds(start_time: f64, end_time: f64) -> (f64, f64) {
     if start_time <= end_time {
         (start_time, end_time)
@@ -799,7 +1619,7 @@ fn normalize_time_bounds(start_time: f64, end_time: f64) -> (f64, f64) {
     }
 }
 
-fn timestamps_match_iter<I>(reference: &[f64], candidate: I) -> bool
+fn timestamps_match_iter<I>(reference: &[f64], candidate: I, offset: f64, tolerance: f64) -> bool
 where
     I: Iterator<Item = f64>,
 {
@@ -807,5 +1627,5 @@ where
         .iter()
         .copied()
         .zip(candidate)
-        .all(|(a, b)| (a - b).abs() <= 1e-9)
+        .all(|(a, b)| (a + offset - b).abs() <= tolerance)
 }
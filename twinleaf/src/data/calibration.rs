@@ -0,0 +1,481 @@
+//! Column calibration
+//!
+//! Transforms from raw column values to calibrated ones: gain and offset
+//! per column, cross-axis matrices over several columns of a stream, and
+//! conversions between units that differ by an SI prefix (nT to pT, V to
+//! mV). They are read from a TOML file with one table per device serial
+//! number and column path:
+//!
+//! ```toml
+//! ["SN1234"."/vector/x"]
+//! gain = 1.0021
+//! offset = -12.5
+//!
+//! ["SN1234".vector]
+//! columns = ["x", "y", "z"]
+//! matrix = [[1.0, 0.002, 0.0], [0.0, 1.0, 0.001], [0.0, 0.0, 0.998]]
+//! offset = [0.0, 0.0, 0.0]
+//!
+//! ["*"."**/x"]
+//! units = "pT"
+//! ```
+//!
+//! Paths are `ColumnFilter` patterns relative to the device, so they do not
+//! depend on where the device is in the tree. Serial `*` matches any device.
+//!
+//! Gains and offsets apply first, then matrices, then unit conversions;
+//! transforms of the same kind apply in file order. Unit conversions skip
+//! columns whose units cannot be converted. Calibrated columns become
+//! `ColumnData::Float`, and their metadata carries the new type and units.
+
+use super::{ColumnData, ColumnFilter, Sample};
+use crate::tio::proto::{ColumnMetadata, DataType, DeviceRoute};
+
+use std::path::Path;
+use std::sync::Arc;
+use toml_edit::{DocumentMut, Item, TableLike, Value};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CalibrationError {
+    #[error("could not read calibration file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid calibration file: {0}")]
+    Parse(String),
+    #[error("calibration for {serial} {path}: {reason}")]
+    Invalid {
+        serial: String,
+        path: String,
+        reason: String,
+    },
+}
+
+/// One transform from a calibration file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Transform {
+    /// `gain * x + offset`
+    Linear { gain: f64, offset: f64 },
+    /// `matrix * [columns] + offset`, over the named columns of a stream.
+    Matrix {
+        columns: Vec<String>,
+        matrix: Vec<Vec<f64>>,
+        offset: Vec<f64>,
+    },
+    /// Conversion to these units.
+    Units(String),
+}
+
+/// Transform for the columns matching `path`, on devices with serial
+/// number `serial`.
+pub struct Rule {
+    pub serial: String,
+    pub path: String,
+    pub transform: Transform,
+    filter: ColumnFilter,
+}
+
+impl Rule {
+    fn matches_serial(&self, serial: &str) -> bool {
+        self.serial == "*" || self.serial == serial
+    }
+
+    fn matches(&self, stream: &str, column: &str) -> bool {
+        self.filter.matches(&DeviceRoute::root(), stream, column)
+    }
+}
+
+/// Calibration transforms for any number of devices.
+#[derive(Default)]
+pub struct Calibration {
+    rules: Vec<Rule>,
+}
+
+/// SI prefixes, with their powers of ten.
+const PREFIXES: &[(&str, i32)] = &[
+    ("p", -12),
+    ("n", -9),
+    ("u", -6),
+    ("µ", -6),
+    ("μ", -6),
+    ("m", -3),
+    ("c", -2),
+    ("k", 3),
+    ("M", 6),
+    ("G", 9),
+];
+
+/// Ways to read `units` as an SI prefix and base units, unprefixed first.
+fn split_prefix(units: &str) -> Vec<(i32, &str)> {
+    let mut splits = vec![(0, units)];
+    for (prefix, exponent) in PREFIXES {
+        if let Some(base) = units.strip_prefix(prefix).filter(|b| !b.is_empty()) {
+            splits.push((*exponent, base));
+        }
+    }
+    splits
+}
+
+/// Factor converting values in units `from` to units `to`, if they are the
+/// same units with different SI prefixes.
+pub fn unit_factor(from: &str, to: &str) -> Option<f64> {
+    if from.is_empty() || to.is_empty() {
+        return None;
+    }
+    let targets = split_prefix(to);
+    split_prefix(from).into_iter().find_map(|(from_exp, base)| {
+        targets
+            .iter()
+            .find(|(_, to_base)| *to_base == base)
+            .map(|(to_exp, _)| 10f64.powi(from_exp - to_exp))
+    })
+}
+
+fn number(value: &Value) -> Option<f64> {
+    value
+        .as_float()
+        .or_else(|| value.as_integer().map(|i| i as f64))
+        .filter(|x| x.is_finite())
+}
+
+fn numbers(value: &Value) -> Option<Vec<f64>> {
+    value.as_array()?.iter().map(number).collect()
+}
+
+fn parse_rule(
+    serial: &str,
+    path: &str,
+    entry: &dyn TableLike,
+) -> Result<Vec<Transform>, CalibrationError> {
+    let invalid = |reason: &str| CalibrationError::Invalid {
+        serial: serial.to_string(),
+        path: path.to_string(),
+        reason: reason.to_string(),
+    };
+    let value = |key: &str| entry.get(key).and_then(Item::as_value);
+    if let Some((key, _)) = entry
+        .iter()
+        .find(|(key, _)| !["gain", "offset", "units", "matrix", "columns"].contains(key))
+    {
+        return Err(invalid(&format!("unknown key '{}'", key)));
+    }
+
+    if let Some(matrix) = value("matrix") {
+        if entry.contains_key("gain") || entry.contains_key("units") {
+            return Err(invalid("a matrix cannot be combined with gain or units"));
+        }
+        let columns = value("columns")
+            .and_then(Value::as_array)
+            .and_then(|a| {
+                a.iter()
+                    .map(|c| c.as_str().map(str::to_string))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .filter(|c| !c.is_empty())
+            .ok_or_else(|| invalid("a matrix needs a list of column names"))?;
+        let n = columns.len();
+        let matrix = matrix
+            .as_array()
+            .and_then(|rows| rows.iter().map(numbers).collect::<Option<Vec<_>>>())
+            .filter(|rows| rows.len() == n && rows.iter().all(|r| r.len() == n))
+            .ok_or_else(|| invalid(&format!("matrix must be {} by {} numbers", n, n)))?;
+        let offset = match value("offset") {
+            None => vec![0.0; n],
+            Some(v) => numbers(v)
+                .filter(|o| o.len() == n)
+                .or_else(|| number(v).map(|o| vec![o; n]))
+                .ok_or_else(|| invalid(&format!("offset must be a number or {} numbers", n)))?,
+        };
+        return Ok(vec![Transform::Matrix {
+            columns,
+            matrix,
+            offset,
+        }]);
+    }
+
+    if entry.contains_key("columns") {
+        return Err(invalid("columns are only used with a matrix"));
+    }
+    let mut transforms = vec![];
+    if entry.contains_key("gain") || entry.contains_key("offset") {
+        let scalar = |key: &str, default: f64| match value(key) {
+            None => Ok(default),
+            Some(v) => number(v).ok_or_else(|| invalid(&format!("{} must be a number", key))),
+        };
+        transforms.push(Transform::Linear {
+            gain: scalar("gain", 1.0)?,
+            offset: scalar("offset", 0.0)?,
+        });
+    }
+    if let Some(units) = value("units") {
+        let units = units
+            .as_str()
+            .filter(|u| !u.is_empty())
+            .ok_or_else(|| invalid("units must be a string"))?;
+        transforms.push(Transform::Units(units.to_string()));
+    }
+    if transforms.is_empty() {
+        return Err(invalid("no gain, offset, matrix or units"));
+    }
+    Ok(transforms)
+}
+
+impl Calibration {
+    pub fn new() -> Calibration {
+        Calibration::default()
+    }
+
+    /// Parse a calibration file.
+    pub fn from_toml(s: &str) -> Result<Calibration, CalibrationError> {
+        let doc: DocumentMut = s
+            .parse()
+            .map_err(|e: toml_edit::TomlError| CalibrationError::Parse(e.to_string()))?;
+        let mut calibration = Calibration::new();
+        for (serial, item) in doc.iter() {
+            let paths = item.as_table_like().ok_or_else(|| {
+                CalibrationError::Parse(format!("{}: expected a table of column paths", serial))
+            })?;
+            for (path, entry) in paths.iter() {
+                let entry = entry
+                    .as_table_like()
+                    .ok_or_else(|| CalibrationError::Invalid {
+                        serial: serial.to_string(),
+                        path: path.to_string(),
+                        reason: "expected a table".to_string(),
+                    })?;
+                for transform in parse_rule(serial, path, entry)? {
+                    calibration.add(serial, path, transform)?;
+                }
+            }
+        }
+        Ok(calibration)
+    }
+
+    /// Read a calibration file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Calibration, CalibrationError> {
+        Calibration::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Add a transform, after those already added.
+    pub fn add(
+        &mut self,
+        serial: &str,
+        path: &str,
+        transform: Transform,
+    ) -> Result<(), CalibrationError> {
+        let filter = ColumnFilter::new(path).map_err(|reason| CalibrationError::Invalid {
+            serial: serial.to_string(),
+            path: path.to_string(),
+            reason,
+        })?;
+        self.rules.push(Rule {
+            serial: serial.to_string(),
+            path: path.to_string(),
+            transform,
+            filter,
+        });
+        Ok(())
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Transforms for a stream of the device with serial number `serial`,
+    /// `None` if nothing applies to it.
+    pub fn stream(
+        &self,
+        serial: &str,
+        stream: &str,
+        columns: &[Arc<ColumnMetadata>],
+    ) -> Option<StreamCalibration> {
+        let rules: Vec<&Rule> = self
+            .rules
+            .iter()
+            .filter(|r| r.matches_serial(serial))
+            .collect();
+        let usable = |i: usize| !matches!(columns[i].data_type, DataType::Unknown(_));
+        let mut steps = vec![];
+        let mut units: Vec<String> = columns.iter().map(|c| c.units.clone()).collect();
+        let mut applied: Vec<Vec<String>> = vec![vec![]; columns.len()];
+
+        for rule in &rules {
+            if let Transform::Linear { gain, offset } = rule.transform {
+                for i in (0..columns.len()).filter(|&i| usable(i)) {
+                    if rule.matches(stream, &columns[i].name) {
+                        steps.push(Step::Linear {
+                            column: i,
+                            gain,
+                            offset,
+                        });
+                        applied[i].push(format!("gain {} offset {}", gain, offset));
+                    }
+                }
+            }
+        }
+        for rule in &rules {
+            if let Transform::Matrix {
+                columns: names,
+                matrix,
+                offset,
+            } = &rule.transform
+            {
+                let indices: Option<Vec<usize>> = names
+                    .iter()
+                    .map(|name| {
+                        columns
+                            .iter()
+                            .position(|c| c.name == *name)
+                            .filter(|&i| usable(i) && rule.matches(stream, name))
+                    })
+                    .collect();
+                let Some(indices) = indices else {
+                    continue;
+                };
+                for (row, &i) in indices.iter().enumerate() {
+                    applied[i].push(format!(
+                        "matrix row {:?} over {} offset {}",
+                        matrix[row],
+                        names.join(", "),
+                        offset[row]
+                    ));
+                }
+                steps.push(Step::Matrix {
+                    columns: indices,
+                    matrix: matrix.clone(),
+                    offset: offset.clone(),
+                });
+            }
+        }
+        for rule in &rules {
+            if let Transform::Units(target) = &rule.transform {
+                for i in (0..columns.len()).filter(|&i| usable(i)) {
+                    if !rule.matches(stream, &columns[i].name) {
+                        continue;
+                    }
+                    if let Some(factor) = unit_factor(&units[i], target) {
+                        if factor != 1.0 {
+                            steps.push(Step::Scale { column: i, factor });
+                        }
+                        applied[i].push(format!("units {}", target));
+                        units[i] = target.clone();
+                    }
+                }
+            }
+        }
+        if steps.is_empty() && applied.iter().all(Vec::is_empty) {
+            return None;
+        }
+
+        let columns = columns
+            .iter()
+            .zip(units)
+            .zip(&applied)
+            .map(|((desc, units), applied)| {
+                if applied.is_empty() {
+                    None
+                } else {
+                    Some(Arc::new(ColumnMetadata {
+                        data_type: DataType::Float64,
+                        units,
+                        ..(**desc).clone()
+                    }))
+                }
+            })
+            .collect();
+        Some(StreamCalibration {
+            steps,
+            columns,
+            applied: applied.into_iter().map(|a| a.join("; ")).collect(),
+        })
+    }
+}
+
+enum Step {
+    Linear {
+        column: usize,
+        gain: f64,
+        offset: f64,
+    },
+    Matrix {
+        columns: Vec<usize>,
+        matrix: Vec<Vec<f64>>,
+        offset: Vec<f64>,
+    },
+    Scale {
+        column: usize,
+        factor: f64,
+    },
+}
+
+/// Calibration of one stream, from `Calibration::stream`.
+pub struct StreamCalibration {
+    steps: Vec<Step>,
+    /// Metadata of calibrated columns, `None` for the others.
+    columns: Vec<Option<Arc<ColumnMetadata>>>,
+    applied: Vec<String>,
+}
+
+impl StreamCalibration {
+    /// Description of the transforms applied to the column at `index`, for
+    /// recording along with calibrated data. `None` if it is not calibrated.
+    pub fn describe(&self, index: usize) -> Option<&str> {
+        self.applied
+            .get(index)
+            .map(String::as_str)
+            .filter(|a| !a.is_empty())
+    }
+
+    /// Calibrate the columns of `sample`, which must have the layout this
+    /// calibration was made for.
+    pub fn apply(&self, sample: &mut Sample) {
+        let mut values: Vec<Option<f64>> = sample
+            .columns
+            .iter()
+            .map(|c| c.value.try_as_f64())
+            .collect();
+        for step in &self.steps {
+            match step {
+                Step::Linear {
+                    column,
+                    gain,
+                    offset,
+                } => {
+                    if let Some(x) = &mut values[*column] {
+                        *x = gain * *x + offset;
+                    }
+                }
+                Step::Matrix {
+                    columns,
+                    matrix,
+                    offset,
+                } => {
+                    let Some(input) = columns
+                        .iter()
+                        .map(|&i| values[i])
+                        .collect::<Option<Vec<f64>>>()
+                    else {
+                        continue;
+                    };
+                    for (row, &i) in columns.iter().enumerate() {
+                        let y: f64 = matrix[row].iter().zip(&input).map(|(m, x)| m * x).sum();
+                        values[i] = Some(y + offset[row]);
+                    }
+                }
+                Step::Scale { column, factor } => {
+                    if let Some(x) = &mut values[*column] {
+                        *x *= factor;
+                    }
+                }
+            }
+        }
+        for ((col, desc), value) in sample.columns.iter_mut().zip(&self.columns).zip(values) {
+            if let (Some(desc), Some(value)) = (desc, value) {
+                col.value = ColumnData::Float(value);
+                col.desc = desc.clone();
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Clock
+//! Map device sample times to UTC.
+//!
+//! Devices whose time reference epoch is `Unix` report UTC directly. For the
+//! `Zero` and `Systime` epochs, a `ClockEstimator` fits the host arrival time
+//! of each packet against device time, which gives the offset to UTC and the
+//! drift of the device clock. The offset includes the mean transport latency,
+//! typically a few milliseconds.
+//!
+//! Devices that share a `TimeRef` keep the same clock, so their sample times
+//! compare exactly. Between references, a `TimeOffset` is estimated through
+//! UTC.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::Sample;
+use crate::tio::proto::identifiers::TimeRefSessionId;
+use crate::tio::proto::meta::MetadataEpoch;
+
+/// Samples before the drift of a clock is trusted.
+pub const MIN_DRIFT_SAMPLES: u64 = 50;
+
+/// Least squares fit of a line through points pushed one at a time.
+#[derive(Debug, Clone, Default)]
+pub struct OnlineSlope {
+    n: u64,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xx: f64,
+    sum_xy: f64,
+    sum_yy: f64,
+    x0: f64,
+    y0: f64,
+}
+
+impl OnlineSlope {
+    pub fn push(&mut self, x: f64, y: f64) {
+        if self.n == 0 {
+            self.x0 = x;
+            self.y0 = y;
+        }
+        let dx = x - self.x0;
+        let dy = y - self.y0;
+        self.n += 1;
+        self.sum_x += dx;
+        self.sum_y += dy;
+        self.sum_xx += dx * dx;
+        self.sum_xy += dx * dy;
+        self.sum_yy += dy * dy;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.n
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.n == 0
+    }
+
+    pub fn slope(&self) -> Option<f64> {
+        if self.n < 2 {
+            return None;
+        }
+        let denom = self.n as f64 * self.sum_xx - self.sum_x * self.sum_x;
+        if denom.abs() < f64::EPSILON {
+            return None;
+        }
+        Some((self.n as f64 * self.sum_xy - self.sum_x * self.sum_y) / denom)
+    }
+
+    /// The first point pushed, as `(x, y)`.
+    pub fn first(&self) -> Option<(f64, f64)> {
+        (self.n > 0).then_some((self.x0, self.y0))
+    }
+
+    /// Mean of the points pushed, as `(x, y)`.
+    pub fn mean(&self) -> Option<(f64, f64)> {
+        if self.n == 0 {
+            return None;
+        }
+        let n = self.n as f64;
+        Some((self.x0 + self.sum_x / n, self.y0 + self.sum_y / n))
+    }
+
+    /// Value of the fitted line at `x`.
+    pub fn predict(&self, x: f64) -> Option<f64> {
+        let (mean_x, mean_y) = self.mean()?;
+        Some(mean_y + self.slope()? * (x - mean_x))
+    }
+
+    /// Standard deviation of the points about their mean.
+    pub fn std_y(&self) -> Option<f64> {
+        if self.n < 2 {
+            return None;
+        }
+        let n = self.n as f64;
+        let ss = self.sum_yy - self.sum_y * self.sum_y / n;
+        Some((ss.max(0.0) / (n - 1.0)).sqrt())
+    }
+
+    /// Standard deviation of the points about the fitted line.
+    pub fn residual_std(&self) -> Option<f64> {
+        if self.n < 3 {
+            return None;
+        }
+        let n = self.n as f64;
+        let sxx = self.sum_xx - self.sum_x * self.sum_x / n;
+        let sxy = self.sum_xy - self.sum_x * self.sum_y / n;
+        let syy = self.sum_yy - self.sum_y * self.sum_y / n;
+        if sxx.abs() < f64::EPSILON {
+            return None;
+        }
+        Some(((syy - sxy * sxy / sxx).max(0.0) / (n - 2.0)).sqrt())
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// Linear map from device time to UTC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UtcMapping {
+    /// Device time of the reference point, in seconds.
+    pub device_time: f64,
+    /// UTC of the reference point, in seconds since the Unix epoch.
+    pub utc: f64,
+    /// UTC seconds gained per device second, e.g. 1e-6 for a device clock
+    /// running 1 ppm slow.
+    pub drift: f64,
+    /// Standard deviation of the arrival times about the mapping, in
+    /// seconds, or infinite from a single arrival.
+    pub uncertainty: f64,
+}
+
+impl UtcMapping {
+    pub fn utc(&self, device_time: f64) -> f64 {
+        self.utc + (device_time - self.device_time) * (1.0 + self.drift)
+    }
+}
+
+/// Estimates the `UtcMapping` of one device clock from arrival times.
+#[derive(Debug, Clone, Default)]
+pub struct ClockEstimator {
+    offsets: OnlineSlope,
+}
+
+impl ClockEstimator {
+    /// Record that the sample ending at `device_time` arrived at `arrival`,
+    /// in seconds since the Unix epoch, and return the updated mapping. The
+    /// drift is held at zero until `MIN_DRIFT_SAMPLES` have been seen.
+    pub fn observe(&mut self, device_time: f64, arrival: f64) -> UtcMapping {
+        self.offsets.push(device_time, arrival - device_time);
+        self.mapping_at(device_time).unwrap()
+    }
+
+    /// The mapping around `device_time`, if any arrival was observed.
+    pub fn mapping_at(&self, device_time: f64) -> Option<UtcMapping> {
+        let (_, mean_offset) = self.offsets.mean()?;
+        let (offset, drift, uncertainty) = match self.offsets.slope() {
+            Some(slope) if self.offsets.len() >= MIN_DRIFT_SAMPLES => (
+                self.offsets.predict(device_time)?,
+                slope,
+                self.offsets.residual_std(),
+            ),
+            _ => (mean_offset, 0.0, self.offsets.std_y()),
+        };
+        Some(UtcMapping {
+            device_time,
+            utc: device_time + offset,
+            drift,
+            uncertainty: uncertainty.unwrap_or(f64::INFINITY),
+        })
+    }
+
+    /// Forget all arrivals, for when the device clock is reset.
+    pub fn reset(&mut self) {
+        self.offsets.reset();
+    }
+}
+
+/// The host clock, in seconds since the Unix epoch.
+pub fn host_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// A clock shared by devices: the serial number of the device keeping it,
+/// and the session of that clock.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TimeRef {
+    pub serial: String,
+    pub session_id: TimeRefSessionId,
+}
+
+impl TimeRef {
+    /// The reference of `sample`, which is the device's own clock if its
+    /// segment names no reference serial.
+    pub fn of(sample: &Sample) -> TimeRef {
+        let serial = if sample.segment.time_ref_serial.is_empty() {
+            &sample.device.serial_number
+        } else {
+            &sample.segment.time_ref_serial
+        };
+        TimeRef {
+            serial: serial.clone(),
+            session_id: sample.segment.time_ref_session_id,
+        }
+    }
+}
+
+impl std::fmt::Display for TimeRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.serial, self.session_id)
+    }
+}
+
+/// Seconds to add to the time of one clock to get the time of another.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TimeOffset {
+    pub seconds: f64,
+    /// Standard deviation of `seconds`; zero between devices on the same
+    /// reference, infinite when the clocks cannot be related.
+    pub uncertainty: f64,
+}
+
+impl TimeOffset {
+    /// Offset of a clock to UTC at `device_time`, trusting devices on the
+    /// `Unix` epoch.
+    pub fn to_utc(
+        epoch: &MetadataEpoch,
+        clock: Option<&UtcMapping>,
+        device_time: f64,
+    ) -> Option<TimeOffset> {
+        match (epoch, clock) {
+            (MetadataEpoch::Unix, _) => Some(TimeOffset::default()),
+            (_, Some(mapping)) => Some(TimeOffset {
+                seconds: mapping.utc(device_time) - device_time,
+                uncertainty: mapping.uncertainty,
+            }),
+            (_, None) => None,
+        }
+    }
+
+    /// The offset from the clock of `self` to the clock of `other`, both
+    /// offsets to a common clock.
+    pub fn between(&self, other: &TimeOffset) -> TimeOffset {
+        TimeOffset {
+            seconds: self.seconds - other.seconds,
+            uncertainty: self.uncertainty.hypot(other.uncertainty),
+        }
+    }
+
+    /// Offset of clocks that cannot be related.
+    pub fn unknown() -> TimeOffset {
+        TimeOffset {
+            seconds: 0.0,
+            uncertainty: f64::INFINITY,
+        }
+    }
+}
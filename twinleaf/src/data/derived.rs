@@ -0,0 +1,731 @@
+//! Derived columns
+//!
+//! Virtual columns computed from other columns, such as the magnitude of a
+//! vector or the difference between two sensors:
+//!
+//! ```text
+//! B[nT] = sqrt(x^2 + y^2 + z^2)
+//! dx = {/0/vector/x} - {/1/vector/x}
+//! ```
+//!
+//! Expressions have numbers, `+ - * / ^`, parentheses, the functions
+//! `sqrt abs exp ln log10 sin cos tan asin acos atan atan2 hypot min max`,
+//! and column references. A bare name such as `x` refers to the column of
+//! that name in any stream; braces take a path of the form
+//! `{[/route/]stream/column}`, where a path starting with `/` names the
+//! route exactly. A reference must match exactly one column.
+//!
+//! A derived column belongs to the stream of its first reference. `Buffer`
+//! evaluates derived columns on the aligned (or resampled) values of their
+//! inputs, and `SampleDeriver` appends them to samples as they arrive.
+
+use super::{Column, ColumnBatch, ColumnData, Sample};
+use crate::tio::proto::identifiers::{ColumnId, ColumnKey, StreamKey};
+use crate::tio::proto::{ColumnMetadata, DataType, DeviceRoute};
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Column ids of derived columns in a `Buffer` start here, above the ids
+/// of any device column.
+pub const FIRST_DERIVED_COLUMN_ID: ColumnId = 1 << 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeriveError {
+    #[error("invalid derived column \"{0}\": expected NAME=EXPRESSION or NAME[UNITS]=EXPRESSION")]
+    InvalidSpec(String),
+    #[error("invalid expression \"{expression}\" at {position}: {reason}")]
+    Parse {
+        expression: String,
+        position: usize,
+        reason: String,
+    },
+    #[error("no column matches \"{0}\"")]
+    UnknownColumn(String),
+    #[error("\"{reference}\" matches {count} columns")]
+    AmbiguousColumn { reference: String, count: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Sqrt,
+    Abs,
+    Exp,
+    Ln,
+    Log10,
+    Sin,
+    Cos,
+    Tan,
+    Asin,
+    Acos,
+    Atan,
+    Atan2,
+    Hypot,
+    Min,
+    Max,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        Some(match name {
+            "sqrt" => Function::Sqrt,
+            "abs" => Function::Abs,
+            "exp" => Function::Exp,
+            "ln" => Function::Ln,
+            "log10" => Function::Log10,
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            "tan" => Function::Tan,
+            "asin" => Function::Asin,
+            "acos" => Function::Acos,
+            "atan" => Function::Atan,
+            "atan2" => Function::Atan2,
+            "hypot" => Function::Hypot,
+            "min" => Function::Min,
+            "max" => Function::Max,
+            _ => return None,
+        })
+    }
+
+    /// Whether the function takes `n` arguments.
+    fn accepts(self, n: usize) -> bool {
+        match self {
+            Function::Atan2 => n == 2,
+            Function::Hypot | Function::Min | Function::Max => n >= 1,
+            _ => n == 1,
+        }
+    }
+
+    fn apply(self, args: &[f64]) -> f64 {
+        let x = args[0];
+        match self {
+            Function::Sqrt => x.sqrt(),
+            Function::Abs => x.abs(),
+            Function::Exp => x.exp(),
+            Function::Ln => x.ln(),
+            Function::Log10 => x.log10(),
+            Function::Sin => x.sin(),
+            Function::Cos => x.cos(),
+            Function::Tan => x.tan(),
+            Function::Asin => x.asin(),
+            Function::Acos => x.acos(),
+            Function::Atan => x.atan(),
+            Function::Atan2 => x.atan2(args[1]),
+            Function::Hypot => args.iter().map(|a| a * a).sum::<f64>().sqrt(),
+            Function::Min => args.iter().copied().fold(f64::INFINITY, f64::min),
+            Function::Max => args.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    /// Index into the expression's references.
+    Column(usize),
+    Neg(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(Function, Vec<Node>),
+}
+
+impl Node {
+    fn eval(&self, values: &[f64]) -> f64 {
+        match self {
+            Node::Number(x) => *x,
+            Node::Column(i) => values[*i],
+            Node::Neg(a) => -a.eval(values),
+            Node::Binary(op, a, b) => {
+                let (a, b) = (a.eval(values), b.eval(values));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a.powf(b),
+                }
+            }
+            Node::Call(f, args) => {
+                let args: Vec<f64> = args.iter().map(|a| a.eval(values)).collect();
+                f.apply(&args)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Path(String),
+    Symbol(char),
+    End,
+}
+
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, (usize, String)> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some(&(pos, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' {
+            let mut end = pos;
+            let mut prev = c;
+            while let Some(&(i, c)) = chars.peek() {
+                let exponent_sign = (c == '+' || c == '-') && (prev == 'e' || prev == 'E');
+                if !(c.is_ascii_alphanumeric() || c == '.' || exponent_sign) {
+                    break;
+                }
+                end = i + c.len_utf8();
+                prev = c;
+                chars.next();
+            }
+            let text = &s[pos..end];
+            let value = text
+                .parse()
+                .map_err(|_| (pos, format!("invalid number \"{}\"", text)))?;
+            tokens.push((pos, Token::Number(value)));
+        } else if c.is_alphabetic() || c == '_' {
+            let mut end = pos;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '.') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+            tokens.push((pos, Token::Name(s[pos..end].to_string())));
+        } else if c == '{' {
+            chars.next();
+            let mut path = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '}')) => break,
+                    Some((_, c)) => path.push(c),
+                    None => return Err((pos, "unclosed \"{\"".to_string())),
+                }
+            }
+            tokens.push((pos, Token::Path(path.trim().to_string())));
+        } else if "+-*/^(),".contains(c) {
+            chars.next();
+            tokens.push((pos, Token::Symbol(c)));
+        } else {
+            return Err((pos, format!("unexpected \"{}\"", c)));
+        }
+    }
+    tokens.push((s.len(), Token::End));
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    references: Vec<String>,
+}
+
+type ParseResult = Result<Node, (usize, String)>;
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].1
+    }
+
+    fn bump(&mut self) -> (usize, Token) {
+        let token = self.tokens[self.next].clone();
+        if token.1 != Token::End {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), (usize, String)> {
+        match self.bump() {
+            (_, Token::Symbol(c)) if c == symbol => Ok(()),
+            (pos, _) => Err((pos, format!("expected \"{}\"", symbol))),
+        }
+    }
+
+    fn reference(&mut self, path: String) -> Node {
+        let index = match self.references.iter().position(|r| *r == path) {
+            Some(index) => index,
+            None => {
+                self.references.push(path);
+                self.references.len() - 1
+            }
+        };
+        Node::Column(index)
+    }
+
+    fn sum(&mut self) -> ParseResult {
+        let mut node = self.product()?;
+        while let Token::Symbol(op @ ('+' | '-')) = *self.peek() {
+            self.bump();
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+        Ok(node)
+    }
+
+    fn product(&mut self) -> ParseResult {
+        let mut node = self.unary()?;
+        while let Token::Symbol(op @ ('*' | '/')) = *self.peek() {
+            self.bump();
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> ParseResult {
+        match self.peek() {
+            Token::Symbol('-') => {
+                self.bump();
+                Ok(Node::Neg(Box::new(self.unary()?)))
+            }
+            Token::Symbol('+') => {
+                self.bump();
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    /// `^` binds tighter than unary minus and is right-associative, so
+    /// `-x^2^3` is `-(x^(2^3))`.
+    fn power(&mut self) -> ParseResult {
+        let base = self.atom()?;
+        if *self.peek() == Token::Symbol('^') {
+            self.bump();
+            let exponent = self.unary()?;
+            return Ok(Node::Binary('^', Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> ParseResult {
+        match self.bump() {
+            (_, Token::Number(x)) => Ok(Node::Number(x)),
+            (_, Token::Path(path)) if !path.is_empty() => Ok(self.reference(path)),
+            (pos, Token::Name(name)) => {
+                if *self.peek() != Token::Symbol('(') {
+                    return Ok(self.reference(name));
+                }
+                let function = Function::from_name(&name)
+                    .ok_or_else(|| (pos, format!("unknown function \"{}\"", name)))?;
+                self.bump();
+                let mut args = vec![self.sum()?];
+                while *self.peek() == Token::Symbol(',') {
+                    self.bump();
+                    args.push(self.sum()?);
+                }
+                self.expect(')')?;
+                if !function.accepts(args.len()) {
+                    return Err((pos, format!("wrong number of arguments to \"{}\"", name)));
+                }
+                Ok(Node::Call(function, args))
+            }
+            (_, Token::Symbol('(')) => {
+                let node = self.sum()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            (pos, Token::End) => Err((pos, "unexpected end".to_string())),
+            (pos, _) => Err((pos, "expected a number, column or \"(\"".to_string())),
+        }
+    }
+}
+
+/// An expression over column references, parsed from text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    text: String,
+    root: Node,
+    references: Vec<String>,
+}
+
+impl Expression {
+    /// The column references, in order of first appearance and without
+    /// duplicates.
+    pub fn references(&self) -> &[String] {
+        &self.references
+    }
+
+    /// Value of the expression, with `values` in the order of
+    /// `references()`.
+    pub fn evaluate(&self, values: &[f64]) -> f64 {
+        self.root.eval(values)
+    }
+}
+
+impl FromStr for Expression {
+    type Err = DeriveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = s.trim();
+        let error = |(position, reason)| DeriveError::Parse {
+            expression: text.to_string(),
+            position,
+            reason,
+        };
+        let mut parser = Parser {
+            tokens: tokenize(text).map_err(error)?,
+            next: 0,
+            references: Vec::new(),
+        };
+        let root = parser.sum().map_err(error)?;
+        match parser.bump() {
+            (_, Token::End) => {}
+            (pos, _) => return Err(error((pos, "expected an operator".to_string()))),
+        }
+        if parser.references.is_empty() {
+            return Err(error((0, "no column references".to_string())));
+        }
+        Ok(Expression {
+            text: text.to_string(),
+            root,
+            references: parser.references,
+        })
+    }
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// A column reference, `[/route/][stream/]column`.
+struct ColumnRef {
+    route: Option<DeviceRoute>,
+    stream: Option<String>,
+    column: String,
+}
+
+impl ColumnRef {
+    fn parse(reference: &str) -> Option<ColumnRef> {
+        let absolute = reference.starts_with('/');
+        let segments: Vec<&str> = reference.trim_start_matches('/').split('/').collect();
+        if segments.iter().any(|s| s.is_empty()) {
+            return None;
+        }
+        let n_route = segments
+            .iter()
+            .take_while(|s| s.parse::<u8>().is_ok())
+            .count();
+        let route = if absolute || n_route > 0 {
+            Some(DeviceRoute::from_str(&segments[..n_route].join("/")).ok()?)
+        } else {
+            None
+        };
+        let (stream, column) = match &segments[n_route..] {
+            [column] => (None, column),
+            [stream, column] => (Some(stream.to_string()), column),
+            _ => return None,
+        };
+        Some(ColumnRef {
+            route,
+            stream,
+            column: column.to_string(),
+        })
+    }
+
+    fn matches(&self, key: &ColumnKey, stream: &str, column: &str) -> bool {
+        self.route.as_ref().is_none_or(|r| *r == key.route)
+            && self.stream.as_deref().is_none_or(|s| s == stream)
+            && self.column == column
+    }
+}
+
+/// The one column among `columns` that `reference` names.
+fn resolve_reference<'a, I>(reference: &str, columns: I) -> Result<ColumnKey, DeriveError>
+where
+    I: IntoIterator<Item = (ColumnKey, &'a str, &'a str)>,
+{
+    let unknown = || DeriveError::UnknownColumn(reference.to_string());
+    let parsed = ColumnRef::parse(reference).ok_or_else(unknown)?;
+    let mut matches: Vec<ColumnKey> = columns
+        .into_iter()
+        .filter(|(key, stream, column)| parsed.matches(key, stream, column))
+        .map(|(key, _, _)| key)
+        .collect();
+    matches.sort();
+    matches.dedup();
+    match matches.len() {
+        0 => Err(unknown()),
+        1 => Ok(matches.remove(0)),
+        count => Err(DeriveError::AmbiguousColumn {
+            reference: reference.to_string(),
+            count,
+        }),
+    }
+}
+
+/// A derived column definition: `NAME=EXPRESSION`, or
+/// `NAME[UNITS]=EXPRESSION` to give it units.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DerivedSpec {
+    pub name: String,
+    pub units: String,
+    pub expression: Expression,
+}
+
+impl DerivedSpec {
+    fn metadata(&self, key: &ColumnKey) -> Arc<ColumnMetadata> {
+        Arc::new(ColumnMetadata {
+            stream_id: key.stream_id,
+            index: key.column_id,
+            data_type: DataType::Float64,
+            name: self.name.clone(),
+            units: self.units.clone(),
+            description: format!("{} = {}", self.name, self.expression),
+        })
+    }
+
+    /// Bind the references to `columns`, given as key, stream name and
+    /// column name. The derived column gets `column_id` in the stream of
+    /// its first reference.
+    pub fn resolve<'a, I>(
+        &self,
+        columns: I,
+        column_id: ColumnId,
+    ) -> Result<DerivedColumn, DeriveError>
+    where
+        I: IntoIterator<Item = (ColumnKey, &'a str, &'a str)>,
+        I::IntoIter: Clone,
+    {
+        let columns = columns.into_iter();
+        let inputs = self
+            .expression
+            .references()
+            .iter()
+            .map(|r| resolve_reference(r, columns.clone()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let home = &inputs[0];
+        let key = ColumnKey::new(home.route.clone(), home.stream_id, column_id);
+        Ok(DerivedColumn {
+            metadata: self.metadata(&key),
+            key,
+            inputs,
+            expression: self.expression.clone(),
+        })
+    }
+}
+
+impl FromStr for DerivedSpec {
+    type Err = DeriveError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DeriveError::InvalidSpec(s.to_string());
+        let (head, expression) = s.split_once('=').ok_or_else(invalid)?;
+        let head = head.trim();
+        let (name, units) = match head.strip_suffix(']').and_then(|h| h.split_once('[')) {
+            Some((name, units)) => (name.trim(), units.trim()),
+            None => (head, ""),
+        };
+        if name.is_empty() || name.contains(['/', '[', ']', '{', '}']) {
+            return Err(invalid());
+        }
+        Ok(DerivedSpec {
+            name: name.to_string(),
+            units: units.to_string(),
+            expression: expression.parse()?,
+        })
+    }
+}
+
+impl std::fmt::Display for DerivedSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.name)?;
+        if !self.units.is_empty() {
+            write!(f, "[{}]", self.units)?;
+        }
+        write!(f, " = {}", self.expression)
+    }
+}
+
+/// A derived column with its references bound to columns.
+#[derive(Debug, Clone)]
+pub struct DerivedColumn {
+    pub key: ColumnKey,
+    pub metadata: Arc<ColumnMetadata>,
+    /// The columns of the expression's references, in the same order.
+    pub inputs: Vec<ColumnKey>,
+    expression: Expression,
+}
+
+impl DerivedColumn {
+    pub fn expression(&self) -> &Expression {
+        &self.expression
+    }
+
+    /// Value for `values` of the inputs.
+    pub fn evaluate(&self, values: &[f64]) -> f64 {
+        self.expression.evaluate(values)
+    }
+
+    /// Values for batches of the inputs, which must have the same length.
+    pub fn evaluate_batches(&self, inputs: &[&ColumnBatch]) -> ColumnBatch {
+        let len = inputs.iter().map(|b| b.len()).min().unwrap_or(0);
+        let mut values = vec![0.0; inputs.len()];
+        let out = (0..len)
+            .map(|i| {
+                for (value, batch) in values.iter_mut().zip(inputs) {
+                    *value = match batch {
+                        ColumnBatch::F64(v) => v[i],
+                        ColumnBatch::I64(v) => v[i] as f64,
+                        ColumnBatch::U64(v) => v[i] as f64,
+                    };
+                }
+                self.evaluate(&values)
+            })
+            .collect();
+        ColumnBatch::F64(out)
+    }
+}
+
+/// A derived column being bound as streams show up.
+struct PendingColumn {
+    spec: DerivedSpec,
+    inputs: Vec<Option<ColumnKey>>,
+    /// Metadata for the column's position in samples of its stream.
+    desc: Option<Arc<ColumnMetadata>>,
+}
+
+impl PendingColumn {
+    fn home(&self) -> Option<StreamKey> {
+        self.inputs[0].as_ref().map(|k| k.stream_key())
+    }
+}
+
+/// Appends derived columns to samples as they arrive, for exports that
+/// work sample by sample.
+///
+/// A derived column is appended to every sample of its stream once its
+/// first reference is bound. Inputs from other streams hold their latest
+/// value, and are NaN until they have been seen. References are bound when
+/// the streams they name first show up, so a bare name is only ambiguous
+/// among the streams seen at that point.
+pub struct SampleDeriver {
+    columns: Vec<PendingColumn>,
+    streams: HashMap<StreamKey, (String, Vec<String>)>,
+    latest: HashMap<ColumnKey, f64>,
+}
+
+impl SampleDeriver {
+    pub fn new(specs: Vec<DerivedSpec>) -> SampleDeriver {
+        SampleDeriver {
+            columns: specs
+                .into_iter()
+                .map(|spec| PendingColumn {
+                    inputs: vec![None; spec.expression.references().len()],
+                    spec,
+                    desc: None,
+                })
+                .collect(),
+            streams: HashMap::new(),
+            latest: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.columns.is_empty()
+    }
+
+    /// Specs with references that no stream seen so far matches.
+    pub fn unresolved(&self) -> impl Iterator<Item = &DerivedSpec> {
+        self.columns
+            .iter()
+            .filter(|c| c.inputs.iter().any(|i| i.is_none()))
+            .map(|c| &c.spec)
+    }
+
+    /// Append the derived columns of `sample`'s stream, from the device at
+    /// `route`.
+    pub fn apply(&mut self, sample: &mut Sample, route: &DeviceRoute) -> Result<(), DeriveError> {
+        let stream_key = StreamKey::new(route.clone(), sample.stream.stream_id);
+        let names: Vec<&str> = sample
+            .columns
+            .iter()
+            .map(|c| c.desc.name.as_str())
+            .collect();
+        let known = self
+            .streams
+            .get(&stream_key)
+            .is_some_and(|(stream, columns)| {
+                *stream == sample.stream.name && columns.iter().eq(names.iter())
+            });
+        if !known {
+            self.streams.insert(
+                stream_key.clone(),
+                (
+                    sample.stream.name.clone(),
+                    names.iter().map(|n| n.to_string()).collect(),
+                ),
+            );
+            self.bind()?;
+        }
+
+        for column in &self.columns {
+            for key in column.inputs.iter().flatten() {
+                if key.stream_key() != stream_key {
+                    continue;
+                }
+                if let Some(value) = sample
+                    .columns
+                    .get(key.column_id)
+                    .and_then(|c| c.value.try_as_f64())
+                {
+                    self.latest.insert(key.clone(), value);
+                }
+            }
+        }
+
+        let mut values = Vec::new();
+        for column in &mut self.columns {
+            if column.home().as_ref() != Some(&stream_key) {
+                continue;
+            }
+            let index = sample.columns.len();
+            if column.desc.as_ref().is_none_or(|d| d.index != index) {
+                let key = ColumnKey::new(route.clone(), stream_key.stream_id, index);
+                column.desc = Some(column.spec.metadata(&key));
+            }
+            values.clear();
+            values.extend(column.inputs.iter().map(|key| {
+                key.as_ref()
+                    .and_then(|k| self.latest.get(k).copied())
+                    .unwrap_or(f64::NAN)
+            }));
+            sample.columns.push(Column {
+                value: ColumnData::Float(column.spec.expression.evaluate(&values)),
+                desc: column.desc.clone().unwrap(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Bind unbound references to the streams seen so far.
+    fn bind(&mut self) -> Result<(), DeriveError> {
+        let catalog = self.streams.iter().flat_map(|(key, (stream, columns))| {
+            columns.iter().enumerate().map(move |(id, column)| {
+                (
+                    ColumnKey::new(key.route.clone(), key.stream_id, id),
+                    stream.as_str(),
+                    column.as_str(),
+                )
+            })
+        });
+        for column in &mut self.columns {
+            let references = column.spec.expression.references();
+            for (input, reference) in column.inputs.iter_mut().zip(references) {
+                if input.is_some() {
+                    continue;
+                }
+                match resolve_reference(reference, catalog.clone()) {
+                    Ok(key) => *input = Some(key),
+                    Err(DeriveError::UnknownColumn(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+}
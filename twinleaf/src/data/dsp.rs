@@ -0,0 +1,812 @@
+//! Streaming signal processing
+//!
+//! Stateful operators that filter one value at a time: IIR and FIR low-pass,
+//! high-pass and band-pass filters, mains notch filters, decimation with an
+//! anti-alias filter, and moving mean and median. Operators are described by
+//! a rate-independent `FilterSpec` and built for a stream's sampling rate.
+//!
+//! `SampleFilter` applies a pipeline to the columns of `Sample`s, keeping
+//! separate state per stream and starting over on non-continuous boundaries.
+//!
+//! Pipelines are written as comma-separated specs, e.g.
+//! `notch:60:3,lowpass:10:4,decimate:5`:
+//!
+//! | Spec | Operator |
+//! |------|----------|
+//! | `lowpass:FC[:ORDER]` | Butterworth low-pass (default order 2) |
+//! | `highpass:FC[:ORDER]` | Butterworth high-pass |
+//! | `bandpass:LO:HI[:ORDER]` | Butterworth high-pass at LO, then low-pass at HI |
+//! | `fir-lowpass:FC[:TAPS]` | Windowed-sinc low-pass (default 63 taps) |
+//! | `fir-highpass:FC[:TAPS]` | Windowed-sinc high-pass |
+//! | `fir-bandpass:LO:HI[:TAPS]` | Windowed-sinc band-pass |
+//! | `notch:F0[:HARMONICS[:Q]]` | Notch at F0 and its harmonics (default 1, Q 30) |
+//! | `decimate:N` | Anti-alias filter, then keep every Nth value |
+//! | `mean:N` | Moving average of the last N values |
+//! | `median:N` | Moving median of the last N values |
+
+use super::{Boundary, BoundaryReason, ColumnBatch, ColumnData, ColumnFilter, Sample};
+use crate::tio::proto::meta::SegmentMetadata;
+use crate::tio::proto::{BufferType, ColumnMetadata, DataType, DeviceRoute};
+
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DspError {
+    #[error("invalid filter spec '{0}'")]
+    InvalidSpec(String),
+    #[error("{freq} Hz is not between 0 and the Nyquist frequency {nyquist} Hz")]
+    InvalidFrequency { freq: f64, nyquist: f64 },
+    #[error("invalid sampling rate {0} Hz")]
+    InvalidRate(f64),
+}
+
+/// Stateful streaming operator over a sequence of values.
+pub trait Operator: Send {
+    /// Feed the next value. Returns `None` when no output corresponds to
+    /// this input, which only happens when decimating.
+    fn process(&mut self, x: f64) -> Option<f64>;
+
+    /// Forget all history, as if no value had been processed.
+    fn reset(&mut self);
+
+    /// Feed a batch of values, returning the outputs.
+    fn process_batch(&mut self, batch: &ColumnBatch) -> ColumnBatch {
+        let out = match batch {
+            ColumnBatch::F64(v) => v.iter().filter_map(|&x| self.process(x)).collect(),
+            ColumnBatch::I64(v) => v.iter().filter_map(|&x| self.process(x as f64)).collect(),
+            ColumnBatch::U64(v) => v.iter().filter_map(|&x| self.process(x as f64)).collect(),
+        };
+        ColumnBatch::F64(out)
+    }
+}
+
+fn check_freq(freq: f64, rate: f64) -> Result<(), DspError> {
+    let nyquist = rate / 2.0;
+    if freq > 0.0 && freq < nyquist {
+        Ok(())
+    } else {
+        Err(DspError::InvalidFrequency { freq, nyquist })
+    }
+}
+
+/// Second-order IIR section, in transposed direct form II. The state is
+/// primed with the first value, so a constant input produces no transient.
+#[derive(Debug, Clone)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+    primed: bool,
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Biquad {
+        Biquad {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            z: [0.0; 2],
+            primed: false,
+        }
+    }
+
+    fn rbj(freq: f64, rate: f64, q: f64) -> (f64, f64) {
+        let w0 = 2.0 * PI * freq / rate;
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    pub fn lowpass(freq: f64, rate: f64, q: f64) -> Result<Biquad, DspError> {
+        check_freq(freq, rate)?;
+        let (cos, alpha) = Self::rbj(freq, rate, q);
+        Ok(Biquad::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        ))
+    }
+
+    pub fn highpass(freq: f64, rate: f64, q: f64) -> Result<Biquad, DspError> {
+        check_freq(freq, rate)?;
+        let (cos, alpha) = Self::rbj(freq, rate, q);
+        Ok(Biquad::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        ))
+    }
+
+    pub fn notch(freq: f64, rate: f64, q: f64) -> Result<Biquad, DspError> {
+        check_freq(freq, rate)?;
+        let (cos, alpha) = Self::rbj(freq, rate, q);
+        Ok(Biquad::new(
+            [1.0, -2.0 * cos, 1.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        ))
+    }
+
+    /// First-order low-pass, by the bilinear transform.
+    pub fn lowpass1(freq: f64, rate: f64) -> Result<Biquad, DspError> {
+        check_freq(freq, rate)?;
+        let k = (PI * freq / rate).tan();
+        Ok(Biquad::new([k, k, 0.0], [1.0 + k, k - 1.0, 0.0]))
+    }
+
+    /// First-order high-pass, by the bilinear transform.
+    pub fn highpass1(freq: f64, rate: f64) -> Result<Biquad, DspError> {
+        check_freq(freq, rate)?;
+        let k = (PI * freq / rate).tan();
+        Ok(Biquad::new([1.0, -1.0, 0.0], [1.0 + k, k - 1.0, 0.0]))
+    }
+}
+
+impl Operator for Biquad {
+    fn process(&mut self, x: f64) -> Option<f64> {
+        let [b0, b1, b2] = self.b;
+        let [a1, a2] = self.a;
+        if !self.primed {
+            self.primed = true;
+            let y = x * (b0 + b1 + b2) / (1.0 + a1 + a2);
+            self.z[1] = b2 * x - a2 * y;
+            self.z[0] = b1 * x - a1 * y + self.z[1];
+        }
+        let y = b0 * x + self.z[0];
+        self.z[0] = b1 * x - a1 * y + self.z[1];
+        self.z[1] = b2 * x - a2 * y;
+        Some(y)
+    }
+
+    fn reset(&mut self) {
+        self.z = [0.0; 2];
+        self.primed = false;
+    }
+}
+
+/// Cascade of biquad sections.
+#[derive(Debug, Clone)]
+pub struct Iir {
+    sections: Vec<Biquad>,
+}
+
+impl Iir {
+    /// Q factors of the second-order sections of a Butterworth filter.
+    fn butterworth_qs(order: usize) -> impl Iterator<Item = f64> {
+        (0..order / 2).map(move |k| {
+            let theta = PI * (2 * k + 1) as f64 / (2 * order) as f64;
+            1.0 / (2.0 * theta.cos())
+        })
+    }
+
+    pub fn butterworth_lowpass(order: usize, freq: f64, rate: f64) -> Result<Iir, DspError> {
+        let mut sections = Self::butterworth_qs(order)
+            .map(|q| Biquad::lowpass(freq, rate, q))
+            .collect::<Result<Vec<_>, _>>()?;
+        if order % 2 == 1 {
+            sections.push(Biquad::lowpass1(freq, rate)?);
+        }
+        Ok(Iir { sections })
+    }
+
+    pub fn butterworth_highpass(order: usize, freq: f64, rate: f64) -> Result<Iir, DspError> {
+        let mut sections = Self::butterworth_qs(order)
+            .map(|q| Biquad::highpass(freq, rate, q))
+            .collect::<Result<Vec<_>, _>>()?;
+        if order % 2 == 1 {
+            sections.push(Biquad::highpass1(freq, rate)?);
+        }
+        Ok(Iir { sections })
+    }
+
+    pub fn butterworth_bandpass(
+        order: usize,
+        low: f64,
+        high: f64,
+        rate: f64,
+    ) -> Result<Iir, DspError> {
+        let mut iir = Self::butterworth_highpass(order, low, rate)?;
+        iir.sections
+            .extend(Self::butterworth_lowpass(order, high, rate)?.sections);
+        Ok(iir)
+    }
+
+    /// Notch at `freq` and its first `harmonics` multiples. Harmonics at
+    /// or above the Nyquist frequency are left out.
+    pub fn notch(freq: f64, harmonics: usize, q: f64, rate: f64) -> Result<Iir, DspError> {
+        check_freq(freq, rate)?;
+        let sections = (1..=harmonics.max(1))
+            .map(|h| freq * h as f64)
+            .take_while(|f| *f < rate / 2.0)
+            .map(|f| Biquad::notch(f, rate, q))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Iir { sections })
+    }
+}
+
+impl Operator for Iir {
+    fn process(&mut self, x: f64) -> Option<f64> {
+        self.sections.iter_mut().try_fold(x, |x, s| s.process(x))
+    }
+
+    fn reset(&mut self) {
+        self.sections.iter_mut().for_each(Biquad::reset);
+    }
+}
+
+/// FIR filter. Like `Biquad`, the history is primed with the first value.
+#[derive(Debug, Clone)]
+pub struct Fir {
+    taps: Vec<f64>,
+    history: VecDeque<f64>,
+}
+
+impl Fir {
+    pub fn new(taps: Vec<f64>) -> Fir {
+        Fir {
+            history: VecDeque::with_capacity(taps.len()),
+            taps,
+        }
+    }
+
+    /// Hamming-windowed sinc low-pass taps with unity gain at DC.
+    fn sinc_taps(freq: f64, rate: f64, ntaps: usize) -> Vec<f64> {
+        let fc = freq / rate;
+        let mid = (ntaps - 1) as f64 / 2.0;
+        let mut taps: Vec<f64> = (0..ntaps)
+            .map(|i| {
+                let t = i as f64 - mid;
+                let sinc = if t == 0.0 {
+                    2.0 * fc
+                } else {
+                    (2.0 * PI * fc * t).sin() / (PI * t)
+                };
+                let window = 0.54 - 0.46 * (2.0 * PI * i as f64 / (ntaps - 1) as f64).cos();
+                sinc * window
+            })
+            .collect();
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|t| *t /= sum);
+        taps
+    }
+
+    /// Round up to an odd number of taps, which the high-pass and
+    /// band-pass designs need for a symmetric center tap.
+    fn odd(ntaps: usize) -> usize {
+        ntaps.max(3) | 1
+    }
+
+    pub fn lowpass(freq: f64, rate: f64, ntaps: usize) -> Result<Fir, DspError> {
+        check_freq(freq, rate)?;
+        Ok(Fir::new(Self::sinc_taps(freq, rate, Self::odd(ntaps))))
+    }
+
+    pub fn highpass(freq: f64, rate: f64, ntaps: usize) -> Result<Fir, DspError> {
+        check_freq(freq, rate)?;
+        let ntaps = Self::odd(ntaps);
+        let mut taps = Self::sinc_taps(freq, rate, ntaps);
+        taps.iter_mut().for_each(|t| *t = -*t);
+        taps[ntaps / 2] += 1.0;
+        Ok(Fir::new(taps))
+    }
+
+    pub fn bandpass(low: f64, high: f64, rate: f64, ntaps: usize) -> Result<Fir, DspError> {
+        check_freq(low, rate)?;
+        check_freq(high, rate)?;
+        if low >= high {
+            return Err(DspError::InvalidSpec(format!(
+                "band-pass edges {} >= {}",
+                low, high
+            )));
+        }
+        let ntaps = Self::odd(ntaps);
+        let hi = Self::sinc_taps(high, rate, ntaps);
+        let lo = Self::sinc_taps(low, rate, ntaps);
+        Ok(Fir::new(hi.iter().zip(&lo).map(|(h, l)| h - l).collect()))
+    }
+}
+
+impl Operator for Fir {
+    fn process(&mut self, x: f64) -> Option<f64> {
+        if self.history.is_empty() {
+            self.history.resize(self.taps.len(), x);
+        }
+        self.history.pop_back();
+        self.history.push_front(x);
+        Some(
+            self.taps
+                .iter()
+                .zip(&self.history)
+                .map(|(t, x)| t * x)
+                .sum(),
+        )
+    }
+
+    fn reset(&mut self) {
+        self.history.clear();
+    }
+}
+
+/// Low-pass filter below the new Nyquist frequency, then keep the last of
+/// every `factor` values.
+#[derive(Debug, Clone)]
+pub struct Decimator {
+    filter: Fir,
+    factor: usize,
+    phase: usize,
+}
+
+impl Decimator {
+    pub fn new(factor: usize, rate: f64) -> Result<Decimator, DspError> {
+        if factor == 0 {
+            return Err(DspError::InvalidSpec("decimate:0".into()));
+        }
+        let filter = if factor == 1 {
+            Fir::new(vec![1.0])
+        } else {
+            Fir::lowpass(0.4 * rate / factor as f64, rate, 10 * factor + 1)?
+        };
+        Ok(Decimator {
+            filter,
+            factor,
+            phase: 0,
+        })
+    }
+}
+
+impl Operator for Decimator {
+    fn process(&mut self, x: f64) -> Option<f64> {
+        let y = self.filter.process(x);
+        let keep = self.phase == self.factor - 1;
+        self.phase = (self.phase + 1) % self.factor;
+        y.filter(|_| keep)
+    }
+
+    fn reset(&mut self) {
+        self.filter.reset();
+        self.phase = 0;
+    }
+}
+
+/// Mean of the last `window` values, or of all values seen so far while
+/// fewer have been processed.
+#[derive(Debug, Clone)]
+pub struct MovingMean {
+    window: usize,
+    values: VecDeque<f64>,
+    sum: f64,
+}
+
+impl MovingMean {
+    pub fn new(window: usize) -> MovingMean {
+        MovingMean {
+            window: window.max(1),
+            values: VecDeque::new(),
+            sum: 0.0,
+        }
+    }
+}
+
+impl Operator for MovingMean {
+    fn process(&mut self, x: f64) -> Option<f64> {
+        self.values.push_back(x);
+        self.sum += x;
+        if self.values.len() > self.window {
+            self.sum -= self.values.pop_front().unwrap_or(0.0);
+        }
+        Some(self.sum / self.values.len() as f64)
+    }
+
+    fn reset(&mut self) {
+        self.values.clear();
+        self.sum = 0.0;
+    }
+}
+
+/// Median of the last `window` values, or of all values seen so far while
+/// fewer have been processed.
+#[derive(Debug, Clone)]
+pub struct MovingMedian {
+    window: usize,
+    values: VecDeque<f64>,
+    /// The same values, in order.
+    sorted: Vec<f64>,
+}
+
+impl MovingMedian {
+    pub fn new(window: usize) -> MovingMedian {
+        MovingMedian {
+            window: window.max(1),
+            values: VecDeque::new(),
+            sorted: Vec::new(),
+        }
+    }
+}
+
+impl Operator for MovingMedian {
+    fn process(&mut self, x: f64) -> Option<f64> {
+        self.values.push_back(x);
+        let at = self.sorted.partition_point(|v| v.total_cmp(&x).is_lt());
+        self.sorted.insert(at, x);
+        if self.values.len() > self.window {
+            let old = self.values.pop_front().unwrap_or_default();
+            if let Ok(at) = self.sorted.binary_search_by(|v| v.total_cmp(&old)) {
+                self.sorted.remove(at);
+            }
+        }
+        let mid = self.sorted.len() / 2;
+        Some(if self.sorted.len().is_multiple_of(2) {
+            (self.sorted[mid - 1] + self.sorted[mid]) / 2.0
+        } else {
+            self.sorted[mid]
+        })
+    }
+
+    fn reset(&mut self) {
+        self.values.clear();
+        self.sorted.clear();
+    }
+}
+
+/// Operators applied one after the other.
+#[derive(Default)]
+pub struct Chain {
+    ops: Vec<Box<dyn Operator>>,
+}
+
+impl Chain {
+    pub fn new() -> Chain {
+        Chain::default()
+    }
+
+    pub fn push(&mut self, op: impl Operator + 'static) {
+        self.ops.push(Box::new(op));
+    }
+}
+
+impl Operator for Chain {
+    fn process(&mut self, x: f64) -> Option<f64> {
+        self.ops.iter_mut().try_fold(x, |x, op| op.process(x))
+    }
+
+    fn reset(&mut self) {
+        self.ops.iter_mut().for_each(|op| op.reset());
+    }
+}
+
+/// Description of an operator, independent of the sampling rate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterSpec {
+    LowPass { freq: f64, order: usize },
+    HighPass { freq: f64, order: usize },
+    BandPass { low: f64, high: f64, order: usize },
+    FirLowPass { freq: f64, taps: usize },
+    FirHighPass { freq: f64, taps: usize },
+    FirBandPass { low: f64, high: f64, taps: usize },
+    Notch { freq: f64, harmonics: usize, q: f64 },
+    Decimate(usize),
+    Mean(usize),
+    Median(usize),
+}
+
+impl FilterSpec {
+    /// Build the operator for values sampled at `rate` Hz.
+    pub fn build(&self, rate: f64) -> Result<Box<dyn Operator>, DspError> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(DspError::InvalidRate(rate));
+        }
+        Ok(match *self {
+            FilterSpec::LowPass { freq, order } => {
+                Box::new(Iir::butterworth_lowpass(order, freq, rate)?)
+            }
+            FilterSpec::HighPass { freq, order } => {
+                Box::new(Iir::butterworth_highpass(order, freq, rate)?)
+            }
+            FilterSpec::BandPass { low, high, order } => {
+                Box::new(Iir::butterworth_bandpass(order, low, high, rate)?)
+            }
+            FilterSpec::FirLowPass { freq, taps } => Box::new(Fir::lowpass(freq, rate, taps)?),
+            FilterSpec::FirHighPass { freq, taps } => Box::new(Fir::highpass(freq, rate, taps)?),
+            FilterSpec::FirBandPass { low, high, taps } => {
+                Box::new(Fir::bandpass(low, high, rate, taps)?)
+            }
+            FilterSpec::Notch { freq, harmonics, q } => {
+                Box::new(Iir::notch(freq, harmonics, q, rate)?)
+            }
+            FilterSpec::Decimate(factor) => Box::new(Decimator::new(factor, rate)?),
+            FilterSpec::Mean(window) => Box::new(MovingMean::new(window)),
+            FilterSpec::Median(window) => Box::new(MovingMedian::new(window)),
+        })
+    }
+
+    /// Ratio of input to output rate.
+    pub fn decimation(&self) -> usize {
+        match *self {
+            FilterSpec::Decimate(factor) => factor.max(1),
+            _ => 1,
+        }
+    }
+}
+
+impl FromStr for FilterSpec {
+    type Err = DspError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || DspError::InvalidSpec(s.to_string());
+        let mut parts = s.trim().split(':');
+        let kind = parts.next().unwrap_or_default();
+        let args = parts
+            .map(|p| p.parse::<f64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let count = |x: f64| {
+            if x >= 1.0 && x.fract() == 0.0 {
+                Ok(x as usize)
+            } else {
+                Err(invalid())
+            }
+        };
+        let opt = |i: usize, default: usize| args.get(i).map_or(Ok(default), |&x| count(x));
+
+        let (required, allowed) = match kind {
+            "lowpass" | "highpass" | "fir-lowpass" | "fir-highpass" => (1, 2),
+            "bandpass" | "fir-bandpass" => (2, 3),
+            "notch" => (1, 3),
+            "decimate" | "mean" | "median" => (1, 1),
+            _ => return Err(invalid()),
+        };
+        if args.len() < required || args.len() > allowed {
+            return Err(invalid());
+        }
+
+        Ok(match kind {
+            "lowpass" => FilterSpec::LowPass {
+                freq: args[0],
+                order: opt(1, 2)?,
+            },
+            "highpass" => FilterSpec::HighPass {
+                freq: args[0],
+                order: opt(1, 2)?,
+            },
+            "bandpass" => FilterSpec::BandPass {
+                low: args[0],
+                high: args[1],
+                order: opt(2, 2)?,
+            },
+            "fir-lowpass" => FilterSpec::FirLowPass {
+                freq: args[0],
+                taps: opt(1, 63)?,
+            },
+            "fir-highpass" => FilterSpec::FirHighPass {
+                freq: args[0],
+                taps: opt(1, 63)?,
+            },
+            "fir-bandpass" => FilterSpec::FirBandPass {
+                low: args[0],
+                high: args[1],
+                taps: opt(2, 63)?,
+            },
+            "notch" => FilterSpec::Notch {
+                freq: args[0],
+                harmonics: opt(1, 1)?,
+                q: args.get(2).copied().unwrap_or(30.0),
+            },
+            "decimate" => FilterSpec::Decimate(count(args[0])?),
+            "mean" => FilterSpec::Mean(count(args[0])?),
+            "median" => FilterSpec::Median(count(args[0])?),
+            _ => unreachable!(),
+        })
+    }
+}
+
+/// Sequence of filter specs, each applied to the output of the previous.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    pub specs: Vec<FilterSpec>,
+}
+
+impl Pipeline {
+    /// Build the operators for values sampled at `rate` Hz. Specs after a
+    /// decimation are built for the reduced rate.
+    pub fn build(&self, rate: f64) -> Result<Chain, DspError> {
+        let mut chain = Chain::new();
+        let mut rate = rate;
+        for spec in &self.specs {
+            chain.ops.push(spec.build(rate)?);
+            rate /= spec.decimation() as f64;
+        }
+        Ok(chain)
+    }
+
+    /// Ratio of input to output rate.
+    pub fn decimation(&self) -> usize {
+        self.specs.iter().map(FilterSpec::decimation).product()
+    }
+}
+
+impl FromStr for Pipeline {
+    type Err = DspError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let specs = s
+            .split(',')
+            .map(FilterSpec::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Pipeline { specs })
+    }
+}
+
+struct StreamState {
+    rate: f64,
+    /// Segment of the input samples, and the same at the decimated rate.
+    segment: (Arc<SegmentMetadata>, Arc<SegmentMetadata>),
+    /// One chain per column, with the metadata of the filtered column,
+    /// `None` for columns passed through unchanged.
+    chains: Vec<Option<(Chain, Arc<ColumnMetadata>)>>,
+    /// Position within the decimation cycle, used when no column is
+    /// filtered.
+    phase: usize,
+}
+
+/// Applies a pipeline to the numeric columns of samples, keeping separate
+/// state per stream.
+///
+/// Filter state starts over at non-continuous boundaries and when the
+/// stream's rate changes. Filtered columns become `ColumnData::Float`, and
+/// their metadata says so.
+///
+/// Samples dropped by decimation are not returned. Samples are decimated in
+/// groups of `decimation()` starting at a number that is a multiple of it,
+/// and the last sample of each group is kept. Kept samples are renumbered at
+/// the decimated rate, and their segment metadata has the decimation
+/// multiplied by the pipeline's, so that they end at the same time: each
+/// spans its whole group. After a boundary, samples are dropped until the
+/// next group starts, and the first one kept gets the boundary.
+pub struct SampleFilter {
+    pipeline: Pipeline,
+    columns: Option<ColumnFilter>,
+    streams: HashMap<(DeviceRoute, u8), StreamState>,
+    /// Boundaries of samples dropped while waiting for a sample to keep.
+    boundaries: HashMap<(DeviceRoute, u8), Boundary>,
+}
+
+impl SampleFilter {
+    pub fn new(pipeline: Pipeline) -> SampleFilter {
+        SampleFilter {
+            pipeline,
+            columns: None,
+            streams: HashMap::new(),
+            boundaries: HashMap::new(),
+        }
+    }
+
+    /// Only filter the columns matching `columns`, passing the others
+    /// through (but still decimating them).
+    pub fn with_columns(mut self, columns: ColumnFilter) -> SampleFilter {
+        self.columns = Some(columns);
+        self
+    }
+
+    pub fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    /// Forget the state of every stream.
+    pub fn reset(&mut self) {
+        self.streams.clear();
+        self.boundaries.clear();
+    }
+
+    /// Filter `sample` from the device at `route`. Returns `None` for
+    /// samples dropped by decimation.
+    pub fn process(
+        &mut self,
+        mut sample: Sample,
+        route: &DeviceRoute,
+    ) -> Result<Option<Sample>, DspError> {
+        let rate = f64::from(sample.segment.sampling_rate) / f64::from(sample.segment.decimation);
+        let key = (route.clone(), sample.stream.stream_id);
+        let factor = self.pipeline.decimation() as u32;
+
+        let stale = match self.streams.get(&key) {
+            Some(state) => {
+                !sample.is_continuous()
+                    || state.rate != rate
+                    || state.chains.len() != sample.columns.len()
+            }
+            None => true,
+        };
+        if stale {
+            self.streams.remove(&key);
+            if !sample.n.is_multiple_of(factor) {
+                if let Some(boundary) = sample.boundary {
+                    self.boundaries.entry(key).or_insert(boundary);
+                }
+                return Ok(None);
+            }
+            let chains = sample
+                .columns
+                .iter()
+                .map(|col| {
+                    let selected = self
+                        .columns
+                        .as_ref()
+                        .is_none_or(|f| f.matches(route, &sample.stream.name, &col.desc.name));
+                    if selected && !matches!(col.value, ColumnData::Unknown) {
+                        let desc = match col.desc.data_type.buffer_type() {
+                            BufferType::Float => col.desc.clone(),
+                            _ => Arc::new(ColumnMetadata {
+                                data_type: DataType::Float64,
+                                ..(*col.desc).clone()
+                            }),
+                        };
+                        Ok(Some((self.pipeline.build(rate)?, desc)))
+                    } else {
+                        Ok(None)
+                    }
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            self.streams.insert(
+                key.clone(),
+                StreamState {
+                    rate,
+                    segment: (sample.segment.clone(), decimated(&sample.segment, factor)),
+                    chains,
+                    phase: 0,
+                },
+            );
+        }
+        let Some(state) = self.streams.get_mut(&key) else {
+            return Ok(None);
+        };
+
+        let keep = state.phase == self.pipeline.decimation() - 1;
+        state.phase = (state.phase + 1) % self.pipeline.decimation();
+        if !Arc::ptr_eq(&state.segment.0, &sample.segment) {
+            state.segment = (sample.segment.clone(), decimated(&sample.segment, factor));
+        }
+        let mut kept = None;
+        for (col, chain) in sample.columns.iter_mut().zip(&mut state.chains) {
+            let (Some((chain, desc)), Some(x)) = (chain, col.value.try_as_f64()) else {
+                continue;
+            };
+            let y = chain.process(x);
+            kept = Some(y.is_some());
+            if let Some(y) = y {
+                col.value = ColumnData::Float(y);
+                col.desc = desc.clone();
+            }
+        }
+        if !kept.unwrap_or(keep) {
+            if let Some(boundary) = sample.boundary {
+                self.boundaries.entry(key).or_insert(boundary);
+            }
+            return Ok(None);
+        }
+        if let Some(boundary) = self.boundaries.remove(&key) {
+            sample.boundary = Some(boundary);
+        }
+        if factor > 1 {
+            sample.n /= factor;
+            sample.segment = state.segment.1.clone();
+            if let Some(Boundary {
+                reason: BoundaryReason::SamplesLost { expected, received },
+                ..
+            }) = &mut sample.boundary
+            {
+                // The group of the expected sample is the first not seen
+                // in full.
+                *expected /= factor;
+                *received = sample.n;
+            }
+        }
+        Ok(Some(sample))
+    }
+}
+
+/// `segment` at a rate reduced by `factor`.
+fn decimated(segment: &Arc<SegmentMetadata>, factor: u32) -> Arc<SegmentMetadata> {
+    if factor == 1 {
+        return segment.clone();
+    }
+    Arc::new(SegmentMetadata {
+        decimation: segment.decimation * factor,
+        ..(**segment).clone()
+    })
+}
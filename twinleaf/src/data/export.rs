@@ -1,3 +1,5 @@
+use crate::data::calibration::StreamCalibration;
+use crate::data::clock::{TimeRef, UtcMapping};
 use crate::data::sample::Sample;
 use crate::data::ColumnFilter;
 use crate::tio::proto::identifiers::{ColumnId, DeviceRoute, SampleNumber, StreamKey};
@@ -54,24 +56,32 @@ enum ColumnBatch {
 struct PendingBatch {
     sample_numbers: Vec<SampleNumber>,
     timestamps: Vec<f64>,
+    /// UTC of each sample, if the stream's first sample had one.
+    utc: Option<Vec<f64>>,
     columns: HashMap<ColumnId, ColumnBatch>,
     stream_metadata: Arc<StreamMetadata>,
     segment_metadata: Arc<SegmentMetadata>,
     column_metadata: HashMap<ColumnId, Arc<ColumnMetadata>>,
+    /// Transforms applied to calibrated columns.
+    calibration: HashMap<ColumnId, String>,
     session_id: u32,
+    serial: String,
     is_first_chunk: bool,
 }
 
 impl PendingBatch {
-    fn new(sample: &Sample) -> Self {
+    fn new(sample: &Sample, clock: Option<&UtcMapping>) -> Self {
         Self {
             sample_numbers: Vec::new(),
             timestamps: Vec::new(),
+            utc: sample.utc(clock).map(|_| Vec::new()),
             columns: HashMap::new(),
             stream_metadata: sample.stream.clone(),
             segment_metadata: sample.segment.clone(),
             column_metadata: HashMap::new(),
+            calibration: HashMap::new(),
             session_id: sample.device.session_id,
+            serial: sample.device.serial_number.clone(),
             is_first_chunk: true,
         }
     }
@@ -84,19 +94,32 @@ impl PendingBatch {
         self.timestamps.is_empty()
     }
 
-    fn push(&mut self, sample: &Sample) {
+    fn push(
+        &mut self,
+        sample: &Sample,
+        clock: Option<&UtcMapping>,
+        calibration: Option<&StreamCalibration>,
+    ) {
         use crate::data::sample::ColumnData;
 
         self.sample_numbers.push(sample.n);
         self.timestamps.push(sample.timestamp_end());
+        if let Some(utc) = &mut self.utc {
+            utc.push(sample.utc(clock).unwrap_or(f64::NAN));
+        }
         self.segment_metadata = sample.segment.clone();
 
-        for col in &sample.columns {
+        for (position, col) in sample.columns.iter().enumerate() {
             let col_id = col.desc.index as ColumnId;
 
             self.column_metadata
                 .entry(col_id)
                 .or_insert_with(|| col.desc.clone());
+            if let Some(applied) = calibration.and_then(|c| c.describe(position)) {
+                self.calibration
+                    .entry(col_id)
+                    .or_insert_with(|| applied.to_string());
+            }
 
             let batch = self.columns.entry(col_id).or_insert_with(|| {
                 match col.desc.data_type.buffer_type() {
@@ -120,11 +143,14 @@ impl PendingBatch {
         let batch = PendingBatch {
             sample_numbers: std::mem::take(&mut self.sample_numbers),
             timestamps: std::mem::take(&mut self.timestamps),
+            utc: self.utc.as_mut().map(std::mem::take),
             columns: std::mem::take(&mut self.columns),
             stream_metadata: self.stream_metadata.clone(),
             segment_metadata: self.segment_metadata.clone(),
             column_metadata: std::mem::take(&mut self.column_metadata),
+            calibration: std::mem::take(&mut self.calibration),
             session_id: self.session_id,
+            serial: self.serial.clone(),
             is_first_chunk: self.is_first_chunk,
         };
         self.is_first_chunk = false;
@@ -146,6 +172,7 @@ pub struct Hdf5Appender {
     device_runs: HashMap<DeviceRoute, RunId>,
     global_run: RunId,
     seen_debug: HashSet<String>,
+    clocks: HashMap<TimeRef, UtcMapping>,
     stats: ExportStats,
 }
 
@@ -210,11 +237,30 @@ impl Hdf5Appender {
             device_runs: HashMap::new(),
             global_run: 0,
             seen_debug: HashSet::new(),
+            clocks: HashMap::new(),
             stats: ExportStats::default(),
         })
     }
 
+    /// Relate the clock `time_ref` to UTC with `clock`, such as the latest
+    /// `Device::clock`, for the `utc` dataset of the samples written next.
+    pub fn set_clock(&mut self, time_ref: TimeRef, clock: UtcMapping) {
+        self.clocks.insert(time_ref, clock);
+    }
+
     pub fn write_sample(&mut self, sample: Sample, key: StreamKey) -> Result<()> {
+        self.write_calibrated_sample(sample, key, None)
+    }
+
+    /// Write a sample that was calibrated with `calibration`, recording the
+    /// transforms applied to each column in a `calibration` attribute. See
+    /// `DeviceDataParser::stream_calibration`.
+    pub fn write_calibrated_sample(
+        &mut self,
+        sample: Sample,
+        key: StreamKey,
+        calibration: Option<&StreamCalibration>,
+    ) -> Result<()> {
         let should_split = match self.split_policy {
             SplitPolicy::Continuous => !sample.is_continuous(),
             SplitPolicy::Monotonic => !sample.is_monotonic(),
@@ -224,11 +270,16 @@ impl Hdf5Appender {
             self.handle_discontinuity(&key)?;
         }
 
+        let clock = self.clocks.get(&sample.time_ref());
         if !self.pending.contains_key(&key) {
-            self.pending.insert(key.clone(), PendingBatch::new(&sample));
+            self.pending
+                .insert(key.clone(), PendingBatch::new(&sample, clock));
         }
 
-        self.pending.get_mut(&key).unwrap().push(&sample);
+        self.pending
+            .get_mut(&key)
+            .unwrap()
+            .push(&sample, clock, calibration);
 
         if self.pending.get(&key).unwrap().len() >= self.batch_size {
             self.flush_stream(&key)?;
@@ -401,6 +452,7 @@ impl Hdf5Appender {
             &batch.sample_numbers,
             None,
             Some("Sample number from device"),
+            None,
         )?;
 
         self.append_dataset(
@@ -409,21 +461,37 @@ impl Hdf5Appender {
             &batch.timestamps,
             None,
             Some("Time in seconds"),
+            None,
         )?;
 
+        if let Some(utc) = &batch.utc {
+            self.append_dataset(
+                &group_path,
+                "utc",
+                utc,
+                None,
+                Some("UTC in seconds since the Unix epoch"),
+                None,
+            )?;
+        }
+
         for (_, col_batch, meta) in valid_columns {
             let units = Some(&meta.units).filter(|u| !u.is_empty());
             let desc = Some(meta.description.as_str()).filter(|d| !d.is_empty());
+            let applied = batch
+                .calibration
+                .get(&(meta.index as ColumnId))
+                .map(String::as_str);
 
             match col_batch {
                 ColumnBatch::F64(data) => {
-                    self.append_dataset(&group_path, &meta.name, data, units, desc)?
+                    self.append_dataset(&group_path, &meta.name, data, units, desc, applied)?
                 }
                 ColumnBatch::I64(data) => {
-                    self.append_dataset(&group_path, &meta.name, data, units, desc)?
+                    self.append_dataset(&group_path, &meta.name, data, units, desc, applied)?
                 }
                 ColumnBatch::U64(data) => {
-                    self.append_dataset(&group_path, &meta.name, data, units, desc)?
+                    self.append_dataset(&group_path, &meta.name, data, units, desc, applied)?
                 }
             }
         }
@@ -486,6 +554,7 @@ impl Hdf5Appender {
         data: &[T],
         units: Option<&String>,
         description: Option<&str>,
+        calibration: Option<&str>,
     ) -> Result<()> {
         let full_path = format!("{}/{}", group_path, name);
 
@@ -510,6 +579,9 @@ impl Hdf5Appender {
                 if let Some(d) = description {
                     self.write_attr_string(&ds, "description", d)?;
                 }
+                if let Some(c) = calibration {
+                    self.write_attr_string(&ds, "calibration", c)?;
+                }
                 ds
             };
             self.datasets.insert(full_path.clone(), ds);
@@ -1,5 +1,11 @@
+mod archive;
 mod buffer;
+pub mod calibration;
+pub mod clock;
+pub mod derived;
+pub mod dsp;
 mod filter;
+pub mod noise;
 mod parser;
 mod reader;
 mod sample;
@@ -8,9 +14,14 @@ mod subscription;
 #[cfg(feature = "hdf5")]
 pub mod export;
 
-pub use buffer::{AlignedWindow, Buffer, ColumnBatch, ReadError, RunId};
+pub use archive::Archive;
+pub use buffer::{
+    AlignedWindow, Buffer, ColumnBatch, ReadError, ResampleMethod, RunId, FILL_I64, FILL_U64,
+};
 pub use filter::ColumnFilter;
 pub use parser::{DeviceDataParser, DeviceFullMetadata};
 pub use reader::{CursorPosition, Reader};
 pub use sample::{Boundary, BoundaryReason, Column, ColumnData, Sample};
-pub use subscription::SubscriptionManager;
+pub use subscription::{
+    Subscription, SubscriptionId, SubscriptionManager, SubscriptionSpan, SubscriptionStats,
+};
@@ -0,0 +1,544 @@
+//! Noise statistics
+//!
+//! Overlapping and modified Allan deviation, power and amplitude spectral
+//! densities by Welch's method, and RMS noise over a frequency band. They
+//! take evenly sampled values, such as a `ColumnBatch` read from a `Buffer`
+//! (see `ColumnBatch::to_f64`) or the continuous runs of a column in log
+//! files (see `read_log`).
+//!
+//! Deviations and densities are in the units of the values: an Allan
+//! deviation of a field in nT is in nT, and its amplitude spectral density
+//! in nT/√Hz.
+
+use super::{ColumnFilter, DeviceDataParser};
+use crate::tio;
+use crate::tio::proto::identifiers::{ColumnId, StreamId};
+use crate::tio::proto::{ColumnMetadata, DeviceRoute};
+
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NoiseError {
+    #[error("invalid sampling rate {0}")]
+    InvalidRate(f64),
+    #[error("too few samples: {have}, need at least {need}")]
+    TooFewSamples { have: usize, need: usize },
+    #[error("invalid segment of {segment} samples with overlap {overlap}")]
+    InvalidSegment { segment: usize, overlap: f64 },
+    #[error("no frequencies in band [{low}, {high}] Hz")]
+    InvalidBand { low: f64, high: f64 },
+    #[error("unknown {kind} \"{name}\"")]
+    UnknownName { kind: &'static str, name: String },
+    #[error("could not read log file: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+fn check_rate(rate: f64) -> Result<(), NoiseError> {
+    if rate.is_finite() && rate > 0.0 {
+        Ok(())
+    } else {
+        Err(NoiseError::InvalidRate(rate))
+    }
+}
+
+/// The Allan deviation at one averaging time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AllanPoint {
+    /// Averaging time in seconds.
+    pub tau: f64,
+    pub deviation: f64,
+    /// Number of terms averaged, which sets the confidence of the estimate.
+    pub terms: usize,
+}
+
+/// Averaging factors 1, 2, 4, 8, … up to `max`.
+pub fn octave_factors(max: usize) -> Vec<usize> {
+    std::iter::successors(Some(1usize), |m| m.checked_mul(2))
+        .take_while(|&m| m <= max)
+        .collect()
+}
+
+/// Averaging factors 1, 2, 5, 10, 20, 50, … up to `max`.
+pub fn decade_factors(max: usize) -> Vec<usize> {
+    std::iter::successors(Some(1usize), |m| m.checked_mul(10))
+        .flat_map(|decade| [decade, 2 * decade, 5 * decade])
+        .take_while(|&m| m <= max)
+        .collect()
+}
+
+/// Time integral of `values` after removing their mean, which leaves the
+/// deviations unchanged but keeps the differences of large phases exact.
+fn phase(values: &[f64], rate: f64) -> Vec<f64> {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let tau0 = 1.0 / rate;
+    let mut x = Vec::with_capacity(values.len() + 1);
+    x.push(0.0);
+    let mut sum = 0.0;
+    for v in values {
+        sum += (v - mean) * tau0;
+        x.push(sum);
+    }
+    x
+}
+
+/// Overlapping Allan deviation of `values` sampled at `rate`, at averaging
+/// times `m / rate` for each factor `m`. Factors too large for the data are
+/// skipped.
+pub fn allan_deviation(
+    values: &[f64],
+    rate: f64,
+    factors: &[usize],
+) -> Result<Vec<AllanPoint>, NoiseError> {
+    check_rate(rate)?;
+    if values.len() < 2 {
+        return Err(NoiseError::TooFewSamples {
+            have: values.len(),
+            need: 2,
+        });
+    }
+    let x = phase(values, rate);
+    let n = x.len();
+    Ok(factors
+        .iter()
+        .filter(|&&m| m > 0 && 2 * m < n)
+        .map(|&m| {
+            let tau = m as f64 / rate;
+            let terms = n - 2 * m;
+            let sum: f64 = (0..terms)
+                .map(|i| {
+                    let d = x[i + 2 * m] - 2.0 * x[i + m] + x[i];
+                    d * d
+                })
+                .sum();
+            AllanPoint {
+                tau,
+                deviation: (sum / (2.0 * tau * tau * terms as f64)).sqrt(),
+                terms,
+            }
+        })
+        .collect())
+}
+
+/// Modified Allan deviation of `values` sampled at `rate`, at averaging
+/// times `m / rate` for each factor `m`. Factors too large for the data are
+/// skipped.
+pub fn modified_allan_deviation(
+    values: &[f64],
+    rate: f64,
+    factors: &[usize],
+) -> Result<Vec<AllanPoint>, NoiseError> {
+    check_rate(rate)?;
+    if values.len() < 2 {
+        return Err(NoiseError::TooFewSamples {
+            have: values.len(),
+            need: 2,
+        });
+    }
+    let x = phase(values, rate);
+    let n = x.len();
+    Ok(factors
+        .iter()
+        .filter(|&&m| m > 0 && 3 * m <= n)
+        .map(|&m| {
+            let tau = m as f64 / rate;
+            let d = |i: usize| x[i + 2 * m] - 2.0 * x[i + m] + x[i];
+            let terms = n - 3 * m + 1;
+            // Sliding sum of m second differences.
+            let mut window: f64 = (0..m).map(d).sum();
+            let mut sum = window * window;
+            for j in 1..terms {
+                window += d(j + m - 1) - d(j - 1);
+                sum += window * window;
+            }
+            let mf = m as f64;
+            AllanPoint {
+                tau,
+                deviation: (sum / (2.0 * mf * mf * tau * tau * terms as f64)).sqrt(),
+                terms,
+            }
+        })
+        .collect())
+}
+
+/// Window applied to each segment of a Welch estimate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    Rectangular,
+    Hann,
+    Hamming,
+    Blackman,
+    /// Flat top, for accurate amplitudes of narrow peaks.
+    FlatTop,
+}
+
+impl Window {
+    /// Periodic window weights for a segment of `n` samples.
+    pub fn weights(self, n: usize) -> Vec<f64> {
+        let coefficients: &[f64] = match self {
+            Window::Rectangular => &[1.0],
+            Window::Hann => &[0.5, 0.5],
+            Window::Hamming => &[0.54, 0.46],
+            Window::Blackman => &[0.42, 0.5, 0.08],
+            Window::FlatTop => &[
+                0.215_578_95,
+                0.416_631_58,
+                0.277_263_158,
+                0.083_578_947,
+                0.006_947_368,
+            ],
+        };
+        (0..n)
+            .map(|i| {
+                let phase = 2.0 * std::f64::consts::PI * i as f64 / n as f64;
+                coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(k, a)| {
+                        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                        sign * a * (k as f64 * phase).cos()
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Window {
+    type Err = NoiseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "rectangular" | "boxcar" => Ok(Window::Rectangular),
+            "hann" | "hanning" => Ok(Window::Hann),
+            "hamming" => Ok(Window::Hamming),
+            "blackman" => Ok(Window::Blackman),
+            "flattop" | "flat-top" => Ok(Window::FlatTop),
+            _ => Err(NoiseError::UnknownName {
+                kind: "window",
+                name: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Window::Rectangular => "rectangular",
+            Window::Hann => "hann",
+            Window::Hamming => "hamming",
+            Window::Blackman => "blackman",
+            Window::FlatTop => "flattop",
+        })
+    }
+}
+
+/// How a Welch estimate combines the periodograms of its segments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Averaging {
+    Mean,
+    /// Median, corrected for its bias. Robust to transients in a few
+    /// segments.
+    Median,
+}
+
+impl FromStr for Averaging {
+    type Err = NoiseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "mean" => Ok(Averaging::Mean),
+            "median" => Ok(Averaging::Median),
+            _ => Err(NoiseError::UnknownName {
+                kind: "averaging",
+                name: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for Averaging {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Averaging::Mean => "mean",
+            Averaging::Median => "median",
+        })
+    }
+}
+
+/// Ratio of the median to the mean of `n` exponentially distributed
+/// periodogram values.
+fn median_bias(n: usize) -> f64 {
+    1.0 + (1..=(n.saturating_sub(1)) / 2)
+        .map(|i| {
+            let k = 2.0 * i as f64;
+            1.0 / (k + 1.0) - 1.0 / k
+        })
+        .sum::<f64>()
+}
+
+/// Welch's method: the periodograms of overlapping windowed segments,
+/// averaged. Each segment's mean is removed first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Welch {
+    /// Samples per segment. The frequency resolution is `rate / segment`.
+    pub segment: usize,
+    /// Fraction of each segment shared with the next, in `[0, 1)`.
+    pub overlap: f64,
+    pub window: Window,
+    pub averaging: Averaging,
+}
+
+impl Welch {
+    /// Hann-windowed segments of `segment` samples, overlapping by half,
+    /// with their periodograms averaged.
+    pub fn new(segment: usize) -> Welch {
+        Welch {
+            segment,
+            overlap: 0.5,
+            window: Window::Hann,
+            averaging: Averaging::Mean,
+        }
+    }
+
+    pub fn with_overlap(mut self, overlap: f64) -> Welch {
+        self.overlap = overlap;
+        self
+    }
+
+    pub fn with_window(mut self, window: Window) -> Welch {
+        self.window = window;
+        self
+    }
+
+    pub fn with_averaging(mut self, averaging: Averaging) -> Welch {
+        self.averaging = averaging;
+        self
+    }
+
+    /// Samples between the starts of consecutive segments.
+    pub fn hop(&self) -> usize {
+        let shared = (self.segment as f64 * self.overlap).round() as usize;
+        self.segment.saturating_sub(shared).max(1)
+    }
+
+    /// One-sided power spectral density of `values` sampled at `rate`.
+    pub fn psd(&self, values: &[f64], rate: f64) -> Result<Spectrum, NoiseError> {
+        check_rate(rate)?;
+        if self.segment < 2 || !(0.0..1.0).contains(&self.overlap) {
+            return Err(NoiseError::InvalidSegment {
+                segment: self.segment,
+                overlap: self.overlap,
+            });
+        }
+        if values.len() < self.segment {
+            return Err(NoiseError::TooFewSamples {
+                have: values.len(),
+                need: self.segment,
+            });
+        }
+
+        let n = self.segment;
+        let weights = self.window.weights(n);
+        let power: f64 = weights.iter().map(|w| w * w).sum();
+        let fft = FftPlanner::new().plan_fft_forward(n);
+        let bins = n / 2 + 1;
+
+        let mut periodograms: Vec<Vec<f64>> = Vec::new();
+        let mut buffer = vec![Complex::new(0.0, 0.0); n];
+        for start in (0..=values.len() - n).step_by(self.hop()) {
+            let segment = &values[start..start + n];
+            let mean = segment.iter().sum::<f64>() / n as f64;
+            for ((c, v), w) in buffer.iter_mut().zip(segment).zip(&weights) {
+                *c = Complex::new((v - mean) * w, 0.0);
+            }
+            fft.process(&mut buffer);
+            let periodogram = (0..bins)
+                .map(|k| {
+                    // Fold negative frequencies, except DC and Nyquist.
+                    let fold = if k == 0 || 2 * k == n { 1.0 } else { 2.0 };
+                    fold * buffer[k].norm_sqr() / (rate * power)
+                })
+                .collect();
+            periodograms.push(periodogram);
+        }
+
+        let segments = periodograms.len();
+        let density = (0..bins)
+            .map(|k| match self.averaging {
+                Averaging::Mean => periodograms.iter().map(|p| p[k]).sum::<f64>() / segments as f64,
+                Averaging::Median => {
+                    let mut bin: Vec<f64> = periodograms.iter().map(|p| p[k]).collect();
+                    bin.sort_by(f64::total_cmp);
+                    let mid = segments / 2;
+                    let median = if segments.is_multiple_of(2) {
+                        (bin[mid - 1] + bin[mid]) / 2.0
+                    } else {
+                        bin[mid]
+                    };
+                    median / median_bias(segments)
+                }
+            })
+            .collect();
+
+        let resolution = rate / n as f64;
+        Ok(Spectrum {
+            frequencies: (0..bins).map(|k| k as f64 * resolution).collect(),
+            density,
+            resolution,
+            segments,
+        })
+    }
+}
+
+/// A one-sided power spectral density.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spectrum {
+    pub frequencies: Vec<f64>,
+    /// Power spectral density, in units²/Hz.
+    pub density: Vec<f64>,
+    /// Spacing of the frequencies, in Hz.
+    pub resolution: f64,
+    /// Number of segments averaged.
+    pub segments: usize,
+}
+
+impl Spectrum {
+    /// Amplitude spectral density, in units/√Hz.
+    pub fn asd(&self) -> Vec<f64> {
+        self.density.iter().map(|d| d.sqrt()).collect()
+    }
+
+    fn band(&self, low: f64, high: f64) -> Result<std::ops::Range<usize>, NoiseError> {
+        let start = self.frequencies.partition_point(|&f| f < low);
+        let end = self.frequencies.partition_point(|&f| f <= high);
+        if low > high || start >= end {
+            return Err(NoiseError::InvalidBand { low, high });
+        }
+        Ok(start..end)
+    }
+
+    /// RMS of the noise between `low` and `high` Hz, the square root of the
+    /// density integrated over the band.
+    pub fn band_rms(&self, low: f64, high: f64) -> Result<f64, NoiseError> {
+        let band = self.band(low, high)?;
+        Ok((self.density[band].iter().sum::<f64>() * self.resolution).sqrt())
+    }
+
+    /// Median amplitude spectral density between `low` and `high` Hz, a
+    /// noise floor that ignores narrow peaks.
+    pub fn noise_floor(&self, low: f64, high: f64) -> Result<f64, NoiseError> {
+        let band = self.band(low, high)?;
+        let mut asd: Vec<f64> = self.density[band].iter().map(|d| d.sqrt()).collect();
+        asd.sort_by(f64::total_cmp);
+        let mid = asd.len() / 2;
+        Ok(if asd.len().is_multiple_of(2) {
+            (asd[mid - 1] + asd[mid]) / 2.0
+        } else {
+            asd[mid]
+        })
+    }
+}
+
+/// Evenly sampled values of a column, between discontinuities.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    pub rate: f64,
+    /// Timestamp of the first sample.
+    pub start: f64,
+    pub values: Vec<f64>,
+}
+
+/// The values of one column in log files, split into runs at
+/// discontinuities and rate changes.
+#[derive(Debug, Clone)]
+pub struct Series {
+    pub route: DeviceRoute,
+    pub stream: String,
+    pub column: Arc<ColumnMetadata>,
+    pub runs: Vec<Run>,
+}
+
+impl Series {
+    /// `/route/stream/column`, as matched by `ColumnFilter`.
+    pub fn path(&self) -> String {
+        let route = self.route.to_string();
+        let route = route.trim_start_matches('/');
+        if route.is_empty() {
+            format!("/{}/{}", self.stream, self.column.name)
+        } else {
+            format!("/{}/{}/{}", route, self.stream, self.column.name)
+        }
+    }
+
+    /// The run with the most samples.
+    pub fn longest(&self) -> Option<&Run> {
+        self.runs.iter().max_by_key(|run| run.values.len())
+    }
+}
+
+/// The numeric columns of the samples in `files`, or those matching
+/// `columns`, ordered by route, stream and column. Files are read in order,
+/// as parts of one log.
+pub fn read_log<P: AsRef<Path>>(
+    files: &[P],
+    columns: Option<&ColumnFilter>,
+) -> Result<Vec<Series>, NoiseError> {
+    let mut parsers: HashMap<DeviceRoute, DeviceDataParser> = HashMap::new();
+    let mut series: BTreeMap<(DeviceRoute, StreamId, ColumnId), Series> = BTreeMap::new();
+    let ignore_session = files.len() > 1;
+
+    for path in files {
+        let data = std::fs::read(path)?;
+        let mut rest: &[u8] = &data;
+        while !rest.is_empty() {
+            let Ok((pkt, len)) = tio::Packet::deserialize(rest) else {
+                break;
+            };
+            rest = &rest[len..];
+
+            let parser = parsers
+                .entry(pkt.routing.clone())
+                .or_insert_with(|| DeviceDataParser::new(ignore_session));
+            for sample in parser.process_packet(&pkt) {
+                let rate = f64::from(sample.segment.sampling_rate)
+                    / f64::from(sample.segment.decimation.max(1));
+                for col in &sample.columns {
+                    let Some(value) = col.value.try_as_f64() else {
+                        continue;
+                    };
+                    if columns.is_some_and(|f| {
+                        !f.matches(&pkt.routing, &sample.stream.name, &col.desc.name)
+                    }) {
+                        continue;
+                    }
+                    let entry = series
+                        .entry((pkt.routing.clone(), sample.stream.stream_id, col.desc.index))
+                        .or_insert_with(|| Series {
+                            route: pkt.routing.clone(),
+                            stream: sample.stream.name.clone(),
+                            column: col.desc.clone(),
+                            runs: Vec::new(),
+                        });
+                    let new_run = match entry.runs.last() {
+                        Some(run) => !sample.is_continuous() || run.rate != rate,
+                        None => true,
+                    };
+                    if new_run {
+                        entry.runs.push(Run {
+                            rate,
+                            start: sample.timestamp_end(),
+                            values: Vec::new(),
+                        });
+                    }
+                    entry.runs.last_mut().unwrap().values.push(value);
+                }
+            }
+        }
+    }
+    Ok(series.into_values().collect())
+}
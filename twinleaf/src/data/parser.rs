@@ -1,3 +1,5 @@
+use super::calibration::{Calibration, StreamCalibration};
+use super::clock::{host_now, ClockEstimator, TimeRef, UtcMapping};
 use super::sample::{Boundary, BoundaryReason, Column, PriorState, Sample};
 use crate::tio;
 use proto::meta::MetadataType;
@@ -5,7 +7,7 @@ use proto::DeviceRoute;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tio::proto::meta::{
-    ColumnMetadata, DeviceMetadata, MetadataContent, SegmentMetadata, StreamMetadata,
+    ColumnMetadata, DeviceMetadata, MetadataContent, MetadataEpoch, SegmentMetadata, StreamMetadata,
 };
 use tio::{proto, util};
 
@@ -424,7 +426,7 @@ impl DeviceStream {
 
             let sample = Sample {
                 n: sample_n,
-                columns: columns,
+                columns,
                 segment: segment.clone(),
                 stream: stream.clone(),
                 device: dev.clone(),
@@ -477,10 +479,43 @@ pub struct DeviceFullMetadata {
     pub streams: HashMap<u8, DeviceStreamMetadata>,
 }
 
+/// Calibration of a stream, valid while the stream has the same device
+/// and column metadata.
+struct CalibrationCache {
+    serial: String,
+    stream_name: String,
+    columns: Vec<Arc<ColumnMetadata>>,
+    calibration: Option<StreamCalibration>,
+}
+
+impl CalibrationCache {
+    fn is_valid_for(&self, sample: &Sample) -> bool {
+        self.serial == sample.device.serial_number
+            && self.stream_name == sample.stream.name
+            && self.columns.len() == sample.columns.len()
+            && self
+                .columns
+                .iter()
+                .zip(&sample.columns)
+                .all(|(desc, col)| Arc::ptr_eq(desc, &col.desc))
+    }
+}
+
+/// Host estimate of a device clock, from the arrival times of packets.
+#[derive(Default)]
+struct HostClock {
+    estimator: ClockEstimator,
+    mapping: Option<UtcMapping>,
+}
+
 pub struct DeviceDataParser {
     device: Option<Arc<DeviceMetadata>>,
     streams: HashMap<u8, DeviceStream>,
     ignore_session: bool,
+    calibration: Option<Arc<Calibration>>,
+    calibrated: HashMap<u8, CalibrationCache>,
+    host_clock: bool,
+    clocks: HashMap<TimeRef, HostClock>,
 }
 
 impl DeviceDataParser {
@@ -489,6 +524,90 @@ impl DeviceDataParser {
             device: None,
             streams: HashMap::new(),
             ignore_session,
+            calibration: None,
+            calibrated: HashMap::new(),
+            host_clock: false,
+            clocks: HashMap::new(),
+        }
+    }
+
+    /// Map sample times to UTC from the host clock at each packet, for
+    /// devices whose time reference is not `Unix`. Only meaningful when
+    /// packets are parsed as they arrive, not when reading logs.
+    pub fn set_host_clock(&mut self, enabled: bool) {
+        self.host_clock = enabled;
+    }
+
+    /// Latest host estimate of UTC for the device clock `time_ref`, to pass
+    /// to `Sample::utc`. `None` unless `set_host_clock` is enabled and a
+    /// packet timed by that clock arrived.
+    pub fn clock(&self, time_ref: &TimeRef) -> Option<UtcMapping> {
+        self.clocks.get(time_ref)?.mapping
+    }
+
+    /// Update the clock of `samples`, which arrived in one packet at
+    /// `arrival` after the last of them was taken.
+    fn observe_clock(&mut self, samples: &[Sample], arrival: f64) {
+        let (Some(first), Some(last)) = (samples.first(), samples.last()) else {
+            return;
+        };
+        if last.segment.time_ref_epoch == MetadataEpoch::Unix {
+            return;
+        }
+        let clock = self.clocks.entry(last.time_ref()).or_default();
+        // Other streams on the clock may be established already, so only
+        // forget the arrivals when the clock itself went back.
+        if let Some(Boundary {
+            reason: BoundaryReason::TimeBackward { .. },
+            ..
+        }) = first.boundary
+        {
+            clock.estimator.reset();
+        }
+        clock.mapping = Some(clock.estimator.observe(last.timestamp_end(), arrival));
+    }
+
+    /// Calibrate the samples returned by `process_packet`.
+    pub fn set_calibration(&mut self, calibration: Option<Arc<Calibration>>) {
+        self.calibration = calibration;
+        self.calibrated.clear();
+    }
+
+    /// The calibration applied to the latest samples of stream `stream_id`,
+    /// `None` if they were not calibrated. Its `describe` gives the
+    /// transforms applied to each column, worked out from the columns
+    /// before calibration.
+    pub fn stream_calibration(&self, stream_id: u8) -> Option<&StreamCalibration> {
+        self.calibrated.get(&stream_id)?.calibration.as_ref()
+    }
+
+    fn calibrate(&mut self, samples: &mut [Sample]) {
+        let Some(calibration) = &self.calibration else {
+            return;
+        };
+        for sample in samples {
+            let id = sample.stream.stream_id;
+            if !self
+                .calibrated
+                .get(&id)
+                .is_some_and(|c| c.is_valid_for(sample))
+            {
+                let columns: Vec<_> = sample.columns.iter().map(|c| c.desc.clone()).collect();
+                let cache = CalibrationCache {
+                    serial: sample.device.serial_number.clone(),
+                    stream_name: sample.stream.name.clone(),
+                    calibration: calibration.stream(
+                        &sample.device.serial_number,
+                        &sample.stream.name,
+                        &columns,
+                    ),
+                    columns,
+                };
+                self.calibrated.insert(id, cache);
+            }
+            if let Some(calibration) = &self.calibrated[&id].calibration {
+                calibration.apply(sample);
+            }
         }
     }
 
@@ -622,8 +741,14 @@ impl DeviceDataParser {
                         self.streams.clear();
                     } else {
                         let ndev = dev.clone();
+                        let arrival = self.host_clock.then(host_now);
                         let dstream = self.get_stream(data.stream_id);
-                        return dstream.process_samples(data, ndev);
+                        let mut samples = dstream.process_samples(data, ndev);
+                        if let Some(arrival) = arrival {
+                            self.observe_clock(&samples, arrival);
+                        }
+                        self.calibrate(&mut samples);
+                        return samples;
                     }
                 }
             }
@@ -109,17 +109,25 @@ impl Reader {
     }
 
     fn advance_cursor(&mut self, window: &AlignedWindow) {
-        for (stream_key, &run_id) in &window.run_ids {
-            if let Some(sample_nums) = window.sample_numbers.get(stream_key) {
-                if let Some(&last_sample_number) = sample_nums.last() {
-                    self.cursor.positions.insert(
-                        stream_key.clone(),
-                        CursorPosition {
-                            run_id,
-                            last_sample_number,
-                        },
-                    );
-                }
+        advance_cursors(&mut self.cursor.positions, window);
+    }
+}
+
+/// Move `positions` to the last sample of each stream in `window`.
+pub(crate) fn advance_cursors(
+    positions: &mut HashMap<StreamKey, CursorPosition>,
+    window: &AlignedWindow,
+) {
+    for (stream_key, &run_id) in &window.run_ids {
+        if let Some(sample_nums) = window.sample_numbers.get(stream_key) {
+            if let Some(&last_sample_number) = sample_nums.last() {
+                positions.insert(
+                    stream_key.clone(),
+                    CursorPosition {
+                        run_id,
+                        last_sample_number,
+                    },
+                );
             }
         }
     }
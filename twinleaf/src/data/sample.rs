@@ -1,8 +1,11 @@
+use crate::data::clock::{TimeRef, UtcMapping};
 use crate::tio;
 
 use std::sync::Arc;
 use tio::proto::identifiers::{SampleNumber, SegmentId, SessionId, TimeRefSessionId};
-use tio::proto::meta::{ColumnMetadata, DeviceMetadata, SegmentMetadata, StreamMetadata};
+use tio::proto::meta::{
+    ColumnMetadata, DeviceMetadata, MetadataEpoch, SegmentMetadata, StreamMetadata,
+};
 
 #[derive(Debug, Clone)]
 pub enum ColumnData {
@@ -189,6 +192,22 @@ impl Sample {
             1.0 / f64::from(self.segment.sampling_rate) * f64::from(self.segment.decimation);
         f64::from(self.segment.start_time) + period * f64::from(self.n + 1)
     }
+
+    /// The clock that the sample's times are on.
+    pub fn time_ref(&self) -> TimeRef {
+        TimeRef::of(self)
+    }
+
+    /// UTC at the end of the sample, in seconds since the Unix epoch, if the
+    /// device time reference is `Unix` or `clock` maps it to UTC. `clock` is
+    /// the host estimate for the sample's `time_ref`, see
+    /// `DeviceDataParser::clock`.
+    pub fn utc(&self, clock: Option<&UtcMapping>) -> Option<f64> {
+        match self.segment.time_ref_epoch {
+            MetadataEpoch::Unix => Some(self.timestamp_end()),
+            _ => Some(clock?.utc(self.timestamp_end())),
+        }
+    }
 }
 
 impl std::fmt::Display for Sample {
@@ -1,67 +1,272 @@
-use std::collections::HashMap;
+//! Subscriptions
+//! Deliver windows of one shared `Buffer` to several consumers, such as UI
+//! widgets, each with its own columns, span and cadence.
+//!
+//! Windows are pushed to a channel or a callback whenever the owner calls
+//! `broadcast`, for the subscriptions whose interval has elapsed. Samples
+//! given to `process_samples`, such as those of one packet, are buffered
+//! together and then delivered only to the due subscriptions that read
+//! their streams. Read errors are counted in the subscription's
+//! `SubscriptionStats` rather than dropped.
 
-use crate::data::{AlignedWindow, Buffer};
-use crate::tio::proto::identifiers::ColumnKey;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crossbeam::channel::{Receiver, Sender, TrySendError};
+
+use crate::data::reader::advance_cursors;
+use crate::data::{AlignedWindow, Buffer, CursorPosition, ReadError, Sample};
+use crate::tio::proto::identifiers::{ColumnKey, StreamKey};
 
 pub type SubscriptionId = usize;
-pub struct SubscriptionManager {
-    pub buffer: Buffer,
-    subscriptions: HashMap<SubscriptionId, Subscription>,
-    next_id: SubscriptionId,
+
+/// Which samples each delivery of a subscription holds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubscriptionSpan {
+    /// The last `n` aligned samples.
+    Samples(usize),
+    /// The aligned samples of the last `seconds`, or as many as the buffer
+    /// retains.
+    Seconds(f64),
+    /// The samples since the previous delivery, so that each sample is
+    /// delivered once. The first delivery holds all retained samples.
+    New,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SubscriptionStats {
+    /// Windows delivered.
+    pub delivered: u64,
+    /// Windows dropped because the subscriber's channel was full.
+    pub dropped: u64,
+    /// Samples of a `SubscriptionSpan::New` subscription that left the
+    /// buffer before they could be delivered.
+    pub lagged: u64,
+    /// Reads that failed, other than for lack of data.
+    pub errors: u64,
+    pub last_error: Option<ReadError>,
+}
+
+enum Sink {
+    Channel(Sender<AlignedWindow>),
+    Callback(Box<dyn FnMut(AlignedWindow) + Send>),
 }
 
 pub struct Subscription {
     columns: Vec<ColumnKey>,
-    n_samples: usize,
-    tx: crossbeam::channel::Sender<AlignedWindow>,
+    /// The streams of `columns`.
+    streams: HashSet<StreamKey>,
+    span: SubscriptionSpan,
+    interval: Duration,
+    sink: Sink,
+    cursors: HashMap<StreamKey, CursorPosition>,
+    /// When a window was last delivered.
+    last_delivery: Option<Instant>,
+    stats: SubscriptionStats,
+}
+
+impl Subscription {
+    fn reads_any(&self, streams: &HashSet<StreamKey>) -> bool {
+        !self.streams.is_disjoint(streams)
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        self.last_delivery
+            .is_none_or(|last| now.duration_since(last) >= self.interval)
+    }
+
+    fn read(&mut self, buffer: &Buffer) -> Result<AlignedWindow, ReadError> {
+        match self.span {
+            SubscriptionSpan::Samples(n) => buffer.read_aligned_window(&self.columns, n),
+            SubscriptionSpan::Seconds(seconds) => {
+                buffer.read_aligned_seconds(&self.columns, seconds)
+            }
+            SubscriptionSpan::New => {
+                if !self.cursors.is_empty() {
+                    match buffer.read_since_cursor(&self.columns, &self.cursors) {
+                        Err(ReadError::CursorInvalidated { .. }) => {}
+                        Err(ReadError::CursorOutOfBuffer {
+                            cursor_sample,
+                            earliest_available,
+                            ..
+                        }) => {
+                            let missed = earliest_available.wrapping_sub(cursor_sample);
+                            self.stats.lagged += u64::from(missed.saturating_sub(1));
+                        }
+                        result => return result,
+                    }
+                }
+                // Start over from all retained samples of the current runs.
+                self.cursors.clear();
+                buffer.read_aligned_window(&self.columns, usize::MAX)
+            }
+        }
+    }
+
+    /// Read and deliver a window. Returns false if the subscriber is gone.
+    fn deliver(&mut self, buffer: &Buffer, now: Instant) -> bool {
+        let window = match self.read(buffer) {
+            Ok(window) => window,
+            Err(ReadError::InsufficientData { .. } | ReadError::NoActiveRun { .. }) => {
+                return true;
+            }
+            Err(err) => {
+                self.stats.errors += 1;
+                self.stats.last_error = Some(err);
+                return true;
+            }
+        };
+        if self.span == SubscriptionSpan::New {
+            advance_cursors(&mut self.cursors, &window);
+        }
+        match &mut self.sink {
+            Sink::Channel(tx) => match tx.try_send(window) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    self.stats.dropped += 1;
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            },
+            Sink::Callback(callback) => callback(window),
+        }
+        self.stats.delivered += 1;
+        self.last_delivery = Some(now);
+        true
+    }
+}
+
+pub struct SubscriptionManager {
+    pub buffer: Buffer,
+    subscriptions: Mutex<BTreeMap<SubscriptionId, Subscription>>,
+    next_id: SubscriptionId,
 }
 
 impl SubscriptionManager {
     pub fn new(buffer: Buffer) -> Self {
         Self {
             buffer,
-            subscriptions: HashMap::new(),
+            subscriptions: Mutex::new(BTreeMap::new()),
             next_id: 0,
         }
     }
 
+    /// Subscribe to the last `n_samples` of `columns` on every broadcast.
     pub fn subscribe(
         &mut self,
         columns: Vec<ColumnKey>,
         n_samples: usize,
-    ) -> (SubscriptionId, crossbeam::channel::Receiver<AlignedWindow>) {
+    ) -> (SubscriptionId, Receiver<AlignedWindow>) {
+        self.subscribe_span(
+            columns,
+            SubscriptionSpan::Samples(n_samples),
+            Duration::ZERO,
+        )
+    }
+
+    /// Subscribe to `span` of `columns`, delivered at most once per
+    /// `interval`. Windows are dropped, and counted, while the channel is
+    /// full. The subscription is removed at its first delivery after the
+    /// receiver is dropped.
+    pub fn subscribe_span(
+        &mut self,
+        columns: Vec<ColumnKey>,
+        span: SubscriptionSpan,
+        interval: Duration,
+    ) -> (SubscriptionId, Receiver<AlignedWindow>) {
         let (tx, rx) = crossbeam::channel::bounded(10); // bounded to allow drops
+        let id = self.insert(columns, span, interval, Sink::Channel(tx));
+        (id, rx)
+    }
+
+    /// Like `subscribe_span`, calling `callback` with each window.
+    pub fn subscribe_callback<F>(
+        &mut self,
+        columns: Vec<ColumnKey>,
+        span: SubscriptionSpan,
+        interval: Duration,
+        callback: F,
+    ) -> SubscriptionId
+    where
+        F: FnMut(AlignedWindow) + Send + 'static,
+    {
+        self.insert(columns, span, interval, Sink::Callback(Box::new(callback)))
+    }
+
+    fn insert(
+        &mut self,
+        columns: Vec<ColumnKey>,
+        span: SubscriptionSpan,
+        interval: Duration,
+        sink: Sink,
+    ) -> SubscriptionId {
         let id = self.next_id;
         self.next_id += 1;
 
-        self.subscriptions.insert(
+        self.subscriptions.get_mut().unwrap().insert(
             id,
             Subscription {
+                streams: columns.iter().map(ColumnKey::stream_key).collect(),
                 columns,
-                n_samples,
-                tx,
+                span,
+                interval,
+                sink,
+                cursors: HashMap::new(),
+                last_delivery: None,
+                stats: SubscriptionStats::default(),
             },
         );
 
-        (id, rx)
+        id
     }
 
     pub fn unsubscribe(&mut self, id: SubscriptionId) {
-        self.subscriptions.remove(&id);
+        self.subscriptions.get_mut().unwrap().remove(&id);
     }
 
     pub fn unsubscribe_all(&mut self) {
-        self.subscriptions.clear();
+        self.subscriptions.get_mut().unwrap().clear();
     }
 
-    pub fn broadcast(&self) {
-        for sub in self.subscriptions.values() {
-            match self.buffer.read_aligned_window(&sub.columns, sub.n_samples) {
-                Ok(window) => {
-                    let _ = sub.tx.try_send(window);
-                }
-                Err(_) => {}
-            }
+    pub fn stats(&self, id: SubscriptionId) -> Option<SubscriptionStats> {
+        let subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.get(&id).map(|sub| sub.stats.clone())
+    }
+
+    /// Buffer `sample`, then deliver to the due subscriptions that read
+    /// its stream.
+    pub fn process_sample(&mut self, sample: Sample, stream_key: StreamKey) {
+        self.process_samples([(sample, stream_key)]);
+    }
+
+    /// Buffer `samples`, then deliver once to the due subscriptions that
+    /// read any of their streams.
+    pub fn process_samples<I>(&mut self, samples: I)
+    where
+        I: IntoIterator<Item = (Sample, StreamKey)>,
+    {
+        let mut streams = HashSet::new();
+        for (sample, stream_key) in samples {
+            self.buffer.process_sample(sample, stream_key.clone());
+            streams.insert(stream_key);
+        }
+        if streams.is_empty() {
+            return;
         }
+        let now = Instant::now();
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|_, sub| {
+            !sub.reads_any(&streams) || !sub.is_due(now) || sub.deliver(&self.buffer, now)
+        });
+    }
+
+    /// Deliver, in subscription order, to the subscriptions whose interval
+    /// has elapsed since their previous delivery. A subscription with no
+    /// data to deliver yet stays due.
+    pub fn broadcast(&self) {
+        let now = Instant::now();
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|_, sub| !sub.is_due(now) || sub.deliver(&self.buffer, now));
     }
 }
@@ -0,0 +1,386 @@
+//! Chunked bulk transfers
+//!
+//! Large blobs are moved through RPCs one chunk at a time. Reads call the
+//! RPC with a `u16` chunk index until the device answers `InvalidArgs`,
+//! writes send consecutive pieces of the data, which the device must
+//! acknowledge in order. Several chunks can be in flight at once, read
+//! chunks that time out are sent again, and the result can be checked
+//! against a CRC-32.
+//!
+//! ```no_run
+//! # use twinleaf::tio::{proto::DeviceRoute, proxy};
+//! # use twinleaf::device::bulk::BulkTransfer;
+//! let proxy = proxy::Interface::new("tcp://localhost");
+//! let port = proxy.device_rpc(DeviceRoute::root()).unwrap();
+//! let blob = BulkTransfer::new(&port, "dev.desc.block")
+//!     .window(4)
+//!     .on_progress(|p| eprintln!("{} bytes", p.bytes))
+//!     .read()
+//!     .unwrap();
+//! ```
+
+use crate::tio::proto::{DeviceRoute, Packet, Payload, RpcErrorCode, RpcErrorPayload};
+use crate::tio::{proxy, util};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use std::collections::{BTreeMap, HashMap};
+
+/// Largest chunk index a read can ask for.
+const MAX_CHUNKS: usize = 1 << 16;
+
+/// Request ids used by transfers. Other users of a port, such as the
+/// metadata requests of `Device`, stay below this.
+const ID_BASE: u16 = 0x8000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BulkError {
+    #[error("failed to send request: {0}")]
+    Send(#[from] proxy::SendError),
+    #[error("failed to receive reply: {0}")]
+    Recv(#[from] proxy::RecvError),
+    #[error("device rejected chunk {chunk}: {error}")]
+    Rejected { chunk: usize, error: RpcErrorCode },
+    #[error("chunk {chunk} timed out {attempts} times")]
+    Timeout { chunk: usize, attempts: usize },
+    #[error("chunk {got} acknowledged before chunk {expected}")]
+    OutOfOrder { expected: usize, got: usize },
+    #[error("too much data for {0} chunks")]
+    TooLarge(usize),
+    #[error("checksum RPC failed: {0}")]
+    ChecksumRpc(RpcErrorCode),
+    #[error("checksum mismatch: expected {expected:08x}, got {actual:08x}")]
+    Checksum { expected: u32, actual: u32 },
+}
+
+impl From<BulkError> for proxy::RpcError {
+    /// Errors of reads as plain RPC errors, for callers that predate this
+    /// module.
+    fn from(err: BulkError) -> proxy::RpcError {
+        let exec = |error| {
+            proxy::RpcError::ExecError(RpcErrorPayload {
+                id: 0,
+                error,
+                extra: vec![],
+            })
+        };
+        match err {
+            BulkError::Send(e) => proxy::RpcError::SendFailed(e),
+            BulkError::Recv(e) => proxy::RpcError::RecvFailed(e),
+            BulkError::Rejected { error, .. } | BulkError::ChecksumRpc(error) => exec(error),
+            BulkError::Timeout { .. } => exec(RpcErrorCode::Timeout),
+            BulkError::OutOfOrder { .. } | BulkError::TooLarge(_) | BulkError::Checksum { .. } => {
+                proxy::RpcError::TypeError
+            }
+        }
+    }
+}
+
+/// Transport for bulk transfers. Implementations deliver the packets of
+/// the target device, and handle any other traffic on the way.
+pub trait BulkPort {
+    /// Send a request to the target device. The routing of `pkt` is the
+    /// root, relative to the target.
+    fn send_request(&mut self, pkt: Packet) -> Result<(), proxy::SendError>;
+    /// Next packet from the target device.
+    fn recv_packet(&mut self) -> Result<Packet, proxy::RecvError>;
+}
+
+impl BulkPort for &proxy::Port {
+    fn send_request(&mut self, pkt: Packet) -> Result<(), proxy::SendError> {
+        self.send(pkt)
+    }
+
+    fn recv_packet(&mut self) -> Result<Packet, proxy::RecvError> {
+        self.recv()
+    }
+}
+
+/// State of a transfer, passed to the progress callback.
+#[derive(Debug, Clone, Default)]
+pub struct BulkProgress {
+    /// Chunks completed.
+    pub chunks: usize,
+    /// Total number of chunks, if known. Reads don't know it.
+    pub total_chunks: Option<usize>,
+    /// Bytes transferred.
+    pub bytes: usize,
+    /// Chunks sent again after a timeout.
+    pub retries: usize,
+}
+
+type ProgressFn<'a> = Box<dyn FnMut(&BulkProgress) + 'a>;
+
+enum Reply {
+    Ok(Vec<u8>),
+    Err(RpcErrorCode),
+}
+
+/// A bulk read or write through one RPC.
+pub struct BulkTransfer<'a, P: BulkPort> {
+    port: P,
+    rpc: String,
+    window: usize,
+    retries: Option<usize>,
+    chunk_size: usize,
+    verify_rpc: Option<String>,
+    expected_crc: Option<u32>,
+    progress_cb: Option<ProgressFn<'a>>,
+
+    next_seq: u16,
+    progress: BulkProgress,
+}
+
+impl<'a, P: BulkPort> BulkTransfer<'a, P> {
+    /// Transfer through the RPC `rpc`, one chunk at a time. Reads retry a
+    /// chunk up to 3 times, writes don't retry unless asked to.
+    pub fn new(port: P, rpc: &str) -> BulkTransfer<'a, P> {
+        BulkTransfer {
+            port,
+            rpc: rpc.to_string(),
+            window: 1,
+            retries: None,
+            chunk_size: 288,
+            verify_rpc: None,
+            expected_crc: None,
+            progress_cb: None,
+            next_seq: 0,
+            progress: BulkProgress::default(),
+        }
+    }
+
+    /// Number of chunks in flight at once.
+    pub fn window(mut self, chunks: usize) -> Self {
+        self.window = chunks.max(1);
+        self
+    }
+
+    /// How many times a chunk that timed out is sent again.
+    ///
+    /// Only set this for a write if the RPC takes an explicit offset or
+    /// sequence number that the device checks. A chunk that timed out may
+    /// still have been written, and the chunks sent after it are sent again
+    /// too, so an append-style RPC such as `dev.firmware.upload` would write
+    /// them twice.
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Size of the chunks sent by `write`.
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = bytes.max(1);
+        self
+    }
+
+    /// After the transfer, call `rpc` and compare the `u32` it returns with
+    /// the CRC-32 of the data.
+    pub fn verify_rpc(mut self, rpc: &str) -> Self {
+        self.verify_rpc = Some(rpc.to_string());
+        self
+    }
+
+    /// Check the CRC-32 of the data against a known value.
+    pub fn expect_crc(mut self, crc: u32) -> Self {
+        self.expected_crc = Some(crc);
+        self
+    }
+
+    /// Call `cb` every time a chunk completes.
+    pub fn on_progress<F: FnMut(&BulkProgress) + 'a>(mut self, cb: F) -> Self {
+        self.progress_cb = Some(Box::new(cb));
+        self
+    }
+
+    fn send_chunk(&mut self, arg: &[u8]) -> Result<u16, BulkError> {
+        let id = ID_BASE | (self.next_seq & !ID_BASE);
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.port
+            .send_request(util::PacketBuilder::make_rpc_request(
+                &self.rpc,
+                arg,
+                id,
+                DeviceRoute::root(),
+            ))?;
+        Ok(id)
+    }
+
+    fn recv_reply(&mut self) -> Result<(u16, Reply), BulkError> {
+        loop {
+            match self.port.recv_packet()?.payload {
+                Payload::RpcReply(rep) if rep.id >= ID_BASE => {
+                    return Ok((rep.id, Reply::Ok(rep.reply)))
+                }
+                Payload::RpcError(err) if err.id >= ID_BASE => {
+                    return Ok((err.id, Reply::Err(err.error)))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Read the checksum from `rpc`, ignoring stale chunk replies.
+    fn read_crc(&mut self, rpc: &str) -> Result<u32, BulkError> {
+        let id = ID_BASE | (self.next_seq & !ID_BASE);
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.port
+            .send_request(util::PacketBuilder::make_rpc_request(
+                rpc,
+                &[],
+                id,
+                DeviceRoute::root(),
+            ))?;
+        loop {
+            match self.recv_reply()? {
+                (rid, Reply::Ok(reply)) if rid == id => {
+                    return <u32 as util::TioRpcReplyable<u32>>::from_reply(&reply)
+                        .map_err(|_| BulkError::ChecksumRpc(RpcErrorCode::WrongSizeArgs));
+                }
+                (rid, Reply::Err(err)) if rid == id => return Err(BulkError::ChecksumRpc(err)),
+                _ => {}
+            }
+        }
+    }
+
+    fn chunk_done(&mut self, bytes: usize) {
+        self.progress.chunks += 1;
+        self.progress.bytes += bytes;
+        if let Some(cb) = self.progress_cb.as_mut() {
+            cb(&self.progress);
+        }
+    }
+
+    fn retry(
+        &mut self,
+        chunk: usize,
+        attempts: &mut usize,
+        retries: usize,
+    ) -> Result<(), BulkError> {
+        *attempts += 1;
+        if *attempts > retries {
+            return Err(BulkError::Timeout {
+                chunk,
+                attempts: *attempts,
+            });
+        }
+        self.progress.retries += 1;
+        Ok(())
+    }
+
+    fn verify(&mut self, data: &[u8]) -> Result<(), BulkError> {
+        let actual = Crc::<u32>::new(&CRC_32_ISO_HDLC).checksum(data);
+        if let Some(expected) = self.expected_crc {
+            if expected != actual {
+                return Err(BulkError::Checksum { expected, actual });
+            }
+        }
+        if let Some(rpc) = self.verify_rpc.clone() {
+            let expected = self.read_crc(&rpc)?;
+            if expected != actual {
+                return Err(BulkError::Checksum { expected, actual });
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the whole blob, calling the RPC with chunk indices 0, 1, ...
+    /// until the device answers `InvalidArgs`.
+    pub fn read(mut self) -> Result<Vec<u8>, BulkError> {
+        let retries = self.retries.unwrap_or(3);
+        let mut chunks: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        // Request id to chunk index and attempt count.
+        let mut in_flight: HashMap<u16, (usize, usize)> = HashMap::new();
+        let mut next_chunk = 0;
+        let mut end = MAX_CHUNKS;
+
+        loop {
+            while (in_flight.len() < self.window) && (next_chunk < end) {
+                let id = self.send_chunk(&(next_chunk as u16).to_le_bytes())?;
+                in_flight.insert(id, (next_chunk, 0));
+                next_chunk += 1;
+            }
+            if in_flight.is_empty() {
+                break;
+            }
+
+            let (id, reply) = self.recv_reply()?;
+            let Some((chunk, mut attempts)) = in_flight.remove(&id) else {
+                continue;
+            };
+            match reply {
+                Reply::Ok(data) => {
+                    if chunk < end {
+                        self.chunk_done(data.len());
+                        chunks.insert(chunk, data);
+                    }
+                }
+                Reply::Err(RpcErrorCode::InvalidArgs) => end = end.min(chunk),
+                Reply::Err(RpcErrorCode::Timeout) => {
+                    self.retry(chunk, &mut attempts, retries)?;
+                    let id = self.send_chunk(&(chunk as u16).to_le_bytes())?;
+                    in_flight.insert(id, (chunk, attempts));
+                }
+                Reply::Err(error) if chunk < end => {
+                    return Err(BulkError::Rejected { chunk, error })
+                }
+                Reply::Err(_) => {}
+            }
+        }
+
+        let data: Vec<u8> = chunks.range(..end).flat_map(|(_, d)| d.clone()).collect();
+        self.verify(&data)?;
+        Ok(data)
+    }
+
+    /// Write `data` in chunks. A chunk that times out fails the write,
+    /// unless `retries` allows sending it again along with every chunk
+    /// after it, so the device sees them in order.
+    pub fn write(mut self, data: &[u8]) -> Result<(), BulkError> {
+        let retries = self.retries.unwrap_or(0);
+        let pieces: Vec<&[u8]> = data.chunks(self.chunk_size).collect();
+        let total = pieces.len();
+        if total > MAX_CHUNKS {
+            return Err(BulkError::TooLarge(MAX_CHUNKS));
+        }
+        self.progress.total_chunks = Some(total);
+
+        let mut in_flight: HashMap<u16, usize> = HashMap::new();
+        let mut attempts = vec![0usize; total];
+        let mut next_send = 0;
+        let mut next_ack = 0;
+
+        while next_ack < total {
+            while (next_send < total) && (next_send - next_ack < self.window) {
+                let id = self.send_chunk(pieces[next_send])?;
+                in_flight.insert(id, next_send);
+                next_send += 1;
+            }
+
+            let (id, reply) = self.recv_reply()?;
+            let Some(chunk) = in_flight.remove(&id) else {
+                continue;
+            };
+            match reply {
+                Reply::Ok(_) if chunk == next_ack => {
+                    self.chunk_done(pieces[chunk].len());
+                    next_ack += 1;
+                }
+                Reply::Ok(_) => {
+                    return Err(BulkError::OutOfOrder {
+                        expected: next_ack,
+                        got: chunk,
+                    })
+                }
+                Reply::Err(RpcErrorCode::Timeout) => {
+                    self.retry(chunk, &mut attempts[chunk], retries)?;
+                    // Forget about chunks sent after it, and start over
+                    // from there.
+                    in_flight.retain(|_, c| *c < chunk);
+                    next_send = chunk;
+                }
+                Reply::Err(error) => return Err(BulkError::Rejected { chunk, error }),
+            }
+        }
+
+        self.verify(data)
+    }
+}
@@ -1,9 +1,14 @@
+use super::bulk;
+use crate::data::calibration::Calibration;
+use crate::data::clock::{TimeRef, UtcMapping};
 use crate::data::{DeviceDataParser, DeviceFullMetadata, Sample};
 use crate::tio;
 use proto::DeviceRoute;
 use tio::{proto, proxy, util};
 
 use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// Device-level events (transport/connection layer).
 ///
@@ -60,6 +65,7 @@ pub enum DeviceItem {
 pub struct Device {
     dev_port: proxy::Port,
     parser: DeviceDataParser,
+    calibration: Option<Arc<Calibration>>,
     n_reqs: usize,
     metadata_announced: bool,
     sample_queue: VecDeque<Sample>,
@@ -68,9 +74,12 @@ pub struct Device {
 
 impl Device {
     pub fn new(dev_port: proxy::Port) -> Device {
+        let mut parser = DeviceDataParser::new(false);
+        parser.set_host_clock(true);
         Device {
             dev_port: dev_port,
-            parser: DeviceDataParser::new(false),
+            parser,
+            calibration: None,
             n_reqs: 0,
             metadata_announced: false,
             sample_queue: VecDeque::new(),
@@ -83,6 +92,18 @@ impl Device {
         Ok(Self::new(port))
     }
 
+    /// Calibrate samples from now on. See `DeviceDataParser::set_calibration`.
+    pub fn set_calibration(&mut self, calibration: Option<Arc<Calibration>>) {
+        self.parser.set_calibration(calibration.clone());
+        self.calibration = calibration;
+    }
+
+    /// Host estimate of UTC for the clock `time_ref`, to pass to
+    /// `Sample::utc`. See `DeviceDataParser::clock`.
+    pub fn clock(&self, time_ref: &TimeRef) -> Option<UtcMapping> {
+        self.parser.clock(time_ref)
+    }
+
     fn internal_rpcs(&mut self) -> Result<(), proxy::SendError> {
         if self.n_reqs == 0 {
             let reqs = self.parser.requests();
@@ -103,6 +124,8 @@ impl Device {
                 if matches!(ps.0, proto::ProxyStatus::SensorDisconnected) {
                     self.metadata_announced = false;
                     self.parser = DeviceDataParser::new(false);
+                    self.parser.set_calibration(self.calibration.clone());
+                    self.parser.set_host_clock(true);
                 }
 
                 // We might have a new hash on reconnect
@@ -159,6 +182,22 @@ impl Device {
     }
 
     pub fn get_metadata(&mut self) -> Result<DeviceFullMetadata, proxy::RpcError> {
+        self.metadata_until(None)
+    }
+
+    /// Like `get_metadata`, but fails with a `Timeout` error if the device
+    /// has not answered within `timeout`.
+    pub fn get_metadata_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<DeviceFullMetadata, proxy::RpcError> {
+        self.metadata_until(Some(Instant::now() + timeout))
+    }
+
+    fn metadata_until(
+        &mut self,
+        deadline: Option<Instant>,
+    ) -> Result<DeviceFullMetadata, proxy::RpcError> {
         loop {
             if self.n_reqs == 0 {
                 match self.parser.get_metadata() {
@@ -173,7 +212,26 @@ impl Device {
                     }
                 }
             }
-            let pkt = self.dev_port.recv().map_err(proxy::RpcError::RecvFailed)?;
+            let pkt = match deadline {
+                Some(deadline) => {
+                    self.dev_port
+                        .receiver()
+                        .recv_deadline(deadline)
+                        .map_err(|e| match e {
+                            crossbeam::channel::RecvTimeoutError::Timeout => {
+                                proxy::RpcError::ExecError(proto::RpcErrorPayload {
+                                    id: 0,
+                                    error: proto::RpcErrorCode::Timeout,
+                                    extra: vec![],
+                                })
+                            }
+                            crossbeam::channel::RecvTimeoutError::Disconnected => {
+                                proxy::RpcError::RecvFailed(proxy::RecvError::ProxyDisconnected)
+                            }
+                        })?
+                }
+                None => self.dev_port.recv().map_err(proxy::RpcError::RecvFailed)?,
+            };
             self.process_packet(&pkt);
         }
     }
@@ -323,27 +381,14 @@ impl Device {
         self.rpc(name, ())
     }
 
-    pub fn get_multi(&mut self, name: &str) -> Result<Vec<u8>, proxy::RpcError> {
-        let mut full_reply = vec![];
-
-        for i in 0u16..=65535u16 {
-            match self.raw_rpc(&name, &i.to_le_bytes().to_vec()) {
-                Ok(mut rep) => full_reply.append(&mut rep),
-                Err(err @ proxy::RpcError::ExecError(_)) => {
-                    if let proxy::RpcError::ExecError(payload) = &err {
-                        if let tio::proto::RpcErrorCode::InvalidArgs = payload.error {
-                            break;
-                        }
-                    }
-                    return Err(err);
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            }
-        }
+    /// Bulk transfer through the RPC `name`. Samples and events received
+    /// during the transfer are queued as usual.
+    pub fn bulk<'a>(&'a mut self, name: &str) -> bulk::BulkTransfer<'a, &'a mut Device> {
+        bulk::BulkTransfer::new(self, name)
+    }
 
-        Ok(full_reply)
+    pub fn get_multi(&mut self, name: &str) -> Result<Vec<u8>, proxy::RpcError> {
+        Ok(self.bulk(name).read()?)
     }
 
     pub fn get_multi_str(&mut self, name: &str) -> Result<String, proxy::RpcError> {
@@ -352,3 +397,16 @@ impl Device {
         Ok(result_string)
     }
 }
+
+impl bulk::BulkPort for &mut Device {
+    fn send_request(&mut self, pkt: tio::Packet) -> Result<(), proxy::SendError> {
+        self.internal_rpcs()?;
+        self.dev_port.send(pkt)
+    }
+
+    fn recv_packet(&mut self) -> Result<tio::Packet, proxy::RecvError> {
+        let pkt = self.dev_port.recv()?;
+        self.process_packet(&pkt);
+        Ok(pkt)
+    }
+}
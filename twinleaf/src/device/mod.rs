@@ -1,9 +1,10 @@
+pub mod bulk;
 mod device;
 pub mod discovery;
-mod rpc;
+pub mod rpc;
 mod tree;
 pub mod util;
 
 pub use device::{Device, DeviceEvent, DeviceItem};
-pub use rpc::{RpcClient, RpcDescriptor, RpcList, RpcRegistry, RpcValue, RpcValueType};
+pub use rpc::{RpcClient, RpcDescriptor, RpcList, RpcRegistry, RpcValue, RpcValueType, RpcWatcher};
 pub use tree::{DeviceTree, TreeEvent, TreeItem};
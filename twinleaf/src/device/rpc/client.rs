@@ -5,6 +5,12 @@ use directories::BaseDirs;
 use std::fs;
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// Cache files not used for this long are removed.
+const CACHE_EXPIRY: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 #[derive(Debug, thiserror::Error)]
 pub enum RpcListError {
@@ -29,11 +35,16 @@ pub struct RpcList {
 pub struct RpcClient {
     port: proxy::Port,
     root_route: DeviceRoute,
+    lists: Mutex<HashMap<DeviceRoute, RpcList>>,
 }
 
 impl RpcClient {
     pub fn new(port: proxy::Port, root_route: DeviceRoute) -> Self {
-        Self { port, root_route }
+        Self {
+            port,
+            root_route,
+            lists: Mutex::new(HashMap::new()),
+        }
     }
 
     pub fn open(proxy: &proxy::Interface, route: DeviceRoute) -> Result<Self, proxy::PortError> {
@@ -173,6 +184,7 @@ impl RpcClient {
         }
 
         writeln!(writer, "{:016x}", hasher.finish())?;
+        writer.flush()?;
         Ok(RpcList {
             route: route.clone(),
             hash,
@@ -181,7 +193,38 @@ impl RpcClient {
         })
     }
 
+    /// RPC list of the device at `route`. Lists are cached on disk as
+    /// `<dev_name>.<rpc hash>.rpcs` in the user's cache directory, and in
+    /// memory for the lifetime of the client, so only `rpc.hash` is queried
+    /// unless the device reports a hash that was not seen before.
     pub fn rpc_list(&self, route: &DeviceRoute) -> Result<RpcList, RpcListError> {
+        let hash: u32 = self
+            .get(route, "rpc.hash")
+            .map_err(RpcListError::DeviceRpcError)?;
+        self.rpc_list_with_hash(route, hash)
+    }
+
+    /// RPC list of the device at `route`, whose `rpc.hash` is already
+    /// known, for example from a `DeviceEvent::NewHash`.
+    pub fn rpc_list_with_hash(
+        &self,
+        route: &DeviceRoute,
+        hash: u32,
+    ) -> Result<RpcList, RpcListError> {
+        if let Some(list) = self.lists.lock().unwrap().get(route) {
+            if list.hash == hash {
+                return Ok(list.clone());
+            }
+        }
+        let list = self.load_rpc_list(route, hash)?;
+        self.lists
+            .lock()
+            .unwrap()
+            .insert(route.clone(), list.clone());
+        Ok(list)
+    }
+
+    fn load_rpc_list(&self, route: &DeviceRoute, hash: u32) -> Result<RpcList, RpcListError> {
         let tl_cache_dir = BaseDirs::new()
             .ok_or(RpcListError::CacheDirError)?
             .cache_dir()
@@ -191,39 +234,90 @@ impl RpcClient {
         let dev_name: String = self
             .get(route, "dev.name")
             .map_err(RpcListError::DeviceRpcError)?;
-        let hash: u32 = self
-            .get(route, "rpc.hash")
-            .map_err(RpcListError::DeviceRpcError)?;
-        // TODO: evict stale cache files from old firmware versions (<dev_name>.*.rpcs)
         let base_name = format!("{}.{:x}.rpcs", dev_name, hash);
         let file_path = tl_cache_dir.join(&base_name);
 
-        // Remove empty files left by aborted writes
-        match fs::metadata(&file_path) {
-            Ok(metadata) => match metadata.len() {
-                0 => fs::remove_file(&file_path),
-                _ => Ok(()),
-            },
-            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
-            Err(other_err) => Err(other_err),
-        }?;
-
-        let cache_file = fs::File::open(&file_path);
-        match cache_file {
-            Ok(file) => match self.read_rpc_cache(route, hash, file) {
-                Ok(rpclist) => Ok(rpclist),
-                Err(RpcListError::InvalidCacheError) => {
-                    fs::remove_file(&file_path)?;
-                    let cache_file = fs::File::create(&file_path)?;
-                    self.write_rpc_cache(route, hash, cache_file)
+        // Opened for writing too, which setting the modification time needs
+        // on some platforms.
+        match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&file_path)
+        {
+            Ok(file) => {
+                // Keep the file from being evicted while it is in use.
+                if let Err(e) = file.set_modified(SystemTime::now()) {
+                    eprintln!(
+                        "rpc cache: failed to mark {} as used: {e}",
+                        file_path.display()
+                    );
+                }
+                match self.read_rpc_cache(route, hash, file) {
+                    Ok(rpclist) => return Ok(rpclist),
+                    Err(RpcListError::InvalidCacheError) => fs::remove_file(&file_path)?,
+                    Err(other_err) => return Err(other_err),
                 }
-                Err(other_err) => Err(other_err),
-            },
-            Err(err) if err.kind() == io::ErrorKind::NotFound => {
-                let cache_file = fs::File::create(&file_path)?;
-                self.write_rpc_cache(route, hash, cache_file)
             }
-            Err(other_err) => Err(RpcListError::CacheFileError(other_err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(other_err) => return Err(RpcListError::CacheFileError(other_err)),
+        }
+
+        // Write to a temporary file first, so that other processes never
+        // see a partial list.
+        let tmp_path = tl_cache_dir.join(format!("{}.{}.tmp", base_name, std::process::id()));
+        let result = fs::File::create(&tmp_path)
+            .map_err(RpcListError::from)
+            .and_then(|file| self.write_rpc_cache(route, hash, file))
+            .and_then(|rpclist| {
+                fs::rename(&tmp_path, &file_path)?;
+                Ok(rpclist)
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        evict_stale_caches(&tl_cache_dir, &dev_name, CACHE_EXPIRY);
+        result
+    }
+
+    /// Forget the in-memory RPC list of `route`, so that the next
+    /// `rpc_list` reads it again from the disk cache or the device.
+    pub fn invalidate_rpc_list(&self, route: &DeviceRoute) {
+        self.lists.lock().unwrap().remove(route);
+    }
+}
+
+/// Remove cache files of `dev_name` in `dir` that have not been used for
+/// `max_age`, such as lists for firmware that was since upgraded, and
+/// temporary files left by interrupted writes. `RpcClient` does this with
+/// an age of 30 days whenever it writes a new list.
+pub fn evict_stale_caches(dir: &Path, dev_name: &str, max_age: Duration) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let prefix = format!("{}.", dev_name);
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(rest) = file_name.to_str().and_then(|n| n.strip_prefix(&prefix)) else {
+            continue;
+        };
+        // "<hash>.rpcs" or "<hash>.rpcs.<pid>.tmp". Checking the hash keeps
+        // "vmr.x.1234.rpcs" from counting as a cache file of "vmr".
+        let Some((hash, suffix)) = rest.split_once('.') else {
+            continue;
+        };
+        let ours = u32::from_str_radix(hash, 16).is_ok()
+            && (suffix == "rpcs" || (suffix.starts_with("rpcs.") && suffix.ends_with(".tmp")));
+        if !ours {
+            continue;
+        }
+        let unused = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.elapsed().ok())
+            .is_some_and(|age| age > max_age);
+        if unused {
+            let _ = fs::remove_file(entry.path());
         }
     }
 }
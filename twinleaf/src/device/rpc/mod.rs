@@ -1,7 +1,14 @@
 mod client;
 mod registry;
+mod settings;
 mod value;
+mod watch;
 
-pub use client::{RpcClient, RpcList};
+pub use client::{evict_stale_caches, RpcClient, RpcList, RpcListError};
 pub use registry::{RpcDescriptor, RpcRegistry};
+pub use settings::{
+    RestorePlan, Setting, SettingChange, SettingDiff, SettingsError, SettingsSnapshot, SkipReason,
+};
+pub(crate) use value::split_top_level;
 pub use value::{DecodeError, EncodeError, RpcValue, RpcValueType};
+pub use watch::{RpcWatcher, WatchError, WatchEvent};
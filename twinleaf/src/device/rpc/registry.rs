@@ -33,39 +33,11 @@ impl RpcDescriptor {
     }
 
     pub fn type_str(&self) -> String {
-        match self.data_kind {
-            RpcValueType::Unit => "".to_string(),
-            RpcValueType::Int { signed, size } => {
-                let bits = (size as usize) * 8;
-                if signed {
-                    format!("i{bits}")
-                } else {
-                    format!("u{bits}")
-                }
-            }
-            RpcValueType::Float { size } => {
-                let bits = (size as usize) * 8;
-                format!("f{bits}")
-            }
-            RpcValueType::String { max_len } => {
-                if let Some(n) = max_len {
-                    format!("string<{n}>")
-                } else {
-                    "string".to_string()
-                }
-            }
-            RpcValueType::Raw { .. } => "".to_string(),
-        }
+        self.data_kind.type_str()
     }
 
     pub fn size_bytes(&self) -> Option<usize> {
-        match self.data_kind {
-            RpcValueType::Unit => Some(0),
-            RpcValueType::Int { size, .. } => Some(size as usize),
-            RpcValueType::Float { size } => Some(size as usize),
-            RpcValueType::String { .. } => None,
-            RpcValueType::Raw { .. } => None,
-        }
+        self.data_kind.fixed_size()
     }
 }
 
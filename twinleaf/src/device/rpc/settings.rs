@@ -0,0 +1,479 @@
+//! Device settings snapshots
+//!
+//! A snapshot holds the values of every readable and writable RPC of a
+//! device, with their types, so that they can be saved to a TOML file and
+//! written back later to the same device, or to another one of the same
+//! kind. Snapshots of all readable values can also be compared, between two
+//! devices or against a saved file.
+
+use super::client::{RpcClient, RpcListError};
+use super::registry::RpcDescriptor;
+use super::value::{DecodeError, EncodeError, RpcValue, RpcValueType};
+use crate::device::util::{parse_rpc_spec, rpc_decode_reply, rpc_encode_arg};
+use crate::tio::{proto::DeviceRoute, proxy};
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use toml_edit::{DocumentMut, InlineTable, Item, Table, Value};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SettingsError {
+    #[error("failed to get RPC list: {0}")]
+    RpcList(#[from] RpcListError),
+    #[error("RPC {name} failed: {source}")]
+    Rpc {
+        name: String,
+        source: proxy::RpcError,
+    },
+    #[error("cannot decode value of {name}: {source}")]
+    Decode { name: String, source: DecodeError },
+    #[error("invalid value for {name}: {source}")]
+    Encode { name: String, source: EncodeError },
+    #[error("invalid settings file: {0}")]
+    Parse(String),
+}
+
+/// Saved value of one RPC.
+#[derive(Debug, Clone)]
+pub struct Setting {
+    pub name: String,
+    pub kind: RpcValueType,
+    pub writable: bool,
+    pub persistent: bool,
+    pub value: RpcValue,
+    /// Value as sent to and received from the device.
+    pub raw: Vec<u8>,
+}
+
+impl Setting {
+    fn new(desc: &RpcDescriptor, raw: Vec<u8>) -> Result<Setting, SettingsError> {
+        let value =
+            rpc_decode_reply(&raw, &desc.data_kind).map_err(|source| SettingsError::Decode {
+                name: desc.full_name.clone(),
+                source,
+            })?;
+        Ok(Setting {
+            name: desc.full_name.clone(),
+            kind: desc.data_kind.clone(),
+            writable: desc.writable,
+            persistent: desc.persistent,
+            value,
+            raw,
+        })
+    }
+
+    /// Permissions in the format of `RpcDescriptor::perm_str`.
+    pub fn perm_str(&self) -> String {
+        format!(
+            "R{}{}",
+            if self.writable { "W" } else { "-" },
+            if self.persistent { "P" } else { "-" },
+        )
+    }
+}
+
+/// Settings of a device at a point in time.
+#[derive(Debug, Clone, Default)]
+pub struct SettingsSnapshot {
+    /// Name of the device the settings were read from (`dev.name`).
+    pub device: Option<String>,
+    pub settings: Vec<Setting>,
+    /// Settings that could not be read from the device, with the error, and
+    /// are missing from `settings`. Not saved to TOML.
+    pub unread: Vec<(String, String)>,
+}
+
+/// Change that restoring a snapshot makes to one RPC.
+#[derive(Debug, Clone)]
+pub struct SettingChange {
+    pub setting: Setting,
+    /// Value on the device before restoring.
+    pub current: RpcValue,
+}
+
+/// Difference between two snapshots, for one RPC.
+#[derive(Debug, Clone)]
+pub enum SettingDiff {
+    /// Only in the first snapshot.
+    OnlyLeft(Setting),
+    /// Only in the second snapshot.
+    OnlyRight(Setting),
+    /// Same RPC, with a different type. Values are not compared.
+    Type { left: Setting, right: Setting },
+    /// Same RPC and type, with different values and/or permissions.
+    Value { left: Setting, right: Setting },
+}
+
+impl SettingDiff {
+    pub fn name(&self) -> &str {
+        match self {
+            SettingDiff::OnlyLeft(s) | SettingDiff::OnlyRight(s) => &s.name,
+            SettingDiff::Type { left, .. } | SettingDiff::Value { left, .. } => &left.name,
+        }
+    }
+}
+
+/// Why a saved setting cannot be restored to a device.
+#[derive(Debug, Clone)]
+pub enum SkipReason {
+    NotFound,
+    NotWritable,
+    TypeChanged { device: String },
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SkipReason::NotFound => write!(f, "not found on device"),
+            SkipReason::NotWritable => write!(f, "not writable on device"),
+            SkipReason::TypeChanged { device } => write!(f, "device type is {}", device),
+        }
+    }
+}
+
+/// What restoring a snapshot to a device would do.
+#[derive(Debug, Clone, Default)]
+pub struct RestorePlan {
+    pub changes: Vec<SettingChange>,
+    /// Settings already at the saved value.
+    pub unchanged: usize,
+    pub skipped: Vec<(String, SkipReason)>,
+}
+
+fn same_type(a: &RpcValueType, b: &RpcValueType) -> bool {
+    a.type_str() == b.type_str()
+}
+
+/// True for RPCs with a value that can be read and compared.
+fn is_value(desc: &RpcDescriptor) -> bool {
+    desc.readable
+        && !matches!(
+            desc.data_kind,
+            RpcValueType::Unit | RpcValueType::Raw { .. }
+        )
+}
+
+/// True for RPCs whose value can be saved and restored.
+fn is_setting(desc: &RpcDescriptor) -> bool {
+    is_value(desc) && desc.writable
+}
+
+fn toml_value(value: &RpcValue) -> Value {
+    match value {
+        RpcValue::U64(n) => match i64::try_from(*n) {
+            Ok(n) => n.into(),
+            Err(_) => n.to_string().into(),
+        },
+        RpcValue::I64(n) => (*n).into(),
+        RpcValue::F64(x) => (*x).into(),
+        RpcValue::Array(values) => Value::Array(values.iter().map(toml_value).collect()),
+        RpcValue::Struct(fields) => Value::InlineTable(
+            fields
+                .iter()
+                .map(|(name, value)| (name.as_str(), toml_value(value)))
+                .collect(),
+        ),
+        other => other.to_string().into(),
+    }
+}
+
+/// Text form of a TOML value, as accepted by `rpc_encode_arg`.
+fn toml_text(value: &Value) -> Option<String> {
+    Some(match value {
+        Value::String(s) => format!("{:?}", s.value()),
+        Value::Integer(n) => n.value().to_string(),
+        Value::Float(x) => x.value().to_string(),
+        Value::Array(values) => {
+            let items: Option<Vec<String>> = values.iter().map(toml_text).collect();
+            format!("[{}]", items?.join(", "))
+        }
+        Value::InlineTable(table) => {
+            let fields: Option<Vec<String>> = table
+                .iter()
+                .map(|(name, value)| Some(format!("{}: {}", name, toml_text(value)?)))
+                .collect();
+            format!("{{{}}}", fields?.join(", "))
+        }
+        _ => return None,
+    })
+}
+
+impl SettingsSnapshot {
+    /// Serialize to a TOML document.
+    pub fn to_toml(&self) -> String {
+        let mut doc = DocumentMut::new();
+        if let Some(name) = &self.device {
+            doc["device"] = toml_edit::value(name.as_str());
+        }
+        let mut table = Table::new();
+        for setting in &self.settings {
+            let mut entry = InlineTable::new();
+            entry.insert("type", setting.kind.type_str().into());
+            entry.insert("value", toml_value(&setting.value));
+            if !setting.writable {
+                entry.insert("writable", false.into());
+            }
+            if setting.persistent {
+                entry.insert("persistent", true.into());
+            }
+            table.insert(&setting.name, Item::Value(Value::InlineTable(entry)));
+        }
+        doc["settings"] = Item::Table(table);
+        doc.to_string()
+    }
+
+    /// Parse a TOML document written by `to_toml`.
+    pub fn from_toml(s: &str) -> Result<SettingsSnapshot, SettingsError> {
+        let doc: DocumentMut = s
+            .parse()
+            .map_err(|e: toml_edit::TomlError| SettingsError::Parse(e.to_string()))?;
+        let device = doc
+            .get("device")
+            .and_then(|d| d.as_str())
+            .map(|s| s.to_string());
+        let table = doc
+            .get("settings")
+            .and_then(|t| t.as_table_like())
+            .ok_or_else(|| SettingsError::Parse("missing [settings] table".to_string()))?;
+
+        let mut settings = vec![];
+        for (name, item) in table.iter() {
+            let invalid = |what: &str| SettingsError::Parse(format!("{}: {}", name, what));
+            let entry = item
+                .as_table_like()
+                .ok_or_else(|| invalid("expected a table"))?;
+            let kind = entry
+                .get("type")
+                .and_then(|t| t.as_str())
+                .and_then(RpcValueType::from_type_str)
+                .ok_or_else(|| invalid("missing or unknown type"))?;
+            let value = entry
+                .get("value")
+                .and_then(|v| v.as_value())
+                .ok_or_else(|| invalid("missing value"))?;
+            let text = match value {
+                Value::String(s) => s.value().clone(),
+                other => toml_text(other).ok_or_else(|| invalid("unsupported value"))?,
+            };
+            let raw = rpc_encode_arg(&text, &kind).map_err(|source| SettingsError::Encode {
+                name: name.to_string(),
+                source,
+            })?;
+            let value = rpc_decode_reply(&raw, &kind).map_err(|source| SettingsError::Decode {
+                name: name.to_string(),
+                source,
+            })?;
+            settings.push(Setting {
+                name: name.to_string(),
+                kind,
+                writable: entry
+                    .get("writable")
+                    .and_then(|w| w.as_bool())
+                    .unwrap_or(true),
+                persistent: entry
+                    .get("persistent")
+                    .and_then(|p| p.as_bool())
+                    .unwrap_or(false),
+                value,
+                raw,
+            });
+        }
+        Ok(SettingsSnapshot {
+            device,
+            settings,
+            unread: vec![],
+        })
+    }
+
+    /// Compare with another snapshot. Differences are sorted by RPC name.
+    pub fn diff(&self, other: &SettingsSnapshot) -> Vec<SettingDiff> {
+        let right: HashMap<&str, &Setting> = other
+            .settings
+            .iter()
+            .map(|s| (s.name.as_str(), s))
+            .collect();
+        let mut diffs = vec![];
+        for left in &self.settings {
+            let right = match right.get(left.name.as_str()) {
+                Some(right) => *right,
+                None => {
+                    diffs.push(SettingDiff::OnlyLeft(left.clone()));
+                    continue;
+                }
+            };
+            let (left, right) = (left.clone(), right.clone());
+            if !same_type(&left.kind, &right.kind) {
+                diffs.push(SettingDiff::Type { left, right });
+            } else if (left.raw != right.raw) || (left.perm_str() != right.perm_str()) {
+                diffs.push(SettingDiff::Value { left, right });
+            }
+        }
+        let left: HashMap<&str, &Setting> =
+            self.settings.iter().map(|s| (s.name.as_str(), s)).collect();
+        for right in &other.settings {
+            if !left.contains_key(right.name.as_str()) {
+                diffs.push(SettingDiff::OnlyRight(right.clone()));
+            }
+        }
+        diffs.sort_by(|a, b| a.name().cmp(b.name()));
+        diffs
+    }
+}
+
+impl RestorePlan {
+    /// Human readable preview of the changes, one per line.
+    pub fn preview(&self) -> String {
+        let mut out = String::new();
+        for change in &self.changes {
+            let _ = writeln!(
+                out,
+                "{}: {} -> {}",
+                change.setting.name, change.current, change.setting.value
+            );
+        }
+        for (name, reason) in &self.skipped {
+            let _ = writeln!(out, "{}: skipped, {}", name, reason);
+        }
+        out
+    }
+}
+
+impl RpcClient {
+    fn settings_descriptors(
+        &self,
+        route: &DeviceRoute,
+    ) -> Result<HashMap<String, RpcDescriptor>, SettingsError> {
+        let list = self.rpc_list(route)?;
+        Ok(list
+            .vec
+            .iter()
+            .map(|(name, meta)| (name.clone(), parse_rpc_spec(*meta, name.clone())))
+            .collect())
+    }
+
+    fn read_setting(
+        &self,
+        route: &DeviceRoute,
+        desc: &RpcDescriptor,
+    ) -> Result<Setting, SettingsError> {
+        let raw = self
+            .raw_rpc(route, &desc.full_name, &[])
+            .map_err(|source| SettingsError::Rpc {
+                name: desc.full_name.clone(),
+                source,
+            })?;
+        Setting::new(desc, raw)
+    }
+
+    fn snapshot(
+        &self,
+        route: &DeviceRoute,
+        filter: fn(&RpcDescriptor) -> bool,
+    ) -> Result<SettingsSnapshot, SettingsError> {
+        let list = self.rpc_list(route)?;
+        let mut settings = vec![];
+        let mut unread = vec![];
+        for (name, meta) in &list.vec {
+            let desc = parse_rpc_spec(*meta, name.clone());
+            if filter(&desc) {
+                match self.read_setting(route, &desc) {
+                    Ok(setting) => settings.push(setting),
+                    Err(err) => unread.push((name.clone(), err.to_string())),
+                }
+            }
+        }
+        Ok(SettingsSnapshot {
+            device: self.get(route, "dev.name").ok(),
+            settings,
+            unread,
+        })
+    }
+
+    /// Read every readable and writable RPC of the device at `route`. RPCs
+    /// that fail to read are listed in `SettingsSnapshot::unread`.
+    pub fn save_settings(&self, route: &DeviceRoute) -> Result<SettingsSnapshot, SettingsError> {
+        self.snapshot(route, is_setting)
+    }
+
+    /// Read every readable RPC of the device at `route`, including read-only
+    /// ones. Mostly useful to compare devices.
+    pub fn read_values(&self, route: &DeviceRoute) -> Result<SettingsSnapshot, SettingsError> {
+        self.snapshot(route, is_value)
+    }
+
+    /// Compare a snapshot with the current settings of the device at
+    /// `route`, without changing anything.
+    pub fn plan_restore(
+        &self,
+        route: &DeviceRoute,
+        snapshot: &SettingsSnapshot,
+    ) -> Result<RestorePlan, SettingsError> {
+        let descs = self.settings_descriptors(route)?;
+        let mut plan = RestorePlan::default();
+        for setting in &snapshot.settings {
+            let desc = match descs.get(&setting.name) {
+                Some(desc) => desc,
+                None => {
+                    plan.skipped
+                        .push((setting.name.clone(), SkipReason::NotFound));
+                    continue;
+                }
+            };
+            if !setting.writable || !is_setting(desc) {
+                plan.skipped
+                    .push((setting.name.clone(), SkipReason::NotWritable));
+                continue;
+            }
+            if !same_type(&desc.data_kind, &setting.kind) {
+                plan.skipped.push((
+                    setting.name.clone(),
+                    SkipReason::TypeChanged {
+                        device: desc.type_str(),
+                    },
+                ));
+                continue;
+            }
+            let current = self.read_setting(route, desc)?;
+            if current.raw == setting.raw {
+                plan.unchanged += 1;
+            } else {
+                plan.changes.push(SettingChange {
+                    setting: setting.clone(),
+                    current: current.value,
+                });
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Write the changes of a plan from `plan_restore` to the device.
+    pub fn apply_restore(
+        &self,
+        route: &DeviceRoute,
+        plan: &RestorePlan,
+    ) -> Result<(), SettingsError> {
+        for change in &plan.changes {
+            self.raw_rpc(route, &change.setting.name, &change.setting.raw)
+                .map_err(|source| SettingsError::Rpc {
+                    name: change.setting.name.clone(),
+                    source,
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Restore a snapshot to the device at `route`. With `dry_run`, only
+    /// report what would change.
+    pub fn restore_settings(
+        &self,
+        route: &DeviceRoute,
+        snapshot: &SettingsSnapshot,
+        dry_run: bool,
+    ) -> Result<RestorePlan, SettingsError> {
+        let plan = self.plan_restore(route, snapshot)?;
+        if !dry_run {
+            self.apply_restore(route, &plan)?;
+        }
+        Ok(plan)
+    }
+}
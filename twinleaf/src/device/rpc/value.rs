@@ -6,6 +6,17 @@ pub enum RpcValue {
     F64(f64),
     Str(String),
     Bytes(Vec<u8>),
+    Array(Vec<RpcValue>),
+    Struct(Vec<(String, RpcValue)>),
+}
+
+/// Element of an array or struct, with strings quoted so the result can be
+/// parsed back by `rpc_encode_arg`.
+fn fmt_element(value: &RpcValue, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match value {
+        RpcValue::Str(s) => write!(f, "{:?}", s),
+        other => write!(f, "{}", other),
+    }
 }
 
 impl std::fmt::Display for RpcValue {
@@ -22,6 +33,27 @@ impl std::fmt::Display for RpcValue {
                 }
                 Ok(())
             }
+            RpcValue::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    fmt_element(value, f)?;
+                }
+                write!(f, "]")
+            }
+            RpcValue::Struct(fields) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: ", name)?;
+                    fmt_element(value, f)?;
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
@@ -29,10 +61,198 @@ impl std::fmt::Display for RpcValue {
 #[derive(Debug, Clone)]
 pub enum RpcValueType {
     Unit,
-    Int { signed: bool, size: u8 },
-    Float { size: u8 },
-    String { max_len: Option<u16> },
-    Raw { meta: u16 },
+    Int {
+        signed: bool,
+        size: u8,
+    },
+    Float {
+        size: u8,
+    },
+    String {
+        max_len: Option<u16>,
+    },
+    Raw {
+        meta: u16,
+    },
+    /// Elements packed back to back. Without a length, the array takes up
+    /// the rest of the payload.
+    Array {
+        elem: Box<RpcValueType>,
+        len: Option<usize>,
+    },
+    /// Fields packed back to back, in order. Only the last field can have a
+    /// variable size.
+    Struct {
+        fields: Vec<(String, RpcValueType)>,
+    },
+}
+
+impl RpcValueType {
+    /// Short type name, e.g. `u16`, `f32`, `string<8>`, `[f32; 3]` or
+    /// `{x: f32, y: f32}`. Empty for unit and raw types.
+    pub fn type_str(&self) -> String {
+        match *self {
+            Self::Unit => "".to_string(),
+            Self::Int { signed, size } => {
+                let bits = (size as usize) * 8;
+                if signed {
+                    format!("i{bits}")
+                } else {
+                    format!("u{bits}")
+                }
+            }
+            Self::Float { size } => {
+                let bits = (size as usize) * 8;
+                format!("f{bits}")
+            }
+            Self::String { max_len } => {
+                if let Some(n) = max_len {
+                    format!("string<{n}>")
+                } else {
+                    "string".to_string()
+                }
+            }
+            Self::Raw { .. } => "".to_string(),
+            Self::Array { ref elem, len } => match len {
+                Some(n) => format!("[{}; {}]", elem.type_str(), n),
+                None => format!("[{}]", elem.type_str()),
+            },
+            Self::Struct { ref fields } => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(name, kind)| format!("{}: {}", name, kind.type_str()))
+                    .collect();
+                format!("{{{}}}", fields.join(", "))
+            }
+        }
+    }
+
+    /// Size of values of this type in bytes, `None` if it varies.
+    pub fn fixed_size(&self) -> Option<usize> {
+        match self {
+            Self::Unit => Some(0),
+            Self::Int { size, .. } | Self::Float { size } => Some(*size as usize),
+            Self::String { .. } | Self::Raw { .. } => None,
+            Self::Array { elem, len } => Some(elem.fixed_size()? * (*len)?),
+            Self::Struct { fields } => fields.iter().map(|(_, kind)| kind.fixed_size()).sum(),
+        }
+    }
+
+    /// Parse a type name from `type_str`.
+    pub fn from_type_str(s: &str) -> Option<RpcValueType> {
+        Some(match s {
+            "u8" => Self::Int {
+                signed: false,
+                size: 1,
+            },
+            "u16" => Self::Int {
+                signed: false,
+                size: 2,
+            },
+            "u32" => Self::Int {
+                signed: false,
+                size: 4,
+            },
+            "u64" => Self::Int {
+                signed: false,
+                size: 8,
+            },
+            "i8" => Self::Int {
+                signed: true,
+                size: 1,
+            },
+            "i16" => Self::Int {
+                signed: true,
+                size: 2,
+            },
+            "i32" => Self::Int {
+                signed: true,
+                size: 4,
+            },
+            "i64" => Self::Int {
+                signed: true,
+                size: 8,
+            },
+            "f32" => Self::Float { size: 4 },
+            "f64" => Self::Float { size: 8 },
+            "string" => Self::String { max_len: None },
+            s if s.starts_with('[') => {
+                let inner = s.strip_prefix('[')?.strip_suffix(']')?;
+                let (elem, len) = match split_top_level(inner, ';').as_slice() {
+                    [elem] => (*elem, None),
+                    [elem, len] => (*elem, Some(len.trim().parse().ok()?)),
+                    _ => return None,
+                };
+                let elem = Self::from_type_str(elem.trim())?;
+                if elem.fixed_size()? == 0 {
+                    return None;
+                }
+                Self::Array {
+                    elem: Box::new(elem),
+                    len,
+                }
+            }
+            s if s.starts_with('{') => {
+                let inner = s.strip_prefix('{')?.strip_suffix('}')?;
+                let mut fields = vec![];
+                for field in split_top_level(inner, ',') {
+                    let (name, kind) = field.split_once(':')?;
+                    let name = name.trim();
+                    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                        return None;
+                    }
+                    fields.push((name.to_string(), Self::from_type_str(kind.trim())?));
+                }
+                let (_, init) = fields.split_last()?;
+                if init.iter().any(|(_, kind)| kind.fixed_size().is_none()) {
+                    return None;
+                }
+                Self::Struct { fields }
+            }
+            _ => {
+                let n = s.strip_prefix("string<")?.strip_suffix('>')?;
+                Self::String {
+                    max_len: Some(n.parse().ok()?),
+                }
+            }
+        })
+    }
+}
+
+/// Split `s` at `sep`, except within brackets, braces or double quotes.
+/// Returns no parts for a blank string.
+pub(crate) fn split_top_level(s: &str, sep: char) -> Vec<&str> {
+    if s.trim().is_empty() {
+        return vec![];
+    }
+    let mut parts = vec![];
+    let mut depth = 0i32;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        if quoted {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => quoted = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => quoted = true,
+            '[' | '{' => depth += 1,
+            ']' | '}' => depth -= 1,
+            c if (c == sep) && (depth == 0) => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -49,6 +269,14 @@ pub enum EncodeError {
     UnsupportedFloatSize(u8),
     #[error("value not encodable for kind '{0}'")]
     NotEncodableForKind(&'static str),
+    #[error("expected {expected} values, got {actual}")]
+    WrongLength { expected: usize, actual: usize },
+    #[error("malformed value: {0}")]
+    Malformed(String),
+    #[error("unknown field '{0}'")]
+    UnknownField(String),
+    #[error("missing field '{0}'")]
+    MissingField(String),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -61,4 +289,8 @@ pub enum DecodeError {
     UnsupportedIntSize(u8),
     #[error("unsupported float size: {0} bytes")]
     UnsupportedFloatSize(u8),
+    #[error("elements of '{0}' have a variable size")]
+    VariableSize(String),
+    #[error("{0} bytes left over")]
+    TrailingBytes(usize),
 }
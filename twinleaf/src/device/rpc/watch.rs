@@ -0,0 +1,218 @@
+//! RPC value watcher
+//!
+//! Polls readable RPCs of one device at individual intervals and reports
+//! values only when they change. RPCs are re-read right away when the proxy
+//! reports that another client changed them.
+
+use super::client::{RpcClient, RpcListError};
+use super::registry::RpcDescriptor;
+use super::value::{DecodeError, RpcValue};
+use crate::device::util::{parse_rpc_spec, rpc_decode_reply};
+use crate::device::DeviceEvent;
+use crate::tio::proto::{self, DeviceRoute, RpcMethod};
+use crate::tio::proxy;
+
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, thiserror::Error)]
+pub enum WatchError {
+    #[error("failed to get RPC list: {0}")]
+    RpcList(#[from] RpcListError),
+    #[error("RPC '{0}' not found")]
+    NotFound(String),
+    #[error("RPC '{0}' is not readable")]
+    NotReadable(String),
+}
+
+/// Change reported by `RpcWatcher::poll`.
+#[derive(Debug)]
+pub enum WatchEvent {
+    /// First value read, or a value different from the previous one.
+    Changed {
+        name: String,
+        time: SystemTime,
+        value: RpcValue,
+        previous: Option<RpcValue>,
+    },
+    /// Reading the RPC failed. Reported once, until it succeeds again.
+    Failed {
+        name: String,
+        time: SystemTime,
+        error: proxy::RpcError,
+    },
+    /// The reply could not be decoded as the RPC's type.
+    DecodeFailed {
+        name: String,
+        time: SystemTime,
+        error: DecodeError,
+    },
+}
+
+impl WatchEvent {
+    pub fn name(&self) -> &str {
+        match self {
+            WatchEvent::Changed { name, .. }
+            | WatchEvent::Failed { name, .. }
+            | WatchEvent::DecodeFailed { name, .. } => name,
+        }
+    }
+}
+
+struct Watch {
+    desc: RpcDescriptor,
+    /// Position in the device's RPC list, which is how RPCs called by id
+    /// are identified.
+    id: u16,
+    interval: Duration,
+    next_read: Instant,
+    last: Option<Vec<u8>>,
+    last_value: Option<RpcValue>,
+    failing: bool,
+}
+
+/// Poller for a set of RPCs of the device at one route.
+pub struct RpcWatcher {
+    route: DeviceRoute,
+    watches: Vec<Watch>,
+}
+
+impl RpcWatcher {
+    pub fn new(route: DeviceRoute) -> RpcWatcher {
+        RpcWatcher {
+            route,
+            watches: vec![],
+        }
+    }
+
+    pub fn route(&self) -> &DeviceRoute {
+        &self.route
+    }
+
+    /// Start watching `name`, reading it every `interval`. The first read
+    /// happens on the next `poll`. Watching an RPC again changes its
+    /// interval.
+    pub fn watch(
+        &mut self,
+        client: &RpcClient,
+        name: &str,
+        interval: Duration,
+    ) -> Result<(), WatchError> {
+        if let Some(watch) = self.watches.iter_mut().find(|w| w.desc.full_name == name) {
+            watch.interval = interval;
+            return Ok(());
+        }
+        let list = client.rpc_list(&self.route)?;
+        let id = list
+            .vec
+            .iter()
+            .position(|(rpc, _)| rpc == name)
+            .ok_or_else(|| WatchError::NotFound(name.to_string()))?;
+        let desc = parse_rpc_spec(list.vec[id].1, name.to_string());
+        if !desc.readable {
+            return Err(WatchError::NotReadable(name.to_string()));
+        }
+        self.watches.push(Watch {
+            desc,
+            id: id as u16,
+            interval,
+            next_read: Instant::now(),
+            last: None,
+            last_value: None,
+            failing: false,
+        });
+        Ok(())
+    }
+
+    /// Stop watching `name`.
+    pub fn unwatch(&mut self, name: &str) {
+        self.watches.retain(|w| w.desc.full_name != name);
+    }
+
+    /// Names of the watched RPCs.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.watches.iter().map(|w| w.desc.full_name.as_str())
+    }
+
+    /// Re-read `method` on the next `poll`, if it is watched.
+    pub fn invalidate(&mut self, method: &RpcMethod) {
+        let now = Instant::now();
+        for watch in &mut self.watches {
+            let matches = match method {
+                RpcMethod::Name(name) => *name == watch.desc.full_name,
+                RpcMethod::Id(id) => *id == watch.id,
+            };
+            if matches {
+                watch.next_read = now;
+            }
+        }
+    }
+
+    /// Re-read every watched RPC on the next `poll`.
+    pub fn invalidate_all(&mut self) {
+        let now = Instant::now();
+        for watch in &mut self.watches {
+            watch.next_read = now;
+        }
+    }
+
+    /// Update the schedule from a device event: invalidated RPCs are read
+    /// again, and everything is read again after the sensor reconnects.
+    pub fn handle_event(&mut self, event: &DeviceEvent) {
+        match event {
+            DeviceEvent::RpcInvalidated(method) => self.invalidate(method),
+            DeviceEvent::Status(proto::ProxyStatus::SensorReconnected) => self.invalidate_all(),
+            _ => {}
+        }
+    }
+
+    /// When the next read is due, `None` if nothing is watched.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.watches.iter().map(|w| w.next_read).min()
+    }
+
+    /// Read the RPCs that are due, and return what changed.
+    pub fn poll(&mut self, client: &RpcClient) -> Vec<WatchEvent> {
+        let mut events = vec![];
+        for watch in &mut self.watches {
+            if Instant::now() < watch.next_read {
+                continue;
+            }
+            let result = client.raw_rpc(&self.route, &watch.desc.full_name, &[]);
+            let time = SystemTime::now();
+            watch.next_read = Instant::now() + watch.interval;
+            let name = watch.desc.full_name.clone();
+
+            let raw = match result {
+                Ok(raw) => raw,
+                Err(error) => {
+                    if !watch.failing {
+                        watch.failing = true;
+                        events.push(WatchEvent::Failed { name, time, error });
+                    }
+                    continue;
+                }
+            };
+            let recovered = std::mem::replace(&mut watch.failing, false);
+            if !recovered && (watch.last.as_ref() == Some(&raw)) {
+                continue;
+            }
+            match rpc_decode_reply(&raw, &watch.desc.data_kind) {
+                Ok(value) => {
+                    let previous = watch.last_value.replace(value.clone());
+                    events.push(WatchEvent::Changed {
+                        name,
+                        time,
+                        value,
+                        previous,
+                    });
+                }
+                Err(error) => {
+                    watch.last_value = None;
+                    events.push(WatchEvent::DecodeFailed { name, time, error });
+                }
+            }
+            watch.last = Some(raw);
+        }
+        events
+    }
+}
@@ -1,9 +1,13 @@
+use super::bulk;
+use crate::data::calibration::Calibration;
+use crate::data::clock::{TimeRef, UtcMapping};
 use crate::data::{DeviceDataParser, DeviceFullMetadata, Sample};
 use crate::tio;
 use proto::DeviceRoute;
 use tio::{proto, proxy, util};
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 /// Events from a DeviceTree (multi-device monitoring).
 #[derive(Debug, Clone)]
@@ -28,9 +32,11 @@ pub struct DeviceTree {
     port: proxy::Port,
     root_route: DeviceRoute,
     parsers: HashMap<DeviceRoute, DeviceDataParser>,
+    calibration: Option<Arc<Calibration>>,
     n_reqs: HashMap<DeviceRoute, usize>,
     known_routes: HashSet<DeviceRoute>,
     metadata_announced: HashSet<DeviceRoute>,
+    time_refs: HashMap<DeviceRoute, TimeRef>,
     sample_queue: VecDeque<(Sample, DeviceRoute)>,
     event_queue: VecDeque<TreeEvent>,
 }
@@ -41,9 +47,11 @@ impl DeviceTree {
             port,
             root_route,
             parsers: HashMap::new(),
+            calibration: None,
             n_reqs: HashMap::new(),
             known_routes: HashSet::new(),
             metadata_announced: HashSet::new(),
+            time_refs: HashMap::new(),
             sample_queue: VecDeque::new(),
             event_queue: VecDeque::new(),
         }
@@ -57,10 +65,23 @@ impl DeviceTree {
         Ok(Self::new(port, route))
     }
 
+    /// Calibrate samples from every device from now on. See
+    /// `DeviceDataParser::set_calibration`.
+    pub fn set_calibration(&mut self, calibration: Option<Arc<Calibration>>) {
+        for parser in self.parsers.values_mut() {
+            parser.set_calibration(calibration.clone());
+        }
+        self.calibration = calibration;
+    }
+
     fn get_or_create_parser(&mut self, route: &DeviceRoute) -> &mut DeviceDataParser {
-        self.parsers
-            .entry(route.clone())
-            .or_insert_with(|| DeviceDataParser::new(false))
+        let calibration = &self.calibration;
+        self.parsers.entry(route.clone()).or_insert_with(|| {
+            let mut parser = DeviceDataParser::new(false);
+            parser.set_calibration(calibration.clone());
+            parser.set_host_clock(true);
+            parser
+        })
     }
 
     fn internal_rpcs(&mut self) -> Result<(), proxy::SendError> {
@@ -108,6 +129,7 @@ impl DeviceTree {
                 if matches!(ps.0, proto::ProxyStatus::SensorDisconnected) {
                     self.metadata_announced = HashSet::new();
                     self.parsers = HashMap::new();
+                    self.time_refs = HashMap::new();
                 }
 
                 // We might have new hash(es) on reconnect
@@ -171,6 +193,10 @@ impl DeviceTree {
         let parser = self.get_or_create_parser(&absolute_route);
         let samples: Vec<Sample> = parser.process_packet(&pkt);
 
+        if let Some(sample) = samples.last() {
+            self.time_refs
+                .insert(absolute_route.clone(), sample.time_ref());
+        }
         for sample in samples {
             self.sample_queue
                 .push_back((sample, absolute_route.clone()));
@@ -387,31 +413,22 @@ impl DeviceTree {
         self.rpc(route, name, ())
     }
 
+    /// Bulk transfer through the RPC `name` of the device at `route`.
+    /// Samples and events received during the transfer are queued as usual.
+    pub fn bulk<'a>(
+        &'a mut self,
+        route: DeviceRoute,
+        name: &str,
+    ) -> bulk::BulkTransfer<'a, impl bulk::BulkPort + 'a> {
+        bulk::BulkTransfer::new(RoutePort { tree: self, route }, name)
+    }
+
     pub fn get_multi(
         &mut self,
         route: DeviceRoute,
         name: &str,
     ) -> Result<Vec<u8>, tio::proxy::RpcError> {
-        let mut full_reply = vec![];
-
-        for i in 0u16..=65535u16 {
-            match self.raw_rpc(route.clone(), &name, &i.to_le_bytes().to_vec()) {
-                Ok(mut rep) => full_reply.append(&mut rep),
-                Err(err @ proxy::RpcError::ExecError(_)) => {
-                    if let proxy::RpcError::ExecError(payload) = &err {
-                        if let tio::proto::RpcErrorCode::InvalidArgs = payload.error {
-                            break;
-                        }
-                    }
-                    return Err(err);
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            }
-        }
-
-        Ok(full_reply)
+        Ok(self.bulk(route, name).read()?)
     }
 
     pub fn get_multi_str(
@@ -427,4 +444,60 @@ impl DeviceTree {
     pub fn known_routes(&self) -> Vec<DeviceRoute> {
         self.parsers.keys().cloned().collect()
     }
+
+    /// Devices that have sent samples, grouped by the clock their sample
+    /// times are on. Samples of devices in one group align exactly; see
+    /// `Buffer::time_offset` for devices in different groups.
+    pub fn time_refs(&self) -> BTreeMap<TimeRef, Vec<DeviceRoute>> {
+        let mut groups: BTreeMap<TimeRef, Vec<DeviceRoute>> = BTreeMap::new();
+        for (route, time_ref) in &self.time_refs {
+            groups
+                .entry(time_ref.clone())
+                .or_default()
+                .push(route.clone());
+        }
+        for routes in groups.values_mut() {
+            routes.sort();
+        }
+        groups
+    }
+
+    /// Host estimate of UTC for the clock `time_ref`, to pass to
+    /// `Sample::utc`: the most certain one of the devices timed by it.
+    pub fn clock(&self, time_ref: &TimeRef) -> Option<UtcMapping> {
+        self.parsers
+            .values()
+            .filter_map(|parser| parser.clock(time_ref))
+            .min_by(|a, b| a.uncertainty.total_cmp(&b.uncertainty))
+    }
+}
+
+/// Packets of one device in a tree, for bulk transfers.
+struct RoutePort<'a> {
+    tree: &'a mut DeviceTree,
+    route: DeviceRoute,
+}
+
+impl bulk::BulkPort for RoutePort<'_> {
+    fn send_request(&mut self, mut pkt: tio::Packet) -> Result<(), proxy::SendError> {
+        match self.tree.root_route.relative_route(&self.route) {
+            Ok(rel) => pkt.routing = rel,
+            Err(_) => {
+                pkt.routing = self.route.clone();
+                return Err(proxy::SendError::InvalidRoute(pkt));
+            }
+        }
+        self.tree.internal_rpcs()?;
+        self.tree.port.send(pkt)
+    }
+
+    fn recv_packet(&mut self) -> Result<tio::Packet, proxy::RecvError> {
+        loop {
+            let pkt = self.tree.port.recv()?;
+            self.tree.process_packet(&pkt);
+            if self.tree.root_route.absolute_route(&pkt.routing) == self.route {
+                return Ok(pkt);
+            }
+        }
+    }
 }
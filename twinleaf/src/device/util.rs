@@ -1,4 +1,6 @@
-use crate::device::rpc::{DecodeError, EncodeError, RpcDescriptor, RpcValue, RpcValueType};
+use crate::device::rpc::{
+    split_top_level, DecodeError, EncodeError, RpcDescriptor, RpcValue, RpcValueType,
+};
 use crate::tio::proxy;
 
 pub fn load_rpc_specs(device: &proxy::Port) -> Result<Vec<RpcDescriptor>, proxy::RpcError> {
@@ -95,7 +97,62 @@ pub fn resolve_arg_type(meta: Option<u16>, name: &str) -> RpcValueType {
     }
 }
 
+/// Items of a list written as `[a, b, c]` or `{a, b}`, or without the
+/// brackets.
+fn list_items(input: &str, open: char, close: char) -> Result<Vec<&str>, EncodeError> {
+    let input = input.trim();
+    let inner = match input.strip_prefix(open) {
+        Some(rest) => rest
+            .strip_suffix(close)
+            .ok_or_else(|| EncodeError::Malformed(format!("missing '{}'", close)))?,
+        None => input,
+    };
+    Ok(split_top_level(inner, ',')
+        .into_iter()
+        .map(|item| item.trim())
+        .collect())
+}
+
+/// Remove double quotes around a string element.
+fn unquote(item: &str) -> Result<String, EncodeError> {
+    let Some(inner) = item.strip_prefix('"') else {
+        return Ok(item.to_string());
+    };
+    let inner = inner
+        .strip_suffix('"')
+        .ok_or_else(|| EncodeError::Malformed(format!("unterminated string {}", item)))?;
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            out.extend(chars.next());
+        } else {
+            out.push(c);
+        }
+    }
+    Ok(out)
+}
+
+fn encode_element(item: &str, kind: &RpcValueType) -> Result<Vec<u8>, EncodeError> {
+    match kind {
+        RpcValueType::String { .. } => rpc_encode_arg(&unquote(item)?, kind),
+        _ => rpc_encode_arg(item, kind),
+    }
+}
+
+/// Encode an RPC argument from its text form. Arrays are written as
+/// `[1, 2, 3]` and structs as `{x: 1, y: 2}` or `{1, 2}`. A list given for
+/// a numeric type is encoded as an array of that type.
 pub fn rpc_encode_arg(input: &str, kind: &RpcValueType) -> Result<Vec<u8>, EncodeError> {
+    if matches!(kind, RpcValueType::Int { .. } | RpcValueType::Float { .. })
+        && input.trim_start().starts_with('[')
+    {
+        let array = RpcValueType::Array {
+            elem: Box::new(kind.clone()),
+            len: None,
+        };
+        return rpc_encode_arg(input, &array);
+    }
     match kind {
         RpcValueType::Unit => {
             if input.is_empty() {
@@ -143,10 +200,86 @@ pub fn rpc_encode_arg(input: &str, kind: &RpcValueType) -> Result<Vec<u8>, Encod
         },
 
         RpcValueType::Raw { .. } => Ok(Vec::new()),
+
+        RpcValueType::Array { elem, len } => {
+            let items = list_items(input, '[', ']')?;
+            if let Some(n) = len {
+                if items.len() != *n {
+                    return Err(EncodeError::WrongLength {
+                        expected: *n,
+                        actual: items.len(),
+                    });
+                }
+            }
+            let mut out = vec![];
+            for item in items {
+                out.extend(encode_element(item, elem)?);
+            }
+            Ok(out)
+        }
+
+        RpcValueType::Struct { fields } => {
+            let items = list_items(input, '{', '}')?;
+            // Either all values are named, or all are in field order.
+            let named: Vec<Option<(&str, &str)>> = items
+                .iter()
+                .map(|item| {
+                    let (name, value) = item.split_once(':')?;
+                    let name = name.trim();
+                    name.chars()
+                        .all(|c| c.is_alphanumeric() || c == '_')
+                        .then_some((name, value.trim()))
+                })
+                .collect();
+            let values: Vec<&str> = if !items.is_empty() && named.iter().all(|n| n.is_some()) {
+                let named: Vec<(&str, &str)> = named.into_iter().flatten().collect();
+                if let Some((name, _)) = named
+                    .iter()
+                    .find(|(name, _)| !fields.iter().any(|(field, _)| field == name))
+                {
+                    return Err(EncodeError::UnknownField(name.to_string()));
+                }
+                fields
+                    .iter()
+                    .map(|(field, _)| {
+                        named
+                            .iter()
+                            .find(|(name, _)| name == field)
+                            .map(|(_, value)| *value)
+                            .ok_or_else(|| EncodeError::MissingField(field.clone()))
+                    })
+                    .collect::<Result<_, _>>()?
+            } else {
+                if items.len() != fields.len() {
+                    return Err(EncodeError::WrongLength {
+                        expected: fields.len(),
+                        actual: items.len(),
+                    });
+                }
+                items
+            };
+            let mut out = vec![];
+            for (value, (_, kind)) in values.iter().zip(fields) {
+                out.extend(encode_element(value, kind)?);
+            }
+            Ok(out)
+        }
     }
 }
 
+/// Decode an RPC reply. A numeric reply holding several values of its type
+/// is decoded as an array.
 pub fn rpc_decode_reply(reply: &[u8], kind: &RpcValueType) -> Result<RpcValue, DecodeError> {
+    if let RpcValueType::Int { size, .. } | RpcValueType::Float { size } = kind {
+        let size = *size as usize;
+        if (size > 0) && (reply.len() > size) && reply.len().is_multiple_of(size) {
+            let array = RpcValueType::Array {
+                elem: Box::new(kind.clone()),
+                len: None,
+            };
+            return rpc_decode_reply(reply, &array);
+        }
+    }
     match kind {
         RpcValueType::Unit => Ok(RpcValue::Unit),
 
@@ -279,5 +412,52 @@ pub fn rpc_decode_reply(reply: &[u8], kind: &RpcValueType) -> Result<RpcValue, D
         },
 
         RpcValueType::Raw { .. } => Ok(RpcValue::Bytes(reply.to_vec())),
+
+        RpcValueType::Array { elem, len } => {
+            let size = match elem.fixed_size() {
+                Some(size) if size > 0 => size,
+                _ => return Err(DecodeError::VariableSize(kind.type_str())),
+            };
+            let n = match len {
+                Some(n) => *n,
+                None if !reply.len().is_multiple_of(size) => {
+                    return Err(DecodeError::TrailingBytes(reply.len() % size))
+                }
+                None => reply.len() / size,
+            };
+            if reply.len() < n * size {
+                return Err(DecodeError::InsufficientBytes {
+                    expected: n * size,
+                    got: reply.len(),
+                });
+            }
+            let values = reply[..n * size]
+                .chunks(size)
+                .map(|chunk| rpc_decode_reply(chunk, elem))
+                .collect::<Result<_, _>>()?;
+            Ok(RpcValue::Array(values))
+        }
+
+        RpcValueType::Struct { fields } => {
+            let mut values = vec![];
+            let mut rest = reply;
+            for (i, (name, kind)) in fields.iter().enumerate() {
+                let size = match kind.fixed_size() {
+                    Some(size) => size,
+                    None if i == fields.len() - 1 => rest.len(),
+                    None => return Err(DecodeError::VariableSize(kind.type_str())),
+                };
+                if rest.len() < size {
+                    return Err(DecodeError::InsufficientBytes {
+                        expected: reply.len() - rest.len() + size,
+                        got: reply.len(),
+                    });
+                }
+                let (field, tail) = rest.split_at(size);
+                values.push((name.clone(), rpc_decode_reply(field, kind)?));
+                rest = tail;
+            }
+            Ok(RpcValue::Struct(values))
+        }
     }
 }
@@ -4,3 +4,6 @@ pub mod tio;
 
 pub use device::Device;
 pub use tio::proxy::Interface as ProxyInterface;
+
+#[cfg(feature = "derive")]
+pub use twinleaf_derive::TioRpcs;
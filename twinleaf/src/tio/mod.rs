@@ -2,7 +2,9 @@ pub mod os;
 pub mod port;
 pub mod proto;
 pub mod proxy;
+mod proxy_audit;
 mod proxy_core;
+mod proxy_record;
 pub mod util;
 
 pub use port::{RecvError, SendError};
@@ -10,7 +10,9 @@
 
 use super::port;
 use super::proto::{self, DeviceRoute, Packet, ProxyStatus};
+pub use super::proxy_audit::RpcAuditLog;
 use super::proxy_core::{ProxyClient, ProxyCore};
+pub use super::proxy_record::ProxyRecorder;
 use super::util;
 use super::util::{TioRpcReplyable, TioRpcRequestable};
 
@@ -20,8 +22,10 @@ use std::time::Duration;
 
 use crossbeam::channel;
 
-/// Status event that ProxyCore sent back to an optional user specified channel
+/// Status event that ProxyCore sent back to an optional user specified
+/// channel. New events may be added, so matches need a catch-all arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Event {
     SensorConnected,
     SensorDisconnected,
@@ -52,6 +56,22 @@ pub enum Event {
     SetRate(u32),
     SetRateFailed,
     NoData,
+    AuditLogFailed(std::io::Error),
+    /// The sensor is reachable again via this link URL. Sent just before
+    /// `SensorReconnected`.
+    LinkActive(String),
+    /// A standby link leads to a different device, and was not used.
+    LinkSerialMismatch {
+        url: String,
+        expected: String,
+        found: String,
+    },
+    /// A standby link opened, but the device did not answer on it.
+    LinkUnresponsive(String),
+    /// The recorder started writing to a new file.
+    RecordFileStarted(std::path::PathBuf),
+    /// The recorder stopped after failing to write.
+    RecordFailed(std::io::Error),
 }
 
 impl From<ProxyStatus> for super::proxy::Event {
@@ -237,6 +257,49 @@ pub enum PortError {
     FailedNewClientSetup,
 }
 
+/// Optional features of a proxy, set up when the ProxyCore is created.
+#[derive(Default)]
+pub struct ProxyOptions {
+    /// Record every client RPC to this audit log. If writing fails, the
+    /// proxy reports `Event::AuditLogFailed` and stops auditing.
+    pub audit_log: Option<RpcAuditLog>,
+
+    /// Record all traffic from the device tree, independently of clients.
+    /// If writing fails, the proxy reports `Event::RecordFailed` and stops
+    /// recording.
+    pub recorder: Option<ProxyRecorder>,
+
+    /// Other links to the same device (e.g. a network bridge in addition to
+    /// USB serial), tried in order when the active link disconnects. The
+    /// device serial number is checked before a new link is used.
+    pub standby_urls: Vec<String>,
+
+    /// Answer `@proxy.*` RPCs (clients, uptime, link, statistics, kicking
+    /// clients) at the root of the device tree in the proxy itself. Meant
+    /// for proxy servers such as `tio proxy`, so that tools connected to
+    /// them can inspect and manage the proxy.
+    pub introspection: bool,
+}
+
+/// Per-client traffic shaping, applied by the proxy to sample data before it
+/// is queued for the client. RPC traffic is never shaped.
+#[derive(Debug, Clone, Default)]
+pub struct ClientQos {
+    /// Only forward data from these stream ids (all streams if `None`).
+    pub streams: Option<Vec<u8>>,
+
+    /// Forward only one in this many data packets of each stream. 0 or 1
+    /// forward all of them.
+    pub decimation: u32,
+
+    /// Upper bound on the data packets forwarded per second.
+    pub max_data_rate: Option<f64>,
+
+    /// Keep part of the client queue free for RPC replies and other control
+    /// traffic, by dropping data earlier when the client falls behind.
+    pub rpc_priority: bool,
+}
+
 /// Interface to a port proxy. Can create new ports.
 pub struct Interface {
     new_client_queue: channel::Sender<ProxyClient>,
@@ -251,6 +314,21 @@ impl Interface {
         url: &str,
         reconnect_timeout: Option<Duration>,
         status_queue: Option<channel::Sender<Event>>,
+    ) -> Interface {
+        Self::new_proxy_with_options(
+            url,
+            reconnect_timeout,
+            status_queue,
+            ProxyOptions::default(),
+        )
+    }
+
+    /// Like `new_proxy`, enabling the optional features in `options`.
+    pub fn new_proxy_with_options(
+        url: &str,
+        reconnect_timeout: Option<Duration>,
+        status_queue: Option<channel::Sender<Event>>,
+        options: ProxyOptions,
     ) -> Interface {
         let (client_sender, client_receiver) = channel::bounded::<ProxyClient>(5);
         let (status_sender, status_receiver, only_clients) = {
@@ -278,6 +356,7 @@ impl Interface {
                 client_receiver,
                 status_sender,
                 only_clients,
+                options,
             );
             proxy.run();
         });
@@ -326,6 +405,55 @@ impl Interface {
         depth: usize,
         forward_data: bool,
         forward_nonrpc: bool,
+    ) -> Result<Port, PortError> {
+        self.new_client_port(rpc_timeout, scope, depth, |client| {
+            client.forward(forward_data, forward_nonrpc)
+        })
+    }
+
+    /// Create a new port on behalf of a remote peer (e.g. the address of a
+    /// TCP client), which the proxy uses to identify it in the RPC audit log.
+    pub fn new_peer_port(
+        &self,
+        peer: &str,
+        rpc_timeout: Option<Duration>,
+        scope: DeviceRoute,
+        depth: usize,
+        forward_data: bool,
+        forward_nonrpc: bool,
+    ) -> Result<Port, PortError> {
+        self.new_client_port(rpc_timeout, scope, depth, |client| {
+            client
+                .forward(forward_data, forward_nonrpc)
+                .with_peer(peer.to_string())
+        })
+    }
+
+    /// Create a new port receiving all packets, with sample data shaped
+    /// according to `qos`, optionally on behalf of a remote peer.
+    pub fn new_port_with_qos(
+        &self,
+        peer: Option<&str>,
+        qos: ClientQos,
+        rpc_timeout: Option<Duration>,
+        scope: DeviceRoute,
+        depth: usize,
+    ) -> Result<Port, PortError> {
+        self.new_client_port(rpc_timeout, scope, depth, |client| {
+            let client = client.forward(true, true).with_qos(qos);
+            match peer {
+                Some(peer) => client.with_peer(peer.to_string()),
+                None => client,
+            }
+        })
+    }
+
+    fn new_client_port<F: FnOnce(ProxyClient) -> ProxyClient>(
+        &self,
+        rpc_timeout: Option<Duration>,
+        scope: DeviceRoute,
+        depth: usize,
+        setup: F,
     ) -> Result<Port, PortError> {
         let default_rpc_timeout = Duration::from_millis(3000);
         let rpc_timeout = rpc_timeout.unwrap_or(default_rpc_timeout);
@@ -340,15 +468,16 @@ impl Interface {
             channel::bounded::<Packet>(self.client_tx_channel_size);
         let (proxy_to_client_sender, client_from_proxy_receiver) =
             channel::bounded::<Packet>(self.client_rx_channel_size);
-        if let Err(_) = self.new_client_queue.send(ProxyClient::new(
+        let client = setup(ProxyClient::new(
             proxy_to_client_sender,
             proxy_from_client_receiver,
             rpc_timeout,
             scope.clone(),
             depth,
-            forward_data,
-            forward_nonrpc,
-        )) {
+            false,
+            false,
+        ));
+        if let Err(_) = self.new_client_queue.send(client) {
             return Err(PortError::FailedNewClientSetup);
         }
         if let Some(confirm) = &self.new_client_confirm {
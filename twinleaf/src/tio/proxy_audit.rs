@@ -0,0 +1,218 @@
+//! RPC audit log
+//!
+//! Optional JSON-lines record of every RPC that proxy clients send to the
+//! device tree, written by the proxy core. Arguments and replies are decoded
+//! when the proxy knows the RPC type (from `rpc.info`), otherwise they are
+//! logged as hex.
+
+use super::proto::{DeviceRoute, RpcErrorCode, RpcMethod};
+use super::proxy_record::FLUSH_INTERVAL;
+use crate::device::util::{parse_rpc_spec, rpc_decode_reply};
+use crate::device::{RpcValue, RpcValueType};
+
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Destination of the proxy RPC audit records.
+pub struct RpcAuditLog {
+    out: BufWriter<Box<dyn Write + Send>>,
+    /// When the oldest record not yet flushed was written.
+    unflushed: Option<Instant>,
+}
+
+/// How an audited RPC completed.
+pub(crate) enum AuditResult<'a> {
+    Reply(&'a [u8]),
+    Error(RpcErrorCode),
+}
+
+/// Everything the proxy knows about a completed RPC.
+pub(crate) struct AuditRecord<'a> {
+    pub client: u64,
+    pub peer: Option<&'a str>,
+    pub route: &'a DeviceRoute,
+    pub method: &'a RpcMethod,
+    /// `rpc.info` metadata for the method, if known.
+    pub meta: Option<u16>,
+    pub arg: &'a [u8],
+    pub result: AuditResult<'a>,
+    pub elapsed: Option<Duration>,
+}
+
+impl RpcAuditLog {
+    /// Audit log writing to an arbitrary destination.
+    pub fn new<W: Write + Send + 'static>(out: W) -> RpcAuditLog {
+        RpcAuditLog {
+            out: BufWriter::new(Box::new(out)),
+            unflushed: None,
+        }
+    }
+
+    /// Audit log appending to the file at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<RpcAuditLog> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        Ok(Self::new(file))
+    }
+
+    /// Write one record as a single line. Lines are buffered, see
+    /// `flush_if_due`.
+    pub(crate) fn record(&mut self, rec: &AuditRecord) -> io::Result<()> {
+        let mut line = String::with_capacity(256);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let _ = write!(line, "{{\"time\":{:.6},\"client\":{}", time, rec.client);
+        line.push_str(",\"peer\":");
+        match rec.peer {
+            Some(peer) => push_json_str(&mut line, peer),
+            None => line.push_str("null"),
+        }
+        line.push_str(",\"route\":");
+        push_json_str(&mut line, &rec.route.to_string());
+        match rec.method {
+            RpcMethod::Name(name) => {
+                line.push_str(",\"method\":");
+                push_json_str(&mut line, name);
+            }
+            RpcMethod::Id(id) => {
+                let _ = write!(line, ",\"method_id\":{}", id);
+            }
+        }
+
+        let kind = match (rec.meta, rec.method) {
+            (Some(meta), RpcMethod::Name(name)) => {
+                let desc = parse_rpc_spec(meta, name.clone());
+                line.push_str(",\"type\":");
+                push_json_str(&mut line, &desc.type_str());
+                Some(desc.data_kind)
+            }
+            _ => None,
+        };
+
+        line.push_str(",\"arg\":");
+        push_json_value(&mut line, rec.arg, kind.as_ref());
+        match &rec.result {
+            AuditResult::Reply(reply) => {
+                line.push_str(",\"result\":\"ok\",\"reply\":");
+                push_json_value(&mut line, reply, kind.as_ref());
+            }
+            AuditResult::Error(code) => {
+                line.push_str(",\"result\":\"error\",\"error\":");
+                push_json_str(&mut line, &code.to_string());
+                let _ = write!(line, ",\"error_code\":{}", u16::from(*code));
+            }
+        }
+        if let Some(elapsed) = rec.elapsed {
+            let _ = write!(
+                line,
+                ",\"elapsed_ms\":{:.3}",
+                elapsed.as_secs_f64() * 1000.0
+            );
+        }
+        line.push_str("}\n");
+
+        self.out.write_all(line.as_bytes())?;
+        self.unflushed.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+
+    /// Flush the records written at least `FLUSH_INTERVAL` ago, so that the
+    /// log stays usable even if the proxy is killed. Returns how long until
+    /// the next flush is due, if records are waiting.
+    pub(crate) fn flush_if_due(&mut self) -> io::Result<Option<Duration>> {
+        let Some(oldest) = self.unflushed else {
+            return Ok(None);
+        };
+        let age = oldest.elapsed();
+        if age < FLUSH_INTERVAL {
+            return Ok(Some(FLUSH_INTERVAL - age));
+        }
+        self.unflushed = None;
+        self.out.flush()?;
+        Ok(None)
+    }
+}
+
+impl Drop for RpcAuditLog {
+    fn drop(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+/// Decode an argument or reply with the RPC type if known. Empty payloads
+/// are `null`, undecodable ones are hex strings.
+fn push_json_value(line: &mut String, raw: &[u8], kind: Option<&RpcValueType>) {
+    if raw.is_empty() {
+        line.push_str("null");
+        return;
+    }
+    let value = kind
+        .and_then(|k| rpc_decode_reply(raw, k).ok())
+        .unwrap_or_else(|| RpcValue::Bytes(raw.to_vec()));
+    push_json(line, &value);
+}
+
+fn push_json(line: &mut String, value: &RpcValue) {
+    match value {
+        RpcValue::Unit => line.push_str("null"),
+        RpcValue::U64(n) => {
+            let _ = write!(line, "{}", n);
+        }
+        RpcValue::I64(n) => {
+            let _ = write!(line, "{}", n);
+        }
+        RpcValue::F64(x) if x.is_finite() => {
+            let _ = write!(line, "{}", x);
+        }
+        RpcValue::F64(x) => push_json_str(line, &x.to_string()),
+        RpcValue::Str(s) => push_json_str(line, s),
+        bytes @ RpcValue::Bytes(_) => push_json_str(line, &bytes.to_string()),
+        RpcValue::Array(values) => {
+            line.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    line.push(',');
+                }
+                push_json(line, value);
+            }
+            line.push(']');
+        }
+        RpcValue::Struct(fields) => {
+            line.push('{');
+            for (i, (name, value)) in fields.iter().enumerate() {
+                if i > 0 {
+                    line.push(',');
+                }
+                push_json_str(line, name);
+                line.push(':');
+                push_json(line, value);
+            }
+            line.push('}');
+        }
+    }
+}
+
+fn push_json_str(line: &mut String, s: &str) {
+    line.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
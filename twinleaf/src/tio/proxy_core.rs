@@ -2,7 +2,9 @@ use super::port;
 use super::port::Port as HardwarePort;
 use super::port::RecvError;
 use super::proto::{self, DeviceRoute, Packet};
-use super::proxy::Event;
+use super::proxy::{ClientQos, Event, ProxyOptions};
+use super::proxy_audit::{AuditRecord, AuditResult, RpcAuditLog};
+use super::proxy_record::ProxyRecorder;
 use super::util;
 use super::util::TioRpcReplyable;
 
@@ -12,6 +14,9 @@ use std::collections::{BTreeMap, HashMap, HashSet};
 
 use crossbeam::channel;
 
+mod introspect;
+use introspect::ProxyStats;
+
 struct StatusQueue {
     dest: channel::Sender<Event>,
     only_new_client: bool,
@@ -55,6 +60,19 @@ pub struct ProxyClient {
 
     /// Forward packets that are not sample data nor RPC-related.
     forward_nonrpc: bool,
+
+    /// Remote peer this client serves, if any (e.g. a TCP client address).
+    peer: Option<String>,
+
+    /// Traffic shaping for sample data.
+    qos: ClientQos,
+
+    /// Data packets seen per (route, stream), for decimation.
+    data_counters: HashMap<(DeviceRoute, u8), u32>,
+
+    /// Token bucket for the data rate limit, and when it was last refilled.
+    data_tokens: f64,
+    data_tokens_refill: Instant,
 }
 
 impl ProxyClient {
@@ -75,9 +93,40 @@ impl ProxyClient {
             depth,
             forward_data,
             forward_nonrpc,
+            peer: None,
+            qos: ClientQos::default(),
+            data_counters: HashMap::new(),
+            data_tokens: 0.0,
+            data_tokens_refill: Instant::now(),
         }
     }
 
+    /// Shape sample data sent to this client.
+    pub fn with_qos(mut self, qos: ClientQos) -> ProxyClient {
+        self.data_tokens = Self::data_burst(&qos);
+        self.qos = qos;
+        self
+    }
+
+    /// Size of the token bucket for the data rate limit: up to one second
+    /// worth of packets.
+    fn data_burst(qos: &ClientQos) -> f64 {
+        qos.max_data_rate.unwrap_or(0.0).max(1.0)
+    }
+
+    /// Set which kinds of traffic other than RPCs are forwarded.
+    pub fn forward(mut self, forward_data: bool, forward_nonrpc: bool) -> ProxyClient {
+        self.forward_data = forward_data;
+        self.forward_nonrpc = forward_nonrpc;
+        self
+    }
+
+    /// Identify the remote peer served by this client.
+    pub fn with_peer(mut self, peer: String) -> ProxyClient {
+        self.peer = Some(peer);
+        self
+    }
+
     fn send(&self, pkt: &Packet) -> Result<(), channel::TrySendError<Packet>> {
         // ProxyStatus should be route-agnostic
         if matches!(pkt.payload, proto::Payload::ProxyStatus(_)) {
@@ -111,6 +160,62 @@ impl ProxyClient {
         })
     }
 
+    /// Like `send`, but sample data goes through the client QoS settings
+    /// first, and might be dropped.
+    fn send_shaped(&mut self, pkt: &Packet) -> Result<(), channel::TrySendError<Packet>> {
+        let stream_id = match &pkt.payload {
+            proto::Payload::StreamData(data) => data.stream_id,
+            // Legacy devices only have a single stream
+            proto::Payload::LegacyStreamData(_) => 0,
+            _ => {
+                return self.send(pkt);
+            }
+        };
+        let in_scope = self
+            .scope
+            .relative_route(&pkt.routing)
+            .is_ok_and(|r| r.len() <= self.depth);
+        if !self.forward_data || !in_scope {
+            return Ok(());
+        }
+
+        if let Some(streams) = &self.qos.streams {
+            if !streams.contains(&stream_id) {
+                return Ok(());
+            }
+        }
+        if self.qos.decimation > 1 {
+            let count = self
+                .data_counters
+                .entry((pkt.routing.clone(), stream_id))
+                .or_insert(0);
+            let keep = *count == 0;
+            *count = (*count + 1) % self.qos.decimation;
+            if !keep {
+                return Ok(());
+            }
+        }
+        if self.qos.rpc_priority {
+            if let Some(capacity) = self.tx.capacity() {
+                let reserved = std::cmp::max(capacity / 8, 1);
+                if self.tx.len() + reserved >= capacity {
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(rate) = self.qos.max_data_rate {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.data_tokens_refill).as_secs_f64();
+            self.data_tokens_refill = now;
+            self.data_tokens = (self.data_tokens + elapsed * rate).min(Self::data_burst(&self.qos));
+            if self.data_tokens < 1.0 {
+                return Ok(());
+            }
+            self.data_tokens -= 1.0;
+        }
+        self.send(pkt)
+    }
+
     fn recv(&self) -> Result<Packet, channel::TryRecvError> {
         let mut pkt = self.rx.try_recv()?;
         pkt.routing = self.scope.absolute_route(&pkt.routing);
@@ -141,6 +246,8 @@ struct ProxyDevice {
     rpc_meta: HashMap<String, u16>,
     pending_broadcasts: Vec<(String, DeviceRoute, u64)>,
     pending_lookup: Option<(String, DeviceRoute)>,
+    /// False while checking the serial number after switching links.
+    verified: bool,
 }
 
 impl ProxyDevice {
@@ -166,7 +273,12 @@ impl ProxyDevice {
     /// True if it's safe to forward packets to the device due to rate
     /// negotiation concerns. Specifically, packets might be lost around
     /// when the rate transitions, so we hold back on forwarding traffic then.
+    /// Traffic is also held back on a standby link until the device on the
+    /// other end is known to be the expected one.
     fn safe_to_forward(&self) -> bool {
+        if !self.verified {
+            return false;
+        }
         match self.rate_change_state {
             RateChange::SetDeviceRate | RateChange::WaitingNewRate => false,
             _ => true,
@@ -240,10 +352,23 @@ struct RpcMapEntry {
     timeout: Instant,
     has_arg: bool,
     method: proto::RpcMethod,
+    /// Request argument and send time, kept only for the audit log.
+    audit_arg: Option<(Vec<u8>, Instant)>,
 }
 
 pub struct ProxyCore {
-    url: String,
+    /// Links to the device, in order of preference. Only the active one is
+    /// open; the others are hot standbys used when it disconnects. A standby
+    /// stays active until it disconnects in turn, and the links are then
+    /// tried from the first one again.
+    urls: Vec<String>,
+    active_link: usize,
+    /// Links found connected to the wrong device (or to none), with the time
+    /// until which they are skipped. Cleared once a link verifies.
+    rejected_links: HashMap<usize, Instant>,
+    /// Serial number of the device, learned on the first connection when
+    /// there are standby links.
+    expected_serial: Option<String>,
     reconnect_timeout: Option<Duration>,
     new_client_queue: channel::Receiver<ProxyClient>,
     status_queue: StatusQueue,
@@ -259,11 +384,25 @@ pub struct ProxyCore {
     next_rpc_id: u16,
     rpc_map: HashMap<u16, RpcMapEntry>,
     rpc_timeouts: BTreeMap<Instant, HashSet<u16>>,
+
+    audit_log: Option<RpcAuditLog>,
+    recorder: Option<ProxyRecorder>,
+
+    /// Answer `@proxy.*` introspection RPCs.
+    introspection: bool,
+    started: Instant,
+    stats: ProxyStats,
 }
 
 static QUERY_RATE_RPC_ID: u16 = 0x101;
 static SET_RATE_RPC_ID: u16 = 0x102;
 static RPC_INFO_LOOKUP_ID: u16 = 0x103;
+static SERIAL_CHECK_RPC_ID: u16 = 0x104;
+static RECORD_METADATA_RPC_ID: u16 = 0x105;
+
+/// How long a link that failed the serial number check is skipped before
+/// being tried again.
+static LINK_REJECTION_TIME: Duration = Duration::from_secs(5);
 
 impl ProxyCore {
     pub fn new(
@@ -272,9 +411,15 @@ impl ProxyCore {
         new_client_queue: channel::Receiver<ProxyClient>,
         status_queue: channel::Sender<Event>,
         notify_new_client_only: bool,
+        options: ProxyOptions,
     ) -> ProxyCore {
+        let mut urls = vec![url];
+        urls.extend(options.standby_urls);
         ProxyCore {
-            url: url,
+            urls,
+            active_link: 0,
+            rejected_links: HashMap::new(),
+            expected_serial: None,
             reconnect_timeout: reconnect_timeout,
             new_client_queue: new_client_queue,
             status_queue: StatusQueue {
@@ -289,15 +434,48 @@ impl ProxyCore {
             next_rpc_id: 0,
             rpc_map: HashMap::new(),
             rpc_timeouts: BTreeMap::new(),
+            audit_log: options.audit_log,
+            recorder: options.recorder,
+            introspection: options.introspection,
+            started: Instant::now(),
+            stats: ProxyStats::default(),
         }
     }
 
+    fn has_standby_links(&self) -> bool {
+        self.urls.len() > 1
+    }
+
+    fn active_url(&self) -> String {
+        self.urls[self.active_link].clone()
+    }
+
+    /// Open the first link in order of preference that is not currently
+    /// rejected and opens successfully.
     fn try_setup_device(&mut self) -> bool {
         if self.device.is_some() {
             return true;
         }
+        let now = Instant::now();
+        self.rejected_links.retain(|_, until| *until > now);
+        for link in 0..self.urls.len() {
+            if self.rejected_links.contains_key(&link) {
+                continue;
+            }
+            self.active_link = link;
+            if self.try_open_active_link() {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn try_open_active_link(&mut self) -> bool {
         let (port_rx_send, port_rx) = HardwarePort::rx_channel();
-        let port = match HardwarePort::new(&self.url, HardwarePort::rx_to_channel(port_rx_send)) {
+        let port = match HardwarePort::new(
+            &self.urls[self.active_link],
+            HardwarePort::rx_to_channel(port_rx_send),
+        ) {
             Ok(p) => p,
             Err(_) => {
                 return false;
@@ -321,10 +499,66 @@ impl ProxyCore {
             rpc_meta: HashMap::new(),
             pending_broadcasts: Vec::new(),
             pending_lookup: None,
+            verified: !self.has_standby_links() || self.expected_serial.is_none(),
         });
+        if self.has_standby_links() {
+            // Ask for the device metadata to learn or check the serial number.
+            let query = util::PacketBuilder::make_rpc_request(
+                "dev.metadata",
+                &[proto::meta::MetadataType::Device.into(), 0, 0],
+                SERIAL_CHECK_RPC_ID,
+                DeviceRoute::root(),
+            );
+            if self.send_internal_rpc(query).is_err() {
+                self.reject_active_link();
+                return false;
+            }
+        }
         true
     }
 
+    /// Drop the active link, and skip it for a while when reconnecting.
+    fn reject_active_link(&mut self) {
+        self.device = None;
+        self.rejected_links
+            .insert(self.active_link, Instant::now() + LINK_REJECTION_TIME);
+    }
+
+    /// Process the serial number reported by the device after opening a link.
+    /// `None` if the device could not report it.
+    fn link_serial_checked(&mut self, serial: Option<String>) {
+        let verified = match &self.device {
+            Some(dev) => dev.verified,
+            None => return,
+        };
+        if !verified {
+            if let (Some(expected), Some(found)) = (&self.expected_serial, &serial) {
+                if expected != found {
+                    self.status_queue.send(Event::LinkSerialMismatch {
+                        url: self.active_url(),
+                        expected: expected.clone(),
+                        found: found.clone(),
+                    });
+                    self.reject_active_link();
+                    return;
+                }
+            }
+        }
+        if self.expected_serial.is_none() {
+            self.expected_serial = serial;
+        }
+        if !verified {
+            if let Some(dev) = self.device.as_mut() {
+                dev.verified = true;
+            }
+            self.rejected_links.clear();
+            self.stats.reconnects += 1;
+            self.status_queue.send(Event::LinkActive(self.active_url()));
+            self.status_queue.send(Event::SensorReconnected);
+            self.broadcast_status(proto::ProxyStatus::SensorReconnected);
+        }
+    }
+
     /// Clients get dropped as part of the main loop. This function adds a
     /// client to drop to a set to be processed later, and if its ID was not
     /// already in the set, send a status event.
@@ -334,11 +568,7 @@ impl ProxyCore {
         }
     }
 
-    fn rpc_restore(
-        &mut self,
-        wire_id: u16,
-        route: &DeviceRoute,
-    ) -> Option<(u64, u16, proto::RpcMethod, bool)> {
+    fn rpc_restore(&mut self, wire_id: u16, route: &DeviceRoute) -> Option<RpcMapEntry> {
         let remap = match self.rpc_map.remove(&wire_id) {
             None => {
                 return None;
@@ -358,11 +588,113 @@ impl ProxyCore {
             #[cfg(debug_assertions)]
             eprintln!("Failed to find RPC timeout in map");
         }
-        Some((remap.client, remap.id, remap.method, remap.has_arg))
+        Some(remap)
+    }
+
+    /// Write an audit log record for a client RPC, if auditing is enabled.
+    /// Internal RPCs issued by the proxy itself are not recorded.
+    fn audit_rpc(
+        &mut self,
+        client_id: u64,
+        route: &DeviceRoute,
+        method: &proto::RpcMethod,
+        arg: Option<&(Vec<u8>, Instant)>,
+        result: AuditResult,
+    ) {
+        if client_id == 0 {
+            return;
+        }
+        let log = if let Some(log) = self.audit_log.as_mut() {
+            log
+        } else {
+            return;
+        };
+        let meta = match (method, &self.device) {
+            (proto::RpcMethod::Name(name), Some(dev)) => dev.rpc_meta.get(name).copied(),
+            _ => None,
+        }
+        .or_else(|| match method {
+            proto::RpcMethod::Name(name) if self.introspection => introspect::proxy_rpc_meta(name),
+            _ => None,
+        });
+        let record = AuditRecord {
+            client: client_id,
+            peer: self.clients.get(&client_id).and_then(|c| c.peer.as_deref()),
+            route,
+            method,
+            meta,
+            arg: arg.map(|(a, _)| &a[..]).unwrap_or(&[]),
+            result,
+            elapsed: arg.map(|(_, sent)| sent.elapsed()),
+        };
+        if let Err(e) = log.record(&record) {
+            self.audit_log = None;
+            self.status_queue.send(Event::AuditLogFailed(e));
+        }
+    }
+
+    /// Flush the audit log if due. Returns how long until it is due again.
+    fn flush_audit_log(&mut self) -> Option<Duration> {
+        match self.audit_log.as_mut()?.flush_if_due() {
+            Ok(due) => due,
+            Err(e) => {
+                self.audit_log = None;
+                self.status_queue.send(Event::AuditLogFailed(e));
+                None
+            }
+        }
+    }
+
+    /// Record a packet received from the device, if recording, and request
+    /// any metadata the recorder is missing.
+    fn record_packet(&mut self, pkt: &Packet) {
+        let recorder = if let Some(recorder) = self.recorder.as_mut() {
+            recorder
+        } else {
+            return;
+        };
+        match recorder.record(pkt) {
+            Ok(Some(path)) => self.status_queue.send(Event::RecordFileStarted(path)),
+            Ok(None) => {}
+            Err(e) => {
+                self.recorder_failed(e);
+                return;
+            }
+        }
+        if !self
+            .device
+            .as_ref()
+            .is_some_and(|dev| dev.safe_to_forward())
+        {
+            return;
+        }
+        for req in recorder.metadata_requests(RECORD_METADATA_RPC_ID) {
+            let route = req.routing.clone();
+            if self.send_internal_rpc(req).is_err() {
+                self.record_metadata(&route, None);
+            }
+        }
+    }
+
+    /// Pass the outcome of a recorder metadata request back to it.
+    fn record_metadata(&mut self, route: &DeviceRoute, reply: Option<&[u8]>) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.metadata_reply(route, reply) {
+                self.recorder_failed(e);
+            }
+        }
+    }
+
+    fn recorder_failed(&mut self, err: std::io::Error) {
+        self.recorder = None;
+        self.status_queue.send(Event::RecordFailed(err));
     }
 
     // Ok: successful. Err: packet should be sent back to client
     fn forward_to_device(&mut self, mut pkt: Packet, client_id: u64) -> Result<(), Packet> {
+        if let Some(reply) = self.proxy_rpc(&mut pkt, client_id) {
+            return Err(reply);
+        }
         let mut rpc_mapped_id: Option<u16> = None;
         let mut timeout = Instant::now();
         if let proto::Payload::RpcRequest(req) = &mut pkt.payload {
@@ -371,8 +703,18 @@ impl ProxyCore {
             // next time.
             self.next_rpc_id = self.next_rpc_id.wrapping_add(1);
             if self.rpc_map.contains_key(&wire_id) {
-                return Err(util::PacketBuilder::new(pkt.routing)
-                    .rpc_error(req.id, proto::RpcErrorCode::OutOfMemory));
+                let arg = (req.arg.clone(), Instant::now());
+                let method = req.method.clone();
+                let err = util::PacketBuilder::new(pkt.routing.clone())
+                    .rpc_error(req.id, proto::RpcErrorCode::OutOfMemory);
+                self.audit_rpc(
+                    client_id,
+                    &pkt.routing,
+                    &method,
+                    Some(&arg),
+                    AuditResult::Error(proto::RpcErrorCode::OutOfMemory),
+                );
+                return Err(err);
             }
             timeout += if client_id != 0 {
                 self.clients
@@ -392,8 +734,16 @@ impl ProxyCore {
                     timeout: timeout,
                     method: req.method.clone(),
                     has_arg: !req.arg.is_empty(),
+                    audit_arg: if self.audit_log.is_some() {
+                        Some((req.arg.clone(), Instant::now()))
+                    } else {
+                        None
+                    },
                 },
             );
+            if client_id != 0 {
+                self.stats.client_rpcs += 1;
+            }
             self.status_queue
                 .send(Event::RpcRemap((client_id, req.id), wire_id));
             req.id = wire_id;
@@ -423,6 +773,13 @@ impl ProxyCore {
                 .rpc_map
                 .remove(&rpc_id)
                 .expect("Unexpected missing timeout set");
+            self.audit_rpc(
+                remap.client,
+                &remap.route,
+                &remap.method,
+                remap.audit_arg.as_ref(),
+                AuditResult::Error(proto::RpcErrorCode::Undefined),
+            );
             return Err(util::PacketBuilder::new(remap.route)
                 .rpc_error(remap.id, proto::RpcErrorCode::Undefined));
         } else {
@@ -466,6 +823,7 @@ impl ProxyCore {
     fn dispatch_rpc_errors(&mut self, error: proto::RpcErrorCode, until: Option<Instant>) {
         let mut to_remove = Vec::new();
         let mut to_drop = Vec::new();
+        let mut to_audit = Vec::new();
         for (timeout, rpc_ids) in self.rpc_timeouts.iter() {
             if let Some(timeout_bound) = until {
                 if *timeout >= timeout_bound {
@@ -474,6 +832,9 @@ impl ProxyCore {
             }
             to_remove.push(*timeout);
             for rpc_id in rpc_ids {
+                if let proto::RpcErrorCode::Timeout = error {
+                    self.stats.rpc_timeouts += 1;
+                }
                 self.status_queue
                     .send(if let proto::RpcErrorCode::Timeout = error {
                         Event::RpcTimeout(*rpc_id)
@@ -488,12 +849,13 @@ impl ProxyCore {
                     c
                 } else {
                     // Client is gone.
+                    to_audit.push(remap);
                     continue;
                 };
                 if let Err(_) = client.send(&util::PacketBuilder::make_rpc_error(
                     remap.id,
                     error.clone(),
-                    remap.route,
+                    remap.route.clone(),
                 )) {
                     to_drop.push(remap.client);
                     // This can happen without a problem per se, if e.g. a client
@@ -505,11 +867,32 @@ impl ProxyCore {
                         remap.client
                     );
                 }
+                to_audit.push(remap);
             }
         }
         for timeout in to_remove {
             self.rpc_timeouts.remove(&timeout);
         }
+        for remap in to_audit {
+            if (remap.client == 0) && (remap.id == RECORD_METADATA_RPC_ID) {
+                self.record_metadata(&remap.route, None);
+            }
+            if (remap.client == 0) && (remap.id == SERIAL_CHECK_RPC_ID) {
+                let unverified = self.device.as_ref().is_some_and(|dev| !dev.verified);
+                if unverified {
+                    self.status_queue
+                        .send(Event::LinkUnresponsive(self.active_url()));
+                    self.reject_active_link();
+                }
+            }
+            self.audit_rpc(
+                remap.client,
+                &remap.route,
+                &remap.method,
+                remap.audit_arg.as_ref(),
+                AuditResult::Error(error),
+            );
+        }
         for client_id in to_drop {
             self.drop_client(client_id);
         }
@@ -592,6 +975,24 @@ impl ProxyCore {
                 self.device.as_mut().expect("").rate_change_state = next_state;
                 return;
             }
+        } else if rep.id == SERIAL_CHECK_RPC_ID {
+            // Reply is a sequence of (type, length, metadata), device first.
+            let serial = match rep.reply.get(0..2) {
+                Some(&[mtype, len])
+                    if matches!(
+                        proto::meta::MetadataType::from(mtype),
+                        proto::meta::MetadataType::Device
+                    ) =>
+                {
+                    rep.reply
+                        .get(2..2 + usize::from(len))
+                        .and_then(|raw| proto::meta::DeviceMetadata::deserialize(raw, raw).ok())
+                        .map(|(dm, _, _)| dm.serial_number)
+                }
+                _ => None,
+            };
+            self.link_serial_checked(serial);
+            return;
         } else if rep.id == RPC_INFO_LOOKUP_ID {
             let broadcast_info = self.device.as_mut().and_then(|dev| {
                 let (name, _) = dev.pending_lookup.take()?;
@@ -659,6 +1060,11 @@ impl ProxyCore {
     }
 
     fn internal_rpc_error(&mut self, err: &proto::RpcErrorPayload) {
+        if err.id == SERIAL_CHECK_RPC_ID {
+            // The device is there but cannot tell, assume it's the right one.
+            self.link_serial_checked(None);
+            return;
+        }
         if err.id == RPC_INFO_LOOKUP_ID {
             if let Some(dev) = self.device.as_mut() {
                 // Clear current lookup
@@ -781,6 +1187,9 @@ impl ProxyCore {
 
         'mainloop: loop {
             let mut timeout = self.process_rpc_timeouts();
+            if let Some(due) = self.flush_audit_log() {
+                timeout = std::cmp::min(timeout, due);
+            }
 
             if self.device.is_none() {
                 self.cancel_active_rpcs();
@@ -791,10 +1200,14 @@ impl ProxyCore {
                         break;
                     }
                     timeout = std::cmp::min(timeout, Duration::from_secs(1));
-                } else {
+                } else if self.device.as_ref().is_some_and(|dev| dev.verified) {
+                    self.stats.reconnects += 1;
+                    self.status_queue.send(Event::LinkActive(self.active_url()));
                     self.status_queue.send(Event::SensorReconnected);
                     self.broadcast_status(proto::ProxyStatus::SensorReconnected);
                 }
+                // Otherwise, reconnection is reported once the serial number
+                // is verified on the new link.
             }
 
             let (safe_to_forward, needs_autonegotiation, restarted) =
@@ -944,6 +1357,10 @@ impl ProxyCore {
                     };
                     match device.try_recv(&self.status_queue) {
                         Ok(Ok(mut pkt)) => {
+                            self.stats.device_packets += 1;
+                            if self.device.as_ref().is_some_and(|dev| dev.verified) {
+                                self.record_packet(&pkt);
+                            }
                             // In general, packets get forwarded to all clients,
                             // except for RPCs which are directed only to the
                             // client which placed the request.
@@ -953,38 +1370,64 @@ impl ProxyCore {
                                 _ => None,
                             } {
                                 // Remap RPC reply or error ID to client + ID
-                                let (client_id, original_id, method, has_arg) =
-                                    if let Some((client_id, rpc_id, method, has_arg)) =
-                                        self.rpc_restore(wire_id, &pkt.routing)
-                                    {
-                                        if client_id == 0 {
-                                            // internal reply
-                                            (0, rpc_id, method, has_arg)
-                                        } else if self.clients.contains_key(&client_id) {
-                                            self.status_queue.send(Event::RpcRestore(
-                                                wire_id,
-                                                (client_id, rpc_id),
-                                            ));
-                                            (client_id, rpc_id, method, has_arg)
-                                        } else {
-                                            // If we cannot find the client which originally sent the
-                                            // request, just drop the packet and send an event.
-                                            self.status_queue
-                                                .send(Event::RpcClientNotFound(client_id));
-                                            continue;
-                                        }
+                                let (client_id, original_id, method, has_arg) = if let Some(remap) =
+                                    self.rpc_restore(wire_id, &pkt.routing)
+                                {
+                                    self.audit_rpc(
+                                        remap.client,
+                                        &pkt.routing,
+                                        &remap.method,
+                                        remap.audit_arg.as_ref(),
+                                        match &pkt.payload {
+                                            proto::Payload::RpcReply(rep) => {
+                                                AuditResult::Reply(&rep.reply)
+                                            }
+                                            proto::Payload::RpcError(err) => {
+                                                AuditResult::Error(err.error)
+                                            }
+                                            _ => unreachable!(),
+                                        },
+                                    );
+                                    let (client_id, rpc_id, method, has_arg) =
+                                        (remap.client, remap.id, remap.method, remap.has_arg);
+                                    if client_id == 0 {
+                                        // internal reply
+                                        (0, rpc_id, method, has_arg)
+                                    } else if self.clients.contains_key(&client_id) {
+                                        self.status_queue
+                                            .send(Event::RpcRestore(wire_id, (client_id, rpc_id)));
+                                        (client_id, rpc_id, method, has_arg)
                                     } else {
-                                        self.status_queue.send(Event::RpcRestoreNotFound(wire_id));
+                                        // If we cannot find the client which originally sent the
+                                        // request, just drop the packet and send an event.
+                                        self.status_queue.send(Event::RpcClientNotFound(client_id));
                                         continue;
-                                    };
+                                    }
+                                } else {
+                                    self.status_queue.send(Event::RpcRestoreNotFound(wire_id));
+                                    continue;
+                                };
                                 // Restore original ID, and process internal RPCs.
                                 match &mut pkt.payload {
                                     proto::Payload::RpcReply(rep) => {
                                         rep.id = original_id;
                                         if client_id == 0 {
-                                            self.internal_rpc_reply(rep);
+                                            if original_id == RECORD_METADATA_RPC_ID {
+                                                self.record_metadata(
+                                                    &pkt.routing,
+                                                    Some(&rep.reply),
+                                                );
+                                            } else {
+                                                self.internal_rpc_reply(rep);
+                                            }
                                             continue;
                                         }
+                                        self.proxy_rpc_reply(
+                                            &pkt.routing,
+                                            &method,
+                                            has_arg,
+                                            &mut rep.reply,
+                                        );
                                         if has_arg {
                                             if let proto::RpcMethod::Name(ref name) = method {
                                                 let should_broadcast = self
@@ -1053,7 +1496,11 @@ impl ProxyCore {
                                     proto::Payload::RpcError(err) => {
                                         err.id = original_id;
                                         if client_id == 0 {
-                                            self.internal_rpc_error(err);
+                                            if original_id == RECORD_METADATA_RPC_ID {
+                                                self.record_metadata(&pkt.routing, None);
+                                            } else {
+                                                self.internal_rpc_error(err);
+                                            }
                                             continue;
                                         }
                                     }
@@ -1069,10 +1516,18 @@ impl ProxyCore {
                                         self.drop_client(client_id);
                                     }
                                 }
-                            } else {
+                            } else if self.device.as_ref().is_some_and(|dev| dev.verified) {
+                                if let proto::Payload::Settings(proto::SettingsPayload::RpcHash(
+                                    hash,
+                                )) = &mut pkt.payload
+                                {
+                                    if self.introspection && (pkt.routing == DeviceRoute::root()) {
+                                        *hash = introspect::proxied_rpc_hash(*hash);
+                                    }
+                                }
                                 let mut to_drop = vec![];
-                                for (client_id, client) in self.clients.iter() {
-                                    if let Err(_) = client.send(&pkt) {
+                                for (client_id, client) in self.clients.iter_mut() {
+                                    if let Err(_) = client.send_shaped(&pkt) {
                                         self.status_queue.send(Event::ClientSendFailed(*client_id));
                                         to_drop.push(*client_id);
                                     }
@@ -1099,6 +1554,8 @@ impl ProxyCore {
                             break;
                         }
                         Err(TryRecvError::Disconnected) => {
+                            // Reconnect through the first usable link, which
+                            // is checked to lead to the same device.
                             self.device = None;
                             device_timeout = Instant::now()
                                 + match self.reconnect_timeout {
@@ -0,0 +1,233 @@
+//! Proxy introspection RPCs
+//!
+//! When enabled, the proxy core answers `@proxy.*` RPCs addressed to the
+//! root of the device tree itself, without forwarding them to the sensor.
+//! Device RPC names are made of letters, digits, '.' and '_', so the '@'
+//! keeps them from shadowing an RPC of the root device.
+//!
+//! They show up to clients like any other RPC, so that generic tools can
+//! query and manage the proxy: they are answered by `rpc.info`, listed first
+//! by `rpc.listinfo` (device RPCs follow, with their indices shifted), and
+//! folded into the `rpc.hash` of the root device, so that RPC lists cached
+//! through a proxy are not mixed up with those of the device itself.
+
+use super::{ProxyCore, RateChange};
+use crate::tio::proto::{self, DeviceRoute, Packet, RpcErrorCode, RpcMethod};
+use crate::tio::proxy_audit::AuditResult;
+use crate::tio::util;
+
+use std::fmt::Write as _;
+use std::time::Instant;
+
+/// Counters reported by `@proxy.stats`.
+#[derive(Default)]
+pub(super) struct ProxyStats {
+    pub device_packets: u64,
+    pub client_rpcs: u64,
+    pub rpc_timeouts: u64,
+    pub reconnects: u64,
+}
+
+/// Introspection RPCs and their `rpc.info` metadata.
+const PROXY_RPCS: &[(&str, u16)] = &[
+    // Connected clients, one "id peer" per line. The caller is marked with '*'.
+    ("@proxy.clients", 0x0103),
+    // Seconds since the proxy started, f64.
+    ("@proxy.uptime", 0x0182),
+    // URL of the active sensor link.
+    ("@proxy.url", 0x0103),
+    // Current link rate in bits per second, u32. Zero if not applicable.
+    ("@proxy.rate", 0x0140),
+    // Traffic counters, as space separated "key=value" pairs.
+    ("@proxy.stats", 0x0103),
+    // Disconnect the client with the given id, u32.
+    ("@proxy.kick", 0x0240),
+];
+
+/// Largest RPC reply that fits in a packet.
+const MAX_REPLY_SIZE: usize = proto::TIO_PACKET_MAX_TOTAL_SIZE
+    - proto::TIO_PACKET_HEADER_SIZE
+    - proto::TIO_PACKET_MAX_ROUTING_SIZE
+    - 2;
+
+/// `rpc.info` metadata of a proxy RPC.
+pub(super) fn proxy_rpc_meta(name: &str) -> Option<u16> {
+    PROXY_RPCS
+        .iter()
+        .find(|(rpc, _)| *rpc == name)
+        .map(|(_, meta)| *meta)
+}
+
+/// Value mixed into the `rpc.hash` of the root device (FNV-1a of the proxy
+/// RPC list), so that it changes with the list.
+fn proxy_rpc_hash() -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for (name, meta) in PROXY_RPCS {
+        for b in name.bytes().chain(meta.to_le_bytes()) {
+            hash = (hash ^ b as u32).wrapping_mul(0x01000193);
+        }
+    }
+    hash
+}
+
+/// `rpc.hash` of the root device, as reported through a proxy answering
+/// introspection RPCs.
+pub(super) fn proxied_rpc_hash(device_hash: u32) -> u32 {
+    device_hash ^ proxy_rpc_hash()
+}
+
+fn string_reply(mut s: String) -> Vec<u8> {
+    if s.len() > MAX_REPLY_SIZE {
+        let mut end = MAX_REPLY_SIZE;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+    }
+    s.into_bytes()
+}
+
+impl ProxyCore {
+    /// Answer an introspection RPC sent by a client. Returns the reply or
+    /// error packet if `pkt` was one, `None` if it should go to the device.
+    /// `rpc.listinfo` requests for device RPCs get their index shifted past
+    /// the proxy RPCs.
+    pub(super) fn proxy_rpc(&mut self, pkt: &mut Packet, client_id: u64) -> Option<Packet> {
+        if !self.introspection || (client_id == 0) || (pkt.routing != DeviceRoute::root()) {
+            return None;
+        }
+        let req = match &mut pkt.payload {
+            proto::Payload::RpcRequest(req) => req,
+            _ => return None,
+        };
+        let name = match &req.method {
+            RpcMethod::Name(name) => name.as_str(),
+            RpcMethod::Id(_) => return None,
+        };
+
+        let result = if name == "rpc.info" {
+            // Only answer for our own RPCs, the rest are the device's.
+            let meta = proxy_rpc_meta(std::str::from_utf8(&req.arg).ok()?)?;
+            Ok(meta.to_le_bytes().to_vec())
+        } else if (name == "rpc.listinfo") && (req.arg.len() == 2) {
+            let index = u16::from_le_bytes([req.arg[0], req.arg[1]]);
+            match PROXY_RPCS.get(index as usize) {
+                Some((name, meta)) => {
+                    let mut reply = meta.to_le_bytes().to_vec();
+                    reply.extend(name.as_bytes());
+                    Ok(reply)
+                }
+                None => {
+                    let index = index - PROXY_RPCS.len() as u16;
+                    req.arg = index.to_le_bytes().to_vec();
+                    return None;
+                }
+            }
+        } else if let Some(meta) = proxy_rpc_meta(name) {
+            if ((meta & 0x0200) == 0) && !req.arg.is_empty() {
+                Err(RpcErrorCode::ReadOnly)
+            } else {
+                self.proxy_rpc_call(name, &req.arg, client_id)
+            }
+        } else if name.starts_with("@proxy.") {
+            Err(RpcErrorCode::NotFound)
+        } else {
+            return None;
+        };
+
+        let builder = util::PacketBuilder::new(pkt.routing.clone());
+        let (reply, audit) = match &result {
+            Ok(reply) => (builder.rpc_reply(req.id, reply), AuditResult::Reply(reply)),
+            Err(err) => (builder.rpc_error(req.id, *err), AuditResult::Error(*err)),
+        };
+        let arg = (req.arg.clone(), Instant::now());
+        self.audit_rpc(client_id, &pkt.routing, &req.method, Some(&arg), audit);
+        Some(reply)
+    }
+
+    /// Adjust the reply of the device to a client RPC for the proxy RPCs:
+    /// count them in `rpc.listinfo` and fold them into `rpc.hash`.
+    pub(super) fn proxy_rpc_reply(
+        &self,
+        route: &DeviceRoute,
+        method: &RpcMethod,
+        has_arg: bool,
+        reply: &mut Vec<u8>,
+    ) {
+        if !self.introspection || has_arg || (*route != DeviceRoute::root()) {
+            return;
+        }
+        let name = match method {
+            RpcMethod::Name(name) => name.as_str(),
+            RpcMethod::Id(_) => return,
+        };
+        if (name == "rpc.listinfo") && (reply.len() == 2) {
+            let count = u16::from_le_bytes([reply[0], reply[1]]);
+            let count = count.saturating_add(PROXY_RPCS.len() as u16);
+            *reply = count.to_le_bytes().to_vec();
+        } else if (name == "rpc.hash") && (reply.len() == 4) {
+            let hash = u32::from_le_bytes([reply[0], reply[1], reply[2], reply[3]]);
+            *reply = proxied_rpc_hash(hash).to_le_bytes().to_vec();
+        }
+    }
+
+    fn proxy_rpc_call(
+        &mut self,
+        name: &str,
+        arg: &[u8],
+        client_id: u64,
+    ) -> Result<Vec<u8>, RpcErrorCode> {
+        match name {
+            "@proxy.clients" => {
+                let mut ids: Vec<&u64> = self.clients.keys().collect();
+                ids.sort();
+                let mut list = String::new();
+                for id in ids {
+                    let peer = self.clients[id].peer.as_deref().unwrap_or("-");
+                    let mark = if *id == client_id { " *" } else { "" };
+                    let _ = writeln!(list, "{} {}{}", id, peer, mark);
+                }
+                Ok(string_reply(list))
+            }
+            "@proxy.uptime" => Ok(self
+                .started
+                .elapsed()
+                .as_secs_f64()
+                .to_le_bytes()
+                .to_vec()),
+            "@proxy.url" => Ok(string_reply(self.active_url())),
+            "@proxy.rate" => {
+                let rate = match &self.device {
+                    Some(dev) => match dev.tio_port.rate_info() {
+                        Some(rates) if matches!(dev.rate_change_state, RateChange::RateChanged) => {
+                            rates.target_bps
+                        }
+                        Some(rates) => rates.default_bps,
+                        None => 0,
+                    },
+                    None => 0,
+                };
+                Ok(rate.to_le_bytes().to_vec())
+            }
+            "@proxy.stats" => Ok(string_reply(format!(
+                "clients={} device_packets={} client_rpcs={} rpc_timeouts={} reconnects={} pending_rpcs={}",
+                self.clients.len(),
+                self.stats.device_packets,
+                self.stats.client_rpcs,
+                self.stats.rpc_timeouts,
+                self.stats.reconnects,
+                self.rpc_map.len(),
+            ))),
+            "@proxy.kick" => {
+                let id: [u8; 4] = arg.try_into().map_err(|_| RpcErrorCode::WrongSizeArgs)?;
+                let id = u32::from_le_bytes(id) as u64;
+                if !self.clients.contains_key(&id) {
+                    return Err(RpcErrorCode::InvalidArgs);
+                }
+                self.drop_client(id);
+                Ok(vec![])
+            }
+            _ => Err(RpcErrorCode::NotFound),
+        }
+    }
+}
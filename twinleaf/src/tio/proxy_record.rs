@@ -0,0 +1,260 @@
+//! Proxy recorder
+//!
+//! Optional archive of every packet the proxy receives from the device tree,
+//! written by the proxy core before packets are queued for clients, so it is
+//! complete regardless of how clients keep up. Files roll over by size or
+//! time, and each file starts with the metadata of every known device, so it
+//! can be read on its own like a `tio log` file.
+
+use super::proto::{DeviceRoute, Packet, Payload};
+use super::util;
+use crate::data::DeviceDataParser;
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How long to wait before asking again for metadata a device failed to send.
+const METADATA_RETRY: Duration = Duration::from_secs(5);
+
+/// Written data is flushed to disk at least this often.
+pub(crate) const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Metadata tracking for one device in the tree.
+struct RecordedDevice {
+    parser: DeviceDataParser,
+    /// Metadata is incomplete, and should be requested.
+    wants_metadata: bool,
+    /// Metadata requests sent and not yet answered.
+    pending: usize,
+    retry_at: Instant,
+    /// Metadata was written to the current file.
+    metadata_written: bool,
+}
+
+/// Continuous recorder of device traffic, for use in `ProxyOptions`.
+pub struct ProxyRecorder {
+    next_path: Box<dyn FnMut() -> PathBuf + Send>,
+    max_size: Option<u64>,
+    max_duration: Option<Duration>,
+
+    file: Option<BufWriter<File>>,
+    file_size: u64,
+    file_started: Instant,
+    last_flush: Instant,
+
+    devices: HashMap<DeviceRoute, RecordedDevice>,
+}
+
+impl ProxyRecorder {
+    /// Recorder writing to the files named by `next_path`, called every time
+    /// a new file is started. Existing files are never overwritten.
+    pub fn new<F: FnMut() -> PathBuf + Send + 'static>(next_path: F) -> ProxyRecorder {
+        ProxyRecorder {
+            next_path: Box::new(next_path),
+            max_size: None,
+            max_duration: None,
+            file: None,
+            file_size: 0,
+            file_started: Instant::now(),
+            last_flush: Instant::now(),
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Start a new file once the current one reaches this many bytes.
+    pub fn roll_size(mut self, bytes: u64) -> ProxyRecorder {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Start a new file once the current one covers this much time.
+    pub fn roll_interval(mut self, interval: Duration) -> ProxyRecorder {
+        self.max_duration = Some(interval);
+        self
+    }
+
+    fn needs_new_file(&self) -> bool {
+        if self.file.is_none() {
+            return true;
+        }
+        self.max_size.is_some_and(|max| self.file_size >= max)
+            || self
+                .max_duration
+                .is_some_and(|max| self.file_started.elapsed() >= max)
+    }
+
+    fn start_file(&mut self) -> io::Result<PathBuf> {
+        if let Some(mut old) = self.file.take() {
+            old.flush()?;
+        }
+        let path = (self.next_path)();
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        self.file = Some(BufWriter::new(file));
+        self.file_size = 0;
+        self.file_started = Instant::now();
+
+        let mut routes: Vec<DeviceRoute> = self.devices.keys().cloned().collect();
+        routes.sort();
+        for route in routes {
+            if let Some(dev) = self.devices.get_mut(&route) {
+                dev.metadata_written = false;
+            }
+            self.write_metadata(&route)?;
+        }
+        Ok(path)
+    }
+
+    fn write(&mut self, pkt: &Packet) -> io::Result<()> {
+        let raw = pkt
+            .serialize()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "unserializable packet"))?;
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&raw)?;
+            self.file_size += raw.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Write the metadata of the device at `route`, if complete and not
+    /// already in the current file.
+    fn write_metadata(&mut self, route: &DeviceRoute) -> io::Result<()> {
+        let meta = match self.devices.get_mut(route) {
+            Some(dev) if !dev.metadata_written => match dev.parser.get_metadata() {
+                Ok(meta) => {
+                    dev.metadata_written = true;
+                    meta
+                }
+                Err(_) => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        self.write(&meta.device.make_update_with_route(route.clone()))?;
+        let mut ids: Vec<&u8> = meta.streams.keys().collect();
+        ids.sort();
+        for id in ids {
+            let stream = &meta.streams[id];
+            self.write(&stream.stream.make_update_with_route(route.clone()))?;
+            self.write(&stream.segment.make_update_with_route(route.clone()))?;
+            for col in &stream.columns {
+                self.write(&col.make_update_with_route(route.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a packet received from the device tree. Returns the path of
+    /// the new file, if one was started.
+    pub(crate) fn record(&mut self, pkt: &Packet) -> io::Result<Option<PathBuf>> {
+        let dev = self
+            .devices
+            .entry(pkt.routing.clone())
+            .or_insert_with(|| RecordedDevice {
+                parser: DeviceDataParser::new(false),
+                wants_metadata: true,
+                pending: 0,
+                retry_at: Instant::now(),
+                metadata_written: false,
+            });
+        // Only these can change the metadata known to the parser, and
+        // sample data is not needed here.
+        if let Payload::Heartbeat(_) | Payload::Metadata(_) = &pkt.payload {
+            dev.parser.process_packet(pkt);
+            if !dev.parser.requests().is_empty() {
+                dev.wants_metadata = true;
+                dev.metadata_written = false;
+            }
+        }
+
+        let new_file = if self.needs_new_file() {
+            Some(self.start_file()?)
+        } else {
+            None
+        };
+        self.write(pkt)?;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(new_file)
+    }
+
+    /// Metadata requests to send to devices with incomplete metadata, with
+    /// an `id` to be recognized by `metadata_reply`.
+    pub(crate) fn metadata_requests(&mut self, id: u16) -> Vec<Packet> {
+        let now = Instant::now();
+        let mut ret = vec![];
+        for (route, dev) in self.devices.iter_mut() {
+            if !dev.wants_metadata || (dev.pending > 0) || (now < dev.retry_at) {
+                continue;
+            }
+            for req in dev.parser.requests() {
+                if let Payload::RpcRequest(req) = req.payload {
+                    ret.push(util::PacketBuilder::make_rpc_request(
+                        "dev.metadata",
+                        &req.arg,
+                        id,
+                        route.clone(),
+                    ));
+                    dev.pending += 1;
+                }
+            }
+            if dev.pending == 0 {
+                dev.wants_metadata = false;
+            }
+        }
+        ret
+    }
+
+    /// Process the outcome of a request from `metadata_requests`: the reply
+    /// payload, or `None` if it failed.
+    pub(crate) fn metadata_reply(
+        &mut self,
+        route: &DeviceRoute,
+        reply: Option<&[u8]>,
+    ) -> io::Result<()> {
+        let dev = match self.devices.get_mut(route) {
+            Some(dev) => dev,
+            None => return Ok(()),
+        };
+        dev.pending = dev.pending.saturating_sub(1);
+        match reply {
+            Some(reply) => {
+                dev.parser
+                    .process_packet(&util::PacketBuilder::make_rpc_reply(
+                        0,
+                        reply,
+                        route.clone(),
+                    ));
+            }
+            None => {
+                dev.retry_at = Instant::now() + METADATA_RETRY;
+            }
+        }
+        if dev.pending == 0 {
+            dev.wants_metadata = !dev.parser.requests().is_empty();
+        }
+        if self.file.is_some() {
+            self.write_metadata(route)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        match self.file.as_mut() {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ProxyRecorder {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
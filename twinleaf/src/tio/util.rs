@@ -29,6 +29,21 @@ impl PacketBuilder {
         Self::make_rpc_request(name, arg, id, self.routing.clone())
     }
 
+    pub fn make_rpc_reply(id: u16, reply: &[u8], routing: DeviceRoute) -> Packet {
+        Packet {
+            payload: Payload::RpcReply(proto::RpcReplyPayload {
+                id,
+                reply: reply.to_vec(),
+            }),
+            routing,
+            ttl: 0,
+        }
+    }
+
+    pub fn rpc_reply(&self, id: u16, reply: &[u8]) -> Packet {
+        Self::make_rpc_reply(id, reply, self.routing.clone())
+    }
+
     pub fn make_rpc_error(id: u16, error: proto::RpcErrorCode, routing: DeviceRoute) -> Packet {
         Packet {
             payload: Payload::RpcError(proto::RpcErrorPayload {
@@ -166,6 +181,51 @@ impl TioRpcReplyable<String> for String {
     }
 }
 
+// Arrays of fixed size values, packed back to back. A Vec reply takes up
+// the rest of the reply.
+impl<T: TioRpcRequestable<T>> TioRpcRequestable<Vec<T>> for Vec<T> {
+    fn to_request(&self) -> Vec<u8> {
+        self.iter().flat_map(|v| v.to_request()).collect()
+    }
+}
+
+impl<T: TioRpcRequestable<T>, const N: usize> TioRpcRequestable<[T; N]> for [T; N] {
+    fn to_request(&self) -> Vec<u8> {
+        self.iter().flat_map(|v| v.to_request()).collect()
+    }
+}
+
+impl<T: TioRpcReplyable<T> + TioRpcReplyableFixedSize> TioRpcReplyable<Vec<T>> for Vec<T> {
+    fn from_reply_prefix(reply: &[u8]) -> Result<(Vec<T>, &[u8]), ()> {
+        let mut ret = vec![];
+        let mut rest = reply;
+        while !rest.is_empty() {
+            let (value, tail) = T::from_reply_prefix(rest)?;
+            ret.push(value);
+            rest = tail;
+        }
+        Ok((ret, rest))
+    }
+}
+
+impl<T: TioRpcReplyable<T> + TioRpcReplyableFixedSize, const N: usize> TioRpcReplyable<[T; N]>
+    for [T; N]
+{
+    fn from_reply_prefix(reply: &[u8]) -> Result<([T; N], &[u8]), ()> {
+        let mut values = Vec::with_capacity(N);
+        let mut rest = reply;
+        for _ in 0..N {
+            let (value, tail) = T::from_reply_prefix(rest)?;
+            values.push(value);
+            rest = tail;
+        }
+        let array = values.try_into().map_err(|_| ())?;
+        Ok((array, rest))
+    }
+}
+
+impl<T: TioRpcReplyableFixedSize, const N: usize> TioRpcReplyableFixedSize for [T; N] {}
+
 impl<A: TioRpcRequestable<A>, B: TioRpcRequestable<B>> TioRpcRequestable<(A, B)> for (A, B) {
     fn to_request(&self) -> Vec<u8> {
         let mut ret = self.0.to_request();
@@ -0,0 +1,108 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::SampleBuilder;
+use twinleaf::data::{Archive, BoundaryReason, Buffer, ColumnBatch, ColumnData, ReadError, Sample};
+use twinleaf::tio::proto::identifiers::{ColumnKey, StreamKey};
+use twinleaf::tio::proto::DeviceRoute;
+
+/// Sample `n` of a 1 Hz stream with an integer column holding `10 * n`.
+fn sample(n: u32, boundary: Option<BoundaryReason>) -> Sample {
+    SampleBuilder::new(n)
+        .column("x", ColumnData::Int(10 * n as i64))
+        .boundary(boundary)
+        .build()
+}
+
+/// Samples 0 to 9, then 20 to 24 after lost samples, in a buffer keeping 4.
+fn fill(dir: &PathBuf, max_bytes: u64) -> Buffer {
+    let mut buffer = Buffer::new(4).with_archive(Archive::create(dir, max_bytes).unwrap());
+    let key = StreamKey::new(DeviceRoute::root(), 1);
+    for n in 0..10 {
+        buffer.process_sample(sample(n, None), key.clone());
+    }
+    let lost = BoundaryReason::SamplesLost {
+        expected: 10,
+        received: 20,
+    };
+    buffer.process_sample(sample(20, Some(lost)), key.clone());
+    for n in 21..25 {
+        buffer.process_sample(sample(n, None), key.clone());
+    }
+    buffer
+}
+
+fn segment_files(dir: &Path) -> usize {
+    std::fs::read_dir(dir).map_or(0, |entries| entries.count())
+}
+
+#[test]
+fn reads_history_past_memory_and_runs() {
+    let dir = std::env::temp_dir().join(format!("twinleaf-archive-{}", std::process::id()));
+    let buffer = fill(&dir, u64::MAX);
+    let archive_dir = buffer.archive().unwrap().dir().to_path_buf();
+    assert!(archive_dir.starts_with(&dir));
+    let key = StreamKey::new(DeviceRoute::root(), 1);
+    let column = [ColumnKey::new(DeviceRoute::root(), 1, 0)];
+    assert_eq!(buffer.time_bounds(&key), Some((1.0, 25.0)));
+    assert_eq!(segment_files(&archive_dir), 2);
+
+    let window = buffer.read_aligned_time_range(&column, 2.0, 5.0).unwrap();
+    assert_eq!(window.sample_numbers[&key], [1, 2, 3, 4]);
+    assert_eq!(window.timestamps, [2.0, 3.0, 4.0, 5.0]);
+    assert!(matches!(&window.columns[&column[0]], ColumnBatch::I64(v) if v == &[10, 20, 30, 40]));
+
+    let window = buffer.read_aligned_time_range(&column, 9.0, 22.0).unwrap();
+    assert_eq!(window.sample_numbers[&key], [8, 9, 20, 21]);
+    assert_eq!(window.run_ids[&key], buffer.get_run(&key).unwrap().run_id);
+
+    let window = buffer.read_aligned_time_range(&column, 23.0, 25.0).unwrap();
+    assert_eq!(window.sample_numbers[&key], [22, 23, 24]);
+
+    assert!(matches!(
+        buffer.read_aligned_time_range(&column, 12.0, 15.0),
+        Err(ReadError::RequestedRangeExceedsRetention { .. })
+    ));
+
+    drop(buffer);
+    assert!(!archive_dir.exists());
+    let _ = std::fs::remove_dir(&dir);
+}
+
+#[test]
+fn budget_drops_oldest_runs() {
+    let dir = std::env::temp_dir().join(format!("twinleaf-archive-budget-{}", std::process::id()));
+    let buffer = fill(&dir, 0);
+    let key = StreamKey::new(DeviceRoute::root(), 1);
+    let column = [ColumnKey::new(DeviceRoute::root(), 1, 0)];
+
+    // Only the segment being written is kept.
+    assert_eq!(segment_files(buffer.archive().unwrap().dir()), 1);
+    assert_eq!(buffer.time_bounds(&key), Some((21.0, 25.0)));
+    assert!(buffer.archive().unwrap().error().is_none());
+    assert!(matches!(
+        buffer.read_aligned_time_range(&column, 2.0, 5.0),
+        Err(ReadError::RequestedRangeExceedsRetention { .. })
+    ));
+
+    drop(buffer);
+    let _ = std::fs::remove_dir(&dir);
+}
+
+#[test]
+fn archives_sharing_a_dir_keep_apart() {
+    let dir = std::env::temp_dir().join(format!("twinleaf-archive-shared-{}", std::process::id()));
+    let first = fill(&dir, u64::MAX);
+    let second = fill(&dir, u64::MAX);
+    let first_dir = first.archive().unwrap().dir().to_path_buf();
+    assert_ne!(first_dir, second.archive().unwrap().dir());
+
+    drop(second);
+    assert_eq!(segment_files(&first_dir), 2);
+    let column = [ColumnKey::new(DeviceRoute::root(), 1, 0)];
+    assert!(first.read_aligned_time_range(&column, 2.0, 5.0).is_ok());
+
+    drop(first);
+    let _ = std::fs::remove_dir(&dir);
+}
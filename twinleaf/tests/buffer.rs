@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 
-use twinleaf::data::{Buffer, Column, ColumnBatch, ColumnData, CursorPosition, ReadError, Sample};
+use twinleaf::data::{
+    Buffer, Column, ColumnBatch, ColumnData, CursorPosition, ReadError, ResampleMethod, Sample,
+};
 use twinleaf::tio::proto::identifiers::{ColumnKey, SampleNumber, StreamKey};
 use twinleaf::tio::proto::meta::{
     ColumnMetadata, DeviceMetadata, MetadataEpoch, MetadataFilter, SegmentMetadata, StreamMetadata,
@@ -456,3 +458,171 @@ fn read_from_cursor_handles_wrapped_sample_numbers() {
         _ => panic!("expected f64 batch"),
     }
 }
+
+#[test]
+fn read_since_cursor_returns_all_new_samples() {
+    let mut buffer = Buffer::new(16);
+    let (stream_key, columns, column_keys, device, stream, segment) =
+        test_fixture(&[DataType::Float64]);
+    let rows: Vec<_> = (0..6).map(|i| vec![ColumnData::Float(i as f64)]).collect();
+    push_rows(
+        &mut buffer,
+        &stream_key,
+        &columns,
+        &device,
+        &stream,
+        &segment,
+        &rows,
+    );
+
+    let run_id = buffer.get_run(&stream_key).unwrap().run_id;
+    let cursor = |last_sample_number| {
+        HashMap::from([(
+            stream_key.clone(),
+            CursorPosition {
+                run_id,
+                last_sample_number,
+            },
+        )])
+    };
+
+    let window = buffer.read_since_cursor(&column_keys, &cursor(2)).unwrap();
+    assert_eq!(window.sample_numbers[&stream_key], vec![3, 4, 5]);
+    assert!(matches!(
+        buffer.read_since_cursor(&column_keys, &cursor(5)),
+        Err(ReadError::InsufficientData { available: 0, .. })
+    ));
+}
+
+#[test]
+fn read_aligned_seconds_clamps_to_retention() {
+    let mut buffer = Buffer::new(16);
+    let (stream_key, columns, column_keys, device, stream, segment) =
+        test_fixture(&[DataType::Float64]);
+    let rows: Vec<_> = (0..6).map(|i| vec![ColumnData::Float(i as f64)]).collect();
+    push_rows(
+        &mut buffer,
+        &stream_key,
+        &columns,
+        &device,
+        &stream,
+        &segment,
+        &rows,
+    );
+
+    let window = buffer.read_aligned_seconds(&column_keys, 2.0).unwrap();
+    assert_eq!(window.timestamps, vec![4.0, 5.0, 6.0]);
+    let window = buffer.read_aligned_seconds(&column_keys, 100.0).unwrap();
+    assert_eq!(window.sample_numbers[&stream_key], vec![0, 1, 2, 3, 4, 5]);
+}
+
+/// A 1 Hz stream with integer values 0, 10, ..., 50 and a 4 Hz stream
+/// counting samples, both starting at time 0.
+fn two_rate_fixture() -> (Buffer, StreamKey, StreamKey, Vec<ColumnKey>) {
+    let mut buffer = Buffer::new(64);
+    let (slow_key, slow_columns, slow_keys, device, slow_stream, slow_segment) =
+        test_fixture(&[DataType::Int32]);
+    let rows: Vec<_> = (0..6).map(|i| vec![ColumnData::Int(i * 10)]).collect();
+    push_rows(
+        &mut buffer,
+        &slow_key,
+        &slow_columns,
+        &device,
+        &slow_stream,
+        &slow_segment,
+        &rows,
+    );
+
+    let fast_key = StreamKey::new(DeviceRoute::root(), 2);
+    let fast_stream = Arc::new(StreamMetadata {
+        stream_id: 2,
+        ..(*slow_stream).clone()
+    });
+    let fast_segment = Arc::new(SegmentMetadata {
+        stream_id: 2,
+        sampling_rate: 4,
+        ..(*slow_segment).clone()
+    });
+    let fast_columns = vec![Arc::new(ColumnMetadata {
+        stream_id: 2,
+        data_type: DataType::Float64,
+        ..(*slow_columns[0]).clone()
+    })];
+    let rows: Vec<_> = (0..24).map(|i| vec![ColumnData::Float(i as f64)]).collect();
+    push_rows(
+        &mut buffer,
+        &fast_key,
+        &fast_columns,
+        &device,
+        &fast_stream,
+        &fast_segment,
+        &rows,
+    );
+
+    let column_keys = vec![
+        slow_keys[0].clone(),
+        ColumnKey::new(DeviceRoute::root(), 2, 0),
+    ];
+    (buffer, slow_key, fast_key, column_keys)
+}
+
+#[test]
+fn read_resampled_hold_and_linear_use_fastest_stream() {
+    let (buffer, slow_key, fast_key, column_keys) = two_rate_fixture();
+
+    assert!(matches!(
+        buffer.read_aligned_window(&column_keys, 4),
+        Err(ReadError::SamplingRateMismatch { .. })
+    ));
+
+    let window = buffer
+        .read_resampled_window(&column_keys, 4, ResampleMethod::Hold)
+        .unwrap();
+    assert_eq!(window.timestamps, vec![5.25, 5.5, 5.75, 6.0]);
+    assert_eq!(window.sample_numbers[&fast_key], vec![20, 21, 22, 23]);
+    assert_eq!(window.sample_numbers[&slow_key], vec![4, 4, 4, 5]);
+    match &window.columns[&column_keys[0]] {
+        ColumnBatch::I64(values) => assert_eq!(values, &vec![40, 40, 40, 50]),
+        other => panic!("expected i64 column batch, got {other:?}"),
+    }
+
+    let window = buffer
+        .read_resampled_time_range(&column_keys, 2.0, 2.5, ResampleMethod::Linear)
+        .unwrap();
+    assert_eq!(window.timestamps, vec![2.0, 2.25, 2.5]);
+    match &window.columns[&column_keys[0]] {
+        ColumnBatch::F64(values) => assert_eq!(values, &vec![10.0, 12.5, 15.0]),
+        other => panic!("expected f64 column batch, got {other:?}"),
+    }
+    match &window.columns[&column_keys[1]] {
+        ColumnBatch::F64(values) => assert_eq!(values, &vec![7.0, 8.0, 9.0]),
+        other => panic!("expected f64 column batch, got {other:?}"),
+    }
+
+    assert!(matches!(
+        buffer.read_resampled_time_range(&column_keys, 0.0, 2.0, ResampleMethod::Linear),
+        Err(ReadError::RequestedRangeExceedsRetention { .. })
+    ));
+}
+
+#[test]
+fn read_resampled_mean_decimates_onto_slowest_stream() {
+    let (buffer, slow_key, fast_key, column_keys) = two_rate_fixture();
+
+    let window = buffer
+        .read_resampled_tail(&column_keys, ResampleMethod::Mean)
+        .unwrap();
+    assert_eq!(window.timestamps, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    assert_eq!(window.sample_numbers[&slow_key], vec![0, 1, 2, 3, 4, 5]);
+    assert_eq!(window.sample_numbers[&fast_key], vec![3, 7, 11, 15, 19, 23]);
+    match &window.columns[&column_keys[1]] {
+        ColumnBatch::F64(values) => {
+            assert_eq!(values, &vec![1.5, 5.5, 9.5, 13.5, 17.5, 21.5])
+        }
+        other => panic!("expected f64 column batch, got {other:?}"),
+    }
+    match &window.columns[&column_keys[0]] {
+        ColumnBatch::I64(values) => assert_eq!(values, &vec![0, 10, 20, 30, 40, 50]),
+        other => panic!("expected i64 column batch, got {other:?}"),
+    }
+}
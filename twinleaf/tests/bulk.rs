@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+
+use twinleaf::device::bulk::{BulkError, BulkPort, BulkTransfer};
+use twinleaf::tio::proto::{
+    DeviceRoute, Packet, Payload, RpcErrorCode, RpcErrorPayload, RpcMethod, RpcReplyPayload,
+};
+use twinleaf::tio::proxy::{RecvError, SendError};
+
+/// Device with a blob that can be read in chunks of 4 bytes, or written in
+/// any chunks. The requests listed in `drop` get a timeout instead of a
+/// reply, counting requests from 0.
+#[derive(Default)]
+struct MockDevice {
+    blob: Vec<u8>,
+    written: Vec<u8>,
+    drop: Vec<usize>,
+    requests: usize,
+    replies: VecDeque<Packet>,
+}
+
+fn reply(payload: Payload) -> Packet {
+    Packet {
+        payload,
+        routing: DeviceRoute::root(),
+        ttl: 0,
+    }
+}
+
+impl BulkPort for &mut MockDevice {
+    fn send_request(&mut self, pkt: Packet) -> Result<(), SendError> {
+        let Payload::RpcRequest(req) = pkt.payload else {
+            panic!("not a request");
+        };
+        let n = self.requests;
+        self.requests += 1;
+        let error = |error| {
+            Payload::RpcError(RpcErrorPayload {
+                id: req.id,
+                error,
+                extra: vec![],
+            })
+        };
+        let payload = if self.drop.contains(&n) {
+            error(RpcErrorCode::Timeout)
+        } else {
+            match req.method {
+                RpcMethod::Name(name) if name == "blob.read" => {
+                    let chunk = u16::from_le_bytes([req.arg[0], req.arg[1]]) as usize;
+                    match self.blob.chunks(4).nth(chunk) {
+                        Some(data) => Payload::RpcReply(RpcReplyPayload {
+                            id: req.id,
+                            reply: data.to_vec(),
+                        }),
+                        None => error(RpcErrorCode::InvalidArgs),
+                    }
+                }
+                RpcMethod::Name(name) if name == "blob.write" => {
+                    self.written.extend(&req.arg);
+                    Payload::RpcReply(RpcReplyPayload {
+                        id: req.id,
+                        reply: vec![],
+                    })
+                }
+                RpcMethod::Name(name) if name == "blob.crc" => Payload::RpcReply(RpcReplyPayload {
+                    id: req.id,
+                    reply: 0xCBF43926u32.to_le_bytes().to_vec(),
+                }),
+                _ => error(RpcErrorCode::NotFound),
+            }
+        };
+        // Unrelated traffic should be skipped.
+        self.replies
+            .push_back(reply(Payload::RpcReply(RpcReplyPayload {
+                id: 7855,
+                reply: vec![],
+            })));
+        self.replies.push_back(reply(payload));
+        Ok(())
+    }
+
+    fn recv_packet(&mut self) -> Result<Packet, RecvError> {
+        self.replies.pop_front().ok_or(RecvError::ProxyDisconnected)
+    }
+}
+
+#[test]
+fn test_bulk_read() {
+    let mut dev = MockDevice {
+        blob: b"123456789".to_vec(),
+        drop: vec![1],
+        ..Default::default()
+    };
+    let mut chunks = 0;
+    let data = BulkTransfer::new(&mut dev, "blob.read")
+        .window(3)
+        .expect_crc(0xCBF43926)
+        .on_progress(|p| chunks = p.chunks)
+        .read()
+        .unwrap();
+    assert_eq!(data, b"123456789");
+    assert_eq!(chunks, 3);
+
+    dev.drop = (0..10).collect();
+    dev.requests = 0;
+    let err = BulkTransfer::new(&mut dev, "blob.read")
+        .retries(2)
+        .read()
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        BulkError::Timeout {
+            chunk: 0,
+            attempts: 3
+        }
+    ));
+}
+
+#[test]
+fn test_bulk_write() {
+    let mut dev = MockDevice {
+        drop: vec![2],
+        ..Default::default()
+    };
+    BulkTransfer::new(&mut dev, "blob.write")
+        .window(2)
+        .chunk_size(2)
+        .retries(1)
+        .verify_rpc("blob.crc")
+        .write(b"123456789")
+        .unwrap();
+    // Chunk 2 timed out, so chunk 3 was discarded and sent again after it.
+    assert_eq!(dev.written, b"12347856789");
+
+    // Writes don't retry by default.
+    let mut dev = MockDevice {
+        drop: vec![2],
+        ..Default::default()
+    };
+    let err = BulkTransfer::new(&mut dev, "blob.write")
+        .chunk_size(2)
+        .write(b"123456789")
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        BulkError::Timeout {
+            chunk: 2,
+            attempts: 1
+        }
+    ));
+
+    let mut dev = MockDevice::default();
+    let err = BulkTransfer::new(&mut dev, "blob.write")
+        .expect_crc(0)
+        .write(b"123456789")
+        .unwrap_err();
+    assert!(matches!(err, BulkError::Checksum { expected: 0, .. }));
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::SampleBuilder;
+use twinleaf::data::calibration::{unit_factor, Calibration, CalibrationError};
+use twinleaf::data::{ColumnData, Sample};
+use twinleaf::tio::proto::DataType;
+
+const CALIBRATION: &str = r#"
+["SN123"."/vector/x"]
+gain = 2.0
+offset = 1
+
+["SN123".vector]
+columns = ["x", "y"]
+matrix = [[1.0, 0.5], [0.0, 1.0]]
+offset = [0.0, 10.0]
+
+["*"."**/y"]
+units = "pT"
+
+["OTHER"."**/z"]
+gain = 100.0
+"#;
+
+fn sample(serial: &str) -> Sample {
+    SampleBuilder::new(0)
+        .typed_column("x", DataType::Int32, ColumnData::Int(3))
+        .typed_column("y", DataType::Float32, ColumnData::Int(3))
+        .units("nT")
+        .stream("vector")
+        .serial(serial)
+        .rate(10)
+        .build()
+}
+
+#[test]
+fn test_unit_factor() {
+    assert_eq!(unit_factor("nT", "pT"), Some(1000.0));
+    assert_eq!(unit_factor("V", "mV"), Some(1000.0));
+    assert_eq!(unit_factor("m", "mm"), Some(1000.0));
+    assert_eq!(unit_factor("mT", "T"), Some(1e-3));
+    assert_eq!(unit_factor("nT", "V"), None);
+    assert_eq!(unit_factor("", "V"), None);
+}
+
+#[test]
+fn test_apply() {
+    let calibration = Calibration::from_toml(CALIBRATION).unwrap();
+    assert_eq!(calibration.rules().len(), 4);
+
+    let mut s = sample("SN123");
+    let descs: Vec<_> = s.columns.iter().map(|c| c.desc.clone()).collect();
+    let stream = calibration.stream("SN123", "vector", &descs).unwrap();
+    stream.apply(&mut s);
+    // x = 2 * 3 + 1 = 7, then x = 7 + 0.5 * 3 and y = 3 + 10, then y in pT.
+    let values: Vec<_> = s.columns.iter().map(|c| c.value.try_as_f64()).collect();
+    assert_eq!(values, [Some(8.5), Some(13000.0)]);
+    assert!(matches!(s.columns[0].value, ColumnData::Float(_)));
+    assert!(matches!(s.columns[0].desc.data_type, DataType::Float64));
+    assert_eq!(s.columns[0].desc.units, "nT");
+    assert_eq!(s.columns[1].desc.units, "pT");
+    assert!(stream
+        .describe(0)
+        .unwrap()
+        .starts_with("gain 2 offset 1; matrix"));
+    assert!(stream.describe(1).unwrap().ends_with("; units pT"));
+
+    let mut s = sample("SN999");
+    let stream = calibration.stream("SN999", "vector", &descs).unwrap();
+    stream.apply(&mut s);
+    assert!(matches!(s.columns[0].value, ColumnData::Int(3)));
+    assert_eq!(s.columns[1].value.try_as_f64(), Some(3000.0));
+    assert_eq!(stream.describe(0), None);
+
+    assert!(calibration.stream("SN999", "other", &descs[..1]).is_none());
+}
+
+#[test]
+fn test_invalid() {
+    let err = |toml: &str| Calibration::from_toml(toml).err().unwrap();
+    assert!(matches!(err("[SN1"), CalibrationError::Parse(_)));
+    let invalid = [
+        "[SN1.x]\ngain = \"2\"",
+        "[SN1.x]\nscale = 2",
+        "[SN1.x]\nunits = \"\"",
+        "[SN1.v]\ncolumns = [\"x\", \"y\"]\nmatrix = [[1.0, 0.0]]",
+        "[SN1.v]\nmatrix = [[1.0]]",
+        "[SN1.x]\n",
+    ];
+    for toml in invalid {
+        assert!(
+            matches!(err(toml), CalibrationError::Invalid { .. }),
+            "{}",
+            toml
+        );
+    }
+}
@@ -0,0 +1,186 @@
+mod common;
+
+use common::SampleBuilder;
+use twinleaf::data::clock::{ClockEstimator, TimeRef, UtcMapping};
+use twinleaf::data::{Buffer, ColumnData, ReadError, Sample};
+use twinleaf::tio::proto::identifiers::{ColumnKey, StreamKey};
+use twinleaf::tio::proto::meta::MetadataEpoch;
+use twinleaf::tio::proto::DeviceRoute;
+
+/// Sample 49 of a 10 Hz stream whose segment started at device time 100.
+fn sample(epoch: MetadataEpoch) -> Sample {
+    SampleBuilder::new(49)
+        .rate(10)
+        .start_time(100)
+        .epoch(epoch)
+        .build()
+}
+
+#[test]
+fn estimates_offset_then_drift() {
+    const START: f64 = 1.7e9;
+    let arrival = |t: f64| START + t * (1.0 + 20e-6) + 0.004;
+
+    let mut clock = ClockEstimator::default();
+    let first = clock.observe(0.0, arrival(0.0));
+    assert_eq!((first.utc, first.drift), (START + 0.004, 0.0));
+
+    for i in 1..200 {
+        clock.observe(i as f64, arrival(i as f64));
+    }
+    let mapping = clock.mapping_at(500.0).unwrap();
+    assert!((mapping.drift - 20e-6).abs() < 1e-9);
+    assert!((mapping.utc(1000.0) - arrival(1000.0)).abs() < 1e-6);
+
+    clock.reset();
+    assert!(clock.mapping_at(0.0).is_none());
+}
+
+#[test]
+fn utc_depends_on_epoch() {
+    assert_eq!(sample(MetadataEpoch::Unix).utc(None), Some(105.0));
+    assert_eq!(sample(MetadataEpoch::Zero).utc(None), None);
+
+    let clock = UtcMapping {
+        device_time: 100.0,
+        utc: START_OF_2026,
+        drift: 0.0,
+        uncertainty: 0.0,
+    };
+    let mapped = sample(MetadataEpoch::Systime);
+    assert_eq!(mapped.utc(Some(&clock)), Some(START_OF_2026 + 5.0));
+    // Printed times stay on the device clock.
+    assert_eq!(mapped.to_string(), "SAMPLE(42:1:0) 105.000000 [#49]");
+}
+
+const START_OF_2026: f64 = 1_767_225_600.0;
+
+/// Sample `n` of a 10 Hz stream on the clock of `time_ref`, started at time
+/// 100.
+fn clocked_sample(time_ref: &str, n: u32) -> Sample {
+    SampleBuilder::new(n)
+        .column("x", ColumnData::Float(n as f64))
+        .rate(10)
+        .start_time(100)
+        .epoch(MetadataEpoch::Zero)
+        .time_ref(time_ref, 1)
+        .build()
+}
+
+/// Relate the clock of `time_ref` to UTC, reading `utc_offset` seconds
+/// behind it.
+fn set_clock(buffer: &mut Buffer, time_ref: &str, utc_offset: f64) {
+    let time_ref = TimeRef {
+        serial: time_ref.to_string(),
+        session_id: 1,
+    };
+    let clock = UtcMapping {
+        device_time: 100.0,
+        utc: 100.0 + utc_offset,
+        drift: 0.0,
+        uncertainty: 0.001,
+    };
+    buffer.set_clock(time_ref, clock);
+}
+
+fn keys() -> (StreamKey, StreamKey, Vec<ColumnKey>) {
+    let a = DeviceRoute::from_str("/0").unwrap();
+    let b = DeviceRoute::from_str("/1").unwrap();
+    let columns = vec![
+        ColumnKey::new(a.clone(), 1, 0),
+        ColumnKey::new(b.clone(), 1, 0),
+    ];
+    (StreamKey::new(a, 1), StreamKey::new(b, 1), columns)
+}
+
+#[test]
+fn shared_reference_aligns_by_index() {
+    let (a, b, columns) = keys();
+    let mut buffer = Buffer::new(64);
+    // On one clock, but the second device samples at 10.0001 Hz, so that
+    // its times drift off those of the first.
+    for n in 0..20 {
+        buffer.process_sample(clocked_sample("HUB", n), a.clone());
+        let fast = SampleBuilder::new(n)
+            .column("x", ColumnData::Float(n as f64))
+            .rate(100_001)
+            .decimation(10_000)
+            .start_time(100)
+            .epoch(MetadataEpoch::Zero)
+            .time_ref("HUB", 1)
+            .build();
+        buffer.process_sample(fast, b.clone());
+    }
+
+    let groups = buffer.time_refs();
+    let hub = TimeRef {
+        serial: "HUB".to_string(),
+        session_id: 1,
+    };
+    assert_eq!(groups[&hub], [a.clone(), b.clone()]);
+    assert_eq!(buffer.time_offset(&a, &b).unwrap().uncertainty, 0.0);
+
+    let window = buffer.read_aligned_window(&columns, 5).unwrap();
+    assert_eq!(window.sample_numbers[&a], [15, 16, 17, 18, 19]);
+    assert_eq!(window.sample_numbers[&b], [15, 16, 17, 18, 19]);
+    assert!(window.time_offsets.is_empty());
+
+    // The second device's last sample is just before the first's, so the
+    // common tail ends at sample 18.
+    let window = buffer.read_aligned_tail(&columns).unwrap();
+    assert_eq!(window.sample_numbers[&a], (0..19).collect::<Vec<_>>());
+    assert_eq!(window.sample_numbers[&b], (0..19).collect::<Vec<_>>());
+}
+
+#[test]
+fn separate_references_align_through_utc() {
+    let (a, b, columns) = keys();
+    let mut buffer = Buffer::new(64);
+    // The clock of B reads 0.2 s, two samples, behind that of A.
+    for n in 0..20 {
+        buffer.process_sample(clocked_sample("A", n), a.clone());
+        buffer.process_sample(clocked_sample("B", n), b.clone());
+    }
+    assert_eq!(buffer.time_refs().len(), 2);
+    assert!(buffer.time_offset(&a, &b).is_none());
+    set_clock(&mut buffer, "A", 1000.0);
+    set_clock(&mut buffer, "B", 1000.2);
+
+    let offset = buffer.time_offset(&a, &b).unwrap();
+    assert!((offset.seconds + 0.2).abs() < 1e-9);
+    assert!((offset.uncertainty - 0.001 * 2f64.sqrt()).abs() < 1e-12);
+
+    let window = buffer.read_aligned_window(&columns, 5).unwrap();
+    assert_eq!(window.sample_numbers[&a], [15, 16, 17, 18, 19]);
+    assert_eq!(window.sample_numbers[&b], [13, 14, 15, 16, 17]);
+    assert_eq!(window.time_offsets[&b], offset);
+
+    let window = buffer
+        .read_aligned_time_range(&columns, 100.95, 101.25)
+        .unwrap();
+    assert_eq!(window.sample_numbers[&b], [7, 8, 9]);
+}
+
+#[test]
+fn unmatched_samples_fail_the_read() {
+    let (a, b, columns) = keys();
+    let mut buffer = Buffer::new(64);
+    set_clock(&mut buffer, "A", 1000.0);
+    set_clock(&mut buffer, "B", 1000.0);
+    for n in 0..20 {
+        buffer.process_sample(clocked_sample("A", n), a.clone());
+    }
+    // Samples 10 and 11 of the second device are lost, and its latest
+    // packets have not arrived yet.
+    for n in (0..18).filter(|n| !(10..12).contains(n)) {
+        buffer.process_sample(clocked_sample("B", n), b.clone());
+    }
+
+    let window = buffer.read_aligned_window(&columns, 5).unwrap();
+    assert_eq!(window.sample_numbers[&a], [13, 14, 15, 16, 17]);
+    assert_eq!(window.sample_numbers[&b], [13, 14, 15, 16, 17]);
+    assert!(matches!(
+        buffer.read_aligned_window(&columns, 10),
+        Err(ReadError::InsufficientData { stream_key, .. }) if stream_key == b
+    ));
+}
@@ -0,0 +1,176 @@
+//! Sample builder shared by the integration tests.
+#![allow(dead_code)]
+
+use std::sync::Arc;
+
+use twinleaf::data::{Boundary, BoundaryReason, Column, ColumnData, Sample};
+use twinleaf::tio::proto::meta::{
+    ColumnMetadata, DeviceMetadata, MetadataEpoch, MetadataFilter, SegmentMetadata, StreamMetadata,
+};
+use twinleaf::tio::proto::{DataType, StreamDataPayload};
+
+/// Sample `n` of stream 1 of device "SN123", by default of a 1 Hz
+/// "test-stream" without columns, on a `Unix` clock that started the
+/// segment at time 0.
+pub struct SampleBuilder {
+    n: u32,
+    columns: Vec<(String, DataType, ColumnData)>,
+    units: String,
+    stream: String,
+    serial: String,
+    rate: u32,
+    decimation: u32,
+    start_time: u32,
+    epoch: MetadataEpoch,
+    time_ref: (String, u32),
+    boundary: Option<BoundaryReason>,
+}
+
+impl SampleBuilder {
+    pub fn new(n: u32) -> SampleBuilder {
+        SampleBuilder {
+            n,
+            columns: Vec::new(),
+            units: String::new(),
+            stream: "test-stream".to_string(),
+            serial: "SN123".to_string(),
+            rate: 1,
+            decimation: 1,
+            start_time: 0,
+            epoch: MetadataEpoch::Unix,
+            time_ref: ("clock".to_string(), 7),
+            boundary: None,
+        }
+    }
+
+    /// Add a column holding `value`, of type `Int32`, `UInt32` or `Float64`
+    /// to match.
+    pub fn column(self, name: &str, value: ColumnData) -> SampleBuilder {
+        let data_type = match value {
+            ColumnData::Int(_) => DataType::Int32,
+            ColumnData::UInt(_) => DataType::UInt32,
+            _ => DataType::Float64,
+        };
+        self.typed_column(name, data_type, value)
+    }
+
+    pub fn typed_column(
+        mut self,
+        name: &str,
+        data_type: DataType,
+        value: ColumnData,
+    ) -> SampleBuilder {
+        self.columns.push((name.to_string(), data_type, value));
+        self
+    }
+
+    /// Units of every column.
+    pub fn units(mut self, units: &str) -> SampleBuilder {
+        self.units = units.to_string();
+        self
+    }
+
+    pub fn stream(mut self, name: &str) -> SampleBuilder {
+        self.stream = name.to_string();
+        self
+    }
+
+    pub fn serial(mut self, serial: &str) -> SampleBuilder {
+        self.serial = serial.to_string();
+        self
+    }
+
+    pub fn rate(mut self, hz: u32) -> SampleBuilder {
+        self.rate = hz;
+        self
+    }
+
+    pub fn decimation(mut self, decimation: u32) -> SampleBuilder {
+        self.decimation = decimation;
+        self
+    }
+
+    pub fn start_time(mut self, seconds: u32) -> SampleBuilder {
+        self.start_time = seconds;
+        self
+    }
+
+    pub fn epoch(mut self, epoch: MetadataEpoch) -> SampleBuilder {
+        self.epoch = epoch;
+        self
+    }
+
+    /// Serial number and session of the device whose clock times the
+    /// samples.
+    pub fn time_ref(mut self, serial: &str, session_id: u32) -> SampleBuilder {
+        self.time_ref = (serial.to_string(), session_id);
+        self
+    }
+
+    pub fn boundary(mut self, reason: Option<BoundaryReason>) -> SampleBuilder {
+        self.boundary = reason;
+        self
+    }
+
+    pub fn build(self) -> Sample {
+        let columns: Vec<Column> = self
+            .columns
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, data_type, value))| Column {
+                value,
+                desc: Arc::new(ColumnMetadata {
+                    stream_id: 1,
+                    index,
+                    data_type,
+                    name,
+                    units: self.units.clone(),
+                    description: String::new(),
+                }),
+            })
+            .collect();
+        let n_columns = columns.len();
+        Sample {
+            n: self.n,
+            columns,
+            segment: Arc::new(SegmentMetadata {
+                stream_id: 1,
+                segment_id: 0,
+                flags: 0,
+                time_ref_epoch: self.epoch,
+                time_ref_serial: self.time_ref.0,
+                time_ref_session_id: self.time_ref.1,
+                start_time: self.start_time,
+                sampling_rate: self.rate,
+                decimation: self.decimation,
+                filter_cutoff: 0.0,
+                filter_type: MetadataFilter::Unfiltered,
+            }),
+            stream: Arc::new(StreamMetadata {
+                stream_id: 1,
+                name: self.stream,
+                n_columns,
+                n_segments: 1,
+                sample_size: 0,
+                buf_samples: 1024,
+            }),
+            device: Arc::new(DeviceMetadata {
+                serial_number: self.serial,
+                firmware_hash: "fw".to_string(),
+                n_streams: 1,
+                session_id: 42,
+                name: "test-device".to_string(),
+            }),
+            source: StreamDataPayload {
+                stream_id: 1,
+                first_sample_n: self.n,
+                segment_id: 0,
+                data: Vec::new(),
+            },
+            boundary: self.boundary.map(|reason| Boundary {
+                reason,
+                prior: None,
+            }),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use twinleaf::tio::proxy::RpcError;
+use twinleaf::tio::util::{TioRpcReplyable, TioRpcRequestable};
+use twinleaf_derive::TioRpcs;
+
+/// Stand-in for `proxy::Port`, storing written values and replying with
+/// the last value written to each RPC.
+#[derive(Default)]
+struct MockPort {
+    values: RefCell<HashMap<String, Vec<u8>>>,
+    calls: RefCell<Vec<String>>,
+}
+
+impl MockPort {
+    fn rpc<ReqT: TioRpcRequestable<ReqT>, RepT: TioRpcReplyable<RepT>>(
+        &self,
+        name: &str,
+        arg: ReqT,
+    ) -> Result<RepT, RpcError> {
+        self.calls.borrow_mut().push(name.to_string());
+        let arg = arg.to_request();
+        let mut values = self.values.borrow_mut();
+        if !arg.is_empty() {
+            values.insert(name.to_string(), arg);
+        }
+        let reply = values.get(name).cloned().unwrap_or_default();
+        RepT::from_reply(&reply).map_err(|_| RpcError::TypeError)
+    }
+}
+
+#[derive(TioRpcs)]
+#[rpcs(prefix = "test")]
+#[rpc("test.frequency": f64, rw)]
+#[rpc("test.label": String, rw)]
+#[rpc("test.go": (), action)]
+#[rpc("test.type": u8, r)]
+struct Declared {
+    port: MockPort,
+}
+
+#[derive(TioRpcs)]
+#[rpcs(file = "tests/data/test.rpcs", port = "port")]
+struct FromCache {
+    port: MockPort,
+    #[allow(dead_code)]
+    other: u32,
+}
+
+#[derive(TioRpcs)]
+#[rpcs(file = "tests/data/test-list.txt", prefix = "test.", mutable)]
+struct FromListing(MockPort);
+
+#[test]
+fn test_derive_declared() {
+    let dev = Declared {
+        port: MockPort::default(),
+    };
+    assert_eq!(dev.set_frequency(12.5).unwrap(), 12.5);
+    assert_eq!(dev.frequency().unwrap(), 12.5);
+    assert_eq!(dev.set_label("bench").unwrap(), "bench");
+    assert_eq!(dev.label().unwrap(), "bench");
+    dev.go().unwrap();
+    // Nothing stored, so the reply is too short for a u8.
+    assert!(matches!(dev.r#type(), Err(RpcError::TypeError)));
+    assert_eq!(
+        *dev.port.calls.borrow(),
+        [
+            "test.frequency",
+            "test.frequency",
+            "test.label",
+            "test.label",
+            "test.go",
+            "test.type"
+        ]
+    );
+}
+
+#[test]
+fn test_derive_from_file() {
+    let dev = FromCache {
+        port: MockPort::default(),
+        other: 0,
+    };
+    dev.test_amplitude().unwrap_err();
+    assert_eq!(dev.set_test_amplitude(0.5).unwrap(), 0.5);
+    dev.test_go().unwrap();
+    dev.dev_name().unwrap();
+    // rpc.info has meta 0, an unknown type, so it gets no method.
+    assert_eq!(dev.port.calls.borrow().len(), 4);
+
+    let mut dev = FromListing(MockPort::default());
+    assert_eq!(dev.set_frequency(3.0).unwrap(), 3.0);
+    assert_eq!(dev.set_label("x").unwrap(), "x");
+    dev.go().unwrap();
+    dev.status().unwrap_err();
+    assert_eq!(dev.0.calls.borrow().len(), 4);
+}
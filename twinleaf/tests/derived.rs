@@ -0,0 +1,133 @@
+mod common;
+
+use common::SampleBuilder;
+use twinleaf::data::derived::{DeriveError, DerivedSpec, Expression, SampleDeriver};
+use twinleaf::data::{Buffer, ColumnBatch, ColumnData, Sample};
+use twinleaf::tio::proto::identifiers::StreamKey;
+use twinleaf::tio::proto::{DataType, DeviceRoute};
+
+/// Sample `n` of a "vector" stream with columns x, y and z.
+fn sample(n: u32, values: [i64; 3]) -> Sample {
+    let [x, y, z] = values;
+    SampleBuilder::new(n)
+        .column("x", ColumnData::Int(x))
+        .column("y", ColumnData::Int(y))
+        .column("z", ColumnData::Int(z))
+        .units("nT")
+        .stream("vector")
+        .rate(10)
+        .build()
+}
+
+fn eval(expression: &str, values: &[f64]) -> f64 {
+    expression.parse::<Expression>().unwrap().evaluate(values)
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(eval("1 + 2 * x ^ 2", &[3.0]), 19.0);
+    assert_eq!(eval("-x^2 + 2^3^2", &[3.0]), 503.0);
+    assert_eq!(eval("(a - b) / 2e1", &[50.0, 10.0]), 2.0);
+    assert_eq!(
+        eval("hypot(x, y, z) - sqrt(x^2+y^2+z^2)", &[1.0, 2.0, 2.0]),
+        0.0
+    );
+    assert_eq!(eval("max(abs(x), 2) + min(x, 1.5)", &[-4.0]), 0.0);
+
+    let expression: Expression = "{/0/vector/x} - x + {vector/x} * x".parse().unwrap();
+    assert_eq!(expression.references(), ["/0/vector/x", "x", "vector/x"]);
+
+    let spec: DerivedSpec = " B[nT] = sqrt(x^2 + y^2) ".parse().unwrap();
+    assert_eq!((spec.name.as_str(), spec.units.as_str()), ("B", "nT"));
+    assert_eq!(spec.to_string(), "B[nT] = sqrt(x^2 + y^2)");
+
+    for invalid in ["sqrt(x", "x +", "2 * 3", "foo(x)", "atan2(x)", "x y", "{x"] {
+        assert!(
+            matches!(
+                invalid.parse::<Expression>(),
+                Err(DeriveError::Parse { .. })
+            ),
+            "{}",
+            invalid
+        );
+    }
+    assert!(matches!(
+        "sqrt(x)".parse::<DerivedSpec>(),
+        Err(DeriveError::InvalidSpec(_))
+    ));
+}
+
+#[test]
+fn test_buffer() {
+    let mut buffer = Buffer::new(16);
+    let root = StreamKey::new(DeviceRoute::root(), 1);
+    let child = StreamKey::new(DeviceRoute::from_str("/0").unwrap(), 1);
+    for n in 0..4 {
+        let v = n as i64;
+        buffer.process_sample(sample(n, [v, 2 * v, 2 * v]), root.clone());
+        buffer.process_sample(sample(n, [10 * v, 0, 0]), child.clone());
+    }
+
+    let derive = |buffer: &mut Buffer, spec: &str| buffer.derive(&spec.parse().unwrap());
+    assert!(matches!(
+        derive(&mut buffer, "B = sqrt(x^2 + y^2 + z^2)"),
+        Err(DeriveError::AmbiguousColumn { count: 2, .. })
+    ));
+    assert!(matches!(
+        derive(&mut buffer, "dx = {/1/vector/x}"),
+        Err(DeriveError::UnknownColumn(_))
+    ));
+    let magnitude = derive(&mut buffer, "B[nT] = hypot({/x}, {/y}, {/z})").unwrap();
+    let difference = derive(&mut buffer, "dx = {/0/vector/x} - {/vector/x}").unwrap();
+    assert_eq!(magnitude.stream_key(), root);
+    assert_eq!(difference.stream_key(), child);
+    assert_eq!(buffer.derived_columns().len(), 2);
+
+    let window = buffer
+        .read_aligned_window(&[magnitude.clone(), difference.clone()], 3)
+        .unwrap();
+    assert_eq!(window.columns.len(), 2);
+    assert!(matches!(&window.columns[&magnitude], ColumnBatch::F64(v) if v == &[3.0, 6.0, 9.0]));
+    assert!(matches!(&window.columns[&difference], ColumnBatch::F64(v) if v == &[9.0, 18.0, 27.0]));
+    let metadata = &window.column_metadata[&magnitude];
+    assert_eq!(
+        (metadata.name.as_str(), metadata.units.as_str()),
+        ("B", "nT")
+    );
+    assert_eq!(window.sample_numbers[&root], [1, 2, 3]);
+}
+
+#[test]
+fn test_sample_deriver() {
+    let specs = [
+        "d = {/vector/x} - {/0/vector/x}",
+        "s = {/0/vector/x} + 1",
+        "u = {/2/vector/x}",
+    ]
+    .iter()
+    .map(|s| s.parse().unwrap())
+    .collect();
+    let mut deriver = SampleDeriver::new(specs);
+    let root = DeviceRoute::root();
+    let child = DeviceRoute::from_str("/0").unwrap();
+
+    let mut s = sample(0, [5, 0, 0]);
+    deriver.apply(&mut s, &root).unwrap();
+    assert_eq!(s.columns.len(), 4);
+    assert_eq!(s.columns[3].desc.name, "d");
+    assert_eq!(s.columns[3].desc.index, 3);
+    assert!(matches!(s.columns[3].desc.data_type, DataType::Float64));
+    assert!(matches!(s.columns[3].value, ColumnData::Float(v) if v.is_nan()));
+
+    let mut s = sample(0, [2, 0, 0]);
+    deriver.apply(&mut s, &child).unwrap();
+    assert_eq!(s.columns.len(), 4);
+    assert!(matches!(s.columns[3].value, ColumnData::Float(v) if v == 3.0));
+
+    let mut s = sample(1, [7, 0, 0]);
+    deriver.apply(&mut s, &root).unwrap();
+    assert!(matches!(s.columns[3].value, ColumnData::Float(v) if v == 5.0));
+
+    let unresolved: Vec<_> = deriver.unresolved().map(|s| s.name.as_str()).collect();
+    assert_eq!(unresolved, ["u"]);
+}
@@ -0,0 +1,208 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use twinleaf::data::dsp::{FilterSpec, Operator, Pipeline, SampleFilter};
+use twinleaf::data::{Boundary, BoundaryReason, Column, ColumnBatch, ColumnData, Sample};
+use twinleaf::tio::proto::meta::{
+    ColumnMetadata, DeviceMetadata, MetadataEpoch, MetadataFilter, SegmentMetadata, StreamMetadata,
+};
+use twinleaf::tio::proto::{DataType, DeviceRoute, StreamDataPayload};
+
+const RATE: f64 = 1000.0;
+
+fn sine(freq: f64, n: usize) -> Vec<f64> {
+    (0..n)
+        .map(|i| (2.0 * PI * freq * i as f64 / RATE).sin())
+        .collect()
+}
+
+/// RMS gain of `spec` for a unit sine, after settling.
+fn gain(spec: &str, freq: f64) -> f64 {
+    let mut op = Pipeline::from_str(spec).unwrap().build(RATE).unwrap();
+    let input = sine(freq, 4000);
+    let out: Vec<f64> = input.iter().filter_map(|&x| op.process(x)).collect();
+    let rms = |v: &[f64]| (v.iter().map(|x| x * x).sum::<f64>() / v.len() as f64).sqrt();
+    rms(&out[out.len() / 2..]) / rms(&input[input.len() / 2..])
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!(
+        FilterSpec::from_str("notch:60:3").unwrap(),
+        FilterSpec::Notch {
+            freq: 60.0,
+            harmonics: 3,
+            q: 30.0
+        }
+    );
+    let pipeline = Pipeline::from_str("lowpass:10:4,decimate:5,median:3").unwrap();
+    assert_eq!(pipeline.specs.len(), 3);
+    assert_eq!(pipeline.decimation(), 5);
+    assert!(FilterSpec::from_str("lowpass").is_err());
+    assert!(FilterSpec::from_str("mean:2.5").is_err());
+    assert!(Pipeline::from_str("lowpass:600")
+        .unwrap()
+        .build(RATE)
+        .is_err());
+}
+
+#[test]
+fn test_filters() {
+    assert!((gain("lowpass:50:4", 5.0) - 1.0).abs() < 0.01);
+    assert!(gain("lowpass:50:4", 200.0) < 0.01);
+    assert!(gain("highpass:50:3", 5.0) < 0.01);
+    assert!((gain("bandpass:10:400:4", 100.0) - 1.0).abs() < 0.01);
+    assert!(gain("bandpass:10:400:4", 1.0) < 0.01);
+    assert!((gain("fir-bandpass:50:250:101", 150.0) - 1.0).abs() < 0.01);
+    assert!((gain("fir-lowpass:50:101", 5.0) - 1.0).abs() < 0.01);
+    assert!(gain("fir-lowpass:50:101", 200.0) < 0.01);
+    assert!(gain("fir-highpass:50:101", 5.0) < 0.01);
+    assert!(gain("notch:60:3", 60.0) < 0.01);
+    assert!(gain("notch:60:3", 180.0) < 0.01);
+    assert!((gain("notch:60:3", 100.0) - 1.0).abs() < 0.05);
+    assert!(gain("decimate:10", 200.0) < 0.01);
+}
+
+#[test]
+fn test_window_operators() {
+    let mut median = Pipeline::from_str("median:3").unwrap().build(RATE).unwrap();
+    let out = median.process_batch(&ColumnBatch::I64(vec![1, 100, 2, 3, -50, 4]));
+    assert!(matches!(out, ColumnBatch::F64(v) if v == [1.0, 50.5, 2.0, 3.0, 2.0, 3.0]));
+    // Repeated values leave the window one at a time.
+    let out = median.process_batch(&ColumnBatch::I64(vec![7, 7, 1, 7, 1, 1]));
+    assert!(matches!(out, ColumnBatch::F64(v) if v == [4.0, 7.0, 7.0, 7.0, 1.0, 1.0]));
+
+    let mut mean = Pipeline::from_str("mean:2,decimate:2")
+        .unwrap()
+        .build(RATE)
+        .unwrap();
+    // The last value is the first of a group that is not complete yet.
+    let out = mean.process_batch(&ColumnBatch::F64(vec![2.0, 2.0, 4.0, 4.0, 6.0]));
+    assert!(matches!(out, ColumnBatch::F64(v) if v.len() == 2));
+}
+
+#[test]
+fn test_sample_filter_reset() {
+    let device = Arc::new(DeviceMetadata {
+        serial_number: "SN123".to_string(),
+        firmware_hash: "fw".to_string(),
+        n_streams: 1,
+        session_id: 42,
+        name: "test-device".to_string(),
+    });
+    let stream = Arc::new(StreamMetadata {
+        stream_id: 1,
+        name: "vector".to_string(),
+        n_columns: 2,
+        n_segments: 1,
+        sample_size: 0,
+        buf_samples: 1024,
+    });
+    let segment = Arc::new(SegmentMetadata {
+        stream_id: 1,
+        segment_id: 0,
+        flags: 0,
+        time_ref_epoch: MetadataEpoch::Unix,
+        time_ref_serial: "clock".to_string(),
+        time_ref_session_id: 7,
+        start_time: 0,
+        sampling_rate: 1000,
+        decimation: 1,
+        filter_cutoff: 0.0,
+        filter_type: MetadataFilter::Unfiltered,
+    });
+    let columns: Vec<_> = ["x", "y"]
+        .iter()
+        .enumerate()
+        .map(|(index, name)| {
+            Arc::new(ColumnMetadata {
+                stream_id: 1,
+                index,
+                data_type: DataType::Int32,
+                name: name.to_string(),
+                units: String::new(),
+                description: String::new(),
+            })
+        })
+        .collect();
+    let sample = |n: u32, value: i64, boundary: Option<BoundaryReason>| Sample {
+        n,
+        columns: columns
+            .iter()
+            .map(|desc| Column {
+                value: ColumnData::Int(value),
+                desc: desc.clone(),
+            })
+            .collect(),
+        segment: segment.clone(),
+        stream: stream.clone(),
+        device: device.clone(),
+        source: StreamDataPayload {
+            stream_id: 1,
+            first_sample_n: n,
+            segment_id: 0,
+            data: Vec::new(),
+        },
+        boundary: boundary.map(|reason| Boundary {
+            reason,
+            prior: None,
+        }),
+    };
+
+    let route = DeviceRoute::root();
+    let mut filter = SampleFilter::new(Pipeline::from_str("mean:4,decimate:2").unwrap())
+        .with_columns(twinleaf::data::ColumnFilter::new("**/x").unwrap());
+    let mut out = vec![];
+    for n in 0..6 {
+        let boundary = (n == 0).then_some(BoundaryReason::Initial);
+        out.extend(filter.process(sample(n, 8, boundary), &route).unwrap());
+    }
+    let lost = BoundaryReason::SamplesLost {
+        expected: 6,
+        received: 10,
+    };
+    out.extend(filter.process(sample(10, 0, Some(lost)), &route).unwrap());
+    out.extend(filter.process(sample(11, 0, None), &route).unwrap());
+    // Sample 13 is off the decimated grid, so its boundary moves to 15.
+    let lost = BoundaryReason::SamplesLost {
+        expected: 12,
+        received: 13,
+    };
+    out.extend(filter.process(sample(13, 0, Some(lost)), &route).unwrap());
+    out.extend(filter.process(sample(14, 0, None), &route).unwrap());
+    out.extend(filter.process(sample(15, 0, None), &route).unwrap());
+
+    // Renumbered at half the rate, each spanning the two samples it
+    // replaces, and ending with the second.
+    let numbers: Vec<u32> = out.iter().map(|s| s.n).collect();
+    assert_eq!(numbers, [0, 1, 2, 5, 7]);
+    assert_eq!(out[2].segment.decimation, 2);
+    assert_eq!(out[2].timestamp_begin(), 0.004);
+    let first = sample(1, 8, None);
+    assert_eq!(out[0].timestamp_end(), first.timestamp_end());
+    assert!(matches!(
+        out[0].boundary.as_ref().unwrap().reason,
+        BoundaryReason::Initial
+    ));
+    assert!(matches!(
+        out[3].boundary.as_ref().unwrap().reason,
+        BoundaryReason::SamplesLost {
+            expected: 3,
+            received: 5
+        }
+    ));
+    assert!(matches!(
+        out[4].boundary.as_ref().unwrap().reason,
+        BoundaryReason::SamplesLost {
+            expected: 6,
+            received: 7
+        }
+    ));
+    // The mean started over after samples were lost, and `y` is untouched.
+    let last = out.last().unwrap();
+    assert!(matches!(last.columns[0].value, ColumnData::Float(x) if x == 0.0));
+    assert!(matches!(last.columns[1].value, ColumnData::Int(0)));
+    assert!(matches!(out[1].columns[1].value, ColumnData::Int(8)));
+    assert!(matches!(last.columns[0].desc.data_type, DataType::Float64));
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use common::SampleBuilder;
+use twinleaf::data::{
+    BoundaryReason, Buffer, ColumnBatch, ColumnData, CursorPosition, Sample, FILL_I64,
+};
+use twinleaf::tio::proto::identifiers::{ColumnKey, StreamKey};
+use twinleaf::tio::proto::DeviceRoute;
+
+/// Sample `n` of a 1 Hz stream holding `n` in a float and an integer column.
+fn sample(n: u32, boundary: Option<BoundaryReason>) -> Sample {
+    SampleBuilder::new(n)
+        .column("col_0", ColumnData::Float(n as f64))
+        .column("col_1", ColumnData::Int(n as i64))
+        .boundary(boundary)
+        .build()
+}
+
+/// Samples 0 to 4, then 5 to 7 lost, then 8 and 9.
+fn fill(buffer: &mut Buffer, key: &StreamKey) {
+    for n in 0..5 {
+        buffer.process_sample(sample(n, None), key.clone());
+    }
+    let lost = BoundaryReason::SamplesLost {
+        expected: 5,
+        received: 8,
+    };
+    buffer.process_sample(sample(8, Some(lost)), key.clone());
+    buffer.process_sample(sample(9, None), key.clone());
+}
+
+fn keys() -> (StreamKey, Vec<ColumnKey>) {
+    let route = DeviceRoute::root();
+    let columns = vec![
+        ColumnKey::new(route.clone(), 1, 0),
+        ColumnKey::new(route.clone(), 1, 1),
+    ];
+    (StreamKey::new(route, 1), columns)
+}
+
+#[test]
+fn small_gaps_are_filled_within_the_run() {
+    let (key, columns) = keys();
+    let mut buffer = Buffer::new(16).with_gap_fill(3);
+    for n in 0..5 {
+        buffer.process_sample(sample(n, None), key.clone());
+    }
+    let run_id = buffer.get_run(&key).unwrap().run_id;
+    let cursor = HashMap::from([(
+        key.clone(),
+        CursorPosition {
+            run_id,
+            last_sample_number: 3,
+        },
+    )]);
+    let lost = BoundaryReason::SamplesLost {
+        expected: 5,
+        received: 8,
+    };
+    buffer.process_sample(sample(8, Some(lost)), key.clone());
+    buffer.process_sample(sample(9, None), key.clone());
+    assert_eq!(buffer.get_run(&key).unwrap().run_id, run_id);
+
+    let window = buffer.read_since_cursor(&columns, &cursor).unwrap();
+    assert_eq!(window.sample_numbers[&key], [4, 5, 6, 7, 8, 9]);
+    assert_eq!(window.timestamps, [5.0, 6.0, 7.0, 8.0, 9.0, 10.0]);
+    assert_eq!(window.gaps[&key], [Range { start: 1, end: 4 }]);
+    let ColumnBatch::F64(floats) = &window.columns[&columns[0]] else {
+        panic!("expected a float column");
+    };
+    assert_eq!(floats[0], 4.0);
+    assert!(floats[1..4].iter().all(|v| v.is_nan()));
+    assert!(matches!(
+        &window.columns[&columns[1]],
+        ColumnBatch::I64(v) if v[1..4] == [FILL_I64; 3] && v[4] == 8
+    ));
+    assert!(window.column_f64(&columns[1]).unwrap()[2].is_nan());
+
+    let window = buffer.read_aligned_window(&columns, 2).unwrap();
+    assert!(window.gaps.is_empty());
+}
+
+#[test]
+fn large_gaps_start_a_new_run() {
+    let (key, columns) = keys();
+    // Gap fill is off by default.
+    for mut buffer in [Buffer::new(16).with_gap_fill(2), Buffer::new(16)] {
+        fill(&mut buffer, &key);
+        let window = buffer.read_aligned_window(&columns, 2).unwrap();
+        assert_eq!(window.sample_numbers[&key], [8, 9]);
+        assert_eq!(window.run_ids[&key], 1);
+        assert!(window.gaps.is_empty());
+    }
+}
+
+#[test]
+fn gaps_across_a_rate_change_start_a_new_run() {
+    let (key, columns) = keys();
+    let mut buffer = Buffer::new(16).with_gap_fill(3);
+    for n in 0..5 {
+        buffer.process_sample(sample(n, None), key.clone());
+    }
+    let lost = BoundaryReason::SamplesLost {
+        expected: 5,
+        received: 8,
+    };
+    let faster = SampleBuilder::new(8)
+        .column("col_0", ColumnData::Float(8.0))
+        .column("col_1", ColumnData::Int(8))
+        .rate(2)
+        .boundary(Some(lost))
+        .build();
+    buffer.process_sample(faster, key.clone());
+
+    let window = buffer.read_aligned_window(&columns, 1).unwrap();
+    assert_eq!(window.run_ids[&key], 1);
+    assert!(window.gaps.is_empty());
+}
+
+#[test]
+fn fill_values_are_numbers_without_gap_fill() {
+    let (key, columns) = keys();
+    let mut buffer = Buffer::new(16);
+    let mut min = sample(0, None);
+    min.columns[1].value = ColumnData::Int(FILL_I64);
+    buffer.process_sample(min, key.clone());
+
+    let window = buffer.read_aligned_window(&columns, 1).unwrap();
+    assert_eq!(window.column_f64(&columns[1]).unwrap(), [i64::MIN as f64]);
+}
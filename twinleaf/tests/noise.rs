@@ -0,0 +1,117 @@
+use twinleaf::data::noise::{
+    allan_deviation, decade_factors, modified_allan_deviation, octave_factors, Averaging,
+    NoiseError, Welch, Window,
+};
+
+/// Gaussian white noise with standard deviation `sigma`, from a fixed seed.
+fn white_noise(n: usize, sigma: f64) -> Vec<f64> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut uniform = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 11) as f64 / (1u64 << 53) as f64
+    };
+    (0..n)
+        .map(|_| {
+            let (u, v) = (uniform().max(f64::MIN_POSITIVE), uniform());
+            sigma * (-2.0 * u.ln()).sqrt() * (2.0 * std::f64::consts::PI * v).cos()
+        })
+        .collect()
+}
+
+fn close(a: f64, b: f64, tolerance: f64) -> bool {
+    (a - b).abs() <= tolerance * b.abs()
+}
+
+#[test]
+fn test_factors() {
+    assert_eq!(octave_factors(10), [1, 2, 4, 8]);
+    assert_eq!(decade_factors(100), [1, 2, 5, 10, 20, 50, 100]);
+}
+
+#[test]
+fn test_allan_deviation() {
+    let rate = 100.0;
+    let values = white_noise(100_000, 2.0);
+    let adev = allan_deviation(&values, rate, &[1, 100, 1_000_000]).unwrap();
+    assert_eq!(adev.len(), 2);
+    assert_eq!(adev[0].tau, 0.01);
+    assert!(close(adev[0].deviation, 2.0, 0.02), "{:?}", adev[0]);
+    // White noise averages down as tau^-1/2.
+    assert!(close(adev[1].deviation, 0.2, 0.1), "{:?}", adev[1]);
+
+    let mdev = modified_allan_deviation(&values, rate, &[1, 100]).unwrap();
+    assert!(close(mdev[0].deviation, adev[0].deviation, 1e-9));
+    // For white noise, MDEV settles to about 0.7 of ADEV at long times.
+    assert!(mdev[1].deviation < adev[1].deviation);
+
+    assert!(matches!(
+        allan_deviation(&values, 0.0, &[1]),
+        Err(NoiseError::InvalidRate(_))
+    ));
+    assert!(matches!(
+        allan_deviation(&[1.0], rate, &[1]),
+        Err(NoiseError::TooFewSamples { .. })
+    ));
+}
+
+#[test]
+fn test_welch() {
+    let rate = 1000.0;
+    let sigma = 0.5;
+    let values = white_noise(200_000, sigma);
+    for averaging in [Averaging::Mean, Averaging::Median] {
+        let spectrum = Welch::new(1000)
+            .with_averaging(averaging)
+            .psd(&values, rate)
+            .unwrap();
+        assert_eq!(spectrum.resolution, 1.0);
+        assert_eq!(spectrum.frequencies.len(), 501);
+        assert_eq!(spectrum.segments, 399);
+        // The one-sided density of white noise is 2 sigma^2 / rate.
+        let floor = spectrum.noise_floor(10.0, 400.0).unwrap();
+        assert!(close(floor, (2.0 * sigma * sigma / rate).sqrt(), 0.05));
+        // Over the full band, the noise integrates back to sigma.
+        let rms = spectrum.band_rms(0.0, 500.0).unwrap();
+        assert!(close(rms, sigma, 0.02), "{} {}", averaging, rms);
+    }
+
+    // A sine has an RMS of amplitude / sqrt(2), concentrated around its
+    // frequency.
+    let sine: Vec<f64> = (0..10_000)
+        .map(|i| 3.0 * (2.0 * std::f64::consts::PI * 50.0 * i as f64 / rate).sin())
+        .collect();
+    let spectrum = Welch::new(500)
+        .with_window(Window::FlatTop)
+        .psd(&sine, rate)
+        .unwrap();
+    let rms = spectrum.band_rms(40.0, 60.0).unwrap();
+    assert!(close(rms, 3.0 / 2f64.sqrt(), 0.01), "{}", rms);
+    assert!(spectrum.band_rms(100.0, 400.0).unwrap() < 1e-3);
+
+    assert!(matches!(
+        spectrum.band_rms(60.0, 40.0),
+        Err(NoiseError::InvalidBand { .. })
+    ));
+    assert!(matches!(
+        Welch::new(500).with_overlap(1.0).psd(&sine, rate),
+        Err(NoiseError::InvalidSegment { .. })
+    ));
+    assert!(matches!(
+        Welch::new(20_000).psd(&sine, rate),
+        Err(NoiseError::TooFewSamples { .. })
+    ));
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("Hann".parse::<Window>().unwrap(), Window::Hann);
+    assert_eq!("flattop".parse::<Window>().unwrap(), Window::FlatTop);
+    assert_eq!(Window::Blackman.to_string(), "blackman");
+    assert_eq!("median".parse::<Averaging>().unwrap(), Averaging::Median);
+    assert!(matches!(
+        "kaiser".parse::<Window>(),
+        Err(NoiseError::UnknownName { .. })
+    ));
+}
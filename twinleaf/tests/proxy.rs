@@ -0,0 +1,579 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use twinleaf::tio::proto::meta::{DeviceMetadata, MetadataType};
+use twinleaf::tio::proto::{
+    self, DeviceRoute, Packet, Payload, RpcErrorCode, RpcMethod, StreamDataPayload,
+};
+use twinleaf::tio::proxy::{
+    ClientQos, Event, Interface, Port, ProxyOptions, ProxyRecorder, RpcAuditLog,
+};
+use twinleaf::tio::util::PacketBuilder;
+
+type RpcHandler = dyn Fn(&str, &[u8]) -> Result<Vec<u8>, RpcErrorCode> + Send + Sync;
+
+/// Device served over TCP on a local port, answering RPCs with a handler.
+/// It can also send packets of its own, and be stopped and started again on
+/// the same port to simulate a link going down.
+struct MockDevice {
+    addr: SocketAddr,
+    rpc: Arc<RpcHandler>,
+    connections: Arc<Mutex<Vec<TcpStream>>>,
+    stopped: Arc<AtomicBool>,
+    listener: Option<JoinHandle<()>>,
+}
+
+impl MockDevice {
+    fn new(
+        rpc: impl Fn(&str, &[u8]) -> Result<Vec<u8>, RpcErrorCode> + Send + Sync + 'static,
+    ) -> MockDevice {
+        let mut device = MockDevice {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            rpc: Arc::new(rpc),
+            connections: Arc::new(Mutex::new(Vec::new())),
+            stopped: Arc::new(AtomicBool::new(false)),
+            listener: None,
+        };
+        device.start();
+        device
+    }
+
+    /// Accept connections again, on the same port if the device was stopped.
+    fn start(&mut self) {
+        let listener = TcpListener::bind(self.addr).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        self.addr = listener.local_addr().unwrap();
+        self.stopped.store(false, Ordering::SeqCst);
+        let rpc = self.rpc.clone();
+        let connections = self.connections.clone();
+        let stopped = self.stopped.clone();
+        self.listener = Some(thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nonblocking(false).unwrap();
+                        connections
+                            .lock()
+                            .unwrap()
+                            .push(stream.try_clone().unwrap());
+                        let rpc = rpc.clone();
+                        thread::spawn(move || serve(stream, &*rpc));
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    Err(e) => panic!("accept failed: {}", e),
+                }
+            }
+        }));
+    }
+
+    fn url(&self) -> String {
+        format!("tcp://{}", self.addr)
+    }
+
+    /// Wait for the proxy to connect.
+    fn wait_connected(&self) {
+        let deadline = Instant::now() + TIMEOUT;
+        while self.connections.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "proxy did not connect");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Send a packet to every connected proxy.
+    fn send(&self, pkt: &Packet) {
+        let raw = pkt.serialize().unwrap();
+        for mut stream in self.connections.lock().unwrap().iter() {
+            let _ = stream.write_all(&raw);
+        }
+    }
+
+    /// Stop accepting connections, and close the open ones.
+    fn stop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        if let Some(listener) = self.listener.take() {
+            listener.join().unwrap();
+        }
+        for stream in self.connections.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for MockDevice {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// How long to wait for anything to happen through the proxy.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn serve(mut stream: TcpStream, rpc: &RpcHandler) {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend(&chunk[..n]),
+        }
+        loop {
+            let (pkt, size) = match Packet::deserialize(&buf) {
+                Ok(res) => res,
+                Err(proto::Error::NeedMore) => break,
+                Err(e) => panic!("bad packet from proxy: {:?}", e),
+            };
+            buf.drain(..size);
+            let Payload::RpcRequest(req) = pkt.payload else {
+                continue;
+            };
+            let RpcMethod::Name(name) = &req.method else {
+                continue;
+            };
+            let builder = PacketBuilder::new(pkt.routing);
+            let reply = match rpc(name, &req.arg) {
+                Ok(reply) => builder.rpc_reply(req.id, &reply),
+                Err(err) => builder.rpc_error(req.id, err),
+            };
+            if stream.write_all(&reply.serialize().unwrap()).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+const DEVICE_RPCS: &[(&str, u16)] = &[
+    ("dev.name", 0x0103),
+    ("rpc.hash", 0x0140),
+    ("rpc.listinfo", 0x0000),
+    ("proxy.stats", 0x0103),
+];
+const DEVICE_HASH: u32 = 0x1234_5678;
+
+/// Device with the RPCs in `DEVICE_RPCS`.
+fn listed_rpcs(name: &str, arg: &[u8]) -> Result<Vec<u8>, RpcErrorCode> {
+    match name {
+        "dev.name" => Ok(b"mock".to_vec()),
+        "rpc.hash" => Ok(DEVICE_HASH.to_le_bytes().to_vec()),
+        "rpc.listinfo" if arg.is_empty() => Ok((DEVICE_RPCS.len() as u16).to_le_bytes().to_vec()),
+        "rpc.listinfo" => {
+            let index = u16::from_le_bytes(arg.try_into().unwrap()) as usize;
+            let (name, meta) = DEVICE_RPCS.get(index).ok_or(RpcErrorCode::InvalidArgs)?;
+            let mut reply = meta.to_le_bytes().to_vec();
+            reply.extend(name.as_bytes());
+            Ok(reply)
+        }
+        "proxy.stats" => Ok(b"device".to_vec()),
+        _ => Err(RpcErrorCode::NotFound),
+    }
+}
+
+#[test]
+fn introspection_rpcs_are_listed_with_the_device_rpcs() {
+    let device = MockDevice::new(listed_rpcs);
+    let options = ProxyOptions {
+        introspection: true,
+        ..Default::default()
+    };
+    let proxy = Interface::new_proxy_with_options(&device.url(), None, None, options);
+    let port = proxy.device_rpc(DeviceRoute::root()).unwrap();
+
+    let count: u16 = port.get("rpc.listinfo").unwrap();
+    let list: Vec<(u16, String)> = (0..count)
+        .map(|i| port.rpc("rpc.listinfo", i).unwrap())
+        .collect();
+    let (own, device_rpcs) = list.split_at(list.len() - DEVICE_RPCS.len());
+    assert!(own.iter().all(|(_, name)| name.starts_with("@proxy.")));
+    assert!(own.contains(&(0x0240, "@proxy.kick".to_string())));
+    for ((meta, name), expected) in device_rpcs.iter().zip(DEVICE_RPCS) {
+        assert_eq!((name.as_str(), *meta), *expected);
+    }
+
+    // Proxy RPCs answer like device ones, without shadowing any.
+    let meta: u16 = port.rpc("rpc.info", "@proxy.uptime").unwrap();
+    assert_eq!(meta, 0x0182);
+    let uptime: f64 = port.get("@proxy.uptime").unwrap();
+    assert!(uptime >= 0.0);
+    let stats: String = port.get("proxy.stats").unwrap();
+    assert_eq!(stats, "device");
+
+    // The list differs from the device's, and so must its hash.
+    let hash: u32 = port.get("rpc.hash").unwrap();
+    assert_ne!(hash, DEVICE_HASH);
+}
+
+/// Writer whose output can be read while the proxy owns it.
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuffer {
+    /// Wait until `n` lines were written, and return them.
+    fn lines(&self, n: usize) -> Vec<String> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            let lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+            if lines.len() >= n {
+                return lines;
+            }
+            assert!(Instant::now() < deadline, "got {:?}", lines);
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// Device with a single `f32` setting.
+fn rate_setting() -> impl Fn(&str, &[u8]) -> Result<Vec<u8>, RpcErrorCode> {
+    let rate = Mutex::new(10.0f32.to_le_bytes().to_vec());
+    move |name, arg| match name {
+        "data.rate" => {
+            let mut rate = rate.lock().unwrap();
+            if !arg.is_empty() {
+                *rate = arg.to_vec();
+            }
+            Ok(rate.clone())
+        }
+        "rpc.info" if arg == b"data.rate" => Ok(0x0342u16.to_le_bytes().to_vec()),
+        _ => Err(RpcErrorCode::NotFound),
+    }
+}
+
+/// `line` without its time and duration, which vary from run to run.
+fn stable_fields(line: &str) -> String {
+    let (head, rest) = line.split_once("\"time\":").unwrap();
+    let (_, rest) = rest.split_once(',').unwrap();
+    let (body, _) = rest.split_once(",\"elapsed_ms\":").unwrap();
+    format!("{}{}}}", head, body)
+}
+
+#[test]
+fn audit_log_records_one_json_line_per_rpc() {
+    let device = MockDevice::new(rate_setting());
+    let log = SharedBuffer::default();
+    let options = ProxyOptions {
+        audit_log: Some(RpcAuditLog::new(log.clone())),
+        ..Default::default()
+    };
+    let proxy = Interface::new_proxy_with_options(&device.url(), None, None, options);
+    let port = proxy
+        .new_peer_port(
+            "10.0.0.7:4242",
+            None,
+            DeviceRoute::root(),
+            usize::MAX,
+            false,
+            false,
+        )
+        .unwrap();
+
+    // The type of data.rate is only known to the proxy once it was written.
+    let _: f32 = port.rpc("data.rate", 2.5f32).unwrap();
+    let _: f32 = port.rpc("data.rate", 0.5f32).unwrap();
+    assert!(port.get::<u8>("data.nope").is_err());
+
+    let lines: Vec<String> = log.lines(3).iter().map(|l| stable_fields(l)).collect();
+    assert_eq!(
+        lines,
+        [
+            r#"{"client":1,"peer":"10.0.0.7:4242","route":"/","method":"data.rate","arg":"00002040","result":"ok","reply":"00002040"}"#,
+            r#"{"client":1,"peer":"10.0.0.7:4242","route":"/","method":"data.rate","type":"f32","arg":0.5,"result":"ok","reply":0.5}"#,
+            r#"{"client":1,"peer":"10.0.0.7:4242","route":"/","method":"data.nope","arg":null,"result":"error","error":"RPC not found","error_code":2}"#,
+        ]
+    );
+}
+
+/// Device called `name`, with serial number `serial`.
+fn identified(
+    name: &'static str,
+    serial: &'static str,
+) -> impl Fn(&str, &[u8]) -> Result<Vec<u8>, RpcErrorCode> {
+    move |rpc, _| match rpc {
+        "dev.name" => Ok(name.as_bytes().to_vec()),
+        "dev.metadata" => {
+            let meta = DeviceMetadata {
+                serial_number: serial.to_string(),
+                firmware_hash: "fw".to_string(),
+                n_streams: 0,
+                session_id: 1,
+                name: name.to_string(),
+            };
+            let (mut body, varlen) = meta.serialize(&[], &[]).unwrap();
+            body.extend(varlen);
+            let mut reply = vec![MetadataType::Device.into(), body.len() as u8];
+            reply.extend(body);
+            Ok(reply)
+        }
+        _ => Err(RpcErrorCode::NotFound),
+    }
+}
+
+/// Wait for RPCs through `port` to reach the device called `name`.
+fn wait_for_device(port: &Port, name: &str) {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if port.get::<String>("dev.name").is_ok_and(|n| n == name) {
+            return;
+        }
+        assert!(Instant::now() < deadline, "never reached {}", name);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn standby_links_take_over_and_hand_back_to_the_primary() {
+    let mut primary = MockDevice::new(identified("primary", "A"));
+    let other = MockDevice::new(identified("other", "B"));
+    let mut standby = MockDevice::new(identified("standby", "A"));
+    let backup = MockDevice::new(identified("backup", "A"));
+    let (events, status) = crossbeam::channel::unbounded();
+    let options = ProxyOptions {
+        standby_urls: vec![other.url(), standby.url(), backup.url()],
+        ..Default::default()
+    };
+    let proxy =
+        Interface::new_proxy_with_options(&primary.url(), Some(TIMEOUT), Some(events), options);
+    let port = proxy.device_rpc(DeviceRoute::root()).unwrap();
+    wait_for_device(&port, "primary");
+
+    // The next link leads to another device, so the one after it is used.
+    primary.stop();
+    wait_for_device(&port, "standby");
+    let events: Vec<Event> = status.try_iter().collect();
+    assert!(events.iter().any(|e| matches!(e,
+        Event::LinkSerialMismatch { url, expected, found }
+            if *url == other.url() && expected == "A" && found == "B")));
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Event::LinkActive(url) if *url == standby.url())));
+
+    // Once the standby goes down too, the primary is preferred again over
+    // the links after the standby.
+    primary.start();
+    standby.stop();
+    wait_for_device(&port, "primary");
+    assert!(status
+        .try_iter()
+        .any(|e| matches!(e, Event::LinkActive(url) if url == primary.url())));
+}
+
+/// Data packet of `stream_id`, starting at sample `n`.
+fn data(stream_id: u8, n: u32) -> Packet {
+    Packet {
+        payload: Payload::StreamData(StreamDataPayload {
+            stream_id,
+            first_sample_n: n,
+            segment_id: 0,
+            data: vec![0; 4],
+        }),
+        routing: DeviceRoute::root(),
+        ttl: 0,
+    }
+}
+
+/// (stream, first sample) of the data packets queued for `port`.
+fn received(port: &Port) -> Vec<(u8, u32)> {
+    port.try_iter()
+        .filter_map(|pkt| match pkt.payload {
+            Payload::StreamData(data) => Some((data.stream_id, data.first_sample_n)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn data_is_shaped_per_client() {
+    let device = MockDevice::new(|_, _| Err(RpcErrorCode::NotFound));
+    let proxy = Interface::new(&device.url());
+    let all = proxy.tree_full().unwrap();
+    let shaped = ClientQos {
+        streams: Some(vec![1]),
+        decimation: 2,
+        ..Default::default()
+    };
+    let shaped = proxy
+        .new_port_with_qos(None, shaped, None, DeviceRoute::root(), usize::MAX)
+        .unwrap();
+    let limited = ClientQos {
+        max_data_rate: Some(5.0),
+        ..Default::default()
+    };
+    let limited = proxy
+        .new_port_with_qos(None, limited, None, DeviceRoute::root(), usize::MAX)
+        .unwrap();
+    device.wait_connected();
+
+    for n in 0..10 {
+        device.send(&data(1, n));
+        device.send(&data(2, n));
+    }
+    // Every client got its share once the unshaped one got everything.
+    let mut n_all = 0;
+    while n_all < 20 {
+        let pkt = all.receiver().recv_timeout(TIMEOUT).unwrap();
+        if let Payload::StreamData(_) = pkt.payload {
+            n_all += 1;
+        }
+    }
+    assert_eq!(received(&shaped), [(1, 0), (1, 2), (1, 4), (1, 6), (1, 8)]);
+    // Up to one second worth of packets go through at once.
+    let n_limited = received(&limited).len();
+    assert!((5..10).contains(&n_limited), "{} packets", n_limited);
+}
+
+#[test]
+fn rpc_priority_keeps_room_for_replies() {
+    let device = MockDevice::new(identified("mock", "A"));
+    let proxy = Interface::new(&device.url());
+    let qos = ClientQos {
+        rpc_priority: true,
+        ..Default::default()
+    };
+    let prioritized = proxy
+        .new_port_with_qos(None, qos, None, DeviceRoute::root(), usize::MAX)
+        .unwrap();
+    let plain = proxy.tree_full().unwrap();
+    device.wait_connected();
+
+    // More data than the client queues hold, while the clients do not read.
+    let capacity = Interface::get_client_rx_channel_size();
+    for n in 0..(capacity + 100) as u32 {
+        device.send(&data(1, n));
+    }
+    let reserved = capacity / 8;
+    let deadline = Instant::now() + TIMEOUT;
+    while prioritized.receiver().len() < capacity - reserved {
+        assert!(Instant::now() < deadline, "data did not arrive");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(prioritized.get::<String>("dev.name").unwrap(), "mock");
+    // The client without priority fell behind, and was dropped.
+    assert!(plain.get::<String>("dev.name").is_err());
+}
+
+/// Wait for `n` more events matching `f` from the proxy, returning them.
+fn wait_events<T>(
+    status: &crossbeam::channel::Receiver<Event>,
+    n: usize,
+    f: impl Fn(Event) -> Option<T>,
+) -> Vec<T> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut found = vec![];
+    while found.len() < n {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let event = status.recv_timeout(timeout).expect("missing proxy events");
+        found.extend(f(event));
+    }
+    found
+}
+
+/// Packets in a recorded file.
+fn recorded(path: &std::path::Path) -> Vec<Packet> {
+    let raw = std::fs::read(path).unwrap();
+    let mut rest = &raw[..];
+    let mut packets = vec![];
+    while !rest.is_empty() {
+        let (pkt, size) = Packet::deserialize(rest).unwrap();
+        packets.push(pkt);
+        rest = &rest[size..];
+    }
+    packets
+}
+
+#[test]
+fn recorder_rolls_files_starting_with_metadata() {
+    let dir = std::env::temp_dir().join(format!("twinleaf-proxy-record-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let device = MockDevice::new(identified("mock", "A"));
+    let (events, status) = crossbeam::channel::unbounded();
+    let mut n_files = 0;
+    let next_path = {
+        let dir = dir.clone();
+        move || {
+            n_files += 1;
+            dir.join(format!("{}.tio", n_files))
+        }
+    };
+    // Every packet after the first goes to a new file.
+    let options = ProxyOptions {
+        recorder: Some(ProxyRecorder::new(next_path).roll_size(1)),
+        ..Default::default()
+    };
+    let proxy = Interface::new_proxy_with_options(&device.url(), None, Some(events), options);
+    device.wait_connected();
+    let started = |event| match event {
+        Event::RecordFileStarted(path) => Some(path),
+        _ => None,
+    };
+
+    // The first packet makes the recorder ask for the device metadata.
+    device.send(&data(1, 0));
+    let mut files = wait_events(&status, 2, started);
+    device.send(&data(1, 1));
+    device.send(&data(1, 2));
+    files.extend(wait_events(&status, 2, started));
+    drop(proxy);
+    wait_events(&status, 1, |event| {
+        matches!(event, Event::Exiting).then_some(())
+    });
+    // The last file is flushed once the proxy is gone.
+    let deadline = Instant::now() + TIMEOUT;
+    while recorded(&files[3]).len() < 2 {
+        assert!(Instant::now() < deadline, "last file never flushed");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let names: Vec<String> = files
+        .iter()
+        .map(|f| f.file_name().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(names, ["1.tio", "2.tio", "3.tio", "4.tio"]);
+    let packets: Vec<Vec<Packet>> = files.iter().map(|f| recorded(f)).collect();
+    assert!(matches!(
+        packets[0][..],
+        [Packet {
+            payload: Payload::StreamData(_),
+            ..
+        }]
+    ));
+    // The metadata reply, then the metadata it completed.
+    assert!(matches!(
+        packets[1][..],
+        [
+            Packet {
+                payload: Payload::RpcReply(_),
+                ..
+            },
+            Packet {
+                payload: Payload::Metadata(_),
+                ..
+            }
+        ]
+    ));
+    // Later files can be read on their own.
+    for (file, n) in packets[2..].iter().zip(1..) {
+        assert!(matches!(file[0].payload, Payload::Metadata(_)));
+        assert!(matches!(
+            &file[1].payload,
+            Payload::StreamData(data) if data.first_sample_n == n
+        ));
+        assert_eq!(file.len(), 2);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,49 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use twinleaf::device::rpc::evict_stale_caches;
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+fn touch(dir: &Path, name: &str, age: Duration) {
+    let file = fs::File::create(dir.join(name)).unwrap();
+    file.set_modified(SystemTime::now() - age).unwrap();
+}
+
+#[test]
+fn evicts_only_stale_caches_of_the_device() {
+    let dir = std::env::temp_dir().join(format!("twinleaf-rpc-cache-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let stale = [
+        "vmr.1a2b.rpcs",
+        // Left by an interrupted write.
+        "vmr.1a2b.rpcs.4242.tmp",
+    ];
+    let kept = [
+        // Still in use.
+        "vmr.ffff.rpcs",
+        // Another device whose name starts with "vmr.".
+        "vmr.x.1234.rpcs",
+        "vmr2.1a2b.rpcs",
+        "vmr.1a2b.notes",
+        "vmr.zz.rpcs",
+    ];
+    for name in stale.iter().chain(&kept[1..]) {
+        touch(&dir, name, 2 * HOUR);
+    }
+    touch(&dir, kept[0], Duration::ZERO);
+
+    evict_stale_caches(&dir, "vmr", HOUR);
+
+    let mut left: Vec<String> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    left.sort();
+    let mut expected = kept.map(String::from).to_vec();
+    expected.sort();
+    assert_eq!(left, expected);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
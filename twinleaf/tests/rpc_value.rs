@@ -0,0 +1,100 @@
+use twinleaf::device::util::{rpc_decode_reply, rpc_encode_arg};
+use twinleaf::device::{RpcValue, RpcValueType};
+use twinleaf::tio::util::{TioRpcReplyable, TioRpcRequestable};
+
+fn le_f32s(values: &[f32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+#[test]
+fn test_array_types() {
+    let vec3 = RpcValueType::from_type_str("[f32; 3]").unwrap();
+    assert_eq!(vec3.type_str(), "[f32; 3]");
+    assert_eq!(vec3.fixed_size(), Some(12));
+
+    let raw = rpc_encode_arg("[1.0, 2.5, -3]", &vec3).unwrap();
+    assert_eq!(raw, le_f32s(&[1.0, 2.5, -3.0]));
+    let value = rpc_decode_reply(&raw, &vec3).unwrap();
+    assert_eq!(value.to_string(), "[1, 2.5, -3]");
+    assert_eq!(rpc_encode_arg(&value.to_string(), &vec3).unwrap(), raw);
+
+    assert!(rpc_encode_arg("[1, 2]", &vec3).is_err());
+    assert!(rpc_decode_reply(&raw[..8], &vec3).is_err());
+
+    let list = RpcValueType::from_type_str("[u16]").unwrap();
+    assert_eq!(list.fixed_size(), None);
+    let raw = rpc_encode_arg("[1, 2, 3, 4]", &list).unwrap();
+    assert_eq!(raw.len(), 8);
+    match rpc_decode_reply(&raw, &list).unwrap() {
+        RpcValue::Array(values) => assert_eq!(values.len(), 4),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(rpc_decode_reply(&raw[..7], &list).is_err());
+    assert!(matches!(
+        rpc_decode_reply(&[], &list).unwrap(),
+        RpcValue::Array(v) if v.is_empty()
+    ));
+
+    // A list for a scalar numeric RPC, and several values in its reply.
+    let f32_type = RpcValueType::Float { size: 4 };
+    let raw = rpc_encode_arg("[1, 2, 3]", &f32_type).unwrap();
+    assert_eq!(raw, le_f32s(&[1.0, 2.0, 3.0]));
+    assert_eq!(
+        rpc_decode_reply(&raw, &f32_type).unwrap().to_string(),
+        "[1, 2, 3]"
+    );
+
+    assert!(RpcValueType::from_type_str("[string; 2]").is_none());
+    assert!(RpcValueType::from_type_str("[f32; x]").is_none());
+}
+
+#[test]
+fn test_struct_types() {
+    let kind = RpcValueType::from_type_str("{gain: f32, offset: [i16; 2], label: string}").unwrap();
+    assert_eq!(
+        kind.type_str(),
+        "{gain: f32, offset: [i16; 2], label: string}"
+    );
+    assert_eq!(kind.fixed_size(), None);
+
+    let raw = rpc_encode_arg(r#"{offset: [-1, 2], gain: 0.5, label: "a, b"}"#, &kind).unwrap();
+    let mut expected = 0.5f32.to_le_bytes().to_vec();
+    expected.extend((-1i16).to_le_bytes());
+    expected.extend(2i16.to_le_bytes());
+    expected.extend(b"a, b");
+    assert_eq!(raw, expected);
+    assert_eq!(
+        rpc_encode_arg(r#"{0.5, [-1, 2], "a, b"}"#, &kind).unwrap(),
+        raw
+    );
+
+    let value = rpc_decode_reply(&raw, &kind).unwrap();
+    assert_eq!(
+        value.to_string(),
+        r#"{gain: 0.5, offset: [-1, 2], label: "a, b"}"#
+    );
+    assert_eq!(rpc_encode_arg(&value.to_string(), &kind).unwrap(), raw);
+
+    assert!(rpc_encode_arg("{gain: 1, offset: [0, 0]}", &kind).is_err());
+    assert!(rpc_encode_arg("{gain: 1, offset: [0, 0], label: x, x: 1}", &kind).is_err());
+
+    // Only the last field can vary in size.
+    assert!(RpcValueType::from_type_str("{a: string, b: u8}").is_none());
+}
+
+#[test]
+fn test_array_rpc_traits() {
+    let raw = [1.0f32, 2.0, 3.0].to_request();
+    assert_eq!(raw, le_f32s(&[1.0, 2.0, 3.0]));
+    assert_eq!(vec![1.0f32, 2.0, 3.0].to_request(), raw);
+
+    assert_eq!(<[f32; 3]>::from_reply(&raw).unwrap(), [1.0, 2.0, 3.0]);
+    assert!(<[f32; 2]>::from_reply(&raw).is_err());
+    assert!(<[f32; 4]>::from_reply(&raw).is_err());
+    assert_eq!(Vec::<f32>::from_reply(&raw).unwrap(), [1.0, 2.0, 3.0]);
+    assert!(Vec::<f32>::from_reply(&raw[..5]).is_err());
+
+    let (pair, rest) = <([u16; 2], Vec<u8>)>::from_reply_prefix(&[1, 0, 2, 0, 7, 8]).unwrap();
+    assert_eq!(pair, ([1, 2], vec![7, 8]));
+    assert!(rest.is_empty());
+}
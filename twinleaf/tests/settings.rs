@@ -0,0 +1,103 @@
+use twinleaf::device::rpc::{SettingDiff, SettingsSnapshot};
+use twinleaf::device::{RpcValue, RpcValueType};
+
+const PROFILE: &str = r#"
+device = "VMR"
+
+[settings]
+"data.rate" = { type = "f32", value = 0.1, persistent = true }
+"cell.mode" = { type = "u8", value = 3 }
+"dev.desc" = { type = "string<16>", value = "bench unit" }
+"#;
+
+#[test]
+fn test_settings_toml_roundtrip() {
+    let snapshot = SettingsSnapshot::from_toml(PROFILE).unwrap();
+    assert_eq!(snapshot.device.as_deref(), Some("VMR"));
+    assert_eq!(snapshot.settings.len(), 3);
+
+    let rate = &snapshot.settings[0];
+    assert_eq!(rate.name, "data.rate");
+    assert!(rate.persistent);
+    assert_eq!(rate.raw, 0.1f32.to_le_bytes());
+
+    let mode = &snapshot.settings[1];
+    assert!(matches!(mode.value, RpcValue::U64(3)));
+    assert!(!mode.persistent);
+
+    let desc = &snapshot.settings[2];
+    assert!(matches!(
+        desc.kind,
+        RpcValueType::String { max_len: Some(16) }
+    ));
+
+    let reloaded = SettingsSnapshot::from_toml(&snapshot.to_toml()).unwrap();
+    assert_eq!(reloaded.device, snapshot.device);
+    for (a, b) in reloaded.settings.iter().zip(&snapshot.settings) {
+        assert_eq!(a.name, b.name);
+        assert_eq!(a.raw, b.raw);
+        assert_eq!(a.persistent, b.persistent);
+    }
+}
+
+#[test]
+fn test_settings_invalid_value() {
+    let bad = "[settings]\n\"cell.mode\" = { type = \"u8\", value = 300 }\n";
+    assert!(SettingsSnapshot::from_toml(bad).is_err());
+    let untyped = "[settings]\n\"cell.mode\" = { value = 3 }\n";
+    assert!(SettingsSnapshot::from_toml(untyped).is_err());
+}
+
+#[test]
+fn test_settings_diff() {
+    let left = SettingsSnapshot::from_toml(PROFILE).unwrap();
+    let right = SettingsSnapshot::from_toml(
+        r#"
+[settings]
+"data.rate" = { type = "f32", value = 0.1 }
+"cell.mode" = { type = "u16", value = 3 }
+"cell.temp" = { type = "f32", value = 25.0, writable = false }
+"#,
+    )
+    .unwrap();
+
+    let diffs = left.diff(&right);
+    let summary: Vec<(&str, &str)> = diffs
+        .iter()
+        .map(|d| {
+            let kind = match d {
+                SettingDiff::OnlyLeft(_) => "left",
+                SettingDiff::OnlyRight(_) => "right",
+                SettingDiff::Type { .. } => "type",
+                SettingDiff::Value { .. } => "value",
+            };
+            (d.name(), kind)
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("cell.mode", "type"),
+            ("cell.temp", "right"),
+            ("data.rate", "value"),
+            ("dev.desc", "left"),
+        ]
+    );
+    assert!(left.diff(&left).is_empty());
+}
+
+#[test]
+fn test_settings_toml_arrays() {
+    let profile = r#"
+[settings]
+"cal.offset" = { type = "[f32; 3]", value = [1.0, -2.5, 3.0] }
+"cal.gain" = { type = "{x: f32, y: f32}", value = { x = 1.0, y = 2.0 } }
+"#;
+    let snapshot = SettingsSnapshot::from_toml(profile).unwrap();
+    assert_eq!(snapshot.settings[0].value.to_string(), "[1, -2.5, 3]");
+    assert_eq!(snapshot.settings[1].value.to_string(), "{x: 1, y: 2}");
+    assert_eq!(snapshot.settings[1].raw.len(), 8);
+
+    let reparsed = SettingsSnapshot::from_toml(&snapshot.to_toml()).unwrap();
+    assert!(snapshot.diff(&reparsed).is_empty());
+}
@@ -0,0 +1,160 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::SampleBuilder;
+use twinleaf::data::{
+    Buffer, ColumnData, ReadError, Sample, SubscriptionManager, SubscriptionSpan,
+};
+use twinleaf::tio::proto::identifiers::{ColumnKey, StreamKey};
+use twinleaf::tio::proto::DeviceRoute;
+
+/// Sample `n` of a 1 Hz stream with one column holding `n`.
+fn sample(n: u32) -> Sample {
+    SampleBuilder::new(n)
+        .column("x", ColumnData::Float(n as f64))
+        .build()
+}
+
+fn push(manager: &mut SubscriptionManager, samples: std::ops::Range<u32>) {
+    let key = StreamKey::new(DeviceRoute::root(), 1);
+    for n in samples {
+        manager.buffer.process_sample(sample(n), key.clone());
+    }
+}
+
+fn column() -> Vec<ColumnKey> {
+    vec![ColumnKey::new(DeviceRoute::root(), 1, 0)]
+}
+
+#[test]
+fn new_samples_are_delivered_once() {
+    let key = StreamKey::new(DeviceRoute::root(), 1);
+    let mut manager = SubscriptionManager::new(Buffer::new(4));
+    let (id, rx) = manager.subscribe_span(column(), SubscriptionSpan::New, Duration::ZERO);
+
+    manager.broadcast();
+    assert!(rx.try_recv().is_err());
+
+    push(&mut manager, 0..3);
+    manager.broadcast();
+    assert_eq!(rx.try_recv().unwrap().sample_numbers[&key], [0, 1, 2]);
+    manager.broadcast();
+    assert!(rx.try_recv().is_err());
+
+    push(&mut manager, 3..5);
+    manager.broadcast();
+    assert_eq!(rx.try_recv().unwrap().sample_numbers[&key], [3, 4]);
+
+    // Samples 5 and 6 leave the buffer before the next delivery.
+    push(&mut manager, 5..11);
+    manager.broadcast();
+    assert_eq!(rx.try_recv().unwrap().sample_numbers[&key], [7, 8, 9, 10]);
+
+    let stats = manager.stats(id).unwrap();
+    assert_eq!((stats.delivered, stats.lagged, stats.errors), (3, 2, 0));
+
+    drop(rx);
+    push(&mut manager, 11..12);
+    manager.broadcast();
+    assert!(manager.stats(id).is_none());
+}
+
+#[test]
+fn callbacks_follow_their_interval() {
+    let mut manager = SubscriptionManager::new(Buffer::new(16));
+    let lengths = Arc::new(Mutex::new(Vec::new()));
+    let seen = lengths.clone();
+    manager.subscribe_callback(
+        column(),
+        SubscriptionSpan::Seconds(2.0),
+        Duration::ZERO,
+        move |window| seen.lock().unwrap().push(window.timestamps.len()),
+    );
+    let seen = lengths.clone();
+    let hourly = manager.subscribe_callback(
+        column(),
+        SubscriptionSpan::Samples(1),
+        Duration::from_secs(3600),
+        move |_| seen.lock().unwrap().push(0),
+    );
+
+    let key = StreamKey::new(DeviceRoute::root(), 1);
+    for n in 0..4 {
+        manager.process_sample(sample(n), key.clone());
+    }
+    assert_eq!(*lengths.lock().unwrap(), [1, 0, 2, 3, 3]);
+    assert_eq!(manager.stats(hourly).unwrap().delivered, 1);
+}
+
+#[test]
+fn errors_and_drops_are_counted() {
+    let mut manager = SubscriptionManager::new(Buffer::new(16));
+    push(&mut manager, 0..3);
+
+    let (missing, _missing_rx) =
+        manager.subscribe(vec![ColumnKey::new(DeviceRoute::root(), 1, 5)], 2);
+    let (full, _full_rx) = manager.subscribe(column(), 2);
+    for _ in 0..12 {
+        manager.broadcast();
+    }
+
+    let stats = manager.stats(missing).unwrap();
+    assert_eq!(stats.errors, 12);
+    assert!(matches!(
+        stats.last_error,
+        Some(ReadError::ColumnNotFound { .. })
+    ));
+    let stats = manager.stats(full).unwrap();
+    assert_eq!((stats.delivered, stats.dropped), (10, 2));
+}
+
+#[test]
+fn interval_starts_at_first_delivery() {
+    let key = StreamKey::new(DeviceRoute::root(), 1);
+    let mut manager = SubscriptionManager::new(Buffer::new(16));
+    let (id, rx) = manager.subscribe_span(
+        column(),
+        SubscriptionSpan::Samples(1),
+        Duration::from_secs(3600),
+    );
+
+    // Nothing to read yet, so the subscription stays due.
+    manager.broadcast();
+    assert!(rx.try_recv().is_err());
+
+    push(&mut manager, 0..1);
+    manager.broadcast();
+    assert_eq!(rx.try_recv().unwrap().sample_numbers[&key], [0]);
+
+    push(&mut manager, 1..2);
+    manager.broadcast();
+    assert!(rx.try_recv().is_err());
+    assert_eq!(manager.stats(id).unwrap().delivered, 1);
+}
+
+#[test]
+fn samples_reach_the_subscriptions_of_their_stream() {
+    let key = StreamKey::new(DeviceRoute::root(), 1);
+    let other = StreamKey::new(DeviceRoute::root(), 2);
+    let mut manager = SubscriptionManager::new(Buffer::new(16));
+    let (first, first_rx) = manager.subscribe_span(column(), SubscriptionSpan::New, Duration::ZERO);
+    let (second, second_rx) = manager.subscribe_span(
+        vec![ColumnKey::new(DeviceRoute::root(), 2, 0)],
+        SubscriptionSpan::New,
+        Duration::ZERO,
+    );
+
+    // One packet's samples are delivered together.
+    manager.process_samples((0..3).map(|n| (sample(n), key.clone())));
+    assert_eq!(first_rx.try_recv().unwrap().sample_numbers[&key], [0, 1, 2]);
+    assert!(first_rx.try_recv().is_err());
+    assert!(second_rx.try_recv().is_err());
+
+    manager.process_samples((0..2).map(|n| (sample(n), other.clone())));
+    assert!(first_rx.try_recv().is_err());
+    assert_eq!(second_rx.try_recv().unwrap().sample_numbers[&other], [0, 1]);
+    assert_eq!(manager.stats(first).unwrap().delivered, 1);
+    assert_eq!(manager.stats(second).unwrap().delivered, 1);
+}